@@ -0,0 +1,73 @@
+//! Batch processing, for stations such as ovens and paint booths that process
+//! several parts together in one cycle of fixed length, however many they hold.
+//!
+//! A batch machine takes between a minimum and a maximum number of parts per
+//! cycle. With recipes, a batch runs one recipe as many times as it holds parts,
+//! withdrawing all the inputs from the input buffers when it starts and
+//! depositing all the outputs in the output buffers when it finishes. Its start
+//! policy decides how long it waits for a batch to fill.
+
+use serde::{Deserialize, Serialize};
+use crate::machine::MachineState;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum StartPolicy {
+    /// Only starts with a full batch.
+    FullBatch,
+    /// Starts with a full batch as soon as there is one, and otherwise with at
+    /// least the minimum once it has been able to for `timeout` time steps.
+    MinimumWithTimeout { timeout: f64 },
+    /// Forms batches of parts of the same recipe, starting the largest batch any
+    /// recipe can form under the same rule as `MinimumWithTimeout`.
+    CompatibleItems { timeout: f64 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatchMode {
+    pub min_size: usize,
+    pub max_size: usize,
+    pub policy: StartPolicy,
+}
+
+impl BatchMode {
+    pub fn new(min_size: usize, max_size: usize, policy: StartPolicy) -> Result<BatchMode, &'static str> {
+        let batch = BatchMode { min_size, max_size, policy };
+        batch.validate()?;
+        Ok(batch)
+    }
+
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.min_size == 0 || self.min_size > self.max_size {
+            return Err("Batch sizes must satisfy 0 < minimum <= maximum.");
+        }
+        match self.policy {
+            StartPolicy::MinimumWithTimeout { timeout } | StartPolicy::CompatibleItems { timeout }
+                if !(timeout.is_finite() && timeout >= 0.0) => Err("Batch timeout must be non-negative."),
+            _ => Ok(()),
+        }
+    }
+
+    /// Decides the size of the batch to start, given how many parts are ready, how
+    /// many of those the machine may start with the room left in its output buffers,
+    /// and how long a minimum batch has been ready. Once the timeout has passed, the batch is cut down to what
+    /// fits. Otherwise returns the state the machine waits in.
+    pub fn start_size(&self, ready: usize, fits: usize, waited: f64) -> Result<usize, MachineState> {
+        let ready = ready.min(self.max_size);
+        let size = match self.policy {
+            StartPolicy::FullBatch => self.max_size,
+            StartPolicy::MinimumWithTimeout { timeout } | StartPolicy::CompatibleItems { timeout } => {
+                let able = ready.min(fits);
+                if able >= self.min_size && waited >= timeout { able } else { self.max_size }
+            }
+        };
+        if ready < size {
+            Err(MachineState::Starved)
+        } else if fits < size {
+            Err(MachineState::Blocked)
+        } else {
+            Ok(size)
+        }
+    }
+}
@@ -0,0 +1,50 @@
+//! Blocking policies, deciding when a machine facing a full output buffer stops.
+//!
+//! Under blocking after service a machine processes whatever it can take from its
+//! input buffers and, if there is no room for the finished part, holds it until
+//! there is. Under blocking before service it takes the part but only starts
+//! processing once there is room downstream, holding the unprocessed part until
+//! then. Under communication blocking it leaves the part in its input buffer until
+//! there is room downstream.
+//!
+//! A recipe machine only starts a recipe once its outputs fit, whatever its policy.
+//! The policy decides what it does with outputs that no longer fit when the cycle
+//! is finished, as for single parts.
+//!
+//! For the decomposition, a part held on a machine adds a place to a buffer. A
+//! finished part held after service adds one to the buffer after the machine: the
+//! part is passed on the moment there is room, and the machine starts its next part
+//! in the same step. A part held before service has already left the buffer before
+//! the machine, freeing a place there, so a buffer of N places followed by a machine
+//! blocking before service is taken as a buffer of N + 1 places under communication
+//! blocking. The place is only free while the machine is blocked, so for such lines
+//! the estimate errs high.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockingPolicy {
+    #[default]
+    AfterService,
+    BeforeService,
+    Communication,
+}
+
+impl BlockingPolicy {
+    /// The places a machine following this policy adds to the buffer after it.
+    pub fn places_after(&self) -> usize {
+        match self {
+            BlockingPolicy::AfterService => 1,
+            BlockingPolicy::BeforeService | BlockingPolicy::Communication => 0,
+        }
+    }
+
+    /// The places a machine following this policy adds to the buffer before it.
+    pub fn places_before(&self) -> usize {
+        match self {
+            BlockingPolicy::BeforeService => 1,
+            BlockingPolicy::AfterService | BlockingPolicy::Communication => 0,
+        }
+    }
+}
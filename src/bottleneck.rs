@@ -0,0 +1,240 @@
+//! Bottleneck detection for transfer lines.
+//!
+//! Three views of the same question, which machine limits the line, are combined:
+//! - the active period method, where at every instant the machine with the longest
+//!   uninterrupted active period (working or under repair) is the bottleneck, and
+//!   overlapping periods at a change of bottleneck count as shifting bottleneck time;
+//! - the arrow method, where every buffer points towards its downstream machine when
+//!   its upstream machine is blocked more often than the downstream one is starved,
+//!   and towards its upstream machine otherwise; machines with no arrows pointing
+//!   away from them are bottlenecks;
+//! - throughput sensitivities to each machine's processing time and failure rate,
+//!   estimated by finite differences over runs sharing the same random streams. The
+//!   failure rate covers every way a machine fails: its markov chain, its failure
+//!   modes and its degradation.
+//!
+//! Machines are ranked by impact, the relative throughput gain from a small relative
+//! improvement of both their processing time and their failure rate.
+
+use uuid::Uuid;
+use crate::machine::MachineState;
+use crate::random::RngRegistry;
+use crate::simulation::SimulationRun;
+use crate::transfer_lines::TransferLine;
+
+/// The relative step used for the finite difference sensitivities.
+const PERTURBATION: f64 = 0.05;
+
+/// Bottleneck measures for a single machine of a transfer line.
+#[derive(Clone, Debug)]
+pub struct MachineBottleneck {
+    pub machine_id: Uuid,
+    /// The position of the machine in the line.
+    pub index: usize,
+    /// Fraction of the run the machine was the only bottleneck.
+    pub sole_bottleneck: f64,
+    /// Fraction of the run the machine was a shifting bottleneck.
+    pub shifting_bottleneck: f64,
+    /// Fraction of the run the machine was active (working or under repair).
+    pub active: f64,
+    pub blocked: f64,
+    pub starved: f64,
+    /// Whether no arrow points away from the machine.
+    pub arrow_bottleneck: bool,
+    /// How strongly the neighbouring buffers point at the machine.
+    pub arrow_severity: f64,
+    /// Change in throughput per unit increase of processing time.
+    pub processing_time_sensitivity: f64,
+    /// Change in throughput per relative increase of every failure rate of the
+    /// machine, or None when nothing on the machine fails.
+    pub failure_rate_sensitivity: Option<f64>,
+    /// Relative throughput gain from a one percent improvement of processing time
+    /// and failure rate, in percent.
+    pub impact: f64,
+}
+
+/// The result of a bottleneck analysis, with machines ranked by impact.
+#[derive(Clone, Debug)]
+pub struct BottleneckReport {
+    /// The throughput of the unperturbed run.
+    pub throughput: f64,
+    /// The machines ordered from highest to lowest impact.
+    pub machines: Vec<MachineBottleneck>,
+}
+
+impl BottleneckReport {
+    /// Returns the machine with the highest impact, if the line has any machines.
+    pub fn primary(&self) -> Option<&MachineBottleneck> {
+        self.machines.first()
+    }
+}
+
+/// Simulates the line for the given horizon and ranks its machines by bottleneck impact.
+/// Every run, including the perturbed ones, uses a registry with the same seed, so the
+/// sensitivities are estimated with common random numbers. The line itself is not modified.
+pub fn analyse(line: &TransferLine, horizon: usize, seed: u64) -> BottleneckReport {
+    let base = line.clone().run(horizon, &mut RngRegistry::new(seed));
+    let throughput = base.throughput();
+    let (sole, shifting) = active_period_bottlenecks(&base);
+
+    let mut machines: Vec<MachineBottleneck> = line.machines.iter()
+        .enumerate()
+        .map(|(i, machine)| {
+            let (arrow_bottleneck, arrow_severity) = arrow_bottleneck(&base, i);
+            let processing_time = machine.processing_time.mean();
+            let processing_time_sensitivity = {
+                let step = processing_time * PERTURBATION;
+                let mut perturbed = line.clone();
+                perturbed.set_processing_time(i, machine.processing_time.scaled(1.0 + PERTURBATION));
+                let run = perturbed.run(horizon, &mut RngRegistry::new(seed));
+                (run.throughput() - throughput) / step
+            };
+            let failure_rate_sensitivity = {
+                let mut perturbed = line.clone();
+                perturbed.machines[i].scale_failure_rates(1.0 + PERTURBATION).then(|| {
+                    let run = perturbed.run(horizon, &mut RngRegistry::new(seed));
+                    (run.throughput() - throughput) / PERTURBATION
+                })
+            };
+            let impact = if throughput > 0.0 {
+                -(processing_time_sensitivity * processing_time + failure_rate_sensitivity.unwrap_or(0.0)) / throughput
+            } else {
+                0.0
+            };
+            MachineBottleneck {
+                machine_id: machine.id,
+                index: i,
+                sole_bottleneck: sole[i],
+                shifting_bottleneck: shifting[i],
+                active: active_fraction(&base, i),
+                blocked: base.state_fraction(i, MachineState::Blocked),
+                starved: base.state_fraction(i, MachineState::Starved),
+                arrow_bottleneck,
+                arrow_severity,
+                processing_time_sensitivity,
+                failure_rate_sensitivity,
+                impact,
+            }
+        })
+        .collect();
+
+    machines.sort_by(|a, b| {
+        b.impact.total_cmp(&a.impact)
+            .then((b.sole_bottleneck + b.shifting_bottleneck).total_cmp(&(a.sole_bottleneck + a.shifting_bottleneck)))
+    });
+    BottleneckReport { throughput, machines }
+}
+
+fn active_fraction(run: &SimulationRun, machine_index: usize) -> f64 {
+    if run.is_empty() {
+        return 0.0;
+    }
+    let active = run.states.iter().filter(|step| step[machine_index].is_active()).count();
+    active as f64 / run.len() as f64
+}
+
+/// Returns the sole and shifting bottleneck fractions of every machine under the
+/// active period method.
+pub fn active_period_bottlenecks(run: &SimulationRun) -> (Vec<f64>, Vec<f64>) {
+    let horizon = run.len();
+    let num_machines = run.num_machines;
+    let mut sole = vec![0.0; num_machines];
+    let mut shifting = vec![0.0; num_machines];
+    if horizon == 0 {
+        return (sole, shifting);
+    }
+
+    // The active period of every machine containing every time step, as (start, end) inclusive.
+    let periods: Vec<Vec<Option<(usize, usize)>>> = (0..num_machines)
+        .map(|m| {
+            let mut periods = vec![None; horizon];
+            let mut t = 0;
+            while t < horizon {
+                if !run.states[t][m].is_active() {
+                    t += 1;
+                    continue;
+                }
+                let start = t;
+                while t < horizon && run.states[t][m].is_active() {
+                    t += 1;
+                }
+                for period in periods.iter_mut().take(t).skip(start) {
+                    *period = Some((start, t - 1));
+                }
+            }
+            periods
+        })
+        .collect();
+
+    let bottleneck: Vec<Option<usize>> = (0..horizon)
+        .map(|t| {
+            (0..num_machines)
+                .filter_map(|m| periods[m][t].map(|(start, end)| (m, end - start + 1)))
+                .fold(None, |best: Option<(usize, usize)>, (m, length)| match best {
+                    Some((_, best_length)) if best_length >= length => best,
+                    _ => Some((m, length)),
+                })
+                .map(|(m, _)| m)
+        })
+        .collect();
+
+    let mut is_shifting = vec![vec![false; horizon]; num_machines];
+    for t in 1..horizon {
+        if let (Some(previous), Some(next)) = (bottleneck[t - 1], bottleneck[t]) {
+            if previous == next {
+                continue;
+            }
+            let (previous_start, previous_end) = periods[previous][t - 1].unwrap();
+            let (next_start, next_end) = periods[next][t].unwrap();
+            let overlap_start = previous_start.max(next_start);
+            let overlap_end = previous_end.min(next_end);
+            for marked in is_shifting[previous][overlap_start..=overlap_end].iter_mut() {
+                *marked = true;
+            }
+            for marked in is_shifting[next][overlap_start..=overlap_end].iter_mut() {
+                *marked = true;
+            }
+        }
+    }
+
+    for t in 0..horizon {
+        for m in 0..num_machines {
+            if is_shifting[m][t] {
+                shifting[m] += 1.0;
+            } else if bottleneck[t] == Some(m) {
+                sole[m] += 1.0;
+            }
+        }
+    }
+    for m in 0..num_machines {
+        sole[m] /= horizon as f64;
+        shifting[m] /= horizon as f64;
+    }
+    (sole, shifting)
+}
+
+/// Returns whether the machine is a bottleneck under the arrow method, along with
+/// its severity, the total difference between blocking and starvation across the
+/// buffers on either side of it.
+pub fn arrow_bottleneck(run: &SimulationRun, machine_index: usize) -> (bool, f64) {
+    let blocked = |m: usize| run.state_fraction(m, MachineState::Blocked);
+    let starved = |m: usize| run.state_fraction(m, MachineState::Starved);
+    let mut is_bottleneck = true;
+    let mut severity = 0.0;
+
+    // The buffer upstream points downstream, at this machine, when the machine feeding
+    // it is blocked more often than this machine is starved.
+    if machine_index > 0 {
+        let difference = blocked(machine_index - 1) - starved(machine_index);
+        is_bottleneck &= difference > 0.0;
+        severity += difference.abs();
+    }
+    // The buffer downstream points upstream, at this machine, when the machine it
+    // feeds is starved at least as often as this machine is blocked.
+    if machine_index + 1 < run.num_machines {
+        let difference = blocked(machine_index) - starved(machine_index + 1);
+        is_bottleneck &= difference <= 0.0;
+        severity += difference.abs();
+    }
+    (is_bottleneck, severity)
+}
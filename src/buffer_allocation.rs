@@ -0,0 +1,69 @@
+//! Buffer allocation for transfer lines.
+//!
+//! Buffer space is handed out one unit at a time to the buffer where it raises the
+//! analytical throughput of the line most, starting from one unit per buffer. The
+//! throughput of a line grows with every buffer's capacity and with diminishing
+//! returns, so the greedy allocation is a good one, and it is cheap since each unit
+//! only needs one decomposition per buffer.
+
+use crate::decomposition::{decompose, LineModel};
+
+#[derive(Clone, Debug)]
+pub struct BufferAllocation {
+    pub capacities: Vec<usize>,
+    /// Parts leaving the line per time step with these capacities.
+    pub throughput: f64,
+}
+
+impl BufferAllocation {
+    pub fn total(&self) -> usize {
+        self.capacities.iter().sum()
+    }
+}
+
+/// Shares the given total buffer space between the buffers of the line so as to
+/// maximise its throughput.
+pub fn allocate(model: &LineModel, total: usize) -> Result<BufferAllocation, &'static str> {
+    let buffers = model.capacities.len();
+    if total < buffers {
+        return Err("Every buffer needs room for at least one part.");
+    }
+    let mut allocation = evaluate(model, vec![1; buffers])?;
+    while allocation.total() < total && buffers > 0 {
+        allocation = best_increment(model, &allocation)?;
+    }
+    Ok(allocation)
+}
+
+/// Finds the smallest total buffer space, up to a limit, with which the line reaches
+/// the target throughput, and how to share it. Returns the best allocation found at
+/// the limit if the target is not reached.
+pub fn allocate_for_target(model: &LineModel, target: f64, limit: usize) -> Result<(BufferAllocation, bool), &'static str> {
+    let buffers = model.capacities.len();
+    let mut allocation = evaluate(model, vec![1; buffers])?;
+    while allocation.throughput < target && allocation.total() < limit && buffers > 0 {
+        allocation = best_increment(model, &allocation)?;
+    }
+    let reached = allocation.throughput >= target;
+    Ok((allocation, reached))
+}
+
+/// Adds one unit of space to whichever buffer gains the line the most throughput.
+fn best_increment(model: &LineModel, allocation: &BufferAllocation) -> Result<BufferAllocation, &'static str> {
+    let mut best: Option<BufferAllocation> = None;
+    for buffer in 0..allocation.capacities.len() {
+        let mut capacities = allocation.capacities.clone();
+        capacities[buffer] += 1;
+        let candidate = evaluate(model, capacities)?;
+        if best.as_ref().is_none_or(|best| candidate.throughput > best.throughput) {
+            best = Some(candidate);
+        }
+    }
+    best.ok_or("The line has no buffers.")
+}
+
+fn evaluate(model: &LineModel, capacities: Vec<usize>) -> Result<BufferAllocation, &'static str> {
+    let candidate = LineModel { capacities, ..model.clone() };
+    let throughput = decompose(&candidate)?.throughput;
+    Ok(BufferAllocation { capacities: candidate.capacities, throughput })
+}
@@ -0,0 +1,360 @@
+//! The command line interface.
+//!
+//! Every subcommand reads a model description file (see `model_file`) and works on
+//! one of its transfer lines, chosen with `--line` when the model has several.
+//! Results are printed as tables, JSON or CSV. Problems with the model are printed
+//! to standard error, one per line as `file:line: message`, and make the program
+//! exit with status 1; usage errors exit with status 2.
+
+use std::path::{Path, PathBuf};
+use clap::{Parser, Subcommand};
+use serde_json::{json, Value};
+use crate::buffer_allocation::{allocate, allocate_for_target};
+use crate::clock::ClockMode;
+use crate::decomposition::{decompose, step_machine, LineModel};
+use crate::failure::FailureSummary;
+use crate::maintenance;
+use crate::degradation;
+use crate::model_file::{load, FactoryModel};
+use crate::queue::{departure_cv, effective_cv, kingman_waiting_time, Queue};
+use crate::replication::{replicate, ReplicationConfig};
+use crate::report::{render, OutputFormat, Table};
+use crate::transfer_lines::TransferLine;
+
+/// The exit status for a model that could not be loaded or used.
+const MODEL_ERROR: i32 = 1;
+/// The total buffer space searched up to when optimising for a target throughput.
+const DEFAULT_SPACE_LIMIT: usize = 1000;
+
+#[derive(Parser)]
+#[command(name = "manufacturing_systems", version, about = "Simulate, analyse and optimise manufacturing system models.")]
+pub struct Cli {
+    #[command(subcommand)]
+    command: Command,
+    /// How results are printed.
+    #[arg(long, value_enum, default_value = "table", global = true)]
+    format: OutputFormat,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Simulate a transfer line over independent replications.
+    Simulate {
+        model: PathBuf,
+        #[arg(long)]
+        line: Option<String>,
+        /// Time steps per replication, including warm-up.
+        #[arg(long, default_value_t = 10_000)]
+        horizon: usize,
+        /// Independent replications, at least two for a confidence interval.
+        #[arg(long, default_value_t = 10, value_parser = replication_count)]
+        replications: usize,
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+        /// Time steps discarded at the start of each replication; detected with
+        /// MSER-5 when not given.
+        #[arg(long)]
+        warmup: Option<usize>,
+        /// Pace the replications in real time at this many time steps per wall clock
+        /// second; as fast as possible when not given.
+        #[arg(long, value_parser = speed)]
+        speed: Option<f64>,
+    },
+    /// Compute analytical machine, queue and decomposition metrics of a transfer line.
+    Analyze {
+        model: PathBuf,
+        #[arg(long)]
+        line: Option<String>,
+    },
+    /// Allocate buffer space to a transfer line.
+    Optimize {
+        model: PathBuf,
+        #[arg(long)]
+        line: Option<String>,
+        /// The total buffer space to share out; the line's current total by default.
+        /// With --target, the most space to consider.
+        #[arg(long)]
+        total: Option<usize>,
+        /// Find the least buffer space reaching this throughput, in parts per time step.
+        #[arg(long)]
+        target: Option<f64>,
+    },
+    /// Check a model file.
+    Validate {
+        model: PathBuf,
+    },
+}
+
+/// Parses `--replications`; a single replication gives no confidence interval.
+fn replication_count(value: &str) -> Result<usize, String> {
+    let count: usize = value.parse().map_err(|error| format!("{}", error))?;
+    if count < 2 {
+        return Err("at least 2 replications are needed".to_string());
+    }
+    Ok(count)
+}
+
+/// Parses `--speed`, which must be a positive number of time steps per second.
+fn speed(value: &str) -> Result<f64, String> {
+    let speed: f64 = value.parse().map_err(|error| format!("{}", error))?;
+    if !(speed.is_finite() && speed > 0.0) {
+        return Err("the speed must be a positive number".to_string());
+    }
+    Ok(speed)
+}
+
+/// Runs the command line and returns the exit status.
+pub fn run() -> i32 {
+    let cli = Cli::parse();
+    let result = match &cli.command {
+        Command::Simulate { model, line, horizon, replications, seed, warmup, speed } => {
+            let mut config = ReplicationConfig::new(*horizon, *seed);
+            config.warmup = *warmup;
+            if let Some(speed) = *speed {
+                config.clock = ClockMode::ScaledRealTime { speed };
+            }
+            load_line(model, line.as_deref()).map(|(factory, line)| simulate(&factory, &line, &config, *replications))
+        }
+        Command::Analyze { model, line } => {
+            load_line(model, line.as_deref()).and_then(|(factory, line)| analyze(&factory, &line))
+        }
+        Command::Optimize { model, line, total, target } => {
+            load_line(model, line.as_deref()).and_then(|(factory, line)| optimize(&factory, &line, *total, *target))
+        }
+        Command::Validate { model } => load_model(model).map(|factory| validate(&factory)),
+    };
+    match result {
+        Ok(tables) => {
+            print!("{}", render(&tables, cli.format));
+            0
+        }
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{}", error);
+            }
+            MODEL_ERROR
+        }
+    }
+}
+
+fn load_model(path: &Path) -> Result<FactoryModel, Vec<String>> {
+    load(path).map_err(|errors| {
+        errors.into_iter()
+            .map(|error| match error.line {
+                Some(line) => format!("{}:{}: {}", path.display(), line, error.message),
+                None => format!("{}: {}", path.display(), error.message),
+            })
+            .collect()
+    })
+}
+
+/// Loads a model and builds the named line, or its only line.
+fn load_line(path: &Path, name: Option<&str>) -> Result<(FactoryModel, TransferLine), Vec<String>> {
+    let factory = load_model(path)?;
+    let lines = factory.model.lines();
+    let line_id = match name {
+        Some(name) => factory.line_id(name).ok_or_else(|| vec![format!("{}: no line named '{}'", path.display(), name)])?,
+        None if lines.len() == 1 => lines[0].id,
+        None if lines.is_empty() => return Err(vec![format!("{}: the model declares no lines", path.display())]),
+        None => return Err(vec![format!("{}: the model has several lines; choose one with --line", path.display())]),
+    };
+    let line = factory.model.build_line(line_id).map_err(|error| vec![format!("{}: {}", path.display(), error)])?;
+    Ok((factory, line))
+}
+
+fn machine_name(factory: &FactoryModel, line: &TransferLine, index: usize) -> String {
+    factory.name(line.machines[index].id).map_or_else(|| format!("machine {}", index), str::to_string)
+}
+
+fn buffer_name(factory: &FactoryModel, line: &TransferLine, index: usize) -> String {
+    let id = line.buffers[index].lock().unwrap().id;
+    factory.name(id).map_or_else(|| format!("buffer {}", index), str::to_string)
+}
+
+fn simulate(factory: &FactoryModel, line: &TransferLine, config: &ReplicationConfig, replications: usize) -> Vec<Table> {
+    let report = replicate(line, config, replications);
+    let mut table = Table::new("simulation", &["kpi", "mean", "half_width", "lower", "upper"]);
+    for kpi in &report.kpis {
+        let estimate = &kpi.estimate;
+        let name = named_kpi(factory, line, &kpi.name);
+        table.push(vec![json!(name), json!(estimate.mean), json!(estimate.half_width), json!(estimate.lower()), json!(estimate.upper())]);
+    }
+    let mut runs = Table::new("replications", &["replications", "horizon", "warmup", "confidence"]);
+    let warmup = report.replications.iter().map(|replication| replication.warmup).sum::<usize>() as f64
+        / report.replications.len().max(1) as f64;
+    runs.push(vec![json!(report.replications.len()), json!(config.horizon), json!(warmup), json!(config.confidence)]);
+    vec![table, runs]
+}
+
+/// Replaces machine and buffer positions in a KPI name with their declared names.
+fn named_kpi(factory: &FactoryModel, line: &TransferLine, kpi: &str) -> String {
+    let entity = |prefix: &str| -> Option<(usize, String)> {
+        let rest = kpi.strip_prefix(prefix)?;
+        let (index, measure) = rest.split_once('_')?;
+        Some((index.parse().ok()?, measure.to_string()))
+    };
+    if let Some((index, measure)) = entity("machine_") {
+        return format!("{} {}", machine_name(factory, line, index), measure);
+    }
+    if let Some((index, measure)) = entity("buffer_") {
+        return format!("{} {}", buffer_name(factory, line, index), measure);
+    }
+    kpi.to_string()
+}
+
+fn analyze(factory: &FactoryModel, line: &TransferLine) -> Result<Vec<Table>, Vec<String>> {
+    let model = LineModel::of_line(line);
+    let decomposition = decompose(&model).map_err(|error| vec![error.to_string()])?;
+    let throughput = decomposition.throughput;
+
+    let mut machines = Table::new("machines", &[
+        "machine", "processing_time", "processing_cv", "blocking", "availability", "isolated_rate", "starved", "blocked", "utilisation",
+        "mm1_queue_length", "mm1_waiting_time", "effective_cv", "gg1_waiting_time",
+    ]);
+    let mut bottleneck: Option<(usize, f64)> = None;
+    // Parts enter the line as a Poisson stream.
+    let mut arrival_cv = 1.0;
+    for (index, machine) in line.machines.iter().enumerate() {
+        let processing_time = machine.processing_time.mean();
+        let availability = step_machine(machine).efficiency();
+        let isolated_rate = availability / machine.time_per_part();
+        if bottleneck.is_none_or(|(_, rate)| isolated_rate < rate) {
+            bottleneck = Some((index, isolated_rate));
+        }
+        let failures = FailureSummary::of_machine(machine);
+        let service_cv = if failures.rate > 0.0 {
+            effective_cv(processing_time, machine.processing_time.cv(), availability, failures.repair_time, failures.repair_cv)
+        } else {
+            machine.processing_time.cv()
+        };
+        // Each machine as an M/M/1 and as a G/G/1 station fed at the line's throughput.
+        let queue = Queue::new(throughput, isolated_rate, 0);
+        let stable = throughput < isolated_rate;
+        let finite = |value: f64| if stable { json!(value) } else { Value::Null };
+        let gg1_waiting_time = kingman_waiting_time(throughput, isolated_rate, arrival_cv, service_cv);
+        arrival_cv = departure_cv(throughput / isolated_rate, arrival_cv, service_cv);
+        machines.push(vec![
+            json!(machine_name(factory, line, index)),
+            json!(processing_time),
+            json!(machine.processing_time.cv()),
+            json!(machine.blocking),
+            json!(availability),
+            json!(isolated_rate),
+            json!(decomposition.starved(index)),
+            json!(decomposition.blocked(index)),
+            json!(throughput / isolated_rate),
+            finite(queue.avg_num_items()),
+            finite(queue.avg_time_in_queue()),
+            json!(service_cv),
+            finite(gg1_waiting_time),
+        ]);
+    }
+
+    let mut buffers = Table::new("buffers", &["buffer", "capacity", "average_level", "starvation", "blocking", "production_rate"]);
+    for (index, estimate) in decomposition.buffers.iter().enumerate() {
+        buffers.push(vec![
+            json!(buffer_name(factory, line, index)),
+            json!(model.capacities[index]),
+            json!(estimate.solution.average_level),
+            json!(estimate.solution.starvation),
+            json!(estimate.solution.blocking),
+            json!(estimate.solution.production_rate / model.cycle_time),
+        ]);
+    }
+
+    let mut summary = Table::new("line", &["throughput", "wip", "cycle_time", "bottleneck", "iterations", "converged"]);
+    let cycle_time = if throughput > 0.0 { json!(decomposition.wip / throughput) } else { Value::Null };
+    summary.push(vec![
+        json!(throughput),
+        json!(decomposition.wip),
+        cycle_time,
+        json!(bottleneck.map(|(index, _)| machine_name(factory, line, index))),
+        json!(decomposition.iterations),
+        json!(decomposition.converged),
+    ]);
+    let mut tables = vec![machines, buffers, summary];
+
+    // Each maintained machine's policy against running it to failure.
+    let mut policies = Table::new("maintenance", &[
+        "machine", "trigger", "availability", "cost_rate", "failure_rate", "maintenance_rate",
+        "run_to_failure_availability", "run_to_failure_cost_rate",
+    ]);
+    for (index, machine) in line.machines.iter().enumerate() {
+        let (Some(policy), Some(comparison)) = (&machine.maintenance.policy, maintenance::compare(machine)) else {
+            continue;
+        };
+        policies.push(vec![
+            json!(machine_name(factory, line, index)),
+            json!(policy.trigger.name()),
+            json!(comparison.policy.availability),
+            json!(comparison.policy.cost_rate),
+            json!(comparison.policy.failure_rate),
+            json!(comparison.policy.maintenance_rate),
+            json!(comparison.run_to_failure.availability),
+            json!(comparison.run_to_failure.cost_rate),
+        ]);
+    }
+    if line.machines.iter().any(|machine| machine.maintenance.policy.is_some()) {
+        tables.push(policies);
+    }
+
+    // Each degrading machine maintained at every stage it could be, the failed stage
+    // meaning running it to failure.
+    let mut thresholds = Table::new("degradation", &[
+        "machine", "threshold", "availability", "throughput", "cost_rate", "failure_rate", "maintenance_rate", "optimal",
+    ]);
+    for (index, machine) in line.machines.iter().enumerate() {
+        let Some(stages) = machine.degradation.as_ref().map(|degradation| &degradation.stages) else {
+            continue;
+        };
+        let optimal = degradation::optimal_threshold(machine).map(|evaluation| evaluation.threshold);
+        for evaluation in degradation::thresholds(machine) {
+            thresholds.push(vec![
+                json!(machine_name(factory, line, index)),
+                json!(stages[evaluation.threshold].name),
+                json!(evaluation.availability),
+                json!(evaluation.throughput),
+                json!(evaluation.cost_rate),
+                json!(evaluation.failure_rate),
+                json!(evaluation.maintenance_rate),
+                json!(optimal == Some(evaluation.threshold)),
+            ]);
+        }
+    }
+    if line.machines.iter().any(|machine| machine.degradation.is_some()) {
+        tables.push(thresholds);
+    }
+    Ok(tables)
+}
+
+fn optimize(factory: &FactoryModel, line: &TransferLine, total: Option<usize>, target: Option<f64>) -> Result<Vec<Table>, Vec<String>> {
+    let model = LineModel::of_line(line);
+    let current = decompose(&model).map_err(|error| vec![error.to_string()])?;
+    let current_total: usize = model.capacities.iter().sum();
+    let (allocation, reached) = match target {
+        Some(target) => allocate_for_target(&model, target, total.unwrap_or(DEFAULT_SPACE_LIMIT)),
+        None => allocate(&model, total.unwrap_or(current_total)).map(|allocation| (allocation, true)),
+    }
+    .map_err(|error| vec![error.to_string()])?;
+
+    let mut buffers = Table::new("allocation", &["buffer", "current_capacity", "capacity"]);
+    for (index, capacity) in allocation.capacities.iter().enumerate() {
+        buffers.push(vec![json!(buffer_name(factory, line, index)), json!(model.capacities[index]), json!(capacity)]);
+    }
+    let mut summary = Table::new("line", &["current_total", "total", "current_throughput", "throughput", "target_reached"]);
+    summary.push(vec![json!(current_total), json!(allocation.total()), json!(current.throughput), json!(allocation.throughput), json!(reached)]);
+    Ok(vec![buffers, summary])
+}
+
+fn validate(factory: &FactoryModel) -> Vec<Table> {
+    let model = &factory.model;
+    let mut table = Table::new("model", &["items", "recipes", "machines", "buffers", "lines"]);
+    table.push(vec![
+        json!(model.catalogue().items().len()),
+        json!(model.catalogue().recipes().len()),
+        json!(model.machines().len()),
+        json!(model.buffers().len()),
+        json!(model.lines().len()),
+    ]);
+    vec![table]
+}
@@ -0,0 +1,230 @@
+//! A virtual simulation clock.
+//!
+//! Simulated time is decoupled from wall time. Events are scheduled at simulated
+//! times and handed out in time order by `SimClock::next_event`. In as fast as
+//! possible mode the clock jumps straight to the next event. In scaled real time
+//! mode simulated time tracks wall time at a fixed multiple, so at 60x speed one
+//! simulated minute passes every wall clock second, which is what live dashboards
+//! and operator training need.
+//!
+//! The clock can be paused, resumed and stepped one event at a time through a
+//! `ClockControl` handle, which can be cloned and used from other tasks while the
+//! simulation awaits its next event.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio::time::{sleep_until, Duration, Instant};
+
+/// How simulated time relates to wall time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClockMode {
+    /// Advance straight to the next scheduled event.
+    AsFastAsPossible,
+    /// Advance `speed` simulated time units per wall clock second.
+    ScaledRealTime { speed: f64 },
+}
+
+struct ControlState {
+    paused: bool,
+    /// Events still to be released while paused.
+    steps: usize,
+    mode: ClockMode,
+    /// Bumped on every change so a waiting clock knows to re-anchor.
+    generation: u64,
+}
+
+/// A handle for pausing, resuming and stepping a clock from anywhere.
+#[derive(Clone)]
+pub struct ClockControl {
+    state: Arc<Mutex<ControlState>>,
+    changed: Arc<Notify>,
+}
+
+impl ClockControl {
+    fn update(&self, change: impl FnOnce(&mut ControlState)) {
+        let mut state = self.state.lock().unwrap();
+        change(&mut state);
+        state.generation += 1;
+        drop(state);
+        self.changed.notify_waiters();
+    }
+
+    pub fn pause(&self) {
+        self.update(|state| state.paused = true);
+    }
+
+    pub fn resume(&self) {
+        self.update(|state| {
+            state.paused = false;
+            state.steps = 0;
+        });
+    }
+
+    /// Releases the next event while paused, without waiting for wall time.
+    pub fn step(&self) {
+        self.update(|state| state.steps += 1);
+    }
+
+    pub fn set_mode(&self, mode: ClockMode) {
+        self.update(|state| state.mode = mode);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.lock().unwrap().paused
+    }
+
+    pub fn mode(&self) -> ClockMode {
+        self.state.lock().unwrap().mode
+    }
+}
+
+struct Scheduled<E> {
+    time: f64,
+    /// Breaks ties between events at the same time in scheduling order.
+    sequence: u64,
+    event: E,
+}
+
+impl<E> PartialEq for Scheduled<E> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<E> Eq for Scheduled<E> {}
+
+impl<E> PartialOrd for Scheduled<E> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<E> Ord for Scheduled<E> {
+    // Reversed so that the max heap pops the earliest event first.
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.time.total_cmp(&self.time).then(other.sequence.cmp(&self.sequence))
+    }
+}
+
+/// A simulation clock handing out scheduled events of type E in time order.
+pub struct SimClock<E> {
+    now: f64,
+    events: BinaryHeap<Scheduled<E>>,
+    sequence: u64,
+    control: ClockControl,
+    /// The control generation, wall time and simulated time from which scaled real
+    /// time is measured. Kept across events so that time spent handling events does
+    /// not accumulate as drift, and reset whenever the clock is controlled.
+    anchor: Option<(u64, Instant, f64)>,
+}
+
+impl<E> SimClock<E> {
+    pub fn new(mode: ClockMode) -> SimClock<E> {
+        SimClock {
+            now: 0.0,
+            events: BinaryHeap::new(),
+            sequence: 0,
+            control: ClockControl {
+                state: Arc::new(Mutex::new(ControlState { paused: false, steps: 0, mode, generation: 0 })),
+                changed: Arc::new(Notify::new()),
+            },
+            anchor: None,
+        }
+    }
+
+    /// Returns the current simulated time. While running in scaled real time it moves
+    /// on with wall time between events, up to the time of the next event.
+    pub fn now(&self) -> f64 {
+        let state = self.control.state.lock().unwrap();
+        match (state.mode, self.anchor, self.peek_time()) {
+            (ClockMode::ScaledRealTime { speed }, Some((generation, wall, simulated)), Some(next_time))
+                if !state.paused && generation == state.generation =>
+            {
+                (simulated + wall.elapsed().as_secs_f64() * speed).clamp(self.now, next_time.max(self.now))
+            }
+            _ => self.now,
+        }
+    }
+
+    /// Returns a handle for controlling the clock from other tasks.
+    pub fn control(&self) -> ClockControl {
+        self.control.clone()
+    }
+
+    /// Schedules an event at the given simulated time. Events in the past are
+    /// scheduled at the current time.
+    pub fn schedule(&mut self, time: f64, event: E) {
+        let time = time.max(self.now);
+        self.sequence += 1;
+        self.events.push(Scheduled { time, sequence: self.sequence, event });
+    }
+
+    /// Schedules an event the given simulated duration from now.
+    pub fn schedule_in(&mut self, delay: f64, event: E) {
+        self.schedule(self.now + delay, event);
+    }
+
+    /// Returns the time of the next scheduled event.
+    pub fn peek_time(&self) -> Option<f64> {
+        self.events.peek().map(|scheduled| scheduled.time)
+    }
+
+    pub fn has_events(&self) -> bool {
+        !self.events.is_empty()
+    }
+
+    /// Waits until the next event is due, advances the clock to it and returns it
+    /// along with its time. Returns None once no events are left.
+    pub async fn next_event(&mut self) -> Option<(f64, E)> {
+        loop {
+            let next_time = self.peek_time()?;
+            let changed = self.control.changed.clone();
+            let notified = changed.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            let (paused, mode, generation) = {
+                let mut state = self.control.state.lock().unwrap();
+                if state.paused && state.steps > 0 {
+                    state.steps -= 1;
+                    drop(state);
+                    return self.pop();
+                }
+                (state.paused, state.mode, state.generation)
+            };
+
+            if paused {
+                notified.await;
+                continue;
+            }
+
+            match mode {
+                ClockMode::AsFastAsPossible => return self.pop(),
+                ClockMode::ScaledRealTime { speed } => {
+                    let (_, wall, simulated) = match self.anchor {
+                        Some(anchor) if anchor.0 == generation => anchor,
+                        _ => {
+                            let fresh = (generation, Instant::now(), self.now);
+                            self.anchor = Some(fresh);
+                            fresh
+                        }
+                    };
+                    let delay = ((next_time - simulated) / speed.max(f64::MIN_POSITIVE)).max(0.0);
+                    let deadline = wall + Duration::from_secs_f64(delay.min(1e9));
+                    tokio::select! {
+                        _ = sleep_until(deadline) => return self.pop(),
+                        _ = &mut notified => continue,
+                    }
+                }
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Option<(f64, E)> {
+        let scheduled = self.events.pop()?;
+        self.now = scheduled.time;
+        Some((scheduled.time, scheduled.event))
+    }
+}
@@ -0,0 +1,212 @@
+//! Unit cost roll-up through the recipe graph.
+//!
+//! The cost of an item made by a recipe is built up from one cycle of its primary
+//! recipe: the rolled up cost of the inputs (material), the machine's cost for the
+//! cycle (machine), overhead charged per time unit of machine time, and the cost of
+//! the cycles lost to scrap, which are paid for but yield nothing. The cycle cost is
+//! shared equally by every unit the cycle outputs. Items no recipe makes, or only
+//! a recycling recipe, cost their `Item::cost`.
+//!
+//! Standard costs charge the machine for its mean processing time per cycle, shared
+//! over a full batch on batch machines. Actual costs charge it for the whole
+//! simulated time, idle, blocked and down time included, shared between the
+//! recipes it ran by completed cycles, so the variance between the two shows what
+//! lost capacity costs per unit.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use uuid::Uuid;
+use crate::machine::{Machine, Recipe};
+use crate::mrp::low_level_codes;
+use crate::recipe_analysis::{primary_recipes, recipe_items};
+
+/// Rates applied on top of machine costs.
+#[derive(Clone, Debug, Default)]
+pub struct CostRates {
+    /// Overhead per time unit of machine time.
+    pub overhead_rate: f64,
+    /// The fraction of cycles scrapped, per recipe id.
+    pub scrap_rates: HashMap<Uuid, f64>,
+}
+
+/// The cost of one good unit of an item, broken down by source.
+#[derive(Clone, Debug)]
+pub struct UnitCost {
+    pub item_id: Uuid,
+    pub name: String,
+    pub material: f64,
+    pub machine: f64,
+    pub overhead: f64,
+    pub scrap: f64,
+    pub total: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct CostReport {
+    pub items: Vec<UnitCost>,
+}
+
+impl CostReport {
+    pub fn item(&self, item_id: Uuid) -> Option<&UnitCost> {
+        self.items.iter().find(|item| item.item_id == item_id)
+    }
+}
+
+/// The difference between the actual and standard cost of an item.
+#[derive(Clone, Debug)]
+pub struct CostVariance {
+    pub item_id: Uuid,
+    pub name: String,
+    pub standard: f64,
+    pub actual: f64,
+    /// Actual less standard; positive when the item cost more than planned.
+    pub variance: f64,
+}
+
+/// Rolls up standard unit costs. Each recipe is costed on the first machine that
+/// has it assigned; recipes no machine runs carry no machine or overhead cost.
+pub fn standard_costs(recipes: &[Arc<Recipe>], machines: &[Machine], rates: &CostRates) -> Result<CostReport, &'static str> {
+    roll_up(recipes, rates, |recipe| {
+        machines.iter()
+            .find(|machine| machine.recipes.iter().any(|assigned| assigned.id == recipe.id))
+            .map_or((0.0, 0.0), |machine| (machine.hourly_rate * machine.time_per_part(), machine.time_per_part()))
+    })
+}
+
+/// Rolls up actual unit costs from machines that have been simulated for the given
+/// time. Recipes that completed no cycles are costed at standard.
+pub fn actual_costs(recipes: &[Arc<Recipe>], machines: &[Machine], rates: &CostRates, elapsed: f64) -> Result<CostReport, &'static str> {
+    roll_up(recipes, rates, |recipe| {
+        let mut cost = 0.0;
+        let mut time = 0.0;
+        let mut cycles = 0;
+        for machine in machines {
+            let ran = machine.recipe_cycles.get(&recipe.id).copied().unwrap_or(0);
+            let total: usize = machine.recipe_cycles.values().sum();
+            if ran == 0 {
+                continue;
+            }
+            let share = elapsed * ran as f64 / total as f64;
+            cost += machine.hourly_rate * share;
+            time += share;
+            cycles += ran;
+        }
+        if cycles > 0 {
+            (cost / cycles as f64, time / cycles as f64)
+        } else {
+            machines.iter()
+                .find(|machine| machine.recipes.iter().any(|assigned| assigned.id == recipe.id))
+                .map_or((0.0, 0.0), |machine| (machine.hourly_rate * machine.time_per_part(), machine.time_per_part()))
+        }
+    })
+}
+
+/// Compares actual against standard costs, item by item.
+pub fn variances(standard: &CostReport, actual: &CostReport) -> Vec<CostVariance> {
+    standard.items.iter()
+        .filter_map(|planned| {
+            let actual = actual.item(planned.item_id)?;
+            Some(CostVariance {
+                item_id: planned.item_id,
+                name: planned.name.clone(),
+                standard: planned.total,
+                actual: actual.total,
+                variance: actual.total - planned.total,
+            })
+        })
+        .collect()
+}
+
+/// Rolls up unit costs, deepest components first, with `cycle` giving the machine
+/// cost and machine time of one cycle of a recipe.
+fn roll_up(recipes: &[Arc<Recipe>], rates: &CostRates, cycle: impl Fn(&Recipe) -> (f64, f64)) -> Result<CostReport, &'static str> {
+    let codes = low_level_codes(recipes)?;
+    let primary = primary_recipes(recipes);
+    let mut items = recipe_items(recipes);
+    items.sort_by_key(|item| std::cmp::Reverse(codes[&item.id()]));
+
+    let mut costs: HashMap<Uuid, UnitCost> = HashMap::new();
+    for item in &items {
+        let id = item.id();
+        let cost = match primary.get(&id).filter(|&&index| !recipes[index].recycling) {
+            None => {
+                let material = item.cost.unwrap_or(0.0);
+                UnitCost { item_id: id, name: item.name.clone(), material, machine: 0.0, overhead: 0.0, scrap: 0.0, total: material }
+            }
+            Some(&index) => {
+                let recipe = &recipes[index];
+                let units: f64 = recipe.output.iter().map(|(_, quantity)| quantity).sum();
+                if units <= 0.0 {
+                    return Err("Recipe quantities must be positive.");
+                }
+                let scrap_rate = rates.scrap_rates.get(&recipe.id).copied().unwrap_or(0.0);
+                if !(0.0..1.0).contains(&scrap_rate) {
+                    return Err("Scrap rate must be at least 0 and below 1.");
+                }
+                let material: f64 = recipe.input.iter()
+                    .map(|(input, quantity)| quantity * costs.get(&input.id()).map_or(0.0, |cost| cost.total))
+                    .sum::<f64>() / units;
+                let (machine_cost, machine_time) = cycle(recipe);
+                let machine = machine_cost / units;
+                let overhead = rates.overhead_rate * machine_time / units;
+                let good = material + machine + overhead;
+                let scrap = good * scrap_rate / (1.0 - scrap_rate);
+                UnitCost { item_id: id, name: item.name.clone(), material, machine, overhead, scrap, total: good + scrap }
+            }
+        };
+        costs.insert(id, cost);
+    }
+
+    let items = recipe_items(recipes).iter()
+        .filter_map(|item| costs.remove(&item.id()))
+        .collect();
+    Ok(CostReport { items })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Item;
+    use crate::markov::MarkovChain;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "expected {}, got {}", expected, actual);
+    }
+
+    #[test]
+    fn rolls_up_standard_costs() {
+        // A press makes a bracket from two blanks at 3 each, taking 4 time units at a
+        // rate of 10 with overhead at 0.5 and one cycle in five scrapped. A bracket
+        // is then split into two halves by a recipe no machine runs.
+        let blank = Arc::new(Item::new("blank".to_string(), 1.0, Some(3.0)));
+        let bracket = Arc::new(Item::new("bracket".to_string(), 1.0, None));
+        let half = Arc::new(Item::new("half".to_string(), 1.0, None));
+        let stamp = Arc::new(Recipe::new("stamp".to_string(), vec![(blank.clone(), 2.0)], vec![(bracket.clone(), 1.0)]));
+        let split = Arc::new(Recipe::new("split".to_string(), vec![(bracket.clone(), 1.0)], vec![(half.clone(), 2.0)]));
+        let mut press = Machine::new(MarkovChain::new(), 4.0, None);
+        press.hourly_rate = 10.0;
+        press.recipes.push(stamp.clone());
+        let rates = CostRates { overhead_rate: 0.5, scrap_rates: HashMap::from([(stamp.id, 0.2)]) };
+        let report = standard_costs(&[stamp, split], &[press], &rates).unwrap();
+
+        assert_close(report.item(blank.id()).unwrap().total, 3.0);
+        let bracket_cost = report.item(bracket.id()).unwrap();
+        assert_close(bracket_cost.material, 6.0);
+        assert_close(bracket_cost.machine, 40.0);
+        assert_close(bracket_cost.overhead, 2.0);
+        assert_close(bracket_cost.scrap, 12.0);
+        assert_close(bracket_cost.total, 60.0);
+        let half_cost = report.item(half.id()).unwrap();
+        assert_close(half_cost.material, 30.0);
+        assert_close(half_cost.total, 30.0);
+    }
+
+    #[test]
+    fn rejects_scrapping_every_cycle() {
+        let blank = Arc::new(Item::new("blank".to_string(), 1.0, Some(3.0)));
+        let bracket = Arc::new(Item::new("bracket".to_string(), 1.0, None));
+        let stamp = Arc::new(Recipe::new("stamp".to_string(), vec![(blank, 1.0)], vec![(bracket, 1.0)]));
+        let rates = CostRates { overhead_rate: 0.0, scrap_rates: HashMap::from([(stamp.id, 1.0)]) };
+        assert!(standard_costs(&[stamp], &[], &rates).is_err());
+    }
+}
@@ -0,0 +1,462 @@
+//! Analytical throughput of transfer lines by decomposition.
+//!
+//! Every machine is modelled as up or down, failing with a per cycle probability
+//! while it works and being repaired with a per cycle probability while it is down.
+//! A line of two machines and a buffer of capacity N is then a Markov chain over the
+//! buffer level and the two machine states, which is solved exactly level by level.
+//!
+//! Longer lines are decomposed into one two-machine line per buffer, whose pseudo
+//! machines stand for everything upstream and downstream of the buffer. Their
+//! parameters are found with the Dallery, David and Xie algorithm, which sweeps the
+//! line forwards and backwards until every two-machine line has the same
+//! production rate, as conservation of flow requires.
+//!
+//! The model has a single cycle time, so a line whose machines have different
+//! processing times is homogenised to the longest of them: every machine is given
+//! the failure probability of one of its own cycles and the repair probability of
+//! one common cycle. This somewhat underestimates how quickly faster machines
+//! refill and drain their buffers. A batch machine counts as a machine taking its
+//! cycle time shared out over a full batch for every part.
+//!
+//! A buffer is given one more place than it has when the machine upstream of it
+//! blocks after service, for the finished part the machine holds while blocked,
+//! and one more when the machine downstream of it blocks before service, for the
+//! part that machine has taken out of it (see `blocking`).
+
+use crate::failure::FailureSummary;
+use crate::machine::Machine;
+use crate::transfer_lines::TransferLine;
+
+/// Probabilities are kept at least this far from 0, so that every pseudo machine
+/// can fail and be repaired and the chains stay irreducible.
+const EPSILON: f64 = 1e-9;
+/// Sweeps stop once the production rates of all buffers agree to this tolerance.
+const TOLERANCE: f64 = 1e-7;
+const MAX_ITERATIONS: usize = 500;
+
+/// A machine of the analytical model, with per cycle failure and repair probabilities.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AnalyticMachine {
+    pub failure: f64,
+    pub repair: f64,
+}
+
+impl AnalyticMachine {
+    pub fn new(failure: f64, repair: f64) -> AnalyticMachine {
+        AnalyticMachine { failure: failure.clamp(EPSILON, 1.0), repair: repair.clamp(EPSILON, 1.0) }
+    }
+
+    /// The fraction of cycles the machine produces a part when it is never starved
+    /// or blocked.
+    pub fn efficiency(&self) -> f64 {
+        self.repair / (self.repair + self.failure)
+    }
+}
+
+/// Two machines joined by a buffer. The upstream machine can pass a part on to a
+/// full buffer in a cycle in which the downstream machine takes one out.
+#[derive(Clone, Copy, Debug)]
+pub struct TwoMachineLine {
+    pub upstream: AnalyticMachine,
+    pub downstream: AnalyticMachine,
+    pub capacity: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TwoMachineSolution {
+    /// Parts per cycle.
+    pub production_rate: f64,
+    /// The probability that the downstream machine is up but the buffer is empty.
+    pub starvation: f64,
+    /// The probability that the upstream machine is up but the buffer is full and
+    /// the downstream machine is down.
+    pub blocking: f64,
+    pub average_level: f64,
+}
+
+impl TwoMachineLine {
+    /// Solves the chain for its steady state. States are numbered within a buffer
+    /// level as 2 * upstream + downstream, with 1 for up, and the levels are reduced
+    /// one by one from the empty buffer upwards before the distribution is
+    /// recovered from the full buffer downwards.
+    pub fn solve(&self) -> TwoMachineSolution {
+        let levels = self.capacity + 1;
+        // blocks[n] holds the transitions from level n to levels n - 1, n and n + 1.
+        let blocks: Vec<[Block; 3]> = (0..levels).map(|level| self.transitions(level)).collect();
+
+        let mut censored: Vec<Block> = Vec::with_capacity(levels);
+        let mut inverses: Vec<Block> = Vec::with_capacity(levels);
+        for level in 0..levels {
+            let mut block = blocks[level][1];
+            if level > 0 {
+                let below = multiply(&multiply(&blocks[level][0], &inverses[level - 1]), &blocks[level - 1][2]);
+                block = add(&block, &below);
+            }
+            inverses.push(invert(&subtract(&identity(), &block)));
+            censored.push(block);
+        }
+
+        let mut distribution = vec![[0.0; 4]; levels];
+        distribution[levels - 1] = stationary(&censored[levels - 1]);
+        for level in (1..levels).rev() {
+            let down = multiply(&blocks[level][0], &inverses[level - 1]);
+            distribution[level - 1] = row_times(&distribution[level], &down);
+        }
+        let total: f64 = distribution.iter().flatten().sum();
+        for probability in distribution.iter_mut().flatten() {
+            *probability /= total;
+        }
+
+        let full = self.capacity;
+        let production_rate = (1..levels).map(|level| distribution[level][1] + distribution[level][3]).sum();
+        TwoMachineSolution {
+            production_rate,
+            starvation: distribution[0][1] + distribution[0][3],
+            blocking: distribution[full][2],
+            average_level: distribution.iter().enumerate().map(|(level, states)| level as f64 * states.iter().sum::<f64>()).sum(),
+        }
+    }
+
+    /// The transitions out of a buffer level, to the level below, the same level and
+    /// the level above.
+    #[allow(clippy::needless_range_loop)]
+    fn transitions(&self, level: usize) -> [Block; 3] {
+        let mut blocks = [[[0.0; 4]; 4]; 3];
+        for state in 0..4 {
+            let upstream_up = state & 2 != 0;
+            let downstream_up = state & 1 != 0;
+            let downstream_works = downstream_up && level > 0;
+            let upstream_works = upstream_up && (level < self.capacity || downstream_works);
+            let next = |machine: &AnalyticMachine, up: bool, works: bool| -> [f64; 2] {
+                match (up, works) {
+                    (true, true) => [machine.failure, 1.0 - machine.failure],
+                    (true, false) => [0.0, 1.0],
+                    (false, _) => [1.0 - machine.repair, machine.repair],
+                }
+            };
+            let upstream = next(&self.upstream, upstream_up, upstream_works);
+            let downstream = next(&self.downstream, downstream_up, downstream_works);
+            let change = upstream_works as usize + 1 - downstream_works as usize;
+            for (up, probability_up) in upstream.iter().enumerate() {
+                for (down, probability_down) in downstream.iter().enumerate() {
+                    blocks[change][state][2 * up + down] += probability_up * probability_down;
+                }
+            }
+        }
+        blocks
+    }
+}
+
+/// The analytical model of a transfer line.
+#[derive(Clone, Debug)]
+pub struct LineModel {
+    pub machines: Vec<AnalyticMachine>,
+    pub capacities: Vec<usize>,
+    /// The places each buffer gains from parts held by the machines on either side
+    /// of it while they are blocked.
+    pub held: Vec<usize>,
+    /// Time steps per cycle.
+    pub cycle_time: f64,
+}
+
+impl LineModel {
+    /// Homogenises the machines of a line to its longest mean processing time.
+    pub fn of_line(line: &TransferLine) -> LineModel {
+        let cycle_time = line.machines.iter().map(|machine| machine.time_per_part()).fold(1.0, f64::max);
+        let machines = line.machines.iter()
+            .map(|machine| {
+                let step = step_machine(machine);
+                let steps = machine.time_per_part().max(1.0);
+                AnalyticMachine::new(1.0 - (1.0 - step.failure).powf(steps), 1.0 - (1.0 - step.repair).powf(cycle_time))
+            })
+            .collect();
+        let capacities = line.buffers.iter().map(|buffer| buffer.lock().unwrap().capacity).collect();
+        let held = line.machines.windows(2)
+            .take(line.buffers.len())
+            // A machine passing its parts to an unlimited sink is never blocked.
+            .map(|pair| {
+                let before = if pair[1].output_buffer.is_empty() { 0 } else { pair[1].blocking.places_before() };
+                pair[0].blocking.places_after() + before
+            })
+            .collect();
+        LineModel { machines, capacities, held, cycle_time }
+    }
+}
+
+/// The analytical model of a simulated machine with a cycle of one time step.
+///
+/// A simulated machine spends the step it fails in down, where the model has it
+/// finish its part first, so the failures of its markov chain and failure modes are
+/// converted to keep the mean number of working steps between failures and the
+/// mean downtime.
+pub fn step_machine(machine: &Machine) -> AnalyticMachine {
+    let failures = FailureSummary::of_machine(machine);
+    let repair = if failures.repair_time > 0.0 { 1.0 / failures.repair_time } else { 1.0 };
+    AnalyticMachine::new(failures.rate, repair)
+}
+
+/// The two-machine line standing for one buffer of a decomposed line.
+#[derive(Clone, Copy, Debug)]
+pub struct BufferEstimate {
+    pub upstream: AnalyticMachine,
+    pub downstream: AnalyticMachine,
+    pub solution: TwoMachineSolution,
+}
+
+#[derive(Clone, Debug)]
+pub struct Decomposition {
+    /// Parts leaving the line per time step.
+    pub throughput: f64,
+    /// Parts leaving the line per cycle.
+    pub production_rate: f64,
+    pub buffers: Vec<BufferEstimate>,
+    /// The expected number of parts in the buffers.
+    pub wip: f64,
+    pub iterations: usize,
+    pub converged: bool,
+}
+
+impl Decomposition {
+    /// The fraction of cycles the machine at the given position is starved.
+    pub fn starved(&self, machine_index: usize) -> f64 {
+        machine_index.checked_sub(1).map_or(0.0, |buffer| self.buffers[buffer].solution.starvation)
+    }
+
+    /// The fraction of cycles the machine at the given position is blocked.
+    pub fn blocked(&self, machine_index: usize) -> f64 {
+        self.buffers.get(machine_index).map_or(0.0, |buffer| buffer.solution.blocking)
+    }
+}
+
+/// Estimates the throughput of a line and the behaviour of its buffers.
+pub fn decompose(model: &LineModel) -> Result<Decomposition, &'static str> {
+    let machines = &model.machines;
+    if machines.is_empty() || model.capacities.len() + 1 != machines.len() {
+        return Err("A transfer line needs one buffer fewer than machines.");
+    }
+    if model.capacities.contains(&0) {
+        return Err("Every buffer needs room for at least one part.");
+    }
+    if machines.len() == 1 {
+        let production_rate = machines[0].efficiency();
+        return Ok(Decomposition {
+            throughput: production_rate / model.cycle_time,
+            production_rate,
+            buffers: Vec::new(),
+            wip: 0.0,
+            iterations: 0,
+            converged: true,
+        });
+    }
+
+    let count = model.capacities.len();
+    let capacities: Vec<usize> = (0..count)
+        .map(|i| model.capacities[i] + model.held.get(i).copied().unwrap_or(0))
+        .collect();
+    let solve = |upstream: AnalyticMachine, downstream: AnalyticMachine, capacity: usize| BufferEstimate {
+        upstream,
+        downstream,
+        solution: TwoMachineLine { upstream, downstream, capacity }.solve(),
+    };
+    let mut buffers: Vec<BufferEstimate> = (0..count)
+        .map(|i| solve(machines[i], machines[i + 1], capacities[i]))
+        .collect();
+
+    let mut iterations = 0;
+    let mut converged = false;
+    while iterations < MAX_ITERATIONS && !converged {
+        iterations += 1;
+        for i in 1..count {
+            let previous = buffers[i - 1];
+            let upstream = upstream_machine(&previous, &machines[i]);
+            buffers[i] = solve(upstream, buffers[i].downstream, capacities[i]);
+        }
+        for i in (0..count - 1).rev() {
+            let next = buffers[i + 1];
+            let downstream = downstream_machine(&next, &machines[i + 1]);
+            buffers[i] = solve(buffers[i].upstream, downstream, capacities[i]);
+        }
+        let rates = buffers.iter().map(|buffer| buffer.solution.production_rate);
+        let (low, high) = rates.fold((f64::INFINITY, 0.0_f64), |(low, high), rate| (low.min(rate), high.max(rate)));
+        converged = high - low <= TOLERANCE * high.max(EPSILON);
+    }
+
+    let production_rate = buffers.iter().map(|buffer| buffer.solution.production_rate).sum::<f64>() / count as f64;
+    Ok(Decomposition {
+        throughput: production_rate / model.cycle_time,
+        production_rate,
+        wip: buffers.iter().map(|buffer| buffer.solution.average_level).sum(),
+        buffers,
+        iterations,
+        converged,
+    })
+}
+
+/// The upstream pseudo machine of a buffer, given the two-machine line of the buffer
+/// before it and the real machine between them. Its downtime mixes the machine's own
+/// failures with the starvation it inherits from upstream.
+fn upstream_machine(previous: &BufferEstimate, machine: &AnalyticMachine) -> AnalyticMachine {
+    let rate = previous.solution.production_rate.max(EPSILON);
+    let share = previous.solution.starvation * previous.upstream.repair / (rate * previous.upstream.failure);
+    let share = share.clamp(0.0, 1.0);
+    let repair = previous.upstream.repair * share + machine.repair * (1.0 - share);
+    let failure = repair * (1.0 / rate + 1.0 / machine.efficiency() - 2.0 - previous.downstream.failure / previous.downstream.repair);
+    AnalyticMachine::new(failure, repair)
+}
+
+/// The downstream pseudo machine of a buffer, given the two-machine line of the
+/// buffer after it and the real machine between them.
+fn downstream_machine(next: &BufferEstimate, machine: &AnalyticMachine) -> AnalyticMachine {
+    let rate = next.solution.production_rate.max(EPSILON);
+    let share = next.solution.blocking * next.downstream.repair / (rate * next.downstream.failure);
+    let share = share.clamp(0.0, 1.0);
+    let repair = next.downstream.repair * share + machine.repair * (1.0 - share);
+    let failure = repair * (1.0 / rate + 1.0 / machine.efficiency() - 2.0 - next.upstream.failure / next.upstream.repair);
+    AnalyticMachine::new(failure, repair)
+}
+
+type Block = [[f64; 4]; 4];
+
+fn identity() -> Block {
+    let mut block = [[0.0; 4]; 4];
+    for (i, row) in block.iter_mut().enumerate() {
+        row[i] = 1.0;
+    }
+    block
+}
+
+fn add(a: &Block, b: &Block) -> Block {
+    let mut sum = *a;
+    for i in 0..4 {
+        for j in 0..4 {
+            sum[i][j] += b[i][j];
+        }
+    }
+    sum
+}
+
+fn subtract(a: &Block, b: &Block) -> Block {
+    let mut difference = *a;
+    for i in 0..4 {
+        for j in 0..4 {
+            difference[i][j] -= b[i][j];
+        }
+    }
+    difference
+}
+
+fn multiply(a: &Block, b: &Block) -> Block {
+    let mut product = [[0.0; 4]; 4];
+    for i in 0..4 {
+        for k in 0..4 {
+            for j in 0..4 {
+                product[i][j] += a[i][k] * b[k][j];
+            }
+        }
+    }
+    product
+}
+
+fn row_times(row: &[f64; 4], block: &Block) -> [f64; 4] {
+    let mut product = [0.0; 4];
+    for (i, value) in row.iter().enumerate() {
+        for j in 0..4 {
+            product[j] += value * block[i][j];
+        }
+    }
+    product
+}
+
+/// Inverts a block by Gauss-Jordan elimination with partial pivoting. States that
+/// cannot be reached leave a zero pivot, which is skipped; their probability is 0.
+fn invert(block: &Block) -> Block {
+    let mut left = *block;
+    let mut right = identity();
+    for column in 0..4 {
+        let pivot = (column..4).max_by(|&a, &b| left[a][column].abs().total_cmp(&left[b][column].abs())).unwrap();
+        if left[pivot][column].abs() < f64::MIN_POSITIVE {
+            continue;
+        }
+        left.swap(column, pivot);
+        right.swap(column, pivot);
+        let scale = left[column][column];
+        for j in 0..4 {
+            left[column][j] /= scale;
+            right[column][j] /= scale;
+        }
+        for row in 0..4 {
+            if row != column {
+                let factor = left[row][column];
+                for j in 0..4 {
+                    left[row][j] -= factor * left[column][j];
+                    right[row][j] -= factor * right[column][j];
+                }
+            }
+        }
+    }
+    right
+}
+
+/// The stationary distribution of a stochastic block, found by solving
+/// x (P - I) = 0 with one equation replaced by the normalisation.
+fn stationary(block: &Block) -> [f64; 4] {
+    // Transposed, so that the distribution is a column: (P - I)^T x = 0.
+    let mut system = [[0.0; 4]; 4];
+    for i in 0..4 {
+        for j in 0..4 {
+            system[i][j] = block[j][i] - if i == j { 1.0 } else { 0.0 };
+        }
+    }
+    system[3] = [1.0; 4];
+    let inverse = invert(&system);
+    [inverse[0][3], inverse[1][3], inverse[2][3], inverse[3][3]]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "expected {}, got {}", expected, actual);
+    }
+
+    #[test]
+    fn solves_two_machine_line_exactly() {
+        // The steady state of the 16 state chain, solved in rational arithmetic.
+        let line = TwoMachineLine {
+            upstream: AnalyticMachine::new(0.1, 0.2),
+            downstream: AnalyticMachine::new(0.05, 0.25),
+            capacity: 3,
+        };
+        let solution = line.solve();
+        assert_close(solution.production_rate, 3_376_882.0 / 5_440_323.0);
+        assert_close(solution.starvation, 2_313_441.0 / 9_067_205.0);
+        assert_close(solution.blocking, 125_000.0 / 1_813_441.0);
+        assert_close(solution.average_level, 7_011_292.0 / 5_440_323.0);
+    }
+
+    #[test]
+    fn reliable_downstream_machine_passes_on_every_part() {
+        // The downstream machine empties the single place every cycle, so the line
+        // makes a part whenever the upstream machine is up: r / (r + p) of cycles.
+        let (failure, repair) = (0.1, 0.3);
+        let line = TwoMachineLine {
+            upstream: AnalyticMachine::new(failure, repair),
+            downstream: AnalyticMachine::new(0.0, 1.0),
+            capacity: 1,
+        };
+        let solution = line.solve();
+        assert!((solution.production_rate - repair / (repair + failure)).abs() < 1e-6);
+        assert!((solution.starvation - failure / (repair + failure)).abs() < 1e-6);
+        assert!(solution.blocking < 1e-6);
+    }
+
+    #[test]
+    fn decomposes_two_machine_line_as_its_exact_solution() {
+        let machines = vec![AnalyticMachine::new(0.1, 0.2), AnalyticMachine::new(0.05, 0.25)];
+        let model = LineModel { machines, capacities: vec![3], held: vec![0], cycle_time: 2.0 };
+        let decomposition = decompose(&model).unwrap();
+        assert_close(decomposition.production_rate, 3_376_882.0 / 5_440_323.0);
+        assert_close(decomposition.throughput, 3_376_882.0 / 5_440_323.0 / 2.0);
+    }
+}
@@ -0,0 +1,389 @@
+//! Condition based degradation of machines.
+//!
+//! A machine may wear through a chain of stages, such as good, worn, critical and
+//! failed, held as a Markov chain over the stages that moves once per working time
+//! step, drawing from the machine's degradation stream. Each stage scales the
+//! processing time of the cycles started in it and may replace the machine's scrap
+//! and rework rates. The last stage is the failed stage: it is absorbing, and a
+//! machine reaching it goes down for a repair drawn from the degradation's time to
+//! repair distribution, after which it is as good as new. A maintenance policy with
+//! a condition trigger maintains the machine once it has reached a threshold stage,
+//! and every maintenance restores the first stage.
+//!
+//! The analysis evaluates each threshold by renewal reward over the absorbing chain.
+//! The expected working steps spent in each stage before the threshold or failure is
+//! reached come from the chain's fundamental matrix, and give the machine's
+//! availability, its good parts per time step and its cost rate, with the duration
+//! and costs of its maintenance policy and the cost of its scrap. The optimal
+//! threshold is the one with the lowest cost rate, and among equally costly ones the
+//! one with the highest throughput.
+
+use std::collections::HashSet;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use crate::distribution::Distribution;
+use crate::machine::Machine;
+use crate::maintenance::MaintenanceTrigger;
+use crate::markov::MarkovChain;
+use crate::quality::YieldRates;
+
+/// Tolerance on probabilities adding up to one.
+const PROBABILITY_TOLERANCE: f64 = 1e-9;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DegradationStage {
+    pub name: String,
+    /// Multiplies the processing time of the cycles started in the stage.
+    pub processing_factor: f64,
+    /// Replaces the machine's scrap and rework rates in the stage.
+    pub rates: Option<YieldRates>,
+}
+
+impl DegradationStage {
+    pub fn new(name: String) -> DegradationStage {
+        DegradationStage { name, processing_factor: 1.0, rates: None }
+    }
+}
+
+/// A per working step probability of moving from one stage to another.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DegradationTransition {
+    pub from: String,
+    pub to: String,
+    pub probability: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Degradation {
+    /// Transitions between the stages per working time step. Its states are the
+    /// stages, in order.
+    pub chain: MarkovChain,
+    pub stages: Vec<DegradationStage>,
+    /// The time steps a failure takes to repair.
+    pub time_to_repair: Distribution,
+    /// The index of the stage the machine is in.
+    pub current: usize,
+    /// Repair time left, in time steps, while the machine is repaired from failure.
+    pub repair_remaining: Option<f64>,
+    /// The number of times the machine has reached the failed stage.
+    pub failures: usize,
+}
+
+impl Degradation {
+    /// Creates a degradation through the given stages, the last being the failed
+    /// one, with no transitions between them yet.
+    pub fn new(stages: Vec<DegradationStage>, time_to_repair: impl Into<Distribution>) -> Degradation {
+        let mut chain = MarkovChain::new();
+        for stage in &stages {
+            chain.add_state(stage.name.clone());
+        }
+        Degradation { chain, stages, time_to_repair: time_to_repair.into(), current: 0, repair_remaining: None, failures: 0 }
+    }
+
+    pub fn stage_index(&self, name: &str) -> Option<usize> {
+        self.stages.iter().position(|stage| stage.name == name)
+    }
+
+    /// Sets the per working step probability of moving from one stage to another.
+    pub fn set_transition(&mut self, from: &str, to: &str, probability: f64) -> Result<(), &'static str> {
+        let (Some(from), Some(to)) = (self.stage_index(from), self.stage_index(to)) else {
+            return Err("Degradation stage not found.");
+        };
+        if from == to {
+            return Err("A degradation stage cannot move to itself.");
+        }
+        self.chain.set_transition_probability(from, to, probability);
+        Ok(())
+    }
+
+    /// Makes the machine wear the given factor faster by scaling every transition
+    /// between stages, keeping the probability of leaving a stage at most one.
+    pub fn scale_wear(&mut self, factor: f64) {
+        for (from, row) in self.transition_matrix().iter().enumerate() {
+            let leaving = (1.0 - row[from]) * factor;
+            let scale = if leaving > 1.0 { factor / leaving } else { factor };
+            for (to, &probability) in row.iter().enumerate() {
+                if to != from && probability > 0.0 {
+                    self.chain.set_transition_probability(from, to, probability * scale);
+                }
+            }
+        }
+    }
+
+    /// The index of the failed stage.
+    pub fn failed(&self) -> usize {
+        self.stages.len() - 1
+    }
+
+    /// The stage the machine is in.
+    pub fn stage(&self) -> &DegradationStage {
+        &self.stages[self.current]
+    }
+
+    /// The stage a maintenance policy maintains the machine in, the failed stage if
+    /// it does not watch the machine's condition.
+    pub fn threshold(&self, trigger: Option<&MaintenanceTrigger>) -> usize {
+        match trigger {
+            Some(MaintenanceTrigger::Condition { stage }) => self.stage_index(stage).unwrap_or(self.failed()),
+            _ => self.failed(),
+        }
+    }
+
+    /// Whether the machine has reached the given stage or a later one.
+    pub fn has_reached(&self, stage: &str) -> bool {
+        self.stage_index(stage).is_some_and(|index| self.current >= index)
+    }
+
+    /// The one step transition probabilities, indexed as matrix[from][to], with the
+    /// probability of staying in a stage on the diagonal.
+    pub fn transition_matrix(&self) -> Vec<Vec<f64>> {
+        let count = self.stages.len();
+        (0..count)
+            .map(|from| {
+                let mut row: Vec<f64> = (0..count)
+                    .map(|to| if to == from { 0.0 } else { self.chain.transition_probability(from, to) })
+                    .collect();
+                row[from] = 1.0 - row.iter().sum::<f64>();
+                row
+            })
+            .collect()
+    }
+
+    /// The expected working steps in each stage before the machine is maintained at
+    /// the given threshold or fails, starting from the first stage: the first row of
+    /// the fundamental matrix (I - Q)^-1 over the stages before the threshold, found
+    /// by solving (I - Q)^T visits = e0.
+    pub fn visits(&self, threshold: usize) -> Option<Vec<f64>> {
+        let matrix = self.transition_matrix();
+        let transient = threshold.clamp(1, self.failed());
+        let system: Vec<Vec<f64>> = (0..transient)
+            .map(|to| (0..transient).map(|from| (if from == to { 1.0 } else { 0.0 }) - matrix[from][to]).collect())
+            .collect();
+        let mut start = vec![0.0; transient];
+        start[0] = 1.0;
+        solve(system, start)
+    }
+
+    /// How much slower than new the machine processes parts on average when it is
+    /// maintained at the given threshold: the working time per part over that of a
+    /// new machine.
+    pub fn mean_processing_factor(&self, threshold: usize) -> f64 {
+        let Some(visits) = self.visits(threshold) else {
+            return 1.0;
+        };
+        let parts: f64 = visits.iter().zip(&self.stages).map(|(visits, stage)| visits / stage.processing_factor).sum();
+        visits.iter().sum::<f64>() / parts
+    }
+
+    /// Whether every stage leaves the machine's own yield alone or is perfect.
+    pub fn is_perfect(&self) -> bool {
+        self.stages.iter().all(|stage| stage.rates.is_none_or(|rates| rates.is_perfect()))
+    }
+
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.stages.len() < 2 {
+            return Err("Degradation needs a working stage and a failed stage.");
+        }
+        let names: HashSet<&str> = self.stages.iter().map(|stage| stage.name.as_str()).collect();
+        if names.len() < self.stages.len() {
+            return Err("Degradation stage names must be unique.");
+        }
+        if !self.chain.is_consistent() || self.chain.states.len() != self.stages.len() || self.current >= self.stages.len() {
+            return Err("Degradation chain does not match its stages.");
+        }
+        for stage in &self.stages {
+            if !(stage.processing_factor.is_finite() && stage.processing_factor > 0.0) {
+                return Err("Processing factors must be positive.");
+            }
+            if let Some(rates) = &stage.rates {
+                rates.validate()?;
+            }
+        }
+        self.time_to_repair.validate()?;
+        let matrix = self.transition_matrix();
+        let valid = |probability: &f64| (-PROBABILITY_TOLERANCE..=1.0 + PROBABILITY_TOLERANCE).contains(probability);
+        if !matrix.iter().flatten().all(valid) {
+            return Err("Degradation probabilities must be between 0 and 1 and add up to at most 1 per stage.");
+        }
+        let failed = self.failed();
+        if matrix[failed][failed] < 1.0 - PROBABILITY_TOLERANCE {
+            return Err("The failed stage must be the last and cannot be left.");
+        }
+        // Every stage has to lead to failure, so that the machine cannot stay in a
+        // stage for ever.
+        let mut leads_to_failure = vec![false; self.stages.len()];
+        leads_to_failure[failed] = true;
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for from in 0..failed {
+                if !leads_to_failure[from] && (0..=failed).any(|to| leads_to_failure[to] && to != from && matrix[from][to] > 0.0) {
+                    leads_to_failure[from] = true;
+                    changed = true;
+                }
+            }
+        }
+        if !leads_to_failure.iter().all(|&leads| leads) {
+            return Err("Every degradation stage must eventually lead to the failed stage.");
+        }
+        Ok(())
+    }
+
+    /// Moves the chain on by a working time step with the given uniform number.
+    /// Returns whether the machine has reached the failed stage.
+    pub fn advance(&mut self, u: f64) -> bool {
+        let mut sum = 0.0;
+        for to in (0..self.stages.len()).filter(|&to| to != self.current) {
+            sum += self.chain.transition_probability(self.current, to);
+            if u < sum {
+                self.current = to;
+                break;
+            }
+        }
+        self.current == self.failed()
+    }
+
+    /// Starts the repair of a failed machine, spending the current time step on it.
+    pub fn start_repair<R: Rng + ?Sized>(&mut self, rng: &mut R) {
+        self.failures += 1;
+        self.repair_remaining = Some(self.time_to_repair.sample(rng) - 1.0);
+    }
+
+    /// Moves the repair in progress on by a time step, as
+    /// `FailureModel::repair_step` does, restoring the first stage once it is done.
+    pub fn repair_step(&mut self) -> Option<bool> {
+        let remaining = self.repair_remaining?;
+        if remaining > 0.0 {
+            self.repair_remaining = Some(remaining - 1.0);
+            return Some(false);
+        }
+        self.repair_remaining = None;
+        self.renew();
+        Some(true)
+    }
+
+    /// Restores the first stage, as maintenance does.
+    pub fn renew(&mut self) {
+        self.current = 0;
+    }
+}
+
+/// The long run behaviour of a machine maintained at a degradation threshold.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ThresholdEvaluation {
+    /// The stage the machine is maintained in, the failed stage for running it to failure.
+    pub threshold: usize,
+    /// Failures per working time step.
+    pub failure_rate: f64,
+    /// Maintenances per working time step.
+    pub maintenance_rate: f64,
+    /// The fraction of time the machine is neither repaired nor maintained, if it
+    /// is never starved or blocked.
+    pub availability: f64,
+    /// Good parts per time step, if the machine is never starved or blocked.
+    pub throughput: f64,
+    /// Failure, maintenance, downtime, resource and scrap cost per time step.
+    pub cost_rate: f64,
+}
+
+/// Evaluates a machine maintained once its degradation reaches the given stage, with
+/// the duration and costs of its maintenance policy. Other failures of the machine
+/// are left out, being the same at every threshold. Returns None if the machine
+/// does not degrade.
+pub fn evaluate_threshold(machine: &Machine, threshold: usize) -> Option<ThresholdEvaluation> {
+    let degradation = machine.degradation.as_ref()?;
+    let matrix = degradation.transition_matrix();
+    let failed = degradation.failed();
+    let threshold = threshold.clamp(1, failed);
+    let visits = degradation.visits(threshold)?;
+
+    let mut failures = 0.0;
+    let mut maintenances = 0.0;
+    for (stage, visits) in visits.iter().enumerate() {
+        failures += visits * matrix[stage][failed];
+        maintenances += visits * matrix[stage][threshold..failed].iter().fold(0.0, |sum, probability| sum + probability);
+    }
+    // The step a machine fails in is spent down rather than working.
+    let working = visits.iter().sum::<f64>() - failures;
+    let policy = machine.maintenance.policy.as_ref();
+    let repair_time = degradation.time_to_repair.mean();
+    let maintenance_time = policy.map_or(0.0, |policy| policy.duration.mean());
+    let cycle = working + failures * repair_time + maintenances * maintenance_time;
+
+    let time_per_part = machine.new_time_per_part();
+    let mut good = 0.0;
+    let mut scrap = 0.0;
+    for (stage, visits) in degradation.stages.iter().zip(&visits) {
+        let parts = visits / (time_per_part * stage.processing_factor);
+        let rates = stage.rates.unwrap_or(machine.quality.rates);
+        good += parts * rates.good();
+        scrap += parts * rates.scrap;
+    }
+    let cost = policy.map_or(0.0, |policy| {
+        failures * (policy.failure_cost + policy.downtime_cost * repair_time)
+            + maintenances * (policy.cost + (policy.downtime_cost + policy.resource_rate()) * maintenance_time)
+    }) + scrap * machine.quality.scrap_cost;
+    Some(ThresholdEvaluation {
+        threshold,
+        failure_rate: failures / working,
+        maintenance_rate: maintenances / working,
+        availability: working / cycle,
+        throughput: good / cycle,
+        cost_rate: cost / cycle,
+    })
+}
+
+/// Evaluates every threshold the machine's maintenance policy could use, from the
+/// second stage to running to failure, or only running to failure without a policy.
+pub fn thresholds(machine: &Machine) -> Vec<ThresholdEvaluation> {
+    let Some(degradation) = &machine.degradation else {
+        return Vec::new();
+    };
+    let first = if machine.maintenance.policy.is_some() { 1 } else { degradation.failed() };
+    (first..=degradation.failed()).filter_map(|threshold| evaluate_threshold(machine, threshold)).collect()
+}
+
+/// The threshold with the lowest cost rate, and among equally costly ones the
+/// highest throughput.
+pub fn optimal_threshold(machine: &Machine) -> Option<ThresholdEvaluation> {
+    thresholds(machine).into_iter().reduce(|best, candidate| {
+        let tolerance = 1e-12 * best.cost_rate.abs().max(1.0);
+        if candidate.cost_rate < best.cost_rate - tolerance
+            || (candidate.cost_rate <= best.cost_rate + tolerance && candidate.throughput > best.throughput)
+        {
+            candidate
+        } else {
+            best
+        }
+    })
+}
+
+/// Solves a small linear system by Gaussian elimination with partial pivoting,
+/// returning None if it is singular.
+fn solve(mut matrix: Vec<Vec<f64>>, mut rhs: Vec<f64>) -> Option<Vec<f64>> {
+    let size = rhs.len();
+    for column in 0..size {
+        let pivot = (column..size).max_by(|&a, &b| matrix[a][column].abs().total_cmp(&matrix[b][column].abs()))?;
+        if matrix[pivot][column].abs() < 1e-14 {
+            return None;
+        }
+        matrix.swap(column, pivot);
+        rhs.swap(column, pivot);
+        let (above, below) = matrix.split_at_mut(column + 1);
+        let pivot_row = &above[column];
+        for (offset, row) in below.iter_mut().enumerate() {
+            let factor = row[column] / pivot_row[column];
+            for (entry, pivot_entry) in row.iter_mut().zip(pivot_row).skip(column) {
+                *entry -= factor * pivot_entry;
+            }
+            rhs[column + 1 + offset] -= factor * rhs[column];
+        }
+    }
+    let mut solution = vec![0.0; size];
+    for row in (0..size).rev() {
+        let known: f64 = (row + 1..size).map(|entry| matrix[row][entry] * solution[entry]).sum();
+        solution[row] = (rhs[row] - known) / matrix[row][row];
+    }
+    Some(solution)
+}
@@ -0,0 +1,405 @@
+//! Probability distributions of durations, such as processing times.
+//!
+//! Every distribution can be sampled from any random stream and has an analytical
+//! mean, variance and coefficient of variation, so that analytical models can use
+//! the same distribution the simulation samples from. All distributions are over
+//! non-negative values: the normal distribution is truncated at zero, and its mean
+//! and variance are those of the truncated distribution.
+//!
+//! Every distribution also has its distribution function, for analytical models of
+//! lifetimes. Samples are drawn by inverting the distribution function wherever it has a
+//! closed form or a good approximation, using one uniform number per sample, so
+//! that antithetic streams give antithetic samples. A deterministic duration draws
+//! nothing from its stream.
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use crate::output_analysis::{normal_cdf, normal_quantile};
+use crate::random::RandomStream;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum Distribution {
+    Deterministic { value: f64 },
+    Exponential { mean: f64 },
+    /// A normal distribution with the given parameters, truncated at zero.
+    Normal { mean: f64, std_dev: f64 },
+    /// The distribution of exp(X) for X normal with mean `mu` and standard deviation `sigma`.
+    #[serde(rename = "lognormal")]
+    LogNormal { mu: f64, sigma: f64 },
+    Gamma { shape: f64, scale: f64 },
+    Weibull { shape: f64, scale: f64 },
+    Triangular { min: f64, mode: f64, max: f64 },
+    Uniform { min: f64, max: f64 },
+    /// Resamples observed values, each with equal probability.
+    Empirical { samples: Vec<f64> },
+}
+
+impl From<f64> for Distribution {
+    fn from(value: f64) -> Distribution {
+        Distribution::Deterministic { value }
+    }
+}
+
+impl Distribution {
+    /// Checks that the parameters describe a distribution of non-negative values
+    /// with a positive mean.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        let positive = |value: f64| value.is_finite() && value > 0.0;
+        let non_negative = |value: f64| value.is_finite() && value >= 0.0;
+        let valid = match self {
+            Distribution::Deterministic { value } => positive(*value),
+            Distribution::Exponential { mean } => positive(*mean),
+            Distribution::Normal { mean, std_dev } => positive(*mean) && non_negative(*std_dev),
+            Distribution::LogNormal { mu, sigma } => mu.is_finite() && non_negative(*sigma),
+            Distribution::Gamma { shape, scale } | Distribution::Weibull { shape, scale } => positive(*shape) && positive(*scale),
+            Distribution::Triangular { min, mode, max } => non_negative(*min) && min <= mode && mode <= max && positive(*max),
+            Distribution::Uniform { min, max } => non_negative(*min) && min <= max && positive(*max),
+            Distribution::Empirical { samples } => {
+                !samples.is_empty() && samples.iter().all(|&sample| non_negative(sample)) && samples.iter().any(|&sample| sample > 0.0)
+            }
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err("Distribution parameters must describe non-negative durations with a positive mean.")
+        }
+    }
+
+    pub fn mean(&self) -> f64 {
+        match self {
+            Distribution::Deterministic { value } => *value,
+            Distribution::Exponential { mean } => *mean,
+            Distribution::Normal { mean, std_dev } => {
+                if *std_dev == 0.0 {
+                    return *mean;
+                }
+                let (_, hazard) = truncation(*mean, *std_dev);
+                mean + std_dev * hazard
+            }
+            Distribution::LogNormal { mu, sigma } => (mu + sigma * sigma / 2.0).exp(),
+            Distribution::Gamma { shape, scale } => shape * scale,
+            Distribution::Weibull { shape, scale } => scale * gamma_function(1.0 + 1.0 / shape),
+            Distribution::Triangular { min, mode, max } => (min + mode + max) / 3.0,
+            Distribution::Uniform { min, max } => (min + max) / 2.0,
+            Distribution::Empirical { samples } => samples.iter().sum::<f64>() / samples.len() as f64,
+        }
+    }
+
+    pub fn variance(&self) -> f64 {
+        match self {
+            Distribution::Deterministic { .. } => 0.0,
+            Distribution::Exponential { mean } => mean * mean,
+            Distribution::Normal { mean, std_dev } => {
+                if *std_dev == 0.0 {
+                    return 0.0;
+                }
+                let (alpha, hazard) = truncation(*mean, *std_dev);
+                std_dev * std_dev * (1.0 + alpha * hazard - hazard * hazard)
+            }
+            Distribution::LogNormal { mu, sigma } => {
+                let sigma2 = sigma * sigma;
+                (sigma2.exp() - 1.0) * (2.0 * mu + sigma2).exp()
+            }
+            Distribution::Gamma { shape, scale } => shape * scale * scale,
+            Distribution::Weibull { shape, scale } => {
+                let first = gamma_function(1.0 + 1.0 / shape);
+                scale * scale * (gamma_function(1.0 + 2.0 / shape) - first * first)
+            }
+            Distribution::Triangular { min, mode, max } => {
+                (min * min + mode * mode + max * max - min * mode - min * max - mode * max) / 18.0
+            }
+            Distribution::Uniform { min, max } => (max - min).powi(2) / 12.0,
+            Distribution::Empirical { samples } => {
+                let mean = self.mean();
+                samples.iter().map(|sample| (sample - mean).powi(2)).sum::<f64>() / samples.len() as f64
+            }
+        }
+    }
+
+    pub fn std_dev(&self) -> f64 {
+        self.variance().max(0.0).sqrt()
+    }
+
+    /// The coefficient of variation, standard deviation over mean.
+    pub fn cv(&self) -> f64 {
+        let mean = self.mean();
+        if mean == 0.0 {
+            return 0.0;
+        }
+        self.std_dev() / mean
+    }
+
+    /// The probability of a value at most `x`.
+    pub fn cdf(&self, x: f64) -> f64 {
+        if x < 0.0 {
+            return 0.0;
+        }
+        let step = |at: f64| if x >= at { 1.0 } else { 0.0 };
+        match self {
+            Distribution::Deterministic { value } => step(*value),
+            Distribution::Exponential { mean } => 1.0 - (-x / mean).exp(),
+            Distribution::Normal { mean, std_dev } => {
+                if *std_dev == 0.0 {
+                    return step(*mean);
+                }
+                let below = normal_cdf(-mean / std_dev);
+                ((normal_cdf((x - mean) / std_dev) - below) / (1.0 - below).max(f64::MIN_POSITIVE)).clamp(0.0, 1.0)
+            }
+            Distribution::LogNormal { mu, sigma } => {
+                if *sigma == 0.0 {
+                    return step(mu.exp());
+                }
+                if x == 0.0 {
+                    return 0.0;
+                }
+                normal_cdf((x.ln() - mu) / sigma)
+            }
+            Distribution::Gamma { shape, scale } => regularized_gamma(*shape, x / scale),
+            Distribution::Weibull { shape, scale } => 1.0 - (-(x / scale).powf(*shape)).exp(),
+            Distribution::Triangular { min, mode, max } => {
+                if x >= *max {
+                    1.0
+                } else if x <= *min {
+                    0.0
+                } else if x <= *mode {
+                    (x - min).powi(2) / ((max - min) * (mode - min))
+                } else {
+                    1.0 - (max - x).powi(2) / ((max - min) * (max - mode))
+                }
+            }
+            Distribution::Uniform { min, max } => {
+                if max == min {
+                    return step(*min);
+                }
+                ((x - min) / (max - min)).clamp(0.0, 1.0)
+            }
+            Distribution::Empirical { samples } => {
+                samples.iter().filter(|&&sample| sample <= x).count() as f64 / samples.len() as f64
+            }
+        }
+    }
+
+    /// Draws a sample.
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> f64 {
+        match self {
+            Distribution::Deterministic { value } => *value,
+            Distribution::Exponential { mean } => -mean * (1.0 - rng.gen::<f64>()).ln(),
+            Distribution::Normal { mean, std_dev } => {
+                if *std_dev == 0.0 {
+                    return *mean;
+                }
+                // Invert the normal distribution function over its part above zero.
+                let below = normal_cdf(-mean / std_dev);
+                let u = below + rng.gen::<f64>() * (1.0 - below);
+                (mean + std_dev * normal_quantile(u)).max(0.0)
+            }
+            Distribution::LogNormal { mu, sigma } => (mu + sigma * normal_quantile(open_uniform(rng))).exp(),
+            Distribution::Gamma { shape, scale } => scale * standard_gamma(*shape, rng),
+            Distribution::Weibull { shape, scale } => scale * (-(1.0 - rng.gen::<f64>()).ln()).powf(1.0 / shape),
+            Distribution::Triangular { min, mode, max } => {
+                let u: f64 = rng.gen();
+                let width = max - min;
+                if width == 0.0 {
+                    return *min;
+                }
+                let split = (mode - min) / width;
+                if u < split {
+                    min + (u * width * (mode - min)).sqrt()
+                } else {
+                    max - ((1.0 - u) * width * (max - mode)).sqrt()
+                }
+            }
+            Distribution::Uniform { min, max } => min + (max - min) * rng.gen::<f64>(),
+            Distribution::Empirical { samples } => {
+                let index = ((rng.gen::<f64>() * samples.len() as f64) as usize).min(samples.len() - 1);
+                samples[index]
+            }
+        }
+    }
+
+    /// Returns the distribution of the values multiplied by a positive factor,
+    /// which scales the mean and keeps the coefficient of variation.
+    pub fn scaled(&self, factor: f64) -> Distribution {
+        match self {
+            Distribution::Deterministic { value } => Distribution::Deterministic { value: value * factor },
+            Distribution::Exponential { mean } => Distribution::Exponential { mean: mean * factor },
+            Distribution::Normal { mean, std_dev } => Distribution::Normal { mean: mean * factor, std_dev: std_dev * factor },
+            Distribution::LogNormal { mu, sigma } => Distribution::LogNormal { mu: mu + factor.ln(), sigma: *sigma },
+            Distribution::Gamma { shape, scale } => Distribution::Gamma { shape: *shape, scale: scale * factor },
+            Distribution::Weibull { shape, scale } => Distribution::Weibull { shape: *shape, scale: scale * factor },
+            Distribution::Triangular { min, mode, max } => {
+                Distribution::Triangular { min: min * factor, mode: mode * factor, max: max * factor }
+            }
+            Distribution::Uniform { min, max } => Distribution::Uniform { min: min * factor, max: max * factor },
+            Distribution::Empirical { samples } => Distribution::Empirical { samples: samples.iter().map(|sample| sample * factor).collect() },
+        }
+    }
+}
+
+/// Samples a distribution from its own seeded stream.
+#[derive(Clone, Debug)]
+pub struct Sampler {
+    pub distribution: Distribution,
+    stream: RandomStream,
+}
+
+impl Sampler {
+    pub fn new(distribution: Distribution, seed: u64) -> Sampler {
+        Sampler { distribution, stream: RandomStream::new(seed, false) }
+    }
+
+    pub fn next_sample(&mut self) -> f64 {
+        self.distribution.sample(&mut self.stream)
+    }
+}
+
+/// For a normal distribution truncated at zero, returns the standardised truncation
+/// point and the inverse Mills ratio at it.
+fn truncation(mean: f64, std_dev: f64) -> (f64, f64) {
+    let alpha = -mean / std_dev;
+    let density = (-alpha * alpha / 2.0).exp() / (2.0 * std::f64::consts::PI).sqrt();
+    let above = (1.0 - normal_cdf(alpha)).max(f64::MIN_POSITIVE);
+    (alpha, density / above)
+}
+
+/// A uniform number strictly between 0 and 1.
+fn open_uniform<R: Rng + ?Sized>(rng: &mut R) -> f64 {
+    rng.gen::<f64>().clamp(f64::EPSILON, 1.0 - f64::EPSILON)
+}
+
+/// Samples a gamma distribution with unit scale by the method of Marsaglia and
+/// Tsang, boosting shapes below one. This is the one sampler that uses more than
+/// one uniform number per sample.
+fn standard_gamma<R: Rng + ?Sized>(shape: f64, rng: &mut R) -> f64 {
+    if shape < 1.0 {
+        return standard_gamma(shape + 1.0, rng) * open_uniform(rng).powf(1.0 / shape);
+    }
+    let d = shape - 1.0 / 3.0;
+    let c = 1.0 / (9.0 * d).sqrt();
+    loop {
+        let z = normal_quantile(open_uniform(rng));
+        let v = (1.0 + c * z).powi(3);
+        if v <= 0.0 {
+            continue;
+        }
+        let u = open_uniform(rng);
+        if u.ln() < z * z / 2.0 + d - d * v + d * v.ln() {
+            return d * v;
+        }
+    }
+}
+
+/// The regularized lower incomplete gamma function P(a, x), by its series below
+/// a + 1 and its continued fraction above, as in Numerical Recipes.
+fn regularized_gamma(a: f64, x: f64) -> f64 {
+    const ITERATIONS: usize = 500;
+    const EPSILON: f64 = 1e-14;
+    if x <= 0.0 {
+        return 0.0;
+    }
+    let prefactor = (-x + a * x.ln() - gamma_function(a).ln()).exp();
+    if x < a + 1.0 {
+        let mut term = 1.0 / a;
+        let mut sum = term;
+        for n in 1..ITERATIONS {
+            term *= x / (a + n as f64);
+            sum += term;
+            if term.abs() < sum.abs() * EPSILON {
+                break;
+            }
+        }
+        return (sum * prefactor).min(1.0);
+    }
+    // Lentz's method for the continued fraction of Q(a, x).
+    let tiny = f64::MIN_POSITIVE / EPSILON;
+    let mut b = x + 1.0 - a;
+    let mut c = 1.0 / tiny;
+    let mut d = 1.0 / b;
+    let mut h = d;
+    for n in 1..ITERATIONS {
+        let an = -(n as f64) * (n as f64 - a);
+        b += 2.0;
+        d = an * d + b;
+        if d.abs() < tiny {
+            d = tiny;
+        }
+        c = b + an / c;
+        if c.abs() < tiny {
+            c = tiny;
+        }
+        d = 1.0 / d;
+        let delta = d * c;
+        h *= delta;
+        if (delta - 1.0).abs() < EPSILON {
+            break;
+        }
+    }
+    (1.0 - prefactor * h).max(0.0)
+}
+
+/// The gamma function, by the Lanczos approximation.
+pub fn gamma_function(x: f64) -> f64 {
+    const G: f64 = 7.0;
+    const COEFFICIENTS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+    if x < 0.5 {
+        // Reflection formula.
+        return std::f64::consts::PI / ((std::f64::consts::PI * x).sin() * gamma_function(1.0 - x));
+    }
+    let x = x - 1.0;
+    let mut sum = COEFFICIENTS[0];
+    for (i, coefficient) in COEFFICIENTS.iter().enumerate().skip(1) {
+        sum += coefficient / (x + i as f64);
+    }
+    let t = x + G + 0.5;
+    (2.0 * std::f64::consts::PI).sqrt() * t.powf(x + 0.5) * (-t).exp() * sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLES: usize = 200_000;
+
+    /// Checks the known mean, and that the sample mean is within four standard errors of it.
+    fn assert_mean(distribution: Distribution, expected: f64) {
+        assert!((distribution.mean() - expected).abs() < 1e-9, "{:?} has mean {}", distribution, distribution.mean());
+        let mut sampler = Sampler::new(distribution.clone(), 7);
+        let sample_mean = (0..SAMPLES).map(|_| sampler.next_sample()).sum::<f64>() / SAMPLES as f64;
+        let tolerance = 4.0 * distribution.std_dev() / (SAMPLES as f64).sqrt() + 1e-12;
+        assert!((sample_mean - expected).abs() <= tolerance, "{:?} sampled a mean of {}, not {}", distribution, sample_mean, expected);
+    }
+
+    #[test]
+    fn sample_means_match_known_means() {
+        let phi = (-0.5f64).exp() / (2.0 * std::f64::consts::PI).sqrt();
+        assert_mean(Distribution::Deterministic { value: 3.0 }, 3.0);
+        assert_mean(Distribution::Exponential { mean: 2.0 }, 2.0);
+        // Truncated at zero one standard deviation below the mean: 1 + phi(1) / Phi(1).
+        assert_mean(Distribution::Normal { mean: 1.0, std_dev: 1.0 }, 1.0 + phi / normal_cdf(1.0));
+        assert_mean(Distribution::LogNormal { mu: 0.0, sigma: 0.5 }, 0.125f64.exp());
+        assert_mean(Distribution::Gamma { shape: 2.0, scale: 3.0 }, 6.0);
+        assert_mean(Distribution::Weibull { shape: 2.0, scale: 1.0 }, std::f64::consts::PI.sqrt() / 2.0);
+        assert_mean(Distribution::Triangular { min: 1.0, mode: 2.0, max: 6.0 }, 3.0);
+        assert_mean(Distribution::Uniform { min: 2.0, max: 4.0 }, 3.0);
+        assert_mean(Distribution::Empirical { samples: vec![1.0, 2.0, 6.0] }, 3.0);
+    }
+
+    #[test]
+    fn scaling_keeps_the_coefficient_of_variation() {
+        let distribution = Distribution::Gamma { shape: 2.0, scale: 3.0 };
+        let scaled = distribution.scaled(0.5);
+        assert!((scaled.mean() - 3.0).abs() < 1e-12);
+        assert!((scaled.cv() - distribution.cv()).abs() < 1e-12);
+    }
+}
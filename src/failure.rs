@@ -0,0 +1,261 @@
+//! Failures driven by time to failure and time to repair distributions.
+//!
+//! A machine may have any number of independent failure modes besides the per step
+//! failures of its Markov chain. Each mode ages on its own clock: calendar time,
+//! time spent working, or completed cycles. Once its age reaches a lifetime drawn
+//! from its time to failure distribution the machine goes down, and it stays down
+//! for a repair time drawn from the mode's own time to repair distribution. The
+//! repair renews only the mode that failed; the other modes keep their age.
+//!
+//! Every mode draws its lifetimes and repair times from its own random streams, so
+//! the Markov chain keeps its single failure number per step and adding a mode to
+//! one machine does not change the numbers seen by any other.
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::distribution::Distribution;
+use crate::machine::Machine;
+use crate::degradation;
+use crate::maintenance;
+use crate::random::{RngRegistry, StreamKind};
+
+/// What a failure mode's age counts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureClock {
+    /// Every time step, whatever the machine is doing.
+    Calendar,
+    /// Time steps spent working.
+    Busy,
+    /// Completed cycles.
+    Cycles,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FailureMode {
+    pub id: Uuid,
+    pub name: String,
+    pub clock: FailureClock,
+    /// The age at which the mode fails, in units of its clock.
+    pub time_to_failure: Distribution,
+    /// The time steps a failure of this mode takes to repair.
+    pub time_to_repair: Distribution,
+    /// Age since the mode was last renewed.
+    pub age: f64,
+    /// The lifetime drawn for the current age, drawn on first use.
+    pub life: Option<f64>,
+    /// The number of times the mode has failed.
+    pub failures: usize,
+    /// Time steps spent down for repairs of this mode.
+    pub downtime: usize,
+}
+
+impl FailureMode {
+    pub fn new(name: String, clock: FailureClock, time_to_failure: Distribution, time_to_repair: Distribution) -> FailureMode {
+        FailureMode {
+            id: Uuid::new_v4(),
+            name,
+            clock,
+            time_to_failure,
+            time_to_repair,
+            age: 0.0,
+            life: None,
+            failures: 0,
+            downtime: 0,
+        }
+    }
+
+    /// Mean failures per working time step of a machine with the given mean
+    /// processing time. Calendar failures are counted as if they only struck
+    /// while the machine works.
+    pub fn rate(&self, processing_time: f64) -> f64 {
+        let mean = self.time_to_failure.mean();
+        match self.clock {
+            FailureClock::Calendar | FailureClock::Busy => 1.0 / mean,
+            FailureClock::Cycles => 1.0 / (mean * processing_time.max(1.0)),
+        }
+    }
+
+    fn is_due(&self) -> bool {
+        self.life.is_some_and(|life| self.age >= life)
+    }
+}
+
+/// The failure modes of a machine and the repair in progress, if any.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FailureModel {
+    pub modes: Vec<FailureMode>,
+    /// The mode being repaired.
+    pub repairing: Option<usize>,
+    /// Repair time left, in time steps.
+    pub repair_remaining: f64,
+}
+
+impl FailureModel {
+    pub fn new() -> FailureModel {
+        FailureModel::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.modes.is_empty()
+    }
+
+    pub fn mode(&self, name: &str) -> Option<&FailureMode> {
+        self.modes.iter().find(|mode| mode.name == name)
+    }
+
+    pub fn add_mode(&mut self, mode: FailureMode) -> Result<(), &'static str> {
+        if self.mode(&mode.name).is_some() {
+            return Err("Failure mode already exists.");
+        }
+        mode.time_to_failure.validate()?;
+        mode.time_to_repair.validate()?;
+        self.modes.push(mode);
+        Ok(())
+    }
+
+    pub fn remove_mode(&mut self, name: &str) -> Result<FailureMode, &'static str> {
+        let index = self.modes.iter().position(|mode| mode.name == name).ok_or("Failure mode not found.")?;
+        self.repairing = match self.repairing {
+            Some(repairing) if repairing == index => {
+                self.repair_remaining = 0.0;
+                None
+            }
+            Some(repairing) if repairing > index => Some(repairing - 1),
+            other => other,
+        };
+        Ok(self.modes.remove(index))
+    }
+
+    pub fn is_repairing(&self) -> bool {
+        self.repairing.is_some()
+    }
+
+    /// Renews every mode, as maintenance does. New lifetimes are drawn on the next step.
+    pub fn renew(&mut self) {
+        for mode in &mut self.modes {
+            mode.age = 0.0;
+            mode.life = None;
+        }
+    }
+
+    /// Makes every mode fail the given factor more often by shortening its lifetimes,
+    /// including those already drawn.
+    pub fn scale_failure_rates(&mut self, factor: f64) {
+        for mode in &mut self.modes {
+            mode.time_to_failure = mode.time_to_failure.scaled(1.0 / factor);
+            mode.life = mode.life.map(|life| life / factor);
+        }
+    }
+
+    /// Draws the lifetimes of modes that do not have one yet.
+    pub fn draw_lives(&mut self, streams: &mut RngRegistry) {
+        for mode in self.modes.iter_mut().filter(|mode| mode.life.is_none()) {
+            mode.life = Some(mode.time_to_failure.sample(streams.stream(mode.id, StreamKind::Failure)));
+        }
+    }
+
+    /// Ages the modes counting the given clock.
+    pub fn advance(&mut self, clock: FailureClock, amount: f64) {
+        for mode in self.modes.iter_mut().filter(|mode| mode.clock == clock) {
+            mode.age += amount;
+        }
+    }
+
+    /// Starts the repair of the first mode that has reached its lifetime, among the
+    /// modes counting the given clock or among all modes, and spends the current
+    /// time step on it. Returns whether a mode had failed.
+    pub fn fail_due(&mut self, clock: Option<FailureClock>, streams: &mut RngRegistry) -> bool {
+        let due = self.modes.iter()
+            .position(|mode| clock.is_none_or(|clock| mode.clock == clock) && mode.is_due());
+        let Some(index) = due else {
+            return false;
+        };
+        let mode = &mut self.modes[index];
+        mode.failures += 1;
+        mode.downtime += 1;
+        self.repair_remaining = mode.time_to_repair.sample(streams.stream(mode.id, StreamKind::Repair)) - 1.0;
+        self.repairing = Some(index);
+        true
+    }
+
+    /// Moves the repair in progress on by a time step. Returns None if no mode is
+    /// being repaired, Some(false) if the step was spent repairing, and Some(true)
+    /// if the repair was already done, in which case the mode is renewed and the
+    /// machine is up for the step. A repair thus keeps the machine down for its
+    /// repair time rounded up to whole time steps.
+    pub fn repair_step(&mut self, streams: &mut RngRegistry) -> Option<bool> {
+        let index = self.repairing?;
+        let mode = &mut self.modes[index];
+        if self.repair_remaining > 0.0 {
+            mode.downtime += 1;
+            self.repair_remaining -= 1.0;
+            return Some(false);
+        }
+        mode.age = 0.0;
+        mode.life = Some(mode.time_to_failure.sample(streams.stream(mode.id, StreamKind::Failure)));
+        self.repairing = None;
+        self.repair_remaining = 0.0;
+        Some(true)
+    }
+}
+
+/// The failures of a machine from its Markov chain and its failure modes together,
+/// as seen by analytical models. Failures of the degradation chain are included,
+/// and preventive maintenance counts as a failure too, changing how often the
+/// failure modes and the degradation chain fail.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FailureSummary {
+    /// Mean failures per working time step.
+    pub rate: f64,
+    /// Mean time steps down per failure, including the step the machine fails in.
+    pub repair_time: f64,
+    /// Coefficient of variation of the time down per failure.
+    pub repair_cv: f64,
+}
+
+impl FailureSummary {
+    pub fn of_machine(machine: &Machine) -> FailureSummary {
+        let processing_time = machine.processing_time.mean();
+        // Each source of failures as (rate, mean repair time, repair time variance).
+        let mut sources = Vec::new();
+        let failure = machine.failure_probability();
+        if failure > 0.0 {
+            // The machine is down for the step it fails in, and the chain is then left
+            // with the same probability every step, so its repair times are geometric.
+            let repair = machine.repair_probability().max(f64::EPSILON);
+            sources.push((failure / (1.0 - failure).max(f64::EPSILON), 1.0 + 1.0 / repair, (1.0 - repair) / (repair * repair)));
+        }
+        match &machine.maintenance.policy {
+            Some(policy) => {
+                let evaluation = maintenance::evaluate(machine, policy);
+                let (repair_time, repair_variance) = maintenance::mode_repairs(machine);
+                sources.push((evaluation.failure_rate, repair_time, repair_variance));
+                sources.push((evaluation.maintenance_rate, policy.duration.mean(), policy.duration.variance()));
+                if let Some(degradation) = &machine.degradation {
+                    let repair = &degradation.time_to_repair;
+                    sources.push((evaluation.degradation_rate, repair.mean(), repair.variance()));
+                }
+            }
+            None => {
+                for mode in &machine.failure_model.modes {
+                    sources.push((mode.rate(processing_time), mode.time_to_repair.mean(), mode.time_to_repair.variance()));
+                }
+                if let Some(degradation) = &machine.degradation {
+                    let rate = degradation::evaluate_threshold(machine, degradation.failed()).map_or(0.0, |threshold| threshold.failure_rate);
+                    sources.push((rate, degradation.time_to_repair.mean(), degradation.time_to_repair.variance()));
+                }
+            }
+        }
+        let rate: f64 = sources.iter().map(|&(rate, _, _)| rate).sum();
+        if rate <= 0.0 {
+            return FailureSummary { rate: 0.0, repair_time: 0.0, repair_cv: 0.0 };
+        }
+        // The repair time of a failure is a mixture weighted by how often each source fails.
+        let repair_time = sources.iter().map(|&(weight, mean, _)| weight * mean).sum::<f64>() / rate;
+        let second_moment = sources.iter().map(|&(weight, mean, variance)| weight * (variance + mean * mean)).sum::<f64>() / rate;
+        let variance = (second_moment - repair_time * repair_time).max(0.0);
+        let repair_cv = if repair_time > 0.0 { variance.sqrt() / repair_time } else { 0.0 };
+        FailureSummary { rate, repair_time, repair_cv }
+    }
+}
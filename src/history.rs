@@ -0,0 +1,317 @@
+//! Event sourced model history.
+//!
+//! Every edit made to the model through a `ModelHistory` is appended to an event log
+//! together with when it was made and by whom. The log is append only: undoing an
+//! edit appends an undo entry rather than deleting the edit, and the model is rebuilt
+//! by replaying every edit that has not been undone. Because commands that create
+//! entities carry the ids they create, replaying a log always rebuilds the same
+//! model, which makes the model at any earlier position of the log available for
+//! comparison.
+//!
+//! A log can be kept in memory or in a file of one JSON encoded event per line,
+//! written as each event is appended. A history may also start from a model built
+//! before it. A snapshot of that model is then the first event of the log, so that
+//! the log alone replays the model.
+//!
+//! The registry actor runs on a `ModelHistory`, so that everything sent through a
+//! `ModelHandle` is logged and the history commands reach it.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::machine::Item;
+use crate::queue::Buffer;
+use crate::registry::{ModelCommand, ModelError, ModelRegistry, ModelResponse};
+use crate::snapshot::ModelSnapshot;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum HistoryEntry {
+    Edit(ModelCommand),
+    /// Starts the model over from a snapshot, the model the history started from.
+    Base(ModelSnapshot),
+    /// Undoes the edit with the given sequence number.
+    Undo { sequence: u64 },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LoggedEvent {
+    /// The position of the event in the log, counting from 0.
+    pub sequence: u64,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub author: Option<String>,
+    pub entry: HistoryEntry,
+}
+
+/// An append only log of model events.
+pub struct EventLog {
+    events: Vec<LoggedEvent>,
+    file: Option<File>,
+}
+
+impl EventLog {
+    pub fn in_memory() -> EventLog {
+        EventLog { events: Vec::new(), file: None }
+    }
+
+    /// Opens a log file, reading the events already in it, and appends new events
+    /// to it. The file is created if it does not exist.
+    pub fn open(path: &Path) -> Result<EventLog, &'static str> {
+        let mut events: Vec<LoggedEvent> = Vec::new();
+        if path.exists() {
+            let reader = BufReader::new(File::open(path).map_err(|_| "Could not open the event log.")?);
+            for line in reader.lines() {
+                let line = line.map_err(|_| "Could not read the event log.")?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let event: LoggedEvent = serde_json::from_str(&line).map_err(|_| "Event log contains an invalid event.")?;
+                if event.sequence != events.len() as u64 {
+                    return Err("Event log is out of sequence.");
+                }
+                events.push(event);
+            }
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|_| "Could not open the event log.")?;
+        Ok(EventLog { events, file: Some(file) })
+    }
+
+    pub fn events(&self) -> &[LoggedEvent] {
+        &self.events
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    fn append(&mut self, entry: HistoryEntry, author: Option<String>) -> Result<&LoggedEvent, &'static str> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+        let event = LoggedEvent { sequence: self.events.len() as u64, timestamp, author, entry };
+        if let Some(file) = &mut self.file {
+            let line = serde_json::to_string(&event).map_err(|_| "Could not encode the event.")?;
+            writeln!(file, "{}", line)
+                .and_then(|_| file.flush())
+                .map_err(|_| "Could not write the event log.")?;
+        }
+        self.events.push(event);
+        Ok(self.events.last().unwrap())
+    }
+}
+
+/// The kind of model entity a change refers to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EntityKind {
+    Item,
+    Recipe,
+    Machine,
+    Buffer,
+    Line,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    Added(EntityKind, Uuid),
+    Removed(EntityKind, Uuid),
+    Modified(EntityKind, Uuid),
+}
+
+/// The difference between the model at two positions of the log.
+#[derive(Clone, Debug)]
+pub struct ModelDiff {
+    pub changes: Vec<Change>,
+    /// The events between the two positions, with their authors and times.
+    pub events: Vec<LoggedEvent>,
+}
+
+/// A model whose edits are recorded in an event log.
+pub struct ModelHistory {
+    model: ModelRegistry,
+    log: EventLog,
+}
+
+impl ModelHistory {
+    /// Rebuilds the model from a log and continues recording into it.
+    pub fn new(log: EventLog) -> Result<ModelHistory, &'static str> {
+        let model = replay(log.events(), log.len())?;
+        Ok(ModelHistory { model, log })
+    }
+
+    /// Starts recording the edits of a model already built into an empty log, which
+    /// begins with a snapshot of the model.
+    pub fn with_base(model: ModelRegistry, mut log: EventLog) -> Result<ModelHistory, &'static str> {
+        if !log.is_empty() {
+            return Err("Event log already holds a history.");
+        }
+        log.append(HistoryEntry::Base(ModelSnapshot::of_registry(&model)), None)?;
+        Ok(ModelHistory { model, log })
+    }
+
+    pub fn model(&self) -> &ModelRegistry {
+        &self.model
+    }
+
+    pub fn log(&self) -> &EventLog {
+        &self.log
+    }
+
+    /// Executes a command, recording it if it is an edit that succeeded.
+    pub fn execute(&mut self, command: ModelCommand, author: Option<String>) -> Result<ModelResponse, ModelError> {
+        match command {
+            ModelCommand::Undo => return Ok(ModelResponse::Undone(self.undo(author)?)),
+            ModelCommand::Replay { position } => return Ok(ModelResponse::Model(self.model_at(position)?)),
+            ModelCommand::Diff { from, to } => return Ok(ModelResponse::Diff(self.diff(from, to)?)),
+            ModelCommand::GetHistory => return Ok(ModelResponse::History(self.log.events().to_vec())),
+            _ => {}
+        }
+        if !command.is_edit() {
+            return self.model.execute(command);
+        }
+        let response = self.model.execute(command.clone())?;
+        self.log.append(HistoryEntry::Edit(command), author)?;
+        Ok(response)
+    }
+
+    /// Undoes the most recent edit that has not been undone yet and returns its
+    /// sequence number.
+    pub fn undo(&mut self, author: Option<String>) -> Result<u64, &'static str> {
+        let undone = undone(self.log.events());
+        let sequence = self.log.events().iter()
+            .rev()
+            .find(|event| matches!(event.entry, HistoryEntry::Edit(_)) && !undone.contains(&event.sequence))
+            .map(|event| event.sequence)
+            .ok_or("Nothing to undo.")?;
+        let mut events = self.log.events().to_vec();
+        events.push(LoggedEvent { sequence: events.len() as u64, timestamp: 0, author: None, entry: HistoryEntry::Undo { sequence } });
+        let model = replay(&events, events.len())?;
+        self.log.append(HistoryEntry::Undo { sequence }, author)?;
+        self.model = model;
+        Ok(sequence)
+    }
+
+    /// Rebuilds the model as it was after the first `position` events of the log.
+    pub fn model_at(&self, position: usize) -> Result<ModelRegistry, &'static str> {
+        if position > self.log.len() {
+            return Err("Log position is out of range.");
+        }
+        replay(self.log.events(), position)
+    }
+
+    /// Compares the model after the first `from` events with the model after the
+    /// first `to` events.
+    pub fn diff(&self, from: usize, to: usize) -> Result<ModelDiff, &'static str> {
+        if from > to || to > self.log.len() {
+            return Err("Log positions are out of range.");
+        }
+        let before = fingerprints(&self.model_at(from)?);
+        let after = fingerprints(&self.model_at(to)?);
+        let mut changes: Vec<Change> = Vec::new();
+        for (key, description) in &after {
+            match before.get(key) {
+                None => changes.push(Change::Added(key.0, key.1)),
+                Some(previous) if previous != description => changes.push(Change::Modified(key.0, key.1)),
+                Some(_) => {}
+            }
+        }
+        for key in before.keys().filter(|key| !after.contains_key(key)) {
+            changes.push(Change::Removed(key.0, key.1));
+        }
+        Ok(ModelDiff { changes, events: self.log.events()[from..to].to_vec() })
+    }
+}
+
+/// Rebuilds a model by replaying the first `position` events of a log, skipping
+/// the edits undone within them.
+pub fn replay(events: &[LoggedEvent], position: usize) -> Result<ModelRegistry, &'static str> {
+    let events = &events[..position.min(events.len())];
+    let undone = undone(events);
+    let mut model = ModelRegistry::new();
+    for event in events {
+        match &event.entry {
+            HistoryEntry::Base(snapshot) => {
+                model = snapshot.to_registry().map_err(|_| "Event log has an invalid base model.")?;
+            }
+            HistoryEntry::Edit(command) if !undone.contains(&event.sequence) => {
+                model.execute(command.clone()).map_err(|_| "Event log does not replay.")?;
+            }
+            _ => {}
+        }
+    }
+    Ok(model)
+}
+
+fn undone(events: &[LoggedEvent]) -> HashSet<u64> {
+    events.iter()
+        .filter_map(|event| match event.entry {
+            HistoryEntry::Undo { sequence } => Some(sequence),
+            HistoryEntry::Edit(_) | HistoryEntry::Base(_) => None,
+        })
+        .collect()
+}
+
+/// Describes every entity of the model, so that two models can be compared.
+fn fingerprints(model: &ModelRegistry) -> HashMap<(EntityKind, Uuid), String> {
+    let mut entities = HashMap::new();
+    for item in model.catalogue().items() {
+        entities.insert((EntityKind::Item, item.id()), format!("{} {} {:?}", item.name, item.size, item.cost));
+    }
+    for recipe in model.catalogue().recipes() {
+        let quantities = |list: &[(Arc<Item>, f64)]| -> Vec<(Uuid, f64)> {
+            list.iter().map(|(item, quantity)| (item.id(), *quantity)).collect()
+        };
+        let description = format!("{} {:?} {:?} {}", recipe.name, quantities(&recipe.input), quantities(&recipe.output), recipe.recycling);
+        entities.insert((EntityKind::Recipe, recipe.id), description);
+    }
+    for machine in model.machines() {
+        let buffer_ids = |links: &[Arc<Mutex<Buffer>>]| -> Vec<Uuid> {
+            links.iter().map(|link| link.lock().unwrap().id).collect()
+        };
+        let recipe_ids: Vec<Uuid> = machine.recipes.iter().map(|recipe| recipe.id).collect();
+        let description = format!(
+            "{:?} {} {} {} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?}",
+            machine.processing_time,
+            machine.hourly_rate,
+            machine.failure_probability(),
+            machine.repair_probability(),
+            machine.failure_model.modes,
+            machine.setup_matrix,
+            machine.dispatch,
+            machine.batch,
+            machine.blocking,
+            machine.quality,
+            machine.maintenance.policy,
+            machine.degradation.as_ref().map(|degradation| (&degradation.stages, degradation.transition_matrix(), &degradation.time_to_repair)),
+            machine.output_name,
+            recipe_ids,
+            buffer_ids(&machine.input_buffer),
+            buffer_ids(&machine.output_buffer),
+            machine.rework_buffer.as_ref().map(|link| link.lock().unwrap().id),
+            buffer_ids(&machine.rework_inputs),
+        );
+        entities.insert((EntityKind::Machine, machine.id), description);
+    }
+    for buffer in model.buffers() {
+        let buffer = buffer.lock().unwrap();
+        let items: Vec<(Uuid, f64)> = buffer.items.iter().map(|(item, quantity)| (item.id(), *quantity)).collect();
+        let description = format!("{} {:?} {:?} {:?}", buffer.capacity, buffer.throughput, buffer.name, items);
+        entities.insert((EntityKind::Buffer, buffer.id), description);
+    }
+    for line in model.lines() {
+        entities.insert((EntityKind::Line, line.id), format!("{:?}", line));
+    }
+    entities
+}
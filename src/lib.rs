@@ -0,0 +1,34 @@
+//! Modelling, simulation and analysis of manufacturing systems.
+
+pub mod markov;
+pub mod transfer_lines;
+pub mod queue;
+pub mod machine;
+pub mod simulation;
+pub mod bottleneck;
+pub mod output_analysis;
+pub mod replication;
+pub mod random;
+pub mod clock;
+pub mod recipe_analysis;
+pub mod lp;
+pub mod planner;
+pub mod mrp;
+pub mod costing;
+pub mod recipe_validation;
+pub mod registry;
+pub mod history;
+pub mod snapshot;
+pub mod model_file;
+pub mod decomposition;
+pub mod buffer_allocation;
+pub mod report;
+pub mod cli;
+pub mod distribution;
+pub mod failure;
+pub mod setup;
+pub mod batch;
+pub mod blocking;
+pub mod quality;
+pub mod maintenance;
+pub mod degradation;
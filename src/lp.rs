@@ -0,0 +1,344 @@
+//! A small embedded linear and mixed integer programming solver.
+//!
+//! Problems are maximisations over non-negative variables with less-or-equal,
+//! greater-or-equal and equality constraints. Linear programs are solved with the
+//! two phase tableau simplex method using Bland's rule, which cannot cycle. Integer
+//! variables are handled by depth first branch and bound on the most fractional
+//! variable. The tableau is dense, which suits the planning problems this crate
+//! builds: tens to hundreds of variables rather than many thousands.
+
+/// Values closer to zero than this are treated as zero.
+const EPSILON: f64 = 1e-9;
+/// Values this close to an integer are treated as integral.
+const INTEGER_TOLERANCE: f64 = 1e-6;
+/// The largest number of branch and bound nodes explored before giving up.
+const MAX_NODES: usize = 100_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Relation {
+    LessEq,
+    GreaterEq,
+    Equal,
+}
+
+#[derive(Clone, Debug)]
+pub struct Constraint {
+    pub coefficients: Vec<f64>,
+    pub relation: Relation,
+    pub rhs: f64,
+}
+
+/// Maximise objective . x subject to the constraints, x >= 0, and x_j integral
+/// wherever integer[j] is set.
+#[derive(Clone, Debug)]
+pub struct LinearProgram {
+    pub objective: Vec<f64>,
+    pub constraints: Vec<Constraint>,
+    pub integer: Vec<bool>,
+}
+
+#[derive(Clone, Debug)]
+pub struct LpSolution {
+    pub values: Vec<f64>,
+    pub objective: f64,
+}
+
+impl LinearProgram {
+    /// Creates a program over the given number of continuous variables.
+    pub fn new(objective: Vec<f64>) -> LinearProgram {
+        let integer = vec![false; objective.len()];
+        LinearProgram { objective, constraints: Vec::new(), integer }
+    }
+
+    pub fn num_variables(&self) -> usize {
+        self.objective.len()
+    }
+
+    pub fn add_constraint(&mut self, coefficients: Vec<f64>, relation: Relation, rhs: f64) {
+        self.constraints.push(Constraint { coefficients, relation, rhs });
+    }
+
+    pub fn set_integer(&mut self, variable: usize) {
+        self.integer[variable] = true;
+    }
+
+    /// Solves the program, returning an error if it is infeasible or unbounded.
+    pub fn solve(&self) -> Result<LpSolution, &'static str> {
+        if self.integer.iter().any(|&integer| integer) {
+            self.branch_and_bound()
+        } else {
+            simplex(self)
+        }
+    }
+
+    fn branch_and_bound(&self) -> Result<LpSolution, &'static str> {
+        let mut best: Option<LpSolution> = None;
+        let mut stack: Vec<LinearProgram> = vec![self.clone()];
+        let mut nodes = 0;
+        let mut unbounded = false;
+        while let Some(node) = stack.pop() {
+            nodes += 1;
+            if nodes > MAX_NODES {
+                return best.ok_or("Branch and bound node limit reached without an integer solution.");
+            }
+            let relaxed = match simplex(&node) {
+                Ok(solution) => solution,
+                Err(UNBOUNDED) => {
+                    unbounded = true;
+                    continue;
+                }
+                Err(_) => continue,
+            };
+            if best.as_ref().is_some_and(|best| relaxed.objective <= best.objective + EPSILON) {
+                continue;
+            }
+            let fractional = (0..node.num_variables())
+                .filter(|&j| node.integer[j])
+                .map(|j| (j, relaxed.values[j] - relaxed.values[j].floor()))
+                .filter(|(_, fraction)| *fraction > INTEGER_TOLERANCE && *fraction < 1.0 - INTEGER_TOLERANCE)
+                .max_by(|a, b| (0.5 - (a.1 - 0.5).abs()).total_cmp(&(0.5 - (b.1 - 0.5).abs())));
+            match fractional {
+                None => {
+                    let mut solution = relaxed;
+                    for j in (0..node.num_variables()).filter(|&j| node.integer[j]) {
+                        solution.values[j] = solution.values[j].round();
+                    }
+                    best = Some(solution);
+                }
+                Some((j, _)) => {
+                    let value = relaxed.values[j];
+                    let mut bound = vec![0.0; node.num_variables()];
+                    bound[j] = 1.0;
+                    let mut down = node.clone();
+                    down.add_constraint(bound.clone(), Relation::LessEq, value.floor());
+                    let mut up = node;
+                    up.add_constraint(bound, Relation::GreaterEq, value.ceil());
+                    // The branch nearer the relaxed value is explored first.
+                    if value - value.floor() < 0.5 {
+                        stack.push(up);
+                        stack.push(down);
+                    } else {
+                        stack.push(down);
+                        stack.push(up);
+                    }
+                }
+            }
+        }
+        match best {
+            Some(solution) => Ok(solution),
+            None if unbounded => Err(UNBOUNDED),
+            None => Err(INFEASIBLE),
+        }
+    }
+}
+
+pub const INFEASIBLE: &str = "Linear program is infeasible.";
+pub const UNBOUNDED: &str = "Linear program is unbounded.";
+
+/// The dense simplex tableau. Row 0..m are constraints, the last column is the
+/// right hand side, and `basis[i]` is the column basic in row i.
+struct Tableau {
+    rows: Vec<Vec<f64>>,
+    basis: Vec<usize>,
+    columns: usize,
+}
+
+impl Tableau {
+    fn rhs(&self, row: usize) -> f64 {
+        self.rows[row][self.columns]
+    }
+
+    fn pivot(&mut self, row: usize, column: usize) {
+        let pivot = self.rows[row][column];
+        for value in self.rows[row].iter_mut() {
+            *value /= pivot;
+        }
+        let pivot_row = self.rows[row].clone();
+        for (i, other) in self.rows.iter_mut().enumerate() {
+            if i == row {
+                continue;
+            }
+            let factor = other[column];
+            if factor.abs() > EPSILON {
+                for (value, pivot_value) in other.iter_mut().zip(&pivot_row) {
+                    *value -= factor * pivot_value;
+                }
+            }
+        }
+        self.basis[row] = column;
+    }
+
+    /// Maximises the given objective over the allowed columns, returning false if
+    /// it is unbounded.
+    fn optimise(&mut self, objective: &[f64], allowed: &[bool]) -> bool {
+        loop {
+            // Reduced cost of column j: c_j - c_B . B^-1 A_j, read off the tableau.
+            let reduced = |tableau: &Tableau, j: usize| {
+                objective[j] - (0..tableau.rows.len())
+                    .map(|i| objective[tableau.basis[i]] * tableau.rows[i][j])
+                    .sum::<f64>()
+            };
+            // Bland's rule: the lowest index improving column enters.
+            let Some(entering) = (0..self.columns)
+                .filter(|&j| allowed[j] && !self.basis.contains(&j))
+                .find(|&j| reduced(self, j) > EPSILON)
+            else {
+                return true;
+            };
+            // Minimum ratio test, ties broken by the lowest basic column index.
+            let leaving = (0..self.rows.len())
+                .filter(|&i| self.rows[i][entering] > EPSILON)
+                .min_by(|&a, &b| {
+                    let ratio_a = self.rhs(a) / self.rows[a][entering];
+                    let ratio_b = self.rhs(b) / self.rows[b][entering];
+                    ratio_a.total_cmp(&ratio_b).then(self.basis[a].cmp(&self.basis[b]))
+                });
+            match leaving {
+                Some(row) => self.pivot(row, entering),
+                None => return false,
+            }
+        }
+    }
+}
+
+fn simplex(lp: &LinearProgram) -> Result<LpSolution, &'static str> {
+    let n = lp.num_variables();
+    let m = lp.constraints.len();
+
+    // Normalise every row to a non-negative right hand side.
+    let rows: Vec<(Vec<f64>, Relation, f64)> = lp.constraints.iter()
+        .map(|constraint| {
+            let mut coefficients = constraint.coefficients.clone();
+            coefficients.resize(n, 0.0);
+            if constraint.rhs < 0.0 {
+                let relation = match constraint.relation {
+                    Relation::LessEq => Relation::GreaterEq,
+                    Relation::GreaterEq => Relation::LessEq,
+                    Relation::Equal => Relation::Equal,
+                };
+                (coefficients.iter().map(|value| -value).collect(), relation, -constraint.rhs)
+            } else {
+                (coefficients, constraint.relation, constraint.rhs)
+            }
+        })
+        .collect();
+
+    // Columns: original variables, one slack or surplus per inequality, then one
+    // artificial per greater-or-equal or equality row.
+    let slacks = rows.iter().filter(|(_, relation, _)| *relation != Relation::Equal).count();
+    let artificials = rows.iter().filter(|(_, relation, _)| *relation != Relation::LessEq).count();
+    let columns = n + slacks + artificials;
+    let mut tableau = Tableau { rows: Vec::with_capacity(m), basis: Vec::with_capacity(m), columns };
+    let mut next_slack = n;
+    let mut next_artificial = n + slacks;
+    for (coefficients, relation, rhs) in rows {
+        let mut row = coefficients;
+        row.resize(columns + 1, 0.0);
+        row[columns] = rhs;
+        match relation {
+            Relation::LessEq => {
+                row[next_slack] = 1.0;
+                tableau.basis.push(next_slack);
+                next_slack += 1;
+            }
+            Relation::GreaterEq => {
+                row[next_slack] = -1.0;
+                next_slack += 1;
+                row[next_artificial] = 1.0;
+                tableau.basis.push(next_artificial);
+                next_artificial += 1;
+            }
+            Relation::Equal => {
+                row[next_artificial] = 1.0;
+                tableau.basis.push(next_artificial);
+                next_artificial += 1;
+            }
+        }
+        tableau.rows.push(row);
+    }
+
+    // Phase one drives the artificials out by minimising their sum.
+    if artificials > 0 {
+        let mut phase_one = vec![0.0; columns];
+        for value in phase_one.iter_mut().skip(n + slacks) {
+            *value = -1.0;
+        }
+        tableau.optimise(&phase_one, &vec![true; columns]);
+        let infeasibility: f64 = (0..m)
+            .filter(|&i| tableau.basis[i] >= n + slacks)
+            .map(|i| tableau.rhs(i))
+            .sum();
+        if infeasibility > 1e-7 {
+            return Err(INFEASIBLE);
+        }
+        // Pivot degenerate artificials out of the basis where possible.
+        for i in 0..m {
+            if tableau.basis[i] >= n + slacks {
+                if let Some(j) = (0..n + slacks).find(|&j| tableau.rows[i][j].abs() > EPSILON) {
+                    tableau.pivot(i, j);
+                }
+            }
+        }
+    }
+
+    let mut objective = lp.objective.clone();
+    objective.resize(columns, 0.0);
+    let allowed: Vec<bool> = (0..columns).map(|j| j < n + slacks).collect();
+    if !tableau.optimise(&objective, &allowed) {
+        return Err(UNBOUNDED);
+    }
+
+    let mut values = vec![0.0; n];
+    for (i, &column) in tableau.basis.iter().enumerate() {
+        if column < n {
+            values[column] = tableau.rhs(i).max(0.0);
+        }
+    }
+    let objective = values.iter().zip(&lp.objective).map(|(value, coefficient)| value * coefficient).sum();
+    Ok(LpSolution { values, objective })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-6, "expected {}, got {}", expected, actual);
+    }
+
+    #[test]
+    fn solves_two_variable_program() {
+        // Maximise 3x + 5y subject to x <= 4, 2y <= 12 and 3x + 2y <= 18, optimal at (2, 6).
+        let mut program = LinearProgram::new(vec![3.0, 5.0]);
+        program.add_constraint(vec![1.0, 0.0], Relation::LessEq, 4.0);
+        program.add_constraint(vec![0.0, 2.0], Relation::LessEq, 12.0);
+        program.add_constraint(vec![3.0, 2.0], Relation::LessEq, 18.0);
+        let solution = program.solve().unwrap();
+        assert_close(solution.objective, 36.0);
+        assert_close(solution.values[0], 2.0);
+        assert_close(solution.values[1], 6.0);
+    }
+
+    #[test]
+    fn branches_to_integer_optimum() {
+        // The relaxation is optimal at (3, 1.5) with 21; the best integral point is (4, 0) with 20.
+        let mut program = LinearProgram::new(vec![5.0, 4.0]);
+        program.add_constraint(vec![6.0, 4.0], Relation::LessEq, 24.0);
+        program.add_constraint(vec![1.0, 2.0], Relation::LessEq, 6.0);
+        assert_close(program.solve().unwrap().objective, 21.0);
+        program.set_integer(0);
+        program.set_integer(1);
+        let solution = program.solve().unwrap();
+        assert_close(solution.objective, 20.0);
+        assert_close(solution.values[0], 4.0);
+        assert_close(solution.values[1], 0.0);
+    }
+
+    #[test]
+    fn reports_infeasible_program() {
+        let mut program = LinearProgram::new(vec![1.0, 1.0]);
+        program.add_constraint(vec![1.0, 1.0], Relation::LessEq, 1.0);
+        program.add_constraint(vec![1.0, 1.0], Relation::GreaterEq, 2.0);
+        assert!(program.solve().is_err());
+    }
+}
@@ -1,45 +1,164 @@
-use crate::queue::Buffer;
-use crate::markov::MarkovChain;
+use crate::queue::{Buffer, QUANTITY_TOLERANCE};
+use crate::markov::{MarkovChain, StateIndex};
 use crate::create_machine_chain;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use crate::distribution::Distribution;
+use crate::batch::{BatchMode, StartPolicy};
+use crate::blocking::BlockingPolicy;
+use crate::degradation::Degradation;
+use crate::failure::{FailureClock, FailureModel};
+use crate::maintenance::MaintenanceSchedule;
+use crate::quality::{QualityCounts, QualityModel};
+use crate::setup::{DispatchRule, SetupMatrix};
+use crate::random::{RngRegistry, StreamKind};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use uuid::Uuid;
 
+/// The state a machine spent its last time step in.
+/// Idle is only seen before the first step; afterwards an unproductive machine is
+/// either starved (nothing to take from its input buffers) or blocked (no room in
+/// its output buffers).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MachineState {
+    Idle,
+    Working,
+    Starved,
+    Blocked,
+    Down,
+    /// Changing over to a different product.
+    Setup,
+    /// Taken down for preventive maintenance.
+    Maintenance,
+}
+
+impl MachineState {
+    /// Whether the machine is active in the sense of the active period method,
+    /// i.e. it is processing, being set up, repaired or maintained rather than
+    /// waiting on its neighbours.
+    pub fn is_active(&self) -> bool {
+        matches!(self, MachineState::Working | MachineState::Down | MachineState::Setup | MachineState::Maintenance)
+    }
+}
+
+#[derive(Clone)]
 pub struct Machine {
     pub id: Uuid,
     pub markov_chain: MarkovChain,
-    pub processing_time: f64,
+    /// Failure modes with their own time to failure and repair distributions, on top
+    /// of the failures of the markov chain.
+    pub failure_model: FailureModel,
+    /// The preventive maintenance policy of the machine and its progress.
+    pub maintenance: MaintenanceSchedule,
+    /// The stages the machine wears through, changing its speed and yield.
+    pub degradation: Option<Degradation>,
+    pub processing_time: Distribution,
+    /// The processing time of the current cycle, drawn when the cycle starts.
+    pub cycle_time: f64,
     pub num_items: usize,
     pub output_name: Option<String>,
     pub input_buffer: Vec<Arc<Mutex<Buffer>>>,
     pub output_buffer: Vec<Arc<Mutex<Buffer>>>,
+    pub state: MachineState,
+    /// Work done so far on the part currently on the machine.
+    pub progress: f64,
+    /// The number of parts the machine has finished and passed on.
+    pub completed: usize,
+    /// The recipes the machine can run. A machine without recipes moves single
+    /// anonymous parts from its input buffers to its output buffers.
+    pub recipes: Vec<Arc<Recipe>>,
+    /// The index of the recipe the current cycle is running.
+    pub current_recipe: Option<usize>,
+    /// The index of the recipe the machine ran last.
+    pub last_recipe: Option<usize>,
+    /// Completed cycles per recipe id, a batch counting once per run of the recipe,
+    /// whatever the yield of the cycle.
+    pub recipe_cycles: HashMap<Uuid, usize>,
+    /// Changeover times between the products of the machine's recipes.
+    pub setup_matrix: SetupMatrix,
+    pub dispatch: DispatchRule,
+    /// Setup time left before the current cycle can be processed.
+    pub setup_remaining: f64,
+    /// The number of changeovers the machine has made.
+    pub setups: usize,
+    /// Consecutive cycles started for the product of the last recipe.
+    pub run_length: usize,
+    /// Makes the machine process several parts per cycle.
+    pub batch: Option<BatchMode>,
+    /// Time steps a minimum batch has been ready without the machine starting it.
+    pub batch_wait: f64,
+    /// When the machine stops for lack of room in its output buffers.
+    pub blocking: BlockingPolicy,
+    /// The scrap and rework rates of the parts the machine finishes.
+    pub quality: QualityModel,
+    /// Where the machine sends parts to be reworked.
+    pub rework_buffer: Option<Arc<Mutex<Buffer>>>,
+    /// Buffers of parts sent back for rework, taken before the input buffers.
+    pub rework_inputs: Vec<Arc<Mutex<Buffer>>>,
+    /// The outcome of the current cycle, drawn when it is first finished.
+    pub cycle_outcome: Option<QualityCounts>,
+    /// The parts of the current cycle on a rework pass, each given by the machine
+    /// whose rework sent it back.
+    pub cycle_rework: Vec<Uuid>,
+    /// The part of the outcome of the current cycle that is on a rework pass.
+    pub cycle_rework_outcome: QualityCounts,
+    /// The outcomes of the parts the machine has finished on their first pass.
+    pub quality_counts: QualityCounts,
+    /// The outcomes of the parts the machine has finished on a rework pass.
+    pub rework_counts: QualityCounts,
+    /// Parts finished since the machine was last repaired, if it has been.
+    pub parts_since_repair: Option<usize>,
+    /// The cost of the machine per time unit, whether or not it is working.
+    pub hourly_rate: f64,
 }
 
 impl Machine {
-    pub fn new(markov_chain: MarkovChain, processing_time: f64, output: Option<String>) -> Machine {
+    pub fn new(markov_chain: MarkovChain, processing_time: impl Into<Distribution>, output: Option<String>) -> Machine {
+        let processing_time = processing_time.into();
         Machine {
             id: Uuid::new_v4(),
-            markov_chain: markov_chain,
-            processing_time: processing_time,
+            markov_chain,
+            failure_model: FailureModel::new(),
+            maintenance: MaintenanceSchedule::default(),
+            degradation: None,
+            cycle_time: processing_time.mean(),
+            processing_time,
             num_items: 0,
             output_name: output,
             input_buffer: Vec::new(),
             output_buffer: Vec::new(),
+            state: MachineState::Idle,
+            progress: 0.0,
+            completed: 0,
+            recipes: Vec::new(),
+            current_recipe: None,
+            last_recipe: None,
+            recipe_cycles: HashMap::new(),
+            setup_matrix: SetupMatrix::default(),
+            dispatch: DispatchRule::default(),
+            setup_remaining: 0.0,
+            setups: 0,
+            run_length: 0,
+            batch: None,
+            batch_wait: 0.0,
+            blocking: BlockingPolicy::default(),
+            quality: QualityModel::default(),
+            rework_buffer: None,
+            rework_inputs: Vec::new(),
+            cycle_outcome: None,
+            cycle_rework: Vec::new(),
+            cycle_rework_outcome: QualityCounts::default(),
+            quality_counts: QualityCounts::default(),
+            rework_counts: QualityCounts::default(),
+            parts_since_repair: None,
+            hourly_rate: 0.0,
         }
     }
     
     /// Creates a machine with a default markov chain. 1% failure rate
-    pub fn new_default_machine(name: String, processing_time: f64) -> Machine {
-        let markov_chain = create_machine_chain!(name);
-        Machine {
-            id: Uuid::new_v4(),
-            markov_chain: markov_chain,
-            processing_time: processing_time,
-            num_items: 0,
-            output_name: None,
-            input_buffer: Vec::new(),
-            output_buffer: Vec::new(),
-        }
+    pub fn new_default_machine(_name: String, processing_time: impl Into<Distribution>) -> Machine {
+        Machine::new(create_machine_chain!(chain), processing_time, None)
     }
     
     pub fn create_and_add_input_buffer(&mut self, capacity: usize, throughput: Option<f64>) {
@@ -121,214 +240,719 @@ impl Machine {
         }
     }
 
-    pub fn step(&mut self) {
-        todo!();
+    /// The mean processing time per part, or per recipe run, with a batch cycle shared
+    /// between the parts of a full batch, and slowed down by the machine's
+    /// degradation until it is maintained or fails.
+    pub fn time_per_part(&self) -> f64 {
+        let factor = self.degradation.as_ref().map_or(1.0, |degradation| {
+            let trigger = self.maintenance.policy.as_ref().map(|policy| &policy.trigger);
+            degradation.mean_processing_factor(degradation.threshold(trigger))
+        });
+        self.new_time_per_part() * factor
     }
 
-    pub fn set_output_name(&mut self, name: String) {
-        self.output_name = Some(name);
+    /// The mean processing time per part of the machine as new.
+    pub fn new_time_per_part(&self) -> f64 {
+        self.processing_time.mean() / self.batch.map_or(1, |batch| batch.max_size) as f64
     }
 
-    pub fn get_output_name(&self) -> Option<String> {
-        self.output_name.clone()
+    /// Returns the per step probability that the machine breaks down while working,
+    /// read from the Working -> Broken transition of its markov chain.
+    pub fn failure_probability(&self) -> f64 {
+        match (self.markov_chain.find_state("Working"), self.markov_chain.find_state("Broken")) {
+            (Some(working), Some(broken)) => self.markov_chain.transition_probability(working, broken),
+            _ => 0.0,
+        }
     }
-}
 
-pub enum MachineEvent {
-    ChangeItem { item: Item, quantity: f64 },
-    CreateMachine { processing_time: f64, output: Option<String> },
-    AddBuffer { machine_id: Uuid, buffer_id: Uuid },
-    RemoveBuffer { machine_id: Uuid, buffer_id: Uuid },
-}
+    /// Returns the per step probability that a broken machine is repaired, i.e. the
+    /// total probability of leaving the Broken state of its markov chain.
+    pub fn repair_probability(&self) -> f64 {
+        match self.markov_chain.find_state("Broken") {
+            Some(broken) => self.markov_chain.exit_probability(broken),
+            None => 1.0,
+        }
+    }
+
+    /// Sets the Working -> Broken transition probability of the machine's markov chain.
+    pub fn set_failure_probability(&mut self, probability: f64) -> Result<(), &'static str> {
+        match (self.markov_chain.find_state("Working"), self.markov_chain.find_state("Broken")) {
+            (Some(working), Some(broken)) => {
+                self.markov_chain.set_transition_probability(working, broken, probability);
+                Ok(())
+            }
+            _ => Err("Machine markov chain has no Working and Broken states."),
+        }
+    }
+
+    /// Makes the machine fail the given factor more often: its markov chain's failure
+    /// probability, the lifetimes of its failure modes and its wear between degradation
+    /// stages are all scaled. Returns false when nothing on the machine fails.
+    pub fn scale_failure_rates(&mut self, factor: f64) -> bool {
+        let probability = self.failure_probability();
+        if probability > 0.0 {
+            self.set_failure_probability((probability * factor).min(1.0)).ok();
+        }
+        self.failure_model.scale_failure_rates(factor);
+        if let Some(degradation) = self.degradation.as_mut() {
+            degradation.scale_wear(factor);
+        }
+        probability > 0.0 || !self.failure_model.is_empty() || self.degradation.is_some()
+    }
+
+    /// Sets the per step probability that a broken machine is repaired. A repaired
+    /// machine is left idle.
+    pub fn set_repair_probability(&mut self, probability: f64) -> Result<(), &'static str> {
+        match (self.markov_chain.find_state("Broken"), self.markov_chain.find_state("Idle")) {
+            (Some(broken), Some(idle)) => {
+                let targets: Vec<StateIndex> = self.markov_chain.successors(broken)
+                    .filter(|&target| target != broken && target != idle)
+                    .collect();
+                for target in targets {
+                    self.markov_chain.set_transition_probability(broken, target, 0.0);
+                }
+                self.markov_chain.set_transition_probability(broken, idle, probability);
+                Ok(())
+            }
+            _ => Err("Machine markov chain has no Broken and Idle states."),
+        }
+    }
+
+    /// Assigns a recipe to the machine, replacing any recipe with the same id.
+    pub fn add_recipe(&mut self, recipe: Arc<Recipe>) {
+        match self.recipes.iter().position(|existing| existing.id == recipe.id) {
+            Some(index) => self.recipes[index] = recipe,
+            None => self.recipes.push(recipe),
+        }
+    }
+
+    pub fn remove_recipe(&mut self, recipe_id: Uuid) -> Result<(), &'static str> {
+        let index = self.recipes.iter()
+            .position(|recipe| recipe.id == recipe_id)
+            .ok_or("Recipe not found.")?;
+        if self.current_recipe == Some(index) {
+            return Err("Recipe is running on the machine.");
+        }
+        self.recipes.remove(index);
+        let shift = |position: Option<usize>| match position {
+            Some(position) if position > index => Some(position - 1),
+            Some(position) if position == index => None,
+            position => position,
+        };
+        self.current_recipe = shift(self.current_recipe);
+        self.last_recipe = shift(self.last_recipe);
+        Ok(())
+    }
+
+    /// Advances the machine by one time step and returns the state it spent the step in.
+    ///
+    /// A machine without input buffers draws from an unlimited source and a machine
+    /// without output buffers passes finished parts to an unlimited sink. A finished
+    /// part that cannot be passed on stays on the machine, blocking it. The blocking
+    /// policy decides whether the machine also waits for room downstream before it
+    /// processes a part, holding the part, or before it takes one.
+    /// Failures and repairs draw from the machine's own failure stream, exactly one
+    /// number per step, so that runs sharing a seed stay in step. Failure modes
+    /// draw from streams of their own; a machine down for a failure mode is
+    /// repaired by that mode alone. Preventive maintenance starts when the policy
+    /// calls for it and the machine is empty, or, if the policy is opportunistic,
+    /// instead of waiting on its neighbours, and draws its duration from the
+    /// machine's maintenance stream. A degrading machine moves through its stages
+    /// once per working step, drawing from its degradation stream, and is repaired
+    /// from its failed stage with repair times drawn from its repair stream.
+    ///
+    /// A machine with recipes runs one recipe per cycle. The cycle only starts once
+    /// every recipe input is held in the input buffers; the inputs are then withdrawn
+    /// together, and the outputs are deposited together once the processing time has
+    /// passed.
+    ///
+    /// The processing time of each cycle is drawn from the machine's processing
+    /// stream when the cycle starts; deterministic processing times draw nothing.
+    /// Parts sent back for rework are taken before anything in the input buffers,
+    /// and the finished parts are sorted into good, scrap and rework (see `quality`).
+    /// A part taken back stays on its rework pass through every machine up to the
+    /// one that sent it to rework, and is counted apart from first pass parts.
+    pub fn step(&mut self, streams: &mut RngRegistry) -> MachineState {
+        let draw: f64 = streams.stream(self.id, StreamKind::Failure).gen();
+        if !self.failure_model.is_empty() {
+            self.failure_model.draw_lives(streams);
+            self.failure_model.advance(FailureClock::Calendar, 1.0);
+        }
+        self.maintenance.elapsed += 1.0;
+
+        if self.state == MachineState::Down {
+            // A failure mode and the degradation may fail in the same step, in which
+            // case the machine is up once both repairs are done.
+            let mode_repaired = self.failure_model.repair_step(streams);
+            let wear_repaired = self.degradation.as_mut().and_then(Degradation::repair_step);
+            let repaired = match (mode_repaired, wear_repaired) {
+                (Some(false), _) | (_, Some(false)) => Some(false),
+                (mode, wear) => mode.or(wear),
+            };
+            match repaired {
+                Some(false) => {
+                    self.maintenance.repair_downtime += 1;
+                    return MachineState::Down;
+                }
+                Some(true) => {
+                    self.state = MachineState::Idle;
+                    self.parts_since_repair = Some(0);
+                    self.maintenance.renew();
+                }
+                None => {
+                    if draw < self.repair_probability() {
+                        self.state = MachineState::Idle;
+                        self.parts_since_repair = Some(0);
+                    }
+                    self.maintenance.repair_downtime += 1;
+                    return MachineState::Down;
+                }
+            }
+        }
+
+        if self.state == MachineState::Maintenance {
+            if self.maintenance.step() {
+                return MachineState::Maintenance;
+            }
+            self.state = MachineState::Idle;
+        }
+
+        if self.failure_model.fail_due(Some(FailureClock::Calendar), streams) {
+            return self.fail();
+        }
+
+        if self.num_items > 0 && self.progress >= self.cycle_time && !self.finish_cycle(streams) {
+            return self.wait(MachineState::Blocked, streams);
+        }
+
+        if self.num_items == 0 && self.maintenance.is_due(self.degradation.as_ref()) {
+            return self.start_maintenance(streams);
+        }
 
-pub async fn machine_event_handler(
-    rx: mpsc::Receiver<MachineEvent>,
-    machines: Arc<Mutex<Vec<Machine>>>,
-) {
-    while let Some(event) = rx.recv().await {
-        match event {
-            MachineEvent::ChangeItem { item, quantity } => {
-                // Iterate through the machines and update items.
-                // In this example, I'll assume each machine might contain the item in its output_buffer.
-                let mut machines_guard = machines.lock().await;
-                for machine in machines_guard.iter_mut() {
-                    for buffer_arc in &machine.output_buffer {
-                        let mut buffer = buffer_arc.lock().await;
-                        if let Some((_item_ref, item_quantity)) = buffer.items.iter_mut().find(|(existing_item, _)| existing_item.id == item.id) {
-                            // Logic for changing the item quantity
-                            if *item_quantity + quantity >= 0.0 {
-                                *item_quantity += quantity;
-                            }
-                        }
+        if self.num_items == 0 {
+            match self.start_cycle() {
+                Ok(setup) => {
+                    self.cycle_time = self.processing_time.sample(streams.stream(self.id, StreamKind::Processing));
+                    if let Some(degradation) = &self.degradation {
+                        self.cycle_time *= degradation.stage().processing_factor;
+                    }
+                    if let Some(setup) = setup {
+                        self.setup_remaining = setup.sample(streams.stream(self.id, StreamKind::Setup));
+                        self.setups += 1;
                     }
                 }
+                Err(state) => return self.wait(state, streams),
+            }
+        }
+
+        if self.setup_remaining > 0.0 {
+            self.setup_remaining -= 1.0;
+            self.state = MachineState::Setup;
+            return self.state;
+        }
+
+        // Progress below a whole step is carried over from the last cycle, so the
+        // part has not been processed yet.
+        if self.blocking == BlockingPolicy::BeforeService && self.progress < 1.0 && !self.cycle_fits() {
+            return self.wait(MachineState::Blocked, streams);
+        }
+
+        // Every failure stream moves on each working step, whichever of them fails,
+        // so that the streams stay in step across scenarios.
+        let mode_failed = self.failure_model.fail_due(None, streams);
+        let degraded = self.degrade(streams);
+        if draw < self.failure_probability() || mode_failed || degraded {
+            return self.fail();
+        }
+
+        self.progress += 1.0;
+        self.failure_model.advance(FailureClock::Busy, 1.0);
+        self.maintenance.age += 1.0;
+        self.state = MachineState::Working;
+        if self.progress >= self.cycle_time {
+            self.finish_cycle(streams);
+        }
+        self.state
+    }
+
+    /// Takes the machine down for a failure that strikes in the current time step.
+    fn fail(&mut self) -> MachineState {
+        self.maintenance.failures += 1;
+        self.maintenance.repair_downtime += 1;
+        self.state = MachineState::Down;
+        self.state
+    }
+
+    /// Moves the machine's degradation on by a working step, starting its repair if
+    /// it reaches the failed stage. Returns whether it did.
+    fn degrade(&mut self, streams: &mut RngRegistry) -> bool {
+        let Some(degradation) = &mut self.degradation else {
+            return false;
+        };
+        if !degradation.advance(streams.stream(self.id, StreamKind::Degradation).gen()) {
+            return false;
+        }
+        degradation.start_repair(streams.stream(self.id, StreamKind::Repair));
+        true
+    }
+
+    /// Leaves the machine waiting on its neighbours in the given state, unless its
+    /// maintenance policy takes the chance to maintain it.
+    fn wait(&mut self, state: MachineState, streams: &mut RngRegistry) -> MachineState {
+        if self.maintenance.is_opportune() {
+            return self.start_maintenance(streams);
+        }
+        self.state = state;
+        self.state
+    }
+
+    /// Starts a maintenance, which renews every failure mode, and spends the current
+    /// time step on it.
+    fn start_maintenance(&mut self, streams: &mut RngRegistry) -> MachineState {
+        let Some(policy) = &self.maintenance.policy else {
+            return self.state;
+        };
+        let duration = policy.duration.sample(streams.stream(self.id, StreamKind::Maintenance));
+        self.maintenance.start(duration);
+        self.failure_model.renew();
+        if let Some(degradation) = &mut self.degradation {
+            degradation.renew();
+        }
+        self.state = MachineState::Maintenance;
+        self.state
+    }
+
+    /// Takes the inputs of the next cycle, or returns the state the machine is left
+    /// in when it cannot start one. Returns the setup time of the changeover the
+    /// cycle needs, if any.
+    fn start_cycle(&mut self) -> Result<Option<Distribution>, MachineState> {
+        if let Some(batch) = self.batch {
+            return self.start_batch(batch);
+        }
+        let communication = self.blocking == BlockingPolicy::Communication;
+        if self.recipes.is_empty() {
+            if communication && self.part_room() == 0 {
+                return Err(if self.parts_ready() > 0 { MachineState::Blocked } else { MachineState::Starved });
             }
-            MachineEvent::CreateMachine { processing_time, output } => {
-                let mut machines_guard = machines.lock().await;
-                let new_machine = Machine {
-                    id: Uuid::new_v4(),
-                    markov_chain: /* initialize here */,
-                    processing_time,
-                    num_items: 0,
-                    output_name: output,
-                    input_buffer: vec![],
-                    output_buffer: vec![],
-                };
-                machines_guard.push(new_machine);
+            if !self.withdraw_part() {
+                return Err(MachineState::Starved);
             }
-            MachineEvent::AddBuffer { machine_id, buffer_id } => {
-                let mut machines_guard = machines.lock().await;
-                if let Some(machine) = machines_guard.iter_mut().find(|m| m.id == machine_id) {
-                    // Assuming you have a way to find or create this buffer.
-                    let buffer = /* find or create buffer by buffer_id */;
-                    machine.output_buffer.push(Arc::new(Mutex::new(buffer)));
+            self.num_items = 1;
+            return Ok(None);
+        }
+        let mut inputs_available = false;
+        let mut started = None;
+        for index in self.dispatch_order() {
+            let recipe = self.recipes[index].clone();
+            if !self.inputs_available(&recipe) {
+                continue;
+            }
+            inputs_available = true;
+            // A recipe only starts once its outputs fit, under every blocking policy.
+            if self.outputs_fit(&recipe) {
+                self.withdraw_inputs(&recipe, 1);
+                started = Some(index);
+                break;
+            }
+        }
+        let index = match started {
+            Some(index) => index,
+            None if inputs_available => return Err(MachineState::Blocked),
+            None => return Err(MachineState::Starved),
+        };
+        Ok(self.begin_recipe(index, 1))
+    }
+
+    /// Records the start of a cycle of the recipe at the given index, returning the
+    /// setup time of the changeover it needs, if any.
+    fn begin_recipe(&mut self, index: usize, parts: usize) -> Option<Distribution> {
+        let product = self.setup_matrix.product(&self.recipes[index]);
+        let previous = self.last_recipe.map(|last| self.setup_matrix.product(&self.recipes[last]));
+        self.run_length = if previous == Some(product) { self.run_length + 1 } else { 1 };
+        self.current_recipe = Some(index);
+        self.last_recipe = Some(index);
+        self.num_items = parts;
+        previous.and_then(|previous| self.setup_matrix.time(previous, product)).cloned()
+    }
+
+    /// Starts a batch if the start policy allows, withdrawing its parts or the inputs
+    /// of its recipe runs.
+    fn start_batch(&mut self, batch: BatchMode) -> Result<Option<Distribution>, MachineState> {
+        let communication = self.blocking == BlockingPolicy::Communication;
+        let mut waiting = MachineState::Starved;
+        let mut minimum_ready = false;
+        if self.recipes.is_empty() {
+            let ready = self.parts_ready().min(batch.max_size);
+            let fits = if communication { ready.min(self.part_room()) } else { ready };
+            minimum_ready = ready >= batch.min_size;
+            match batch.start_size(ready, fits, self.batch_wait) {
+                Ok(size) => {
+                    for _ in 0..size {
+                        self.withdraw_part();
+                    }
+                    self.num_items = size;
+                    self.batch_wait = 0.0;
+                    return Ok(None);
                 }
+                Err(state) => waiting = state,
+            }
+        } else {
+            let mut candidates: Vec<(usize, usize)> = self.dispatch_order()
+                .into_iter()
+                .map(|index| (index, self.recipe_runs_ready(&self.recipes[index]).min(batch.max_size)))
+                .collect();
+            if matches!(batch.policy, StartPolicy::CompatibleItems { .. }) {
+                candidates.sort_by_key(|&(_, ready)| std::cmp::Reverse(ready));
             }
-            MachineEvent::RemoveBuffer { machine_id, buffer_id } => {
-                let mut machines_guard = machines.lock().await;
-                if let Some(machine) = machines_guard.iter_mut().find(|m| m.id == machine_id) {
-                    machine.output_buffer.retain(|buffer_arc| {
-                        let buffer = buffer_arc.lock().await;
-                        buffer.id != buffer_id
-                    });
+            for (index, ready) in candidates {
+                let recipe = self.recipes[index].clone();
+                let fits = (1..=ready).rev().find(|&runs| self.outputs_fit(&recipe.scaled(runs as f64))).unwrap_or(0);
+                minimum_ready |= ready >= batch.min_size;
+                match batch.start_size(ready, fits, self.batch_wait) {
+                    Ok(size) => {
+                        self.withdraw_inputs(&recipe, size);
+                        self.batch_wait = 0.0;
+                        return Ok(self.begin_recipe(index, size));
+                    }
+                    Err(MachineState::Blocked) => waiting = MachineState::Blocked,
+                    Err(_) => {}
                 }
             }
         }
+        self.batch_wait = if minimum_ready { self.batch_wait + 1.0 } else { 0.0 };
+        Err(waiting)
     }
-}
 
-pub struct Buffer {
-    pub id: Uuid,
-    pub name: Option<String>,
-    pub capacity: usize,
-    pub num_items: usize,
-    pub throughput: Option<f64>,
-    pub items: Vec<(Arc<Item>, f64)>,
-}
+    /// The number of parts in the rework and input buffers, unlimited without input
+    /// buffers.
+    fn parts_ready(&self) -> usize {
+        if self.input_buffer.is_empty() {
+            return usize::MAX;
+        }
+        self.input_sources().map(|buffer| buffer.lock().unwrap().num_items).sum()
+    }
 
-impl Buffer {
-    pub fn new(capacity: usize, throughput: Option<f64>, name: Option<String>) -> Buffer {
-        Buffer {
-            id: Uuid::new_v4(),
-            name: name,
-            capacity: capacity,
-            num_items: 0,
-            throughput: throughput,
-            items: Vec::new(),
+    /// The buffers the machine takes its inputs from, rework first.
+    fn input_sources(&self) -> impl Iterator<Item = &Arc<Mutex<Buffer>>> {
+        self.rework_inputs.iter().chain(&self.input_buffer)
+    }
+
+    /// The number of parts the output buffers have room for, unlimited without output buffers.
+    fn part_room(&self) -> usize {
+        if self.output_buffer.is_empty() {
+            return usize::MAX;
         }
+        self.output_buffer.iter()
+            .map(|buffer| {
+                let buffer = buffer.lock().unwrap();
+                buffer.capacity.saturating_sub(buffer.num_items)
+            })
+            .sum()
     }
 
-    pub fn add_item(&mut self) {
-        if self.num_items < self.capacity {
-            self.num_items += 1;
+    /// How many runs of the recipe the input buffers hold the inputs for, unlimited
+    /// without input buffers.
+    fn recipe_runs_ready(&self, recipe: &Recipe) -> usize {
+        if self.input_buffer.is_empty() {
+            return usize::MAX;
         }
+        recipe.input_totals().into_iter()
+            .map(|(item_id, quantity)| {
+                let held: f64 = self.input_sources()
+                    .map(|buffer| buffer.lock().unwrap().quantity_of(item_id))
+                    .sum();
+                ((held + QUANTITY_TOLERANCE) / quantity).floor() as usize
+            })
+            .min()
+            .unwrap_or(usize::MAX)
     }
 
-    pub fn remove_item(&mut self) {
-        if self.num_items > 0 {
-            self.num_items -= 1;
+    /// The order in which the recipes are tried for the next cycle. Round robin starts
+    /// after the last recipe run, so that one recipe cannot starve the others;
+    /// batching first tries the recipes making the same product as the last one.
+    fn dispatch_order(&self) -> Vec<usize> {
+        let count = self.recipes.len();
+        let Some(last) = self.last_recipe else {
+            return (0..count).collect();
+        };
+        match self.dispatch {
+            DispatchRule::Batching { max_run } if max_run.is_none_or(|max_run| self.run_length < max_run) => {
+                let product = self.setup_matrix.product(&self.recipes[last]);
+                let (mut same, other): (Vec<usize>, Vec<usize>) = (0..count)
+                    .map(|offset| (last + offset) % count)
+                    .partition(|&index| self.setup_matrix.product(&self.recipes[index]) == product);
+                same.extend(other);
+                same
+            }
+            _ => (1..=count).map(|offset| (last + offset) % count).collect(),
+        }
+    }
+
+    /// Whether the output buffers have room for everything the current cycle makes.
+    fn cycle_fits(&self) -> bool {
+        match self.current_recipe {
+            Some(index) => self.outputs_fit(&self.recipes[index].scaled(self.num_items as f64)),
+            None => self.part_room() >= self.num_items,
         }
     }
 
-    pub fn is_full(&self) -> bool {
-        self.num_items == self.capacity
+    /// Passes on the outputs of the finished cycle, the good parts to the output
+    /// buffers and those sent to rework to the rework buffer, returning false if there
+    /// is no room for either. The outcome is kept for the next attempt.
+    fn finish_cycle(&mut self, streams: &mut RngRegistry) -> bool {
+        let outcome = match self.cycle_outcome {
+            Some(outcome) => outcome,
+            None => self.draw_outcome(streams),
+        };
+        self.cycle_outcome = Some(outcome);
+        let recipe = self.current_recipe.map(|index| self.recipes[index].clone());
+        if !self.rework_fits(recipe.as_deref(), outcome.rework) || !self.deposit_good(recipe.as_deref(), outcome.good) {
+            return false;
+        }
+        self.deposit_rework(recipe.as_deref(), outcome.rework);
+        self.pass_on_rework();
+        let runs = self.num_items;
+        self.num_items = 0;
+        self.progress -= self.cycle_time;
+        self.completed += outcome.good;
+        self.quality_counts.add(outcome.since(self.cycle_rework_outcome));
+        self.rework_counts.add(self.cycle_rework_outcome);
+        self.cycle_outcome = None;
+        self.cycle_rework_outcome = QualityCounts::default();
+        self.failure_model.advance(FailureClock::Cycles, 1.0);
+        self.maintenance.cycles += 1;
+        if let Some(recipe) = recipe {
+            *self.recipe_cycles.entry(recipe.id).or_insert(0) += runs;
+        }
+        self.current_recipe = None;
+        true
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.num_items == 0
+    /// Draws whether each part of the current cycle is good, scrap or to be reworked.
+    fn draw_outcome(&mut self, streams: &mut RngRegistry) -> QualityCounts {
+        let parts = self.num_items;
+        let since_repair = self.parts_since_repair;
+        self.parts_since_repair = since_repair.map(|since| since + parts);
+        // The parts on a rework pass come first.
+        let reworked = self.cycle_rework.len().min(parts);
+        if self.quality.is_perfect() && self.degradation.as_ref().is_none_or(Degradation::is_perfect) {
+            self.cycle_rework_outcome = QualityCounts::good(reworked);
+            return QualityCounts::good(parts);
+        }
+        let recipe_id = self.current_recipe.map(|index| self.recipes[index].id);
+        // The stage the machine has worn to decides its yield over anything else.
+        let stage_rates = self.degradation.as_ref().and_then(|degradation| degradation.stage().rates);
+        let stream = streams.stream(self.id, StreamKind::Quality);
+        let mut outcome = QualityCounts::default();
+        for part in 0..parts {
+            if part == reworked {
+                self.cycle_rework_outcome = outcome;
+            }
+            let rates = stage_rates.unwrap_or_else(|| self.quality.rates(recipe_id, since_repair.map(|since| since + part)));
+            outcome.draw(rates, stream);
+        }
+        if reworked == parts {
+            self.cycle_rework_outcome = outcome;
+        }
+        outcome
     }
 
-    pub fn num_items(&self) -> usize {
-        self.num_items
+    /// Marks the good parts of the finished cycle that are on a rework pass in the
+    /// output buffers, unless their pass ends at this machine.
+    fn pass_on_rework(&mut self) {
+        let passes: Vec<Uuid> = self.cycle_rework.drain(..)
+            .take(self.cycle_rework_outcome.good)
+            .filter(|&machine_id| machine_id != self.id)
+            .collect();
+        let mut buffers = self.output_buffer.iter();
+        let mut buffer = buffers.next();
+        for machine_id in passes {
+            while let Some(current) = buffer {
+                if current.lock().unwrap().add_rework_pass(machine_id) {
+                    break;
+                }
+                buffer = buffers.next();
+            }
+        }
     }
 
-    pub fn set_throughput(&mut self, throughput: f64) {
-        self.throughput = Some(throughput);
+    fn deposit_good(&mut self, recipe: Option<&Recipe>, parts: usize) -> bool {
+        if parts == 0 {
+            return true;
+        }
+        match recipe {
+            Some(recipe) => self.deposit_outputs(&recipe.scaled(parts as f64)),
+            None => self.part_room() >= parts && (0..parts).all(|_| self.deposit_part()),
+        }
     }
 
-    pub fn get_throughput(&self) -> Option<f64> {
-        self.throughput
+    /// Whether the rework buffer has room for the parts sent to rework. Without a
+    /// rework buffer they leave the system.
+    fn rework_fits(&self, recipe: Option<&Recipe>, parts: usize) -> bool {
+        let Some(buffer) = self.rework_buffer.as_ref().filter(|_| parts > 0) else {
+            return true;
+        };
+        let quantity = recipe.map_or(1.0, |recipe| recipe.output.iter().map(|(_, quantity)| quantity).sum()) * parts as f64;
+        buffer.lock().unwrap().room() + QUANTITY_TOLERANCE >= quantity
     }
 
-    pub fn change_name(&mut self, name: String) {
-        self.name = Some(name);
+    fn deposit_rework(&mut self, recipe: Option<&Recipe>, parts: usize) {
+        let Some(buffer) = self.rework_buffer.as_ref().filter(|_| parts > 0) else {
+            return;
+        };
+        let mut buffer = buffer.lock().unwrap();
+        match recipe {
+            Some(recipe) => {
+                for (item, quantity) in recipe.scaled(parts as f64).output {
+                    let deposited = buffer.deposit(item, quantity);
+                    debug_assert!(deposited.is_ok(), "rework did not fit");
+                }
+            }
+            None => {
+                for _ in 0..parts {
+                    buffer.add_item();
+                }
+            }
+        }
+        for _ in 0..parts {
+            buffer.add_rework_pass(self.id);
+        }
     }
-}
 
-enum BufferEvent {
-    ChangeItem { item: Item, quantity: f64 },
-    CreateBuffer { capacity: usize, throughput: Option<f64>, name: Option<String> },
-    DestroyBuffer { id: Uuid },
-}
+    fn withdraw_part(&mut self) -> bool {
+        for buffer in self.rework_inputs.iter().chain(&self.input_buffer) {
+            let mut buffer = buffer.lock().unwrap();
+            if !buffer.is_empty() {
+                buffer.remove_item();
+                if let Some(machine_id) = buffer.take_rework_pass() {
+                    self.cycle_rework.push(machine_id);
+                }
+                return true;
+            }
+        }
+        self.input_buffer.is_empty()
+    }
 
-pub async fn change_items_to_buffer_by_id(
-    &self,
-    tx: &tokio::sync::mpsc::Sender<(Uuid, BufferEvent)>,
-    buffer_id: Uuid,
-    item: Item,
-    quantity: f64,
-) -> Result<(), &'static str> {
-    // Send a change request event.
-    tx.send((buffer_id, BufferEvent::ChangeItem { item, quantity })).await.map_err(|_| "Failed to send change request")
-}
+    fn deposit_part(&mut self) -> bool {
+        if self.output_buffer.is_empty() {
+            return true;
+        }
+        for buffer in &self.output_buffer {
+            let mut buffer = buffer.lock().unwrap();
+            if !buffer.is_full() {
+                buffer.add_item();
+                return true;
+            }
+        }
+        false
+    }
 
-pub async fn create_buffer(
-    &self,
-    tx: &tokio::sync::mpsc::Sender<(Uuid, BufferEvent)>,
-    capacity: usize,
-    throughput: Option<f64>,
-    name: Option<String>,
-) {
-    // Send a create buffer event.
-    tx.send((Uuid::new_v4(), BufferEvent::CreateBuffer { capacity, throughput, name })).await.map_err(|_| "Failed to send create buffer request")
-}
+    /// Whether the rework and input buffers together hold every input of the recipe,
+    /// counting an item listed on several lines once for their total.
+    /// A machine without input buffers is fed by an unlimited source.
+    pub fn inputs_available(&self, recipe: &Recipe) -> bool {
+        if self.input_buffer.is_empty() {
+            return true;
+        }
+        recipe.input_totals().into_iter().all(|(item_id, quantity)| {
+            let held: f64 = self.input_sources()
+                .map(|buffer| buffer.lock().unwrap().quantity_of(item_id))
+                .sum();
+            held + QUANTITY_TOLERANCE >= quantity
+        })
+    }
 
-pub async fn destroy_buffer(
-    &self,
-    tx: &tokio::sync::mpsc::Sender<(Uuid, BufferEvent)>,
-    id: Uuid,
-) {
-    // Send a destroy buffer event.
-    tx.send((id, BufferEvent::DestroyBuffer { id })).await.map_err(|_| "Failed to send destroy buffer request")
-}
+    /// Whether every output of the recipe fits in the output buffers.
+    /// A machine without output buffers passes its outputs to an unlimited sink.
+    pub fn outputs_fit(&self, recipe: &Recipe) -> bool {
+        self.plan_outputs(recipe).is_some()
+    }
 
-pub async fn buffer_event_handler(rx: tokio::sync::mpsc::Receiver<(Uuid, BufferEvent)>, buffers: Vec<tokio::sync::Mutex<Buffer>>) {
-    while let Some((buffer_id, event)) = rx.recv().await {
-        match event {
-            BufferEvent::ChangeItem { item, quantity } => {
-                if let Some(buffer) = buffers.iter().find(|b| b.lock().await.id == buffer_id) {
-                    let mut locked_buffer = buffer.lock().await;
-
-                    // Try to find the item in the buffer's items list.
-                    if let Some((_item_ref, item_quantity)) = locked_buffer.items.iter_mut().find(|(existing_item, _)| existing_item.id == item.id) {
-                        if *item_quantity + quantity < 0.0 {
-                            // Handle the error, possibly with logging or other mechanisms.
-                        } else {
-                            *item_quantity += quantity;
-                        }
-                    } else {
-                        if quantity >= 0.0 {
-                            locked_buffer.items.push((Arc::new(item), quantity));
-                        } else {
-                            // Handle the error.
-                        }
-                    }
+    /// Assigns every output of the recipe to an output buffer, preferring buffers that
+    /// already hold the item and then buffers named after it, or returns None if some
+    /// output does not fit.
+    fn plan_outputs(&self, recipe: &Recipe) -> Option<Vec<(usize, Arc<Item>, f64)>> {
+        if self.output_buffer.is_empty() {
+            return Some(Vec::new());
+        }
+        let buffers: Vec<(f64, Vec<Uuid>, Option<String>)> = self.output_buffer.iter()
+            .map(|buffer| {
+                let buffer = buffer.lock().unwrap();
+                let held = buffer.items.iter().map(|(item, _)| item.id()).collect();
+                (buffer.room(), held, buffer.name.clone())
+            })
+            .collect();
+        let mut room: Vec<f64> = buffers.iter().map(|(room, _, _)| *room).collect();
+        let mut plan = Vec::new();
+        for (item, quantity) in &recipe.output {
+            let preference = |index: &usize| {
+                let (_, held, name) = &buffers[*index];
+                if held.contains(&item.id()) {
+                    0
+                } else if name.as_deref() == Some(item.name.as_str()) {
+                    1
                 } else {
-                    // Handle the buffer not found error.
+                    2
                 }
-            }
-            BufferEvent::CreateBuffer { capacity, throughput, name } => {
-                // Create a new buffer and add it to the buffers vector.
-                let mut buffer = Buffer::new(capacity, throughput, name);
-                buffers.push(tokio::sync::Mutex::new(buffer));
-            }
-            BufferEvent::DestroyBuffer { id } => {
-                // Remove the buffer from the buffers vector.
-                if let Some(index) = buffers.iter().position(|b| b.lock().await.id == id) {
-                    buffers.remove(index);
+            };
+            let mut candidates: Vec<usize> = (0..buffers.len())
+                .filter(|&index| room[index] + QUANTITY_TOLERANCE >= *quantity)
+                .collect();
+            candidates.sort_by_key(preference);
+            let index = *candidates.first()?;
+            room[index] -= quantity;
+            plan.push((index, item.clone(), *quantity));
+        }
+        Some(plan)
+    }
+
+    /// Withdraws the inputs of the given number of runs of the recipe, along with
+    /// the marks of as many of those runs as are on a rework pass.
+    fn withdraw_inputs(&mut self, recipe: &Recipe, runs: usize) {
+        if self.input_buffer.is_empty() {
+            return;
+        }
+        let recipe = recipe.scaled(runs as f64);
+        let mut passes = Vec::new();
+        for (item, quantity) in &recipe.input {
+            let mut remaining = *quantity;
+            for buffer in self.rework_inputs.iter().chain(&self.input_buffer) {
+                if remaining <= QUANTITY_TOLERANCE {
+                    break;
+                }
+                let mut buffer = buffer.lock().unwrap();
+                let taken = buffer.quantity_of(item.id()).min(remaining);
+                if taken > 0.0 && buffer.withdraw(item.id(), taken).is_ok() {
+                    remaining -= taken;
+                    while passes.len() < runs {
+                        let Some(machine_id) = buffer.take_rework_pass() else {
+                            break;
+                        };
+                        passes.push(machine_id);
+                    }
                 }
             }
         }
+        self.cycle_rework.extend(passes);
+    }
+
+    fn deposit_outputs(&mut self, recipe: &Recipe) -> bool {
+        let Some(plan) = self.plan_outputs(recipe) else {
+            return false;
+        };
+        for (index, item, quantity) in plan {
+            let deposited = self.output_buffer[index].lock().unwrap().deposit(item, quantity);
+            debug_assert!(deposited.is_ok(), "planned output did not fit");
+        }
+        true
+    }
+
+    pub fn set_output_name(&mut self, name: String) {
+        self.output_name = Some(name);
+    }
+
+    pub fn get_output_name(&self) -> Option<String> {
+        self.output_name.clone()
     }
 }
 
@@ -343,59 +967,178 @@ impl Item {
     pub fn new(name: String, size: f64, cost: Option<f64>) -> Item {
         Item {
             id: Uuid::new_v4(),
-            name: name,
-            size: size,
-            cost: cost,
+            name,
+            size,
+            cost,
         }
     }
+
+    /// Creates an item with a known id, for rebuilding a model.
+    pub fn with_id(id: Uuid, name: String, size: f64, cost: Option<f64>) -> Item {
+        Item { id, name, size, cost }
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
 }
 
+#[derive(Clone)]
 pub struct Recipe {
+    pub id: Uuid,
     pub name: String,
     pub input: Vec<(Arc<Item>, f64)>,
     pub output: Vec<(Arc<Item>, f64)>,
+    /// Whether the recipe deliberately feeds items back into an earlier stage, such
+    /// as reprocessing scrap into raw material. Only such recipes may close a cycle
+    /// in the recipe graph.
+    pub recycling: bool,
+}
+
+impl Recipe {
+    /// The recipe run the given number of times at once, as in a batch.
+    pub fn scaled(&self, runs: f64) -> Recipe {
+        let scale = |quantities: &[(Arc<Item>, f64)]| quantities.iter().map(|(item, quantity)| (item.clone(), quantity * runs)).collect();
+        Recipe { input: scale(&self.input), output: scale(&self.output), ..self.clone() }
+    }
+
+    /// The total quantity of every input item, adding up lines that list the same item.
+    pub fn input_totals(&self) -> Vec<(Uuid, f64)> {
+        let mut totals: Vec<(Uuid, f64)> = Vec::new();
+        for (item, quantity) in &self.input {
+            match totals.iter_mut().find(|(id, _)| *id == item.id()) {
+                Some((_, total)) => *total += quantity,
+                None => totals.push((item.id(), *quantity)),
+            }
+        }
+        totals
+    }
+
+    pub fn new(name: String, input: Vec<(Arc<Item>, f64)>, output: Vec<(Arc<Item>, f64)>) -> Recipe {
+        Recipe {
+            id: Uuid::new_v4(),
+            name,
+            input,
+            output,
+            recycling: false,
+        }
+    }
 }
 
-pub enum RecipeEvent {
-    CreateRecipe { name: String },
-    AddInput { recipe_id: Uuid, item_id: Uuid, quantity: f64 },
-    AddOutput { recipe_id: Uuid, item_id: Uuid, quantity: f64 },
+/// The items and recipes of a model. Items are held once and shared: every recipe
+/// that uses an item refers to the registry's own `Arc<Item>`.
+#[derive(Default)]
+pub struct RecipeRegistry {
+    items: Vec<Arc<Item>>,
+    recipes: Vec<Arc<Recipe>>,
 }
 
+impl RecipeRegistry {
+    pub fn new() -> RecipeRegistry {
+        RecipeRegistry::default()
+    }
 
-// we probably want to use rw locks here
-pub async fn recipe_event_handler(
-    rx: tokio::sync::mpsc::Receiver<RecipeEvent>,
-    recipes: Arc<Mutex<Vec<Recipe>>>,
-    items: Arc<Mutex<Vec<Item>>>,
-) {
-    while let Some(event) = rx.recv().await {
-        match event {
-            RecipeEvent::CreateRecipe { name } => {
-                let mut recipes_guard = recipes.lock().await;
-                let new_recipe = Recipe {
-                    name: name,
-                    input: vec![],
-                    output: vec![],
-                };
-                recipes_guard.push(new_recipe);
-            }
-            RecipeEvent::AddInput { recipe_id, item_id, quantity } => {
-                let mut recipes_guard = recipes.lock().await;
-                if let Some(recipe) = recipes_guard.iter_mut().find(|r| r.id == recipe_id) {
-                    if let Some(item) = items.lock().await.iter().find(|i| i.id == item_id) {
-                        recipe.input.push((Arc::new(item), quantity));
-                    }
-                }
-            }
-            RecipeEvent::AddOutput { recipe_id, item_id, quantity } => {
-                let mut recipes_guard = recipes.lock().await;
-                if let Some(recipe) = recipes_guard.iter_mut().find(|r| r.id == recipe_id) {
-                    if let Some(item) = items.lock().await.iter().find(|i| i.id == item_id) {
-                        recipe.output.push((Arc::new(item), quantity));
-                    }
-                }
-            }
+    pub fn add_item(&mut self, item: Item) -> Result<Uuid, &'static str> {
+        self.add_shared_item(Arc::new(item))
+    }
+
+    /// Adds an item that is already shared with other parts of the model.
+    pub fn add_shared_item(&mut self, item: Arc<Item>) -> Result<Uuid, &'static str> {
+        let id = item.id();
+        if self.item(id).is_some() {
+            return Err("Item already exists.");
+        }
+        self.items.push(item);
+        Ok(id)
+    }
+
+    /// Removes an item that no recipe uses.
+    pub fn remove_item(&mut self, item_id: Uuid) -> Result<Arc<Item>, &'static str> {
+        let index = self.items.iter().position(|item| item.id() == item_id).ok_or("Item not found.")?;
+        let used = self.recipes.iter()
+            .any(|recipe| recipe.input.iter().chain(recipe.output.iter()).any(|(item, _)| item.id() == item_id));
+        if used {
+            return Err("Item is used by a recipe.");
+        }
+        Ok(self.items.remove(index))
+    }
+
+    pub fn item(&self, item_id: Uuid) -> Option<Arc<Item>> {
+        self.items.iter().find(|item| item.id() == item_id).cloned()
+    }
+
+    pub fn items(&self) -> &[Arc<Item>] {
+        &self.items
+    }
+
+    pub fn create_recipe(&mut self, name: String) -> Uuid {
+        let recipe = Recipe::new(name, vec![], vec![]);
+        let id = recipe.id;
+        self.recipes.push(Arc::new(recipe));
+        id
+    }
+
+    /// Adds a recipe whose items are all in the registry, replacing its items with
+    /// the registry's shared ones.
+    pub fn add_recipe(&mut self, mut recipe: Recipe) -> Result<Uuid, &'static str> {
+        if self.recipe(recipe.id).is_some() {
+            return Err("Recipe already exists.");
+        }
+        for (item, _) in recipe.input.iter_mut().chain(recipe.output.iter_mut()) {
+            *item = self.item(item.id()).ok_or("Item not found.")?;
+        }
+        let id = recipe.id;
+        self.recipes.push(Arc::new(recipe));
+        Ok(id)
+    }
+
+    pub fn remove_recipe(&mut self, recipe_id: Uuid) -> Result<Arc<Recipe>, &'static str> {
+        let index = self.recipes.iter().position(|recipe| recipe.id == recipe_id).ok_or("Recipe not found.")?;
+        Ok(self.recipes.remove(index))
+    }
+
+    pub fn recipe(&self, recipe_id: Uuid) -> Option<Arc<Recipe>> {
+        self.recipes.iter().find(|recipe| recipe.id == recipe_id).cloned()
+    }
+
+    pub fn recipes(&self) -> &[Arc<Recipe>] {
+        &self.recipes
+    }
+
+    /// Adds an input to a recipe. Machines already holding the recipe keep the
+    /// version they were given.
+    pub fn add_input(&mut self, recipe_id: Uuid, item_id: Uuid, quantity: f64) -> Result<(), &'static str> {
+        let (recipe, item) = self.recipe_and_item(recipe_id, item_id, quantity)?;
+        recipe.input.push((item, quantity));
+        Ok(())
+    }
+
+    /// Adds an output to a recipe. Machines already holding the recipe keep the
+    /// version they were given.
+    pub fn add_output(&mut self, recipe_id: Uuid, item_id: Uuid, quantity: f64) -> Result<(), &'static str> {
+        let (recipe, item) = self.recipe_and_item(recipe_id, item_id, quantity)?;
+        recipe.output.push((item, quantity));
+        Ok(())
+    }
+
+    /// Marks a recipe as recycling or not. Machines already holding the recipe keep
+    /// the version they were given.
+    pub fn set_recycling(&mut self, recipe_id: Uuid, recycling: bool) -> Result<(), &'static str> {
+        let recipe = self.recipes.iter_mut()
+            .find(|recipe| recipe.id == recipe_id)
+            .ok_or("Recipe not found.")?;
+        Arc::make_mut(recipe).recycling = recycling;
+        Ok(())
+    }
+
+    fn recipe_and_item(&mut self, recipe_id: Uuid, item_id: Uuid, quantity: f64) -> Result<(&mut Recipe, Arc<Item>), &'static str> {
+        if !(quantity.is_finite() && quantity > 0.0) {
+            return Err("Quantity must be positive.");
         }
+        let item = self.item(item_id).ok_or("Item not found.")?;
+        let recipe = self.recipes.iter_mut()
+            .find(|recipe| recipe.id == recipe_id)
+            .ok_or("Recipe not found.")?;
+        Ok((Arc::make_mut(recipe), item))
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,482 @@
+//! Preventive maintenance policies and their analysis.
+//!
+//! A machine may follow a maintenance policy that takes it down for maintenance
+//! before it fails: once it has worked for a given time (age based), finished a
+//! given number of cycles (usage based), at fixed calendar intervals (calendar
+//! based), or whenever it would otherwise wait on its neighbours once it has worked
+//! long enough (opportunistic), optionally forced at a maximum age, or once its
+//! degradation has reached a threshold stage (condition based). Scheduled
+//! maintenance waits until the machine has no part on it; opportunistic maintenance
+//! uses the time the machine is starved or blocked. Maintenance lasts a duration
+//! drawn from the machine's maintenance stream, needs resources such as
+//! technicians for as long as it lasts, and renews every failure mode of the
+//! machine and restores its first degradation stage. Repairing a failure mode also
+//! restarts the age and usage counts, so that a machine is maintained at a given
+//! age or at failure, whichever comes first.
+//!
+//! Resources have no capacity: they are priced and counted, not constrained. A
+//! maintenance never waits for a resource, and machines maintained at the same time
+//! each tie up what they need, so that two machines may use a single technician at
+//! once. The mean quantity in use is reported per resource, and may exceed what a
+//! plant has at hand.
+//!
+//! The analysis compares the availability and cost of a policy with running the
+//! machine to failure. Age, usage and opportunistic policies are evaluated as age
+//! replacement, the opportunistic one as if an opportunity came as soon as it is
+//! allowed, and calendar policies as block replacement, with the expected failures
+//! between maintenances from the renewal function of the machine's lifetime. The
+//! lifetime is that of the failure modes together, measured in working time; the
+//! failures of the Markov chain do not age and are the same under every policy.
+//! Condition based policies run the failure modes to failure, and take the
+//! maintenances and failures of the degradation chain from `degradation` at their
+//! threshold; other policies leave the degradation chain to run to failure.
+
+use serde::{Deserialize, Serialize};
+use crate::distribution::Distribution;
+use crate::failure::FailureClock;
+use crate::degradation::{self, Degradation};
+use crate::machine::Machine;
+
+/// Integration steps over the lifetime of a machine.
+const GRID_STEPS: usize = 4000;
+/// Steps of the renewal function of block replacement, which takes quadratic time.
+const RENEWAL_STEPS: usize = 1000;
+
+/// When a machine is taken down for maintenance.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum MaintenanceTrigger {
+    /// After the given time steps of work since the last maintenance or repair.
+    Age { interval: f64 },
+    /// After the given number of completed cycles since the last maintenance or repair.
+    Usage { cycles: usize },
+    /// Every given number of time steps, whatever the machine did in between.
+    Calendar { period: f64 },
+    /// When the machine would be starved or blocked and has worked at least the
+    /// minimum age, and in any case once it reaches the maximum age.
+    Opportunistic { min_age: f64, max_age: Option<f64> },
+    /// Once the machine's degradation has reached the named stage or a later one.
+    Condition { stage: String },
+}
+
+impl MaintenanceTrigger {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MaintenanceTrigger::Age { .. } => "age",
+            MaintenanceTrigger::Usage { .. } => "usage",
+            MaintenanceTrigger::Calendar { .. } => "calendar",
+            MaintenanceTrigger::Opportunistic { .. } => "opportunistic",
+            MaintenanceTrigger::Condition { .. } => "condition",
+        }
+    }
+}
+
+/// A resource maintenance ties up for as long as it lasts.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResourceNeed {
+    pub name: String,
+    pub quantity: f64,
+    /// The cost of a unit of the resource per time step.
+    #[serde(default)]
+    pub rate: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaintenancePolicy {
+    pub trigger: MaintenanceTrigger,
+    /// The time steps a maintenance takes.
+    pub duration: Distribution,
+    #[serde(default)]
+    pub resources: Vec<ResourceNeed>,
+    /// The cost of every maintenance, besides its downtime and resources.
+    #[serde(default)]
+    pub cost: f64,
+    /// The cost of every failure, besides its downtime.
+    #[serde(default)]
+    pub failure_cost: f64,
+    /// The cost of every time step the machine is down or maintained.
+    #[serde(default)]
+    pub downtime_cost: f64,
+}
+
+impl MaintenancePolicy {
+    pub fn new(trigger: MaintenanceTrigger, duration: impl Into<Distribution>) -> MaintenancePolicy {
+        MaintenancePolicy {
+            trigger,
+            duration: duration.into(),
+            resources: Vec::new(),
+            cost: 0.0,
+            failure_cost: 0.0,
+            downtime_cost: 0.0,
+        }
+    }
+
+    /// The cost of the resources of a maintenance per time step.
+    pub fn resource_rate(&self) -> f64 {
+        self.resources.iter().map(|resource| resource.quantity * resource.rate).sum()
+    }
+
+    pub fn validate(&self) -> Result<(), &'static str> {
+        let positive = |value: f64| value.is_finite() && value > 0.0;
+        let non_negative = |value: f64| value.is_finite() && value >= 0.0;
+        match self.trigger {
+            MaintenanceTrigger::Age { interval } if !positive(interval) => return Err("Maintenance interval must be positive."),
+            MaintenanceTrigger::Usage { cycles: 0 } => return Err("Maintenance cycles must be positive."),
+            MaintenanceTrigger::Calendar { period } if !positive(period) => return Err("Maintenance period must be positive."),
+            MaintenanceTrigger::Condition { ref stage } if stage.is_empty() => return Err("Maintenance condition must name a stage."),
+            MaintenanceTrigger::Opportunistic { min_age, max_age }
+                if !non_negative(min_age) || max_age.is_some_and(|max_age| !(positive(max_age) && max_age >= min_age)) =>
+            {
+                return Err("Maintenance ages must be non-negative, with the maximum above the minimum.");
+            }
+            _ => {}
+        }
+        self.duration.validate()?;
+        if !non_negative(self.cost) || !non_negative(self.failure_cost) || !non_negative(self.downtime_cost) {
+            return Err("Maintenance costs must be non-negative.");
+        }
+        if self.resources.iter().any(|resource| !non_negative(resource.quantity) || !non_negative(resource.rate)) {
+            return Err("Maintenance resource quantities and rates must be non-negative.");
+        }
+        Ok(())
+    }
+}
+
+/// A machine's maintenance policy, if any, and how far the machine is along it.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct MaintenanceSchedule {
+    pub policy: Option<MaintenancePolicy>,
+    /// Time steps worked since the last maintenance or repair.
+    pub age: f64,
+    /// Cycles completed since the last maintenance or repair.
+    pub cycles: usize,
+    /// Time steps since the last maintenance started.
+    pub elapsed: f64,
+    /// Maintenance time left, in time steps.
+    pub remaining: f64,
+    /// The number of maintenances started.
+    pub performed: usize,
+    /// Time steps spent in maintenance.
+    pub downtime: usize,
+    /// The number of failures, of the Markov chain and the failure modes together.
+    pub failures: usize,
+    /// Time steps spent down for repairs.
+    pub repair_downtime: usize,
+}
+
+impl MaintenanceSchedule {
+    pub fn new(policy: Option<MaintenancePolicy>) -> MaintenanceSchedule {
+        MaintenanceSchedule { policy, ..MaintenanceSchedule::default() }
+    }
+
+    /// Whether the policy calls for maintenance as soon as the machine, degrading as
+    /// given, is empty.
+    pub fn is_due(&self, degradation: Option<&Degradation>) -> bool {
+        let Some(policy) = &self.policy else {
+            return false;
+        };
+        match &policy.trigger {
+            MaintenanceTrigger::Age { interval } => self.age >= *interval,
+            MaintenanceTrigger::Usage { cycles } => self.cycles >= *cycles,
+            MaintenanceTrigger::Calendar { period } => self.elapsed >= *period,
+            MaintenanceTrigger::Opportunistic { max_age, .. } => max_age.is_some_and(|max_age| self.age >= max_age),
+            MaintenanceTrigger::Condition { stage } => degradation.is_some_and(|degradation| degradation.has_reached(stage)),
+        }
+    }
+
+    /// Whether the policy takes the chance to maintain a machine that would wait.
+    pub fn is_opportune(&self) -> bool {
+        matches!(
+            self.policy.as_ref().map(|policy| &policy.trigger),
+            Some(MaintenanceTrigger::Opportunistic { min_age, .. }) if self.age >= *min_age
+        )
+    }
+
+    /// Starts a maintenance of the given duration, spending the current time step on it.
+    pub fn start(&mut self, duration: f64) {
+        self.remaining = duration - 1.0;
+        self.performed += 1;
+        self.downtime += 1;
+        self.elapsed = 0.0;
+        self.renew();
+    }
+
+    /// Moves the maintenance in progress on by a time step. Returns whether the step
+    /// was spent on it; once it is done the machine is up for the step.
+    pub fn step(&mut self) -> bool {
+        if self.remaining > 0.0 {
+            self.remaining -= 1.0;
+            self.downtime += 1;
+            return true;
+        }
+        self.remaining = 0.0;
+        false
+    }
+
+    /// Restarts the age and usage counts, as after a repair.
+    pub fn renew(&mut self) {
+        self.age = 0.0;
+        self.cycles = 0;
+    }
+
+    /// The cost of the maintenances and failures so far.
+    pub fn cost(&self) -> f64 {
+        let Some(policy) = &self.policy else {
+            return 0.0;
+        };
+        self.performed as f64 * policy.cost
+            + self.failures as f64 * policy.failure_cost
+            + (self.downtime + self.repair_downtime) as f64 * policy.downtime_cost
+            + self.downtime as f64 * policy.resource_rate()
+    }
+}
+
+/// The long run behaviour of a machine under a maintenance policy.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MaintenanceEvaluation {
+    /// Failures of the failure modes per working time step.
+    pub failure_rate: f64,
+    /// Failures of the degradation chain per working time step.
+    pub degradation_rate: f64,
+    /// Maintenances per working time step.
+    pub maintenance_rate: f64,
+    /// The fraction of time the machine is neither down nor maintained, if it is
+    /// never starved or blocked.
+    pub availability: f64,
+    /// Maintenance, failure, downtime and resource cost per time step.
+    pub cost_rate: f64,
+}
+
+/// A machine's maintenance policy against running the same machine to failure.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MaintenanceComparison {
+    pub policy: MaintenanceEvaluation,
+    pub run_to_failure: MaintenanceEvaluation,
+}
+
+/// Compares the maintenance policy of a machine with running it to failure, or
+/// returns None if the machine has no policy.
+pub fn compare(machine: &Machine) -> Option<MaintenanceComparison> {
+    let policy = machine.maintenance.policy.as_ref()?;
+    Some(MaintenanceComparison {
+        policy: evaluate(machine, policy),
+        run_to_failure: run_to_failure(machine, policy),
+    })
+}
+
+/// Evaluates a machine under the given policy.
+pub fn evaluate(machine: &Machine, policy: &MaintenancePolicy) -> MaintenanceEvaluation {
+    let lifetime = Lifetime::of_machine(machine);
+    let processing_time = machine.processing_time.mean().max(1.0);
+    let threshold = machine.degradation.as_ref()
+        .map(|degradation| degradation.threshold(Some(&policy.trigger)))
+        .and_then(|threshold| degradation::evaluate_threshold(machine, threshold));
+    let (failure_rate, maintenance_rate) = match policy.trigger {
+        MaintenanceTrigger::Age { interval } => lifetime.age_replacement(interval),
+        MaintenanceTrigger::Usage { cycles } => lifetime.age_replacement(cycles as f64 * processing_time),
+        MaintenanceTrigger::Opportunistic { min_age, max_age } => {
+            let age = if min_age > 0.0 { min_age } else { max_age.unwrap_or(f64::INFINITY) };
+            lifetime.age_replacement(age)
+        }
+        MaintenanceTrigger::Calendar { period } => {
+            lifetime.block_replacement(period, policy.duration.mean(), mode_repairs(machine).0)
+        }
+        MaintenanceTrigger::Condition { .. } => {
+            let failure_rate = if lifetime.is_immortal() { 0.0 } else { 1.0 / lifetime.mean() };
+            (failure_rate, threshold.map_or(0.0, |threshold| threshold.maintenance_rate))
+        }
+    };
+    let degradation_rate = threshold.map_or(0.0, |threshold| threshold.failure_rate);
+    costs(machine, policy, failure_rate, degradation_rate, maintenance_rate)
+}
+
+/// Evaluates a machine that is only repaired when it fails, with the costs of the
+/// given policy.
+pub fn run_to_failure(machine: &Machine, policy: &MaintenancePolicy) -> MaintenanceEvaluation {
+    let lifetime = Lifetime::of_machine(machine);
+    let failure_rate = if lifetime.is_immortal() { 0.0 } else { 1.0 / lifetime.mean() };
+    let degradation_rate = machine.degradation.as_ref()
+        .and_then(|degradation| degradation::evaluate_threshold(machine, degradation.failed()))
+        .map_or(0.0, |threshold| threshold.failure_rate);
+    costs(machine, policy, failure_rate, degradation_rate, 0.0)
+}
+
+/// The mean and variance of the repair time of a failure mode failure, weighted by
+/// how often each mode fails.
+pub fn mode_repairs(machine: &Machine) -> (f64, f64) {
+    let processing_time = machine.processing_time.mean();
+    let modes = &machine.failure_model.modes;
+    let rate: f64 = modes.iter().map(|mode| mode.rate(processing_time)).sum();
+    if rate <= 0.0 {
+        return (0.0, 0.0);
+    }
+    let mean = modes.iter().map(|mode| mode.rate(processing_time) * mode.time_to_repair.mean()).sum::<f64>() / rate;
+    let second_moment = modes.iter()
+        .map(|mode| {
+            let repair = &mode.time_to_repair;
+            mode.rate(processing_time) * (repair.variance() + repair.mean() * repair.mean())
+        })
+        .sum::<f64>() / rate;
+    (mean, (second_moment - mean * mean).max(0.0))
+}
+
+/// Availability and cost of a machine with the given failure mode, degradation and
+/// maintenance rates per working time step, on top of the failures of its Markov chain.
+fn costs(machine: &Machine, policy: &MaintenancePolicy, failure_rate: f64, degradation_rate: f64, maintenance_rate: f64) -> MaintenanceEvaluation {
+    let (repair_time, _) = mode_repairs(machine);
+    let degradation_repair = machine.degradation.as_ref().map_or(0.0, |degradation| degradation.time_to_repair.mean());
+    let maintenance_time = policy.duration.mean();
+    let markov = machine.failure_probability();
+    let markov_rate = markov / (1.0 - markov).max(f64::EPSILON);
+    let markov_repair = 1.0 + 1.0 / machine.repair_probability().max(f64::EPSILON);
+    let availability = 1.0 / (1.0 + failure_rate * repair_time + degradation_rate * degradation_repair
+        + maintenance_rate * maintenance_time + markov_rate * markov_repair);
+    let failure_cost = |repair: f64| policy.failure_cost + policy.downtime_cost * repair;
+    let maintenance_cost = policy.cost + (policy.downtime_cost + policy.resource_rate()) * maintenance_time;
+    let working_cost = failure_rate * failure_cost(repair_time) + degradation_rate * failure_cost(degradation_repair)
+        + maintenance_rate * maintenance_cost + markov_rate * failure_cost(markov_repair);
+    MaintenanceEvaluation { failure_rate, degradation_rate, maintenance_rate, availability, cost_rate: availability * working_cost }
+}
+
+/// The lifetime of a machine's failure modes together, in working time steps.
+/// Calendar modes are counted as if they only aged while the machine works.
+struct Lifetime<'a> {
+    modes: Vec<(&'a Distribution, f64)>,
+    /// A working time by which the machine has almost surely failed.
+    horizon: f64,
+}
+
+impl<'a> Lifetime<'a> {
+    fn of_machine(machine: &'a Machine) -> Lifetime<'a> {
+        let processing_time = machine.processing_time.mean().max(1.0);
+        let modes: Vec<(&Distribution, f64)> = machine.failure_model.modes.iter()
+            .map(|mode| {
+                let scale = if mode.clock == FailureClock::Cycles { processing_time } else { 1.0 };
+                (&mode.time_to_failure, scale)
+            })
+            .collect();
+        let horizon = modes.iter()
+            .map(|(life, scale)| (life.mean() + 12.0 * life.variance().sqrt()) * scale)
+            .fold(f64::INFINITY, f64::min);
+        Lifetime { modes, horizon }
+    }
+
+    fn is_immortal(&self) -> bool {
+        self.modes.is_empty()
+    }
+
+    fn survival(&self, time: f64) -> f64 {
+        self.modes.iter().map(|(life, scale)| 1.0 - life.cdf(time / scale)).product()
+    }
+
+    /// The expected working time up to the given age or failure, whichever is first.
+    fn truncated_mean(&self, age: f64) -> f64 {
+        let step = age / GRID_STEPS as f64;
+        let mut area = 0.0;
+        let mut previous = self.survival(0.0);
+        for index in 1..=GRID_STEPS {
+            let current = self.survival(step * index as f64);
+            area += 0.5 * (previous + current) * step;
+            previous = current;
+        }
+        area
+    }
+
+    fn mean(&self) -> f64 {
+        self.truncated_mean(self.horizon)
+    }
+
+    /// Failures and maintenances per working time step when the machine is
+    /// maintained at the given age.
+    fn age_replacement(&self, age: f64) -> (f64, f64) {
+        if self.is_immortal() {
+            return (0.0, 1.0 / age);
+        }
+        let age = age.min(self.horizon);
+        let failed = 1.0 - self.survival(age);
+        let cycle = self.truncated_mean(age).max(f64::EPSILON);
+        (failed / cycle, (1.0 - failed) / cycle)
+    }
+
+    /// Failures and maintenances per working time step when the machine is
+    /// maintained every period, each maintenance taking the given time and each
+    /// failure the given repair time out of the period.
+    fn block_replacement(&self, period: f64, maintenance_time: f64, repair_time: f64) -> (f64, f64) {
+        let uptime = (period - maintenance_time).max(f64::EPSILON);
+        if self.is_immortal() {
+            return (0.0, 1.0 / uptime);
+        }
+        // The renewal function M(t) = F(t) + sum over the grid of M(t - s) dF(s).
+        let step = uptime / RENEWAL_STEPS as f64;
+        let failed: Vec<f64> = (0..=RENEWAL_STEPS).map(|index| 1.0 - self.survival(step * index as f64)).collect();
+        let mut renewals = vec![0.0; RENEWAL_STEPS + 1];
+        for index in 1..=RENEWAL_STEPS {
+            renewals[index] = failed[index]
+                + (1..=index).map(|offset| renewals[index - offset] * (failed[offset] - failed[offset - 1])).sum::<f64>();
+        }
+        let renewals_at = |time: f64| renewals[((time / step) as usize).min(RENEWAL_STEPS)];
+        // Repairs eat into the uptime of the period, which leaves fewer failures.
+        let mut working = uptime;
+        for _ in 0..50 {
+            working = (uptime - renewals_at(working) * repair_time).max(f64::EPSILON);
+        }
+        (renewals_at(working) / working, 1.0 / working)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::failure::FailureMode;
+    use crate::markov::MarkovChain;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-6 * expected.abs().max(1.0), "expected {}, got {}", expected, actual);
+    }
+
+    /// A machine that only fails through a mode with a lifetime uniform over 0 to 100
+    /// working steps, repaired in 5.
+    fn wearing_machine() -> Machine {
+        let mut machine = Machine::new(MarkovChain::new(), 1.0, None);
+        let wear = FailureMode::new(
+            "wear".to_string(),
+            FailureClock::Busy,
+            Distribution::Uniform { min: 0.0, max: 100.0 },
+            Distribution::from(5.0),
+        );
+        machine.failure_model.add_mode(wear).unwrap();
+        machine
+    }
+
+    #[test]
+    fn age_replacement_matches_closed_form() {
+        // With a lifetime uniform over 0 to L and maintenance at age T, a cycle fails
+        // with probability T / L and lasts T - T^2 / 2L on average.
+        let (life, age) = (100.0, 40.0);
+        let machine = wearing_machine();
+        let mut policy = MaintenancePolicy::new(MaintenanceTrigger::Age { interval: age }, 2.0);
+        policy.cost = 10.0;
+        policy.failure_cost = 50.0;
+        let evaluation = evaluate(&machine, &policy);
+
+        let cycle = age - age * age / (2.0 * life);
+        let failure_rate = age / life / cycle;
+        let maintenance_rate = (1.0 - age / life) / cycle;
+        assert_close(evaluation.failure_rate, failure_rate);
+        assert_close(evaluation.maintenance_rate, maintenance_rate);
+        let availability = 1.0 / (1.0 + failure_rate * 5.0 + maintenance_rate * 2.0);
+        assert_close(evaluation.availability, availability);
+        assert_close(evaluation.cost_rate, availability * (failure_rate * 50.0 + maintenance_rate * 10.0));
+    }
+
+    #[test]
+    fn run_to_failure_fails_once_per_mean_life() {
+        let machine = wearing_machine();
+        let policy = MaintenancePolicy::new(MaintenanceTrigger::Age { interval: 40.0 }, 2.0);
+        let evaluation = run_to_failure(&machine, &policy);
+        assert!((evaluation.failure_rate - 1.0 / 50.0).abs() < 1e-4, "failure rate {}", evaluation.failure_rate);
+        assert_eq!(evaluation.maintenance_rate, 0.0);
+    }
+}
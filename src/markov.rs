@@ -2,20 +2,25 @@
 // http://smallcultfollowing.com/babysteps/blog/2015/04/06/modeling-graphs-in-rust-using-vector-indices/
 
 use std::collections::HashSet;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
 
 pub type StateIndex = usize;
 pub type TransitionIndex = usize;
 
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct MarkovChain {
     pub states: Vec<State>,
     pub transitions: Vec<Transition>,
 }
 
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct State {
     name: String,
     first_outgoing_transition: Option<TransitionIndex>,
 }
 
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Transition {
     target: StateIndex,
     probability: f64,
@@ -44,14 +49,14 @@ impl MarkovChain {
         let transition_index = self.transitions.len();
         let state_data = &mut self.states[source];
         self.transitions.push(Transition {
-            target: target,
-            probability: probability,
+            target,
+            probability,
             next_outgoing_transition: state_data.first_outgoing_transition,
         });
         state_data.first_outgoing_transition = Some(transition_index);
     }
 
-    pub fn successors(&self, source: StateIndex) -> Successors {
+    pub fn successors(&self, source: StateIndex) -> Successors<'_> {
         let first_outgoing_transition = self.states[source].first_outgoing_transition;
         Successors {
             markov_chain: self,
@@ -59,6 +64,57 @@ impl MarkovChain {
         }
     }
 
+    pub fn find_state(&self, name: &str) -> Option<StateIndex> {
+        self.states.iter().position(|state| state.name == name)
+    }
+
+    // returns the probability of moving from source to target in one step
+    pub fn transition_probability(&self, source: StateIndex, target: StateIndex) -> f64 {
+        self.outgoing_transitions(source)
+            .filter(|&index| self.transitions[index].target == target)
+            .map(|index| self.transitions[index].probability)
+            .sum()
+    }
+
+    // returns the probability of leaving source for any other state in one step
+    pub fn exit_probability(&self, source: StateIndex) -> f64 {
+        self.outgoing_transitions(source)
+            .filter(|&index| self.transitions[index].target != source)
+            .map(|index| self.transitions[index].probability)
+            .sum::<f64>()
+            .min(1.0)
+    }
+
+    // replaces the probability of every source -> target transition, adding one if none exists
+    pub fn set_transition_probability(&mut self, source: StateIndex, target: StateIndex, probability: f64) {
+        let existing: Vec<TransitionIndex> = self.outgoing_transitions(source)
+            .filter(|&index| self.transitions[index].target == target)
+            .collect();
+        match existing.split_first() {
+            Some((first, rest)) => {
+                self.transitions[*first].probability = probability;
+                for &index in rest {
+                    self.transitions[index].probability = 0.0;
+                }
+            }
+            None => self.add_transition(source, target, probability),
+        }
+    }
+
+    fn outgoing_transitions(&self, source: StateIndex) -> impl Iterator<Item = TransitionIndex> + '_ {
+        std::iter::successors(self.states[source].first_outgoing_transition, move |&index| {
+            self.transitions[index].next_outgoing_transition
+        })
+    }
+
+    /// Whether every state and transition index in the chain points at an existing
+    /// state or transition, as a chain read from outside has to be checked for.
+    pub fn is_consistent(&self) -> bool {
+        let transition = |index: &Option<TransitionIndex>| index.is_none_or(|index| index < self.transitions.len());
+        self.states.iter().all(|state| transition(&state.first_outgoing_transition))
+            && self.transitions.iter().all(|t| t.target < self.states.len() && transition(&t.next_outgoing_transition))
+    }
+
     pub fn get_state_name(&self, state_index: StateIndex) -> String {
         self.states[state_index].name.clone()
     }
@@ -71,12 +127,12 @@ impl MarkovChain {
         self.states[state_index].name = state_name;
     }
 
-    pub fn step_chain(machine: &mut Self) {
-        let rng = rand::thread_rng();
+    #[allow(clippy::needless_range_loop)]
+    pub fn step_chain<R: Rng + ?Sized>(machine: &mut Self, rng: &mut R) {
         let mut new_states = Vec::new();
         for i in 0..machine.states.len() {
             let successors: HashSet<usize> = machine.successors(i).collect();  // assuming it returns Iterator<Item=usize>
-            let random_number = rand::random::<f64>();
+            let random_number = rng.gen::<f64>();
             let mut sum = 0.0;
             for j in 0..machine.states.len() {
                 if successors.contains(&j) {
@@ -167,6 +223,7 @@ impl<'markov_chain> Iterator for Successors<'markov_chain> {
 
 // generate_transition_matrix takes a MarkovChain and generates a matrix mapping the transitions
 // $machine: the MarkovChain
+#[allow(clippy::needless_range_loop)]
 pub fn generate_transition_matrix(machine: &MarkovChain) -> Vec<Vec<f64>> {
     let mut matrix = vec![vec![0.0; machine.states.len()]; machine.states.len()];
     for i in 0..machine.states.len() {
@@ -185,14 +242,15 @@ pub fn generate_transition_matrix(machine: &MarkovChain) -> Vec<Vec<f64>> {
 // random_transition_matrix generates a random transition matrix for a MarkovChain to test the function generate_transition_matrix
 // random_transition_matrix takes a MarkovChain with no transitions as input and returns a valid transition matrix
 // $machine: the MarkovChain
-pub fn random_transition_matrix(machine: &mut MarkovChain) -> Vec<Vec<f64>> {
+// $rng: the random stream to draw the probabilities from
+#[allow(clippy::needless_range_loop)]
+pub fn random_transition_matrix<R: Rng + ?Sized>(machine: &mut MarkovChain, rng: &mut R) -> Vec<Vec<f64>> {
     let mut matrix = vec![vec![0.0; machine.states.len()]; machine.states.len()];
-    let mut rng = rand::thread_rng();
     for i in 0..machine.states.len() {
         let successors: HashSet<usize> = machine.successors(i).collect();  // assuming it returns Iterator<Item=usize>
         for j in 0..machine.states.len() {
             if successors.contains(&j) {
-                matrix[i][j] = rand::random::<f64>();
+                matrix[i][j] = rng.gen::<f64>();
             }
         }
     }
@@ -200,44 +258,44 @@ pub fn random_transition_matrix(machine: &mut MarkovChain) -> Vec<Vec<f64>> {
 }
 // a public function which takes an array of markov chains and steps them all forward
 // $machines: an array of MarkovChains
-pub fn step_chains(machines: &mut Vec<MarkovChain>) {
+// $rng: the random stream shared by the chains
+pub fn step_chains<R: Rng + ?Sized>(machines: &mut Vec<MarkovChain>, rng: &mut R) {
     for machine in machines {
-        MarkovChain::step_chain(machine);
+        MarkovChain::step_chain(machine, rng);
     }
 }
 
 
 
-// a public function to run monte carlo simulations on a markov chain
+// a public function to run monte carlo simulations on a markov chain, estimating the
+// probability of being in each state after n steps from each starting state
 // $machine: the MarkovChain
 // $n: the number of steps to run the simulation for
 // $m: the number of simulations to run
-pub fn monte_carlo(machine: &mut MarkovChain, n: usize, m: usize) -> Vec<Vec<f64>> {
-    let rng = rand::thread_rng();
+// $rng: the random stream to draw from
+pub fn monte_carlo<R: Rng + ?Sized>(machine: &mut MarkovChain, n: usize, m: usize, rng: &mut R) -> Vec<Vec<f64>> {
     let mut matrix = vec![vec![0.0; machine.states.len()]; machine.states.len()];
     for _ in 0..m {
-        let mut new_states = Vec::new();
-        for i in 0..machine.states.len() {
-            let successors: HashSet<usize> = machine.successors(i).collect();  // assuming it returns Iterator<Item=usize>
-            let random_number = rand::random::<f64>();
-            let mut sum = 0.0;
-            for j in 0..machine.states.len() {
-                if successors.contains(&j) {
-                    sum += machine.transitions[j].probability;
+        for (start, row) in matrix.iter_mut().enumerate() {
+            let mut state = start;
+            for _ in 0..n {
+                // the probability not taken by any outgoing transition stays in the state
+                let random_number = rng.gen::<f64>();
+                let mut sum = 0.0;
+                for index in machine.outgoing_transitions(state) {
+                    sum += machine.transitions[index].probability;
                     if random_number < sum {
-                        new_states.push(j);
+                        state = machine.transitions[index].target;
                         break;
                     }
                 }
             }
-        }
-        for i in 0..machine.states.len() {
-            matrix[i][new_states[i]] += 1.0;
+            row[state] += 1.0;
         }
     }
-    for i in 0..machine.states.len() {
-        for j in 0..machine.states.len() {
-            matrix[i][j] /= m as f64;
+    for row in matrix.iter_mut() {
+        for probability in row.iter_mut() {
+            *probability /= m as f64;
         }
     }
     matrix
@@ -0,0 +1,1010 @@
+//! Factory description files.
+//!
+//! A factory can be described in a TOML or YAML file rather than built in code. The
+//! file declares items, recipes, machines, buffers, the connections between
+//! machines and buffers, and transfer lines, all referring to each other by name:
+//!
+//! ```toml
+//! [[items]]
+//! name = "blank"
+//! cost = 2.5
+//!
+//! [[items]]
+//! name = "bracket"
+//!
+//! [[recipes]]
+//! name = "stamp"
+//! inputs = [{ item = "blank", quantity = 1 }]
+//! outputs = [{ item = "bracket", quantity = 1 }]
+//!
+//! [[machines]]
+//! name = "press"
+//! processing_time = 2
+//! recipes = ["stamp"]
+//! failure = { failure_probability = 0.01, repair_probability = 0.1 }
+//!
+//! [[machines]]
+//! name = "deburr"
+//! processing_time = { lognormal = { mu = 0.0, sigma = 0.25 } }
+//!
+//! [[buffers]]
+//! name = "stock"
+//! capacity = 50
+//! contents = [{ item = "blank", quantity = 20 }]
+//!
+//! [[buffers]]
+//! name = "b1"
+//! capacity = 5
+//!
+//! [[connections]]
+//! from = "stock"
+//! to = "press"
+//!
+//! [[lines]]
+//! name = "brackets"
+//! machines = ["press", "deburr"]
+//! buffers = ["b1"]
+//! ```
+//!
+//! A processing time is either a fixed number of time steps or a distribution
+//! (see `distribution`), written as a table keyed by its kind. The other machine
+//! settings, from failure modes to degradation, are described on the fields of
+//! `MachineDescription`.
+//!
+//! A connection from a buffer to a machine makes the buffer one of the machine's
+//! inputs, and one from a machine to a buffer makes it an output. Lines connect
+//! their machines through their buffers themselves.
+//!
+//! Loading checks the whole description and reports every problem it finds with
+//! the line it was found on, rather than stopping at the first. The model is then
+//! built with registry commands, so it is held to the same rules as a model edited
+//! any other way.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
+use serde::de::{self, Deserializer, MapAccess, Visitor};
+use serde::Deserialize;
+use uuid::Uuid;
+use crate::batch::BatchMode;
+use crate::blocking::BlockingPolicy;
+use crate::distribution::Distribution;
+use crate::failure::FailureClock;
+use crate::quality::{QualityModel, RecipeYield, RepairYield, YieldRates};
+use crate::maintenance::MaintenancePolicy;
+use crate::degradation::{DegradationStage, DegradationTransition};
+use crate::setup::{DispatchRule, SetupBasis, SetupMatrix};
+use crate::recipe_validation::{validate, Diagnostic, Severity};
+use crate::registry::{BufferRole, ModelCommand, ModelRegistry};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModelFormat {
+    Toml,
+    Yaml,
+}
+
+impl ModelFormat {
+    /// Picks the format from a `.toml`, `.yaml` or `.yml` file extension.
+    pub fn from_path(path: &Path) -> Option<ModelFormat> {
+        match path.extension()?.to_str()? {
+            "toml" => Some(ModelFormat::Toml),
+            "yaml" | "yml" => Some(ModelFormat::Yaml),
+            _ => None,
+        }
+    }
+}
+
+/// A problem with a description file, with the line it was found on when known.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModelFileError {
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for ModelFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {}: {}", line, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FactoryDescription {
+    #[serde(default)]
+    pub items: Vec<ItemDescription>,
+    #[serde(default)]
+    pub recipes: Vec<RecipeDescription>,
+    #[serde(default)]
+    pub machines: Vec<MachineDescription>,
+    #[serde(default)]
+    pub buffers: Vec<BufferDescription>,
+    #[serde(default)]
+    pub connections: Vec<ConnectionDescription>,
+    #[serde(default)]
+    pub lines: Vec<LineDescription>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ItemDescription {
+    pub name: String,
+    #[serde(default = "unit_size")]
+    pub size: f64,
+    pub cost: Option<f64>,
+}
+
+fn unit_size() -> f64 {
+    1.0
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuantityDescription {
+    pub item: String,
+    pub quantity: f64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecipeDescription {
+    pub name: String,
+    #[serde(default)]
+    pub inputs: Vec<QuantityDescription>,
+    #[serde(default)]
+    pub outputs: Vec<QuantityDescription>,
+    #[serde(default)]
+    pub recycling: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MachineDescription {
+    pub name: String,
+    pub processing_time: DurationDescription,
+    /// The name given to the machine's output.
+    pub output: Option<String>,
+    #[serde(default)]
+    pub recipes: Vec<String>,
+    pub failure: Option<FailureDescription>,
+    #[serde(default)]
+    pub failure_modes: Vec<FailureModeDescription>,
+    pub setup: Option<SetupDescription>,
+    /// How cycles of several products are ordered, such as
+    /// `{ batching = { max_run = 20 } }` to run same-product cycles together.
+    pub dispatch: Option<DispatchRule>,
+    /// Such as `{ min_size = 4, max_size = 12, policy = "full_batch" }`, or with a
+    /// policy such as `{ minimum_with_timeout = { timeout = 30 } }`.
+    pub batch: Option<BatchMode>,
+    /// `after_service` (the default), `before_service` or `communication`.
+    pub blocking: Option<BlockingPolicy>,
+    pub quality: Option<QualityDescription>,
+    /// A preventive maintenance policy: a `trigger` such as `{ age = { interval = 400 } }`,
+    /// `{ usage = { cycles = 150 } }`, `{ calendar = { period = 500 } }`,
+    /// `{ opportunistic = { min_age = 200, max_age = 600 } }` or
+    /// `{ condition = { stage = "critical" } }` for a degrading machine, a `duration`
+    /// distribution, the `resources` it ties up, such as
+    /// `[{ name = "technician", quantity = 1, rate = 30 }]`, with no limit on how many
+    /// are in use at once, and the `cost`, `failure_cost` and per step `downtime_cost`.
+    pub maintenance: Option<MaintenancePolicy>,
+    pub degradation: Option<DegradationDescription>,
+}
+
+/// A fixed number of time steps, or a distribution table such as
+/// `{ exponential = { mean = 2 } }`.
+#[derive(Clone, Debug)]
+pub enum DurationDescription {
+    Fixed(f64),
+    Random(Distribution),
+}
+
+impl DurationDescription {
+    pub fn distribution(&self) -> Distribution {
+        match self {
+            DurationDescription::Fixed(value) => Distribution::from(*value),
+            DurationDescription::Random(distribution) => distribution.clone(),
+        }
+    }
+}
+
+// Written out rather than derived as an untagged enum, so that a mistake inside a
+// distribution table is reported as such rather than as matching neither form.
+impl<'de> Deserialize<'de> for DurationDescription {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct DurationVisitor;
+
+        impl<'de> Visitor<'de> for DurationVisitor {
+            type Value = DurationDescription;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a number or a distribution table")
+            }
+
+            fn visit_f64<E: de::Error>(self, value: f64) -> Result<Self::Value, E> {
+                Ok(DurationDescription::Fixed(value))
+            }
+
+            fn visit_i64<E: de::Error>(self, value: i64) -> Result<Self::Value, E> {
+                Ok(DurationDescription::Fixed(value as f64))
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<Self::Value, E> {
+                Ok(DurationDescription::Fixed(value as f64))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                Distribution::deserialize(de::value::MapAccessDeserializer::new(map)).map(DurationDescription::Random)
+            }
+        }
+
+        deserializer.deserialize_any(DurationVisitor)
+    }
+}
+
+/// Per step probabilities of the machine's Markov failure chain.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FailureDescription {
+    pub failure_probability: f64,
+    pub repair_probability: f64,
+}
+
+/// A failure mode with its own time to failure and time to repair distributions.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FailureModeDescription {
+    pub name: String,
+    /// What the time to failure counts, `calendar`, `busy` or `cycles`; working time
+    /// unless given.
+    #[serde(default = "default_clock")]
+    pub clock: FailureClock,
+    pub time_to_failure: Distribution,
+    pub time_to_repair: Distribution,
+}
+
+fn default_clock() -> FailureClock {
+    FailureClock::Busy
+}
+
+/// Changeover times between named recipes, or between the items recipes make with
+/// `basis = "item"`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SetupDescription {
+    #[serde(default)]
+    pub basis: SetupBasis,
+    /// The setup time between products the times do not list.
+    pub default: Option<DurationDescription>,
+    #[serde(default)]
+    pub times: Vec<SetupTimeDescription>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SetupTimeDescription {
+    pub from: String,
+    pub to: String,
+    pub time: DurationDescription,
+}
+
+/// The stages a machine wears through, the last being its failed stage.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DegradationDescription {
+    pub stages: Vec<StageDescription>,
+    /// Per working step transitions, such as
+    /// `{ from = "good", to = "worn", probability = 0.01 }`.
+    #[serde(default)]
+    pub transitions: Vec<DegradationTransition>,
+    /// The time to repair from the failed stage.
+    pub time_to_repair: Distribution,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StageDescription {
+    pub name: String,
+    /// How much longer cycles take in the stage.
+    #[serde(default = "default_processing_factor")]
+    pub processing_factor: f64,
+    /// The scrap and rework rates in the stage, the machine's own unless given.
+    pub scrap: Option<f64>,
+    pub rework: Option<f64>,
+}
+
+fn default_processing_factor() -> f64 {
+    1.0
+}
+
+impl StageDescription {
+    fn stage(&self) -> DegradationStage {
+        let rates = (self.scrap.is_some() || self.rework.is_some())
+            .then(|| YieldRates::new(self.scrap.unwrap_or(0.0), self.rework.unwrap_or(0.0)));
+        DegradationStage { name: self.name.clone(), processing_factor: self.processing_factor, rates }
+    }
+}
+
+/// The yield of a machine, and where the parts it sends to rework go, such as
+/// `{ scrap = 0.02, rework = 0.05, scrap_cost = 40 }`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QualityDescription {
+    /// The fraction of parts scrapped.
+    #[serde(default)]
+    pub scrap: f64,
+    /// The fraction of parts sent to rework.
+    #[serde(default)]
+    pub rework: f64,
+    /// Yields replacing the machine's for cycles of the named recipes.
+    #[serde(default)]
+    pub recipes: Vec<RecipeYieldDescription>,
+    /// The yield of the first parts after a repair, such as `{ parts = 10, scrap = 0.2 }`.
+    pub after_repair: Option<RepairYieldDescription>,
+    #[serde(default)]
+    pub scrap_cost: f64,
+    /// The buffer parts sent to rework go to.
+    pub rework_buffer: Option<String>,
+    /// The machine taking parts back from the rework buffer.
+    pub rework_to: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RecipeYieldDescription {
+    pub recipe: String,
+    #[serde(default)]
+    pub scrap: f64,
+    #[serde(default)]
+    pub rework: f64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RepairYieldDescription {
+    pub parts: usize,
+    #[serde(default)]
+    pub scrap: f64,
+    #[serde(default)]
+    pub rework: f64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BufferDescription {
+    pub name: String,
+    pub capacity: usize,
+    pub throughput: Option<f64>,
+    /// Items held in the buffer at the start.
+    #[serde(default)]
+    pub contents: Vec<QuantityDescription>,
+}
+
+/// Connects a machine and a buffer, from the buffer to the machine for an input and
+/// from the machine to the buffer for an output.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConnectionDescription {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LineDescription {
+    pub name: String,
+    pub machines: Vec<String>,
+    pub buffers: Vec<String>,
+}
+
+/// A model built from a description file.
+pub struct FactoryModel {
+    pub model: ModelRegistry,
+    /// The declared name of every entity, by id.
+    pub names: HashMap<Uuid, String>,
+}
+
+impl FactoryModel {
+    pub fn name(&self, id: Uuid) -> Option<&str> {
+        self.names.get(&id).map(String::as_str)
+    }
+
+    /// Returns the id of the line with the given name.
+    pub fn line_id(&self, name: &str) -> Option<Uuid> {
+        self.model.lines().iter().find(|line| line.name.as_deref() == Some(name)).map(|line| line.id)
+    }
+}
+
+/// Reads a description without checking it.
+pub fn parse(source: &str, format: ModelFormat) -> Result<FactoryDescription, ModelFileError> {
+    match format {
+        ModelFormat::Toml => toml::from_str(source).map_err(|error| ModelFileError {
+            line: error.span().map(|span| line_of_offset(source, span.start)),
+            message: error.message().trim().to_string(),
+        }),
+        ModelFormat::Yaml => serde_yaml::from_str(source).map_err(|error| ModelFileError {
+            line: error.location().map(|location| location.line()),
+            // The line is reported separately, so the position serde_yaml appends goes.
+            message: error.to_string().rsplit_once(" at line ").map_or(error.to_string(), |(message, _)| message.to_string()),
+        }),
+    }
+}
+
+/// Reads, checks and builds a description.
+pub fn load_str(source: &str, format: ModelFormat) -> Result<FactoryModel, Vec<ModelFileError>> {
+    let description = parse(source, format).map_err(|error| vec![error])?;
+    let mut builder = Builder::new(source);
+    builder.build(&description);
+    if builder.errors.is_empty() {
+        Ok(FactoryModel { model: builder.model, names: builder.names })
+    } else {
+        Err(builder.errors)
+    }
+}
+
+/// Loads a description file, telling the format from its extension.
+pub fn load(path: &Path) -> Result<FactoryModel, Vec<ModelFileError>> {
+    let format = ModelFormat::from_path(path).ok_or_else(|| {
+        vec![ModelFileError { line: None, message: "Model files must end in .toml, .yaml or .yml.".to_string() }]
+    })?;
+    let source = std::fs::read_to_string(path).map_err(|error| {
+        vec![ModelFileError { line: None, message: format!("Could not read {}: {}", path.display(), error) }]
+    })?;
+    load_str(&source, format)
+}
+
+/// Builds the model entity by entity, collecting errors as it goes. An entity with
+/// an error is left out, and later references to it are not reported again.
+struct Builder<'a> {
+    locator: Locator<'a>,
+    model: ModelRegistry,
+    names: HashMap<Uuid, String>,
+    /// The ids of the entities built, by section and name.
+    ids: HashMap<(&'static str, String), Uuid>,
+    /// Names declared in each section, including entities left out.
+    declared: HashSet<(&'static str, String)>,
+    errors: Vec<ModelFileError>,
+}
+
+impl<'a> Builder<'a> {
+    fn new(source: &'a str) -> Builder<'a> {
+        Builder {
+            locator: Locator::new(source),
+            model: ModelRegistry::new(),
+            names: HashMap::new(),
+            ids: HashMap::new(),
+            declared: HashSet::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    fn build(&mut self, description: &FactoryDescription) {
+        for (index, item) in description.items.iter().enumerate() {
+            self.add_item(index, item);
+        }
+        for (index, recipe) in description.recipes.iter().enumerate() {
+            self.add_recipe(index, recipe);
+        }
+        self.check_recipe_graph(description);
+        for (index, machine) in description.machines.iter().enumerate() {
+            self.add_machine(index, machine);
+        }
+        for (index, buffer) in description.buffers.iter().enumerate() {
+            self.add_buffer(index, buffer);
+        }
+        for (index, connection) in description.connections.iter().enumerate() {
+            self.add_connection(index, connection);
+        }
+        for (index, machine) in description.machines.iter().enumerate() {
+            self.add_rework_loop(index, machine);
+        }
+        for (index, line) in description.lines.iter().enumerate() {
+            self.add_line(index, line);
+        }
+    }
+
+    fn add_item(&mut self, index: usize, item: &ItemDescription) {
+        let line = self.locator.entry("items", index);
+        if !self.declare("items", &item.name, line) {
+            return;
+        }
+        if !(item.size.is_finite() && item.size > 0.0) {
+            return self.error(line, format!("item '{}' must have a positive size", item.name));
+        }
+        if item.cost.is_some_and(|cost| !(cost.is_finite() && cost >= 0.0)) {
+            return self.error(line, format!("item '{}' must not have a negative cost", item.name));
+        }
+        let id = Uuid::new_v4();
+        let command = ModelCommand::CreateItem { id, name: item.name.clone(), size: item.size, cost: item.cost };
+        if self.execute(line, &item.name, command) {
+            self.ids.insert(("items", item.name.clone()), id);
+            self.names.insert(id, item.name.clone());
+        }
+    }
+
+    fn add_recipe(&mut self, index: usize, recipe: &RecipeDescription) {
+        let line = self.locator.entry("recipes", index);
+        if !self.declare("recipes", &recipe.name, line) {
+            return;
+        }
+        let mut quantities = Vec::new();
+        let mut complete = true;
+        for (quantities_of, output) in [(&recipe.inputs, false), (&recipe.outputs, true)] {
+            for quantity in quantities_of {
+                let at = self.locator.reference("recipes", index, &quantity.item);
+                if !(quantity.quantity.is_finite() && quantity.quantity > 0.0) {
+                    self.error(at, format!("recipe '{}' must use a positive quantity of '{}'", recipe.name, quantity.item));
+                    complete = false;
+                }
+                match self.resolve("items", &quantity.item, at) {
+                    Some(item_id) => quantities.push((item_id, quantity.quantity, output)),
+                    None => complete = false,
+                }
+            }
+        }
+        if !complete {
+            return;
+        }
+        let id = Uuid::new_v4();
+        let mut commands = vec![
+            ModelCommand::CreateRecipe { id, name: recipe.name.clone() },
+            ModelCommand::SetRecycling { recipe_id: id, recycling: recipe.recycling },
+        ];
+        for (item_id, quantity, output) in quantities {
+            commands.push(match output {
+                false => ModelCommand::AddRecipeInput { recipe_id: id, item_id, quantity },
+                true => ModelCommand::AddRecipeOutput { recipe_id: id, item_id, quantity },
+            });
+        }
+        if commands.into_iter().all(|command| self.execute(line, &recipe.name, command)) {
+            self.ids.insert(("recipes", recipe.name.clone()), id);
+            self.names.insert(id, recipe.name.clone());
+        }
+    }
+
+    /// Reports recipes without outputs and loops in the recipe graph. Every item
+    /// counts as a raw material or product here, since the file does not say which
+    /// items are bought in or sold.
+    fn check_recipe_graph(&mut self, description: &FactoryDescription) {
+        let recipes = self.model.catalogue().recipes().to_vec();
+        let items: HashSet<Uuid> = self.model.catalogue().items().iter().map(|item| item.id()).collect();
+        let made: HashSet<Uuid> = recipes.iter()
+            .flat_map(|recipe| recipe.output.iter().map(|(item, _)| item.id()))
+            .collect();
+        let raw_materials = items.difference(&made).copied().collect();
+        let recipe_line = |recipe_id: &Uuid| {
+            let name = &self.names[recipe_id];
+            let index = description.recipes.iter().position(|recipe| &recipe.name == name)?;
+            self.locator.entry("recipes", index)
+        };
+        let mut errors = Vec::new();
+        for diagnostic in validate(&recipes, &raw_materials, &items) {
+            if diagnostic.severity() != Severity::Error {
+                continue;
+            }
+            match &diagnostic {
+                Diagnostic::Cycle { recipe_ids, .. } => {
+                    let names: Vec<&str> = recipe_ids.iter().map(|id| self.names[id].as_str()).collect();
+                    let line = recipe_ids.iter().filter_map(recipe_line).min();
+                    errors.push((line, format!("recipes {} form a cycle and none of them is recycling", names.join(", "))));
+                }
+                Diagnostic::NoOutputs { recipe_id } => {
+                    errors.push((recipe_line(recipe_id), format!("recipe '{}' has no outputs", self.names[recipe_id])));
+                }
+                _ => {}
+            }
+        }
+        for (line, message) in errors {
+            self.error(line, message);
+        }
+    }
+
+    fn add_machine(&mut self, index: usize, machine: &MachineDescription) {
+        let line = self.locator.entry("machines", index);
+        if !self.declare("machines", &machine.name, line) {
+            return;
+        }
+        let mut commands = Vec::new();
+        let id = Uuid::new_v4();
+        match &machine.processing_time {
+            DurationDescription::Fixed(processing_time) => {
+                if !(processing_time.is_finite() && *processing_time > 0.0) {
+                    return self.error(line, format!("machine '{}' must have a positive processing time", machine.name));
+                }
+                commands.push(ModelCommand::CreateMachine { id, processing_time: *processing_time, output: machine.output.clone() });
+            }
+            DurationDescription::Random(distribution) => {
+                if let Err(error) = distribution.validate() {
+                    let at = self.locator.reference("machines", index, "processing_time");
+                    return self.error(at, format!("machine '{}' has an invalid processing time: {}", machine.name, error));
+                }
+                commands.push(ModelCommand::CreateMachine { id, processing_time: distribution.mean(), output: machine.output.clone() });
+                commands.push(ModelCommand::SetProcessingDistribution { machine_id: id, distribution: distribution.clone() });
+            }
+        }
+        if let Some(failure) = &machine.failure {
+            let probabilities = [failure.failure_probability, failure.repair_probability];
+            if !probabilities.iter().all(|probability| (0.0..=1.0).contains(probability)) {
+                let at = self.locator.reference("machines", index, "failure");
+                return self.error(at, format!("machine '{}' must have failure and repair probabilities between 0 and 1", machine.name));
+            }
+            commands.push(ModelCommand::SetFailureRates {
+                machine_id: id,
+                failure_probability: failure.failure_probability,
+                repair_probability: failure.repair_probability,
+            });
+        }
+        for mode in &machine.failure_modes {
+            let at = self.locator.reference("machines", index, &mode.name);
+            if machine.failure_modes.iter().filter(|other| other.name == mode.name).count() > 1 {
+                return self.error(at, format!("machine '{}' declares failure mode '{}' more than once", machine.name, mode.name));
+            }
+            if let Err(error) = mode.time_to_failure.validate().and(mode.time_to_repair.validate()) {
+                return self.error(at, format!("failure mode '{}' of machine '{}' is invalid: {}", mode.name, machine.name, error));
+            }
+            commands.push(ModelCommand::AddFailureMode {
+                machine_id: id,
+                id: Uuid::new_v4(),
+                name: mode.name.clone(),
+                clock: mode.clock,
+                time_to_failure: mode.time_to_failure.clone(),
+                time_to_repair: mode.time_to_repair.clone(),
+            });
+        }
+        if let Some(setup) = &machine.setup {
+            match self.setup_matrix(index, &machine.name, setup) {
+                Some(matrix) => commands.push(ModelCommand::SetSetupMatrix { machine_id: id, matrix }),
+                None => return,
+            }
+        }
+        if let Some(rule) = machine.dispatch {
+            commands.push(ModelCommand::SetDispatchRule { machine_id: id, rule });
+        }
+        if let Some(batch) = machine.batch {
+            if let Err(error) = batch.validate() {
+                let at = self.locator.reference("machines", index, "batch");
+                return self.error(at, format!("machine '{}' has an invalid batch mode: {}", machine.name, error));
+            }
+            commands.push(ModelCommand::SetBatchMode { machine_id: id, batch: Some(batch) });
+        }
+        if let Some(policy) = machine.blocking {
+            commands.push(ModelCommand::SetBlockingPolicy { machine_id: id, policy });
+        }
+        if let Some(quality) = &machine.quality {
+            match self.quality_model(index, &machine.name, quality) {
+                Some(quality) => commands.push(ModelCommand::SetQuality { machine_id: id, quality }),
+                None => return,
+            }
+        }
+        if let Some(degradation) = &machine.degradation {
+            commands.push(ModelCommand::SetDegradation {
+                machine_id: id,
+                stages: degradation.stages.iter().map(StageDescription::stage).collect(),
+                transitions: degradation.transitions.clone(),
+                time_to_repair: degradation.time_to_repair.clone(),
+            });
+        }
+        if let Some(policy) = &machine.maintenance {
+            if let Err(error) = policy.validate() {
+                let at = self.locator.reference("machines", index, "maintenance");
+                return self.error(at, format!("machine '{}' has an invalid maintenance policy: {}", machine.name, error));
+            }
+            commands.push(ModelCommand::SetMaintenancePolicy { machine_id: id, policy: Some(policy.clone()) });
+        }
+        let mut complete = true;
+        for recipe in &machine.recipes {
+            let at = self.locator.reference("machines", index, recipe);
+            match self.resolve("recipes", recipe, at) {
+                Some(recipe_id) => commands.push(ModelCommand::AssignRecipe { machine_id: id, recipe_id }),
+                None => complete = false,
+            }
+        }
+        if complete && commands.into_iter().all(|command| self.execute(line, &machine.name, command)) {
+            self.ids.insert(("machines", machine.name.clone()), id);
+            self.names.insert(id, machine.name.clone());
+        } else if self.model.machine(id).is_some() {
+            // Take back a machine left half built by a failed command.
+            let _ = self.model.execute(ModelCommand::RemoveMachine { machine_id: id });
+        }
+    }
+
+    /// Resolves the products of a machine's setup times, reporting every problem.
+    fn setup_matrix(&mut self, index: usize, machine: &str, setup: &SetupDescription) -> Option<SetupMatrix> {
+        let section = match setup.basis {
+            SetupBasis::Recipe => "recipes",
+            SetupBasis::Item => "items",
+        };
+        let mut matrix = SetupMatrix::new(setup.basis);
+        let mut complete = true;
+        if let Some(default) = &setup.default {
+            matrix.default = Some(default.distribution());
+        }
+        for time in &setup.times {
+            let at = self.locator.reference("machines", index, &time.to);
+            let from = self.resolve(section, &time.from, self.locator.reference("machines", index, &time.from));
+            let to = self.resolve(section, &time.to, at);
+            if let Err(error) = time.time.distribution().validate() {
+                self.error(at, format!("setup of machine '{}' from '{}' to '{}' is invalid: {}", machine, time.from, time.to, error));
+                complete = false;
+            }
+            match (from, to) {
+                (Some(from), Some(to)) => matrix.set(from, to, time.time.distribution()),
+                _ => complete = false,
+            }
+        }
+        if let Err(error) = matrix.default.as_ref().map_or(Ok(()), Distribution::validate) {
+            let at = self.locator.reference("machines", index, "default");
+            self.error(at, format!("default setup of machine '{}' is invalid: {}", machine, error));
+            complete = false;
+        }
+        complete.then_some(matrix)
+    }
+
+    /// Resolves the recipes of a machine's yield and checks its rates, reporting every
+    /// problem.
+    fn quality_model(&mut self, index: usize, machine: &str, quality: &QualityDescription) -> Option<QualityModel> {
+        let mut complete = true;
+        let mut model = QualityModel::new(YieldRates::new(quality.scrap, quality.rework));
+        model.scrap_cost = quality.scrap_cost;
+        model.after_repair = quality.after_repair.as_ref()
+            .map(|repair| RepairYield { parts: repair.parts, rates: YieldRates::new(repair.scrap, repair.rework) });
+        for recipe in &quality.recipes {
+            let at = self.locator.reference("machines", index, &recipe.recipe);
+            match self.resolve("recipes", &recipe.recipe, at) {
+                Some(recipe_id) => model.recipes.push(RecipeYield { recipe_id, rates: YieldRates::new(recipe.scrap, recipe.rework) }),
+                None => complete = false,
+            }
+        }
+        if let Err(error) = model.validate() {
+            let at = self.locator.reference("machines", index, "quality");
+            self.error(at, format!("machine '{}' has an invalid yield: {}", machine, error));
+            complete = false;
+        }
+        if quality.rework_to.is_some() && quality.rework_buffer.is_none() {
+            let at = self.locator.reference("machines", index, "rework_to");
+            self.error(at, format!("machine '{}' sends rework to a machine but has no rework buffer", machine));
+            complete = false;
+        }
+        complete.then_some(model)
+    }
+
+    /// Connects a machine to its rework buffer, and the buffer to the machine taking
+    /// the rework back.
+    fn add_rework_loop(&mut self, index: usize, machine: &MachineDescription) {
+        let Some(quality) = &machine.quality else {
+            return;
+        };
+        let Some(buffer) = &quality.rework_buffer else {
+            return;
+        };
+        let Some(&machine_id) = self.ids.get(&("machines", machine.name.clone())) else {
+            return;
+        };
+        let at = self.locator.reference("machines", index, buffer);
+        let Some(buffer_id) = self.resolve("buffers", buffer, at) else {
+            return;
+        };
+        let name = format!("{} -> {}", machine.name, buffer);
+        self.execute(at, &name, ModelCommand::ConnectBuffer { machine_id, buffer_id, role: BufferRole::Rework });
+        if let Some(rework_to) = &quality.rework_to {
+            let at = self.locator.reference("machines", index, rework_to);
+            if let Some(target_id) = self.resolve("machines", rework_to, at) {
+                let name = format!("{} -> {}", buffer, rework_to);
+                self.execute(at, &name, ModelCommand::ConnectBuffer { machine_id: target_id, buffer_id, role: BufferRole::ReworkInput });
+            }
+        }
+    }
+
+    fn add_buffer(&mut self, index: usize, buffer: &BufferDescription) {
+        let line = self.locator.entry("buffers", index);
+        if !self.declare("buffers", &buffer.name, line) {
+            return;
+        }
+        if buffer.throughput.is_some_and(|throughput| !(throughput.is_finite() && throughput > 0.0)) {
+            return self.error(line, format!("buffer '{}' must have a positive throughput", buffer.name));
+        }
+        let id = Uuid::new_v4();
+        let command = ModelCommand::CreateBuffer { id, capacity: buffer.capacity, throughput: buffer.throughput, name: Some(buffer.name.clone()) };
+        if !self.execute(line, &buffer.name, command) {
+            return;
+        }
+        self.ids.insert(("buffers", buffer.name.clone()), id);
+        self.names.insert(id, buffer.name.clone());
+        for content in &buffer.contents {
+            let at = self.locator.reference("buffers", index, &content.item);
+            if !(content.quantity.is_finite() && content.quantity > 0.0) {
+                self.error(at, format!("buffer '{}' must hold a positive quantity of '{}'", buffer.name, content.item));
+                continue;
+            }
+            if let Some(item_id) = self.resolve("items", &content.item, at) {
+                self.execute(at, &buffer.name, ModelCommand::ChangeBufferItem { buffer_id: id, item_id, quantity: content.quantity });
+            }
+        }
+    }
+
+    fn add_connection(&mut self, index: usize, connection: &ConnectionDescription) {
+        let line = self.locator.entry("connections", index);
+        let (machine, buffer, role) = if self.is_declared("machines", &connection.from) {
+            (&connection.from, &connection.to, BufferRole::Output)
+        } else if self.is_declared("machines", &connection.to) {
+            (&connection.to, &connection.from, BufferRole::Input)
+        } else {
+            return self.error(line, format!("connection from '{}' to '{}' must join a machine and a buffer", connection.from, connection.to));
+        };
+        let machine_id = self.resolve("machines", machine, line);
+        let buffer_id = self.resolve("buffers", buffer, line);
+        if let (Some(machine_id), Some(buffer_id)) = (machine_id, buffer_id) {
+            let name = format!("{} -> {}", connection.from, connection.to);
+            self.execute(line, &name, ModelCommand::ConnectBuffer { machine_id, buffer_id, role });
+        }
+    }
+
+    fn add_line(&mut self, index: usize, line_description: &LineDescription) {
+        let line = self.locator.entry("lines", index);
+        let name = &line_description.name;
+        if !self.declare("lines", name, line) {
+            return;
+        }
+        let machine_ids: Vec<Option<Uuid>> = line_description.machines.iter()
+            .map(|machine| {
+                let at = self.locator.reference("lines", index, machine);
+                self.resolve("machines", machine, at)
+            })
+            .collect();
+        let buffer_ids: Vec<Option<Uuid>> = line_description.buffers.iter()
+            .map(|buffer| {
+                let at = self.locator.reference("lines", index, buffer);
+                self.resolve("buffers", buffer, at)
+            })
+            .collect();
+        if line_description.machines.is_empty() || line_description.buffers.len() + 1 != line_description.machines.len() {
+            return self.error(line, format!(
+                "line '{}' has {} machines and {} buffers, but needs one buffer fewer than machines",
+                name,
+                line_description.machines.len(),
+                line_description.buffers.len(),
+            ));
+        }
+        let (Some(machine_ids), Some(buffer_ids)) = (machine_ids.into_iter().collect(), buffer_ids.into_iter().collect()) else {
+            return;
+        };
+        let id = Uuid::new_v4();
+        if self.execute(line, name, ModelCommand::CreateLine { id, name: Some(name.clone()), machine_ids, buffer_ids }) {
+            self.names.insert(id, name.clone());
+        }
+    }
+
+    /// Records a declared name, reporting it if the section already has it.
+    fn declare(&mut self, section: &'static str, name: &str, line: Option<usize>) -> bool {
+        if !self.declared.insert((section, name.to_string())) {
+            self.error(line, format!("{} '{}' is declared more than once", singular(section), name));
+            return false;
+        }
+        true
+    }
+
+    fn is_declared(&self, section: &'static str, name: &str) -> bool {
+        self.declared.contains(&(section, name.to_string()))
+    }
+
+    /// Looks up a name, reporting it if nothing was declared with it. A name that
+    /// was declared but left out because of an earlier error resolves to None
+    /// without a second report.
+    fn resolve(&mut self, section: &'static str, name: &str, line: Option<usize>) -> Option<Uuid> {
+        if let Some(id) = self.ids.get(&(section, name.to_string())) {
+            return Some(*id);
+        }
+        if !self.is_declared(section, name) {
+            self.error(line, format!("unknown {} '{}'", singular(section), name));
+        }
+        None
+    }
+
+    /// Executes a registry command, reporting its error against the named entity.
+    fn execute(&mut self, line: Option<usize>, name: &str, command: ModelCommand) -> bool {
+        match self.model.execute(command) {
+            Ok(_) => true,
+            Err(error) => {
+                self.error(line, format!("'{}': {}", name, error));
+                false
+            }
+        }
+    }
+
+    fn error(&mut self, line: Option<usize>, message: String) {
+        self.errors.push(ModelFileError { line, message });
+    }
+}
+
+fn singular(section: &str) -> &str {
+    section.strip_suffix('s').unwrap_or(section)
+}
+
+/// Finds the lines entries of a description were read from. Descriptions are
+/// written as TOML arrays of tables (`[[machines]]`) or YAML block sequences
+/// (`machines:` followed by `- name: ...`); entries written any other way have no
+/// known line.
+struct Locator<'a> {
+    lines: Vec<&'a str>,
+}
+
+impl<'a> Locator<'a> {
+    fn new(source: &'a str) -> Locator<'a> {
+        Locator { lines: source.lines().collect() }
+    }
+
+    /// The line, counting from 1, that starts the entry at the index of a section.
+    fn entry(&self, section: &str, index: usize) -> Option<usize> {
+        self.entries(section).get(index).map(|&(start, _)| start + 1)
+    }
+
+    /// The first line of an entry that mentions the value, or the line the entry
+    /// starts on if none does.
+    fn reference(&self, section: &str, index: usize, value: &str) -> Option<usize> {
+        let &(start, end) = self.entries(section).get(index)?;
+        (start..end)
+            .find(|&line| mentions(self.lines[line], value))
+            .map_or(Some(start + 1), |line| Some(line + 1))
+    }
+
+    /// The range of lines, counting from 0, of every entry of a section.
+    fn entries(&self, section: &str) -> Vec<(usize, usize)> {
+        let count = self.lines.len();
+        let header = format!("[[{}]]", section);
+        let tables: Vec<usize> = (0..count).filter(|&line| self.lines[line].trim() == header).collect();
+        if !tables.is_empty() {
+            let own_table = |line: &str| line.starts_with(&format!("[{}.", section)) || line.starts_with(&format!("[[{}.", section));
+            return tables.iter()
+                .map(|&start| {
+                    let end = (start + 1..count)
+                        .find(|&line| self.lines[line].starts_with('[') && !own_table(self.lines[line]))
+                        .unwrap_or(count);
+                    (start, end)
+                })
+                .collect();
+        }
+
+        let Some(key) = (0..count).find(|&line| self.lines[line].trim_end() == format!("{}:", section)) else {
+            return Vec::new();
+        };
+        let end = (key + 1..count)
+            .find(|&line| {
+                let text = self.lines[line];
+                let trimmed = text.trim();
+                !trimmed.is_empty() && !trimmed.starts_with('#') && !text.starts_with(' ') && !text.starts_with('-')
+            })
+            .unwrap_or(count);
+        let mut indentation = None;
+        let starts: Vec<usize> = (key + 1..end)
+            .filter(|&line| {
+                let text = self.lines[line];
+                let trimmed = text.trim_start();
+                if !(trimmed.starts_with("- ") || trimmed == "-") {
+                    return false;
+                }
+                let indent = text.len() - trimmed.len();
+                *indentation.get_or_insert(indent) == indent
+            })
+            .collect();
+        starts.iter()
+            .enumerate()
+            .map(|(i, &start)| (start, starts.get(i + 1).copied().unwrap_or(end)))
+            .collect()
+    }
+}
+
+/// Whether the line holds the value as a whole word, so that looking for `press`
+/// does not find `pressure`.
+fn mentions(line: &str, value: &str) -> bool {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_' || c == '-';
+    line.match_indices(value).any(|(at, _)| {
+        let before = line[..at].chars().next_back();
+        let after = line[at + value.len()..].chars().next();
+        !before.is_some_and(is_word) && !after.is_some_and(is_word)
+    })
+}
+
+fn line_of_offset(source: &str, offset: usize) -> usize {
+    source[..offset.min(source.len())].matches('\n').count() + 1
+}
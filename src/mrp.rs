@@ -0,0 +1,338 @@
+//! Material requirements planning over the recipe graph.
+//!
+//! The bill of materials of an item is read from its primary recipe: one unit of the
+//! item needs each recipe input in proportion to the quantity of the item a cycle
+//! makes. Items are planned level by level, in order of their low level code, the
+//! deepest level at which they appear in any bill of materials, so that all of an
+//! item's gross requirements are known before it is netted.
+//!
+//! For each period the gross requirements of an item are netted against its
+//! projected on hand inventory and scheduled receipts. Net requirements are covered
+//! by planned receipts sized by the item's lot sizing rule, and every planned receipt
+//! is released its lead time earlier. The planned releases of an item become gross
+//! requirements of its components in the same period.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+use crate::machine::Recipe;
+use crate::queue::{Buffer, QUANTITY_TOLERANCE};
+use crate::recipe_analysis::{primary_recipes, recipe_items};
+
+/// How net requirements are turned into order quantities.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LotSizing {
+    /// Order exactly the net requirement.
+    LotForLot,
+    /// Order in multiples of a fixed quantity.
+    FixedOrderQuantity(f64),
+    /// Order in multiples of the economic order quantity sqrt(2 D S / H), where D is
+    /// the average gross requirement per period, S the cost of placing an order and
+    /// H the cost of holding one unit for one period.
+    Eoq { ordering_cost: f64, holding_cost: f64 },
+}
+
+/// The planning parameters of one item.
+#[derive(Clone, Copy, Debug)]
+pub struct PlanningParameters {
+    /// Periods between releasing an order and receiving it.
+    pub lead_time: usize,
+    pub lot_sizing: LotSizing,
+    /// Inventory that projected on hand is not planned to fall below.
+    pub safety_stock: f64,
+}
+
+impl Default for PlanningParameters {
+    fn default() -> PlanningParameters {
+        PlanningParameters { lead_time: 0, lot_sizing: LotSizing::LotForLot, safety_stock: 0.0 }
+    }
+}
+
+/// The inputs of an MRP run. Per period quantities are indexed from period 0 and
+/// shorter vectors are padded with zeros.
+pub struct MrpProblem {
+    pub recipes: Vec<Arc<Recipe>>,
+    pub periods: usize,
+    /// Independent demand per item and period, the master production schedule.
+    pub demand: HashMap<Uuid, Vec<f64>>,
+    pub on_hand: HashMap<Uuid, f64>,
+    pub scheduled_receipts: HashMap<Uuid, Vec<f64>>,
+    /// Items without an entry use the default parameters.
+    pub parameters: HashMap<Uuid, PlanningParameters>,
+}
+
+impl MrpProblem {
+    pub fn new(recipes: Vec<Arc<Recipe>>, periods: usize) -> MrpProblem {
+        MrpProblem {
+            recipes,
+            periods,
+            demand: HashMap::new(),
+            on_hand: HashMap::new(),
+            scheduled_receipts: HashMap::new(),
+            parameters: HashMap::new(),
+        }
+    }
+
+    /// Sets the on hand inventory of every item to the total held in the buffers.
+    pub fn set_on_hand_from_buffers(&mut self, buffers: &[Arc<Mutex<Buffer>>]) {
+        self.on_hand.clear();
+        for buffer in buffers {
+            let buffer = buffer.lock().unwrap();
+            for (item, quantity) in &buffer.items {
+                *self.on_hand.entry(item.id()).or_insert(0.0) += quantity;
+            }
+        }
+    }
+}
+
+/// The time phased plan of one item.
+#[derive(Clone, Debug)]
+pub struct MrpRecord {
+    pub item_id: Uuid,
+    pub name: String,
+    pub low_level_code: usize,
+    pub gross_requirements: Vec<f64>,
+    pub scheduled_receipts: Vec<f64>,
+    /// Inventory at the end of each period.
+    pub projected_on_hand: Vec<f64>,
+    pub net_requirements: Vec<f64>,
+    pub planned_receipts: Vec<f64>,
+    pub planned_releases: Vec<f64>,
+    /// Releases that would have had to happen before period 0 to meet the plan.
+    /// They are also included in the period 0 release.
+    pub past_due: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct MrpPlan {
+    pub records: Vec<MrpRecord>,
+}
+
+impl MrpPlan {
+    pub fn record(&self, item_id: Uuid) -> Option<&MrpRecord> {
+        self.records.iter().find(|record| record.item_id == item_id)
+    }
+
+    /// Returns every planned release as (item, period, quantity), in period order.
+    pub fn planned_orders(&self) -> Vec<(Uuid, usize, f64)> {
+        let mut orders: Vec<(Uuid, usize, f64)> = self.records.iter()
+            .flat_map(|record| {
+                record.planned_releases.iter()
+                    .enumerate()
+                    .filter(|(_, quantity)| **quantity > QUANTITY_TOLERANCE)
+                    .map(|(period, &quantity)| (record.item_id, period, quantity))
+            })
+            .collect();
+        orders.sort_by_key(|&(_, period, _)| period);
+        orders
+    }
+}
+
+/// Returns the components of one unit of every item with a recipe, as read from its
+/// primary recipe. Items whose primary recipe is a recycling one are bought in.
+pub fn bill_of_materials(recipes: &[Arc<Recipe>]) -> HashMap<Uuid, Vec<(Uuid, f64)>> {
+    primary_recipes(recipes).into_iter()
+        .filter(|&(_, index)| !recipes[index].recycling)
+        .map(|(item_id, index)| {
+            let recipe = &recipes[index];
+            let made: f64 = recipe.output.iter()
+                .filter(|(item, _)| item.id() == item_id)
+                .map(|(_, quantity)| quantity)
+                .sum();
+            let components = recipe.input.iter()
+                .map(|(input, quantity)| (input.id(), quantity / made))
+                .collect();
+            (item_id, components)
+        })
+        .collect()
+}
+
+/// Returns the low level code of every item: 0 for items that are no other item's
+/// component, otherwise one more than the deepest parent.
+pub fn low_level_codes(recipes: &[Arc<Recipe>]) -> Result<HashMap<Uuid, usize>, &'static str> {
+    let items = recipe_items(recipes);
+    let bom = bill_of_materials(recipes);
+    let mut codes: HashMap<Uuid, usize> = items.iter().map(|item| (item.id(), 0)).collect();
+    // Codes only grow, and a code of items.len() or more means a component cycle.
+    for _ in 0..=items.len() {
+        let mut changed = false;
+        for (parent, components) in &bom {
+            let code = codes[parent] + 1;
+            for (component, _) in components {
+                if codes[component] < code {
+                    codes.insert(*component, code);
+                    changed = true;
+                }
+            }
+        }
+        if !changed {
+            return Ok(codes);
+        }
+    }
+    Err("Bill of materials contains a cycle.")
+}
+
+/// Explodes a quantity of an item into the total quantity of every component needed
+/// at all levels, without netting against inventory.
+pub fn explode(recipes: &[Arc<Recipe>], item_id: Uuid, quantity: f64) -> Result<HashMap<Uuid, f64>, &'static str> {
+    let codes = low_level_codes(recipes)?;
+    let bom = bill_of_materials(recipes);
+    let mut order: Vec<Uuid> = codes.keys().copied().collect();
+    order.sort_by_key(|id| codes[id]);
+
+    let mut required: HashMap<Uuid, f64> = HashMap::from([(item_id, quantity)]);
+    for parent in order {
+        let Some(&needed) = required.get(&parent) else {
+            continue;
+        };
+        for (component, per_unit) in bom.get(&parent).into_iter().flatten() {
+            *required.entry(*component).or_insert(0.0) += needed * per_unit;
+        }
+    }
+    required.remove(&item_id);
+    Ok(required)
+}
+
+/// Runs MRP, returning the time phased plan of every item in low level code order.
+pub fn run(problem: &MrpProblem) -> Result<MrpPlan, &'static str> {
+    let periods = problem.periods;
+    let items = recipe_items(&problem.recipes);
+    let codes = low_level_codes(&problem.recipes)?;
+    let bom = bill_of_materials(&problem.recipes);
+
+    let padded = |values: Option<&Vec<f64>>| -> Vec<f64> {
+        let mut values = values.cloned().unwrap_or_default();
+        values.resize(periods, 0.0);
+        values
+    };
+    let mut gross: HashMap<Uuid, Vec<f64>> = items.iter()
+        .map(|item| (item.id(), padded(problem.demand.get(&item.id()))))
+        .collect();
+
+    let mut order = items.clone();
+    order.sort_by_key(|item| codes[&item.id()]);
+
+    let mut records = Vec::with_capacity(order.len());
+    for item in order {
+        let id = item.id();
+        let parameters = problem.parameters.get(&id).copied().unwrap_or_default();
+        let gross_requirements = gross[&id].clone();
+        let scheduled_receipts = padded(problem.scheduled_receipts.get(&id));
+        let lot = lot_size(parameters.lot_sizing, &gross_requirements);
+
+        let mut on_hand = problem.on_hand.get(&id).copied().unwrap_or(0.0);
+        let mut projected_on_hand = vec![0.0; periods];
+        let mut net_requirements = vec![0.0; periods];
+        let mut planned_receipts = vec![0.0; periods];
+        let mut planned_releases = vec![0.0; periods];
+        let mut past_due = 0.0;
+        for t in 0..periods {
+            let available = on_hand + scheduled_receipts[t] - gross_requirements[t];
+            let net = (parameters.safety_stock - available).max(0.0);
+            if net > QUANTITY_TOLERANCE {
+                let receipt = match lot {
+                    Some(lot) => (net / lot - QUANTITY_TOLERANCE).ceil() * lot,
+                    None => net,
+                };
+                net_requirements[t] = net;
+                planned_receipts[t] = receipt;
+                match t.checked_sub(parameters.lead_time) {
+                    Some(release) => planned_releases[release] += receipt,
+                    None => {
+                        past_due += receipt;
+                        planned_releases[0] += receipt;
+                    }
+                }
+            }
+            on_hand = available + planned_receipts[t];
+            projected_on_hand[t] = on_hand;
+        }
+
+        for (component, per_unit) in bom.get(&id).into_iter().flatten() {
+            let component_gross = gross.get_mut(component).unwrap();
+            for (requirement, release) in component_gross.iter_mut().zip(&planned_releases) {
+                *requirement += release * per_unit;
+            }
+        }
+
+        records.push(MrpRecord {
+            item_id: id,
+            name: item.name.clone(),
+            low_level_code: codes[&id],
+            gross_requirements,
+            scheduled_receipts,
+            projected_on_hand,
+            net_requirements,
+            planned_receipts,
+            planned_releases,
+            past_due,
+        });
+    }
+    Ok(MrpPlan { records })
+}
+
+/// Returns the order multiple of a lot sizing rule, or None to order exactly the
+/// net requirement.
+fn lot_size(lot_sizing: LotSizing, gross_requirements: &[f64]) -> Option<f64> {
+    match lot_sizing {
+        LotSizing::LotForLot => None,
+        LotSizing::FixedOrderQuantity(quantity) => (quantity > 0.0).then_some(quantity),
+        LotSizing::Eoq { ordering_cost, holding_cost } => {
+            let demand = gross_requirements.iter().sum::<f64>() / gross_requirements.len().max(1) as f64;
+            let eoq = (2.0 * demand * ordering_cost / holding_cost).sqrt();
+            (holding_cost > 0.0 && eoq > QUANTITY_TOLERANCE).then_some(eoq)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Item;
+
+    #[test]
+    fn nets_requirements_level_by_level() {
+        // One product is assembled from two parts, which are bought in lots of 25.
+        let part = Arc::new(Item::new("part".to_string(), 1.0, None));
+        let product = Arc::new(Item::new("product".to_string(), 1.0, None));
+        let assemble = Recipe::new("assemble".to_string(), vec![(part.clone(), 2.0)], vec![(product.clone(), 1.0)]);
+        let mut problem = MrpProblem::new(vec![Arc::new(assemble)], 4);
+        problem.demand.insert(product.id(), vec![0.0, 10.0, 0.0, 15.0]);
+        problem.on_hand.insert(product.id(), 5.0);
+        problem.on_hand.insert(part.id(), 12.0);
+        problem.parameters.insert(product.id(), PlanningParameters { lead_time: 1, ..PlanningParameters::default() });
+        problem.parameters.insert(part.id(), PlanningParameters {
+            lot_sizing: LotSizing::FixedOrderQuantity(25.0),
+            ..PlanningParameters::default()
+        });
+        let plan = run(&problem).unwrap();
+
+        let product_plan = plan.record(product.id()).unwrap();
+        assert_eq!(product_plan.low_level_code, 0);
+        assert_eq!(product_plan.net_requirements, vec![0.0, 5.0, 0.0, 15.0]);
+        assert_eq!(product_plan.planned_releases, vec![5.0, 0.0, 15.0, 0.0]);
+        assert_eq!(product_plan.projected_on_hand, vec![5.0, 0.0, 0.0, 0.0]);
+
+        let part_plan = plan.record(part.id()).unwrap();
+        assert_eq!(part_plan.low_level_code, 1);
+        assert_eq!(part_plan.gross_requirements, vec![10.0, 0.0, 30.0, 0.0]);
+        assert_eq!(part_plan.net_requirements, vec![0.0, 0.0, 28.0, 0.0]);
+        assert_eq!(part_plan.planned_receipts, vec![0.0, 0.0, 50.0, 0.0]);
+        assert_eq!(part_plan.projected_on_hand, vec![2.0, 2.0, 22.0, 22.0]);
+        assert_eq!(part_plan.past_due, 0.0);
+    }
+
+    #[test]
+    fn releases_before_period_zero_are_past_due() {
+        let part = Arc::new(Item::new("part".to_string(), 1.0, None));
+        let product = Arc::new(Item::new("product".to_string(), 1.0, None));
+        let assemble = Recipe::new("assemble".to_string(), vec![(part.clone(), 1.0)], vec![(product.clone(), 1.0)]);
+        let mut problem = MrpProblem::new(vec![Arc::new(assemble)], 3);
+        problem.demand.insert(product.id(), vec![4.0, 0.0, 6.0]);
+        problem.parameters.insert(product.id(), PlanningParameters { lead_time: 2, ..PlanningParameters::default() });
+        let plan = run(&problem).unwrap();
+        let product_plan = plan.record(product.id()).unwrap();
+        assert_eq!(product_plan.past_due, 4.0);
+        assert_eq!(product_plan.planned_releases, vec![10.0, 0.0, 0.0]);
+    }
+}
@@ -0,0 +1,331 @@
+//! Steady state output analysis for simulation runs.
+//!
+//! A transfer line starts empty, so the first part of every run under-reports WIP
+//! and cycle time. The warm-up period is truncated with MSER-5, or with Welch's
+//! moving average method, and the remainder is split into batches whose means are
+//! treated as independent observations for a Student-t confidence interval.
+//! Cycle time is estimated through Little's law as the ratio of mean WIP to mean
+//! throughput, with a delta method interval, so that batches in which no part left
+//! the line do not make it infinite.
+
+use crate::simulation::SimulationRun;
+
+/// The number of observations averaged together before MSER is applied.
+pub const MSER_BATCH: usize = 5;
+/// The default number of batches for the batch means method.
+pub const DEFAULT_BATCHES: usize = 20;
+/// The smallest number of batches that still gives a usable interval.
+const MIN_BATCHES: usize = 10;
+/// The smallest number of observations in a batch for its mean to be meaningful.
+const MIN_BATCH_SIZE: usize = 10;
+/// Batch means with a larger lag one autocorrelation are not treated as independent.
+const MAX_BATCH_AUTOCORRELATION: f64 = 0.2;
+
+/// How the warm-up period is detected.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WarmupMethod {
+    /// The marginal standard error rule applied to batches of five observations.
+    Mser5,
+    /// Welch's method: the warm-up ends where the moving average with the given
+    /// half window first comes within the given relative tolerance of the mean of
+    /// the second half of the run.
+    Welch { window: usize, tolerance: f64 },
+}
+
+/// Reasons why a run is too short to give a reliable steady state answer.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AnalysisIssue {
+    /// The warm-up detection rule truncated half of the run or more, so the run
+    /// most likely never reached steady state.
+    WarmupNotDetected { truncated: usize, length: usize },
+    /// Too few observations were left after truncation to form enough batches.
+    TooFewBatches { batches: usize, required: usize },
+    /// The batches hold too few observations each.
+    BatchesTooShort { batch_size: usize, required: usize },
+    /// The batch means are correlated, so the batches are too short.
+    CorrelatedBatches { autocorrelation: f64 },
+}
+
+/// A steady state mean with its batch means confidence interval.
+#[derive(Clone, Copy, Debug)]
+pub struct Estimate {
+    pub mean: f64,
+    /// The standard deviation of the batch means.
+    pub std_dev: f64,
+    pub half_width: f64,
+    pub confidence: f64,
+}
+
+impl Estimate {
+    pub fn lower(&self) -> f64 {
+        self.mean - self.half_width
+    }
+
+    pub fn upper(&self) -> f64 {
+        self.mean + self.half_width
+    }
+
+    /// Returns the half width relative to the mean.
+    pub fn relative_precision(&self) -> f64 {
+        if self.mean == 0.0 {
+            return f64::INFINITY;
+        }
+        self.half_width / self.mean.abs()
+    }
+}
+
+/// The steady state analysis of a single run.
+#[derive(Clone, Debug)]
+pub struct OutputAnalysis {
+    /// The number of time steps truncated as warm-up.
+    pub warmup: usize,
+    pub batches: usize,
+    pub batch_size: usize,
+    /// Parts leaving the line per time step.
+    pub throughput: Estimate,
+    /// Items in the line.
+    pub wip: Estimate,
+    /// Time steps a part spends in the line.
+    pub cycle_time: Estimate,
+    pub issues: Vec<AnalysisIssue>,
+}
+
+impl OutputAnalysis {
+    /// Whether the run was long enough for the estimates to be trusted.
+    pub fn is_reliable(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// Truncates the warm-up period of a run and estimates steady state throughput,
+/// WIP and cycle time with the given number of batches and confidence level.
+pub fn analyse(run: &SimulationRun, method: WarmupMethod, batches: usize, confidence: f64) -> OutputAnalysis {
+    let output: Vec<f64> = run.output.iter().map(|&parts| parts as f64).collect();
+    let wip: Vec<f64> = run.wip.iter().map(|&items| items as f64).collect();
+    let mut issues = Vec::new();
+
+    let warmup = match method {
+        WarmupMethod::Mser5 => mser(&output, MSER_BATCH).max(mser(&wip, MSER_BATCH)),
+        WarmupMethod::Welch { window, tolerance } => {
+            welch(&output, window, tolerance).max(welch(&wip, window, tolerance))
+        }
+    };
+    if run.is_empty() || warmup * 2 >= run.len() {
+        issues.push(AnalysisIssue::WarmupNotDetected { truncated: warmup, length: run.len() });
+    }
+
+    let remaining = run.len().saturating_sub(warmup);
+    let batches = batches.max(1).min(remaining.max(1));
+    let batch_size = (remaining / batches).max(1);
+    let batches = (remaining / batch_size).min(batches);
+    if batches < MIN_BATCHES {
+        issues.push(AnalysisIssue::TooFewBatches { batches, required: MIN_BATCHES });
+    }
+    if batch_size < MIN_BATCH_SIZE {
+        issues.push(AnalysisIssue::BatchesTooShort { batch_size, required: MIN_BATCH_SIZE });
+    }
+
+    let output_means = batch_means(&output[warmup.min(output.len())..], batch_size, batches);
+    let wip_means = batch_means(&wip[warmup.min(wip.len())..], batch_size, batches);
+
+    let autocorrelation = lag_one_autocorrelation(&wip_means).max(lag_one_autocorrelation(&output_means));
+    if autocorrelation > MAX_BATCH_AUTOCORRELATION {
+        issues.push(AnalysisIssue::CorrelatedBatches { autocorrelation });
+    }
+
+    OutputAnalysis {
+        warmup,
+        batches,
+        batch_size,
+        throughput: estimate(&output_means, confidence),
+        wip: estimate(&wip_means, confidence),
+        cycle_time: ratio_estimate(&wip_means, &output_means, confidence),
+        issues,
+    }
+}
+
+/// Returns the MSER truncation point, in observations, of a series after first
+/// averaging it in batches of the given size. The search is limited to the first
+/// half of the series, so a result at that limit means no steady state was found.
+pub fn mser(series: &[f64], batch_size: usize) -> usize {
+    let batch_size = batch_size.max(1);
+    let means = batch_means(series, batch_size, series.len() / batch_size);
+    let n = means.len();
+    if n < 2 {
+        return 0;
+    }
+
+    // Suffix sums let every candidate truncation be scored in constant time.
+    let mut suffix_sum = vec![0.0; n + 1];
+    let mut suffix_squares = vec![0.0; n + 1];
+    for i in (0..n).rev() {
+        suffix_sum[i] = suffix_sum[i + 1] + means[i];
+        suffix_squares[i] = suffix_squares[i + 1] + means[i] * means[i];
+    }
+
+    let mut best = (0, f64::INFINITY);
+    for d in 0..=n / 2 {
+        let remaining = (n - d) as f64;
+        let mean = suffix_sum[d] / remaining;
+        let squared_deviations = (suffix_squares[d] - remaining * mean * mean).max(0.0);
+        let statistic = squared_deviations / (remaining * remaining);
+        if statistic < best.1 {
+            best = (d, statistic);
+        }
+    }
+    best.0 * batch_size
+}
+
+/// Returns the Welch moving average of a series with the given half window, using
+/// a shrinking window near the start of the series as in Welch's original method.
+pub fn welch_moving_average(series: &[f64], window: usize) -> Vec<f64> {
+    let n = series.len();
+    let length = n.saturating_sub(window);
+    (0..length)
+        .map(|i| {
+            let half = i.min(window);
+            let slice = &series[i - half..=i + half];
+            slice.iter().sum::<f64>() / slice.len() as f64
+        })
+        .collect()
+}
+
+/// Returns the Welch truncation point of a series.
+pub fn welch(series: &[f64], window: usize, tolerance: f64) -> usize {
+    let n = series.len();
+    if n < 2 {
+        return 0;
+    }
+    let second_half = &series[n / 2..];
+    let target = second_half.iter().sum::<f64>() / second_half.len() as f64;
+    let scale = target.abs().max(f64::EPSILON);
+    welch_moving_average(series, window)
+        .iter()
+        .position(|average| (average - target).abs() / scale <= tolerance)
+        .unwrap_or(n / 2)
+        .min(n / 2)
+}
+
+fn batch_means(series: &[f64], batch_size: usize, batches: usize) -> Vec<f64> {
+    series.chunks_exact(batch_size.max(1))
+        .take(batches)
+        .map(|batch| batch.iter().sum::<f64>() / batch.len() as f64)
+        .collect()
+}
+
+fn lag_one_autocorrelation(values: &[f64]) -> f64 {
+    let n = values.len();
+    if n < 3 {
+        return 0.0;
+    }
+    let mean = values.iter().sum::<f64>() / n as f64;
+    let variance: f64 = values.iter().map(|value| (value - mean).powi(2)).sum();
+    if variance == 0.0 {
+        return 0.0;
+    }
+    let covariance: f64 = values.windows(2).map(|pair| (pair[0] - mean) * (pair[1] - mean)).sum();
+    covariance / variance
+}
+
+/// Returns the sample mean and a Student-t confidence interval treating the values
+/// as independent observations.
+pub fn estimate(values: &[f64], confidence: f64) -> Estimate {
+    let n = values.len();
+    let mean = if n > 0 { values.iter().sum::<f64>() / n as f64 } else { 0.0 };
+    if n < 2 {
+        return Estimate { mean, std_dev: 0.0, half_width: f64::INFINITY, confidence };
+    }
+    let variance = values.iter().map(|value| (value - mean).powi(2)).sum::<f64>() / (n - 1) as f64;
+    let std_dev = variance.sqrt();
+    let quantile = student_t_quantile(0.5 + confidence / 2.0, (n - 1) as f64);
+    Estimate { mean, std_dev, half_width: quantile * std_dev / (n as f64).sqrt(), confidence }
+}
+
+/// Returns the ratio of the means of paired observations with a delta method
+/// confidence interval, as for cycle time from WIP and throughput. The ratio is
+/// infinite when the denominators sum to zero.
+pub fn ratio_estimate(numerators: &[f64], denominators: &[f64], confidence: f64) -> Estimate {
+    let n = numerators.len().min(denominators.len());
+    let numerator = numerators[..n].iter().sum::<f64>();
+    let denominator = denominators[..n].iter().sum::<f64>();
+    if denominator <= 0.0 {
+        return Estimate { mean: f64::INFINITY, std_dev: f64::INFINITY, half_width: f64::INFINITY, confidence };
+    }
+    let ratio = numerator / denominator;
+    if n < 2 {
+        return Estimate { mean: ratio, std_dev: 0.0, half_width: f64::INFINITY, confidence };
+    }
+    // The residuals of the ratio carry its variance, scaled by the mean denominator.
+    let residuals: Vec<f64> = numerators[..n].iter()
+        .zip(&denominators[..n])
+        .map(|(numerator, denominator)| numerator - ratio * denominator)
+        .collect();
+    let residual = estimate(&residuals, confidence);
+    let scale = denominator / n as f64;
+    Estimate { mean: ratio, std_dev: residual.std_dev / scale, half_width: residual.half_width / scale, confidence }
+}
+
+/// Returns the p quantile of the standard normal distribution (Acklam's algorithm).
+pub fn normal_quantile(p: f64) -> f64 {
+    const A: [f64; 6] = [-3.969683028665376e1, 2.209460984245205e2, -2.759285104469687e2,
+        1.38357751867269e2, -3.066479806614716e1, 2.506628277459239];
+    const B: [f64; 5] = [-5.447609879822406e1, 1.615858368580409e2, -1.556989798598866e2,
+        6.680131188771972e1, -1.328068155288572e1];
+    const C: [f64; 6] = [-7.784894002430293e-3, -3.223964580411365e-1, -2.400758277161838,
+        -2.549732539343734, 4.374664141464968, 2.938163982698783];
+    const D: [f64; 4] = [7.784695709041462e-3, 3.224671290700398e-1, 2.445134137142996,
+        3.754408661907416];
+    const P_LOW: f64 = 0.02425;
+
+    if p <= 0.0 {
+        return f64::NEG_INFINITY;
+    }
+    if p >= 1.0 {
+        return f64::INFINITY;
+    }
+    if p < P_LOW {
+        let q = (-2.0 * p.ln()).sqrt();
+        (((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5])
+            / ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.0)
+    } else if p <= 1.0 - P_LOW {
+        let q = p - 0.5;
+        let r = q * q;
+        (((((A[0] * r + A[1]) * r + A[2]) * r + A[3]) * r + A[4]) * r + A[5]) * q
+            / (((((B[0] * r + B[1]) * r + B[2]) * r + B[3]) * r + B[4]) * r + 1.0)
+    } else {
+        -normal_quantile(1.0 - p)
+    }
+}
+
+/// Returns the standard normal distribution function at x, through the complementary
+/// error function approximation of Numerical Recipes (relative error below 1.2e-7).
+pub fn normal_cdf(x: f64) -> f64 {
+    let z = x.abs() / std::f64::consts::SQRT_2;
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let erfc = t * (-z * z - 1.265_512_23 + t * (1.000_023_68 + t * (0.374_091_96 + t * (0.096_784_18
+        + t * (-0.186_288_06 + t * (0.278_868_07 + t * (-1.135_203_98 + t * (1.488_515_87
+        + t * (-0.822_152_23 + t * 0.170_872_77))))))))).exp();
+    if x >= 0.0 { 1.0 - erfc / 2.0 } else { erfc / 2.0 }
+}
+
+/// Returns the p quantile of Student's t distribution with the given degrees of
+/// freedom, exactly for one and two degrees of freedom and through the
+/// Cornish-Fisher expansion around the normal quantile otherwise.
+pub fn student_t_quantile(p: f64, degrees_of_freedom: f64) -> f64 {
+    let v = degrees_of_freedom;
+    if v <= 1.0 {
+        return (std::f64::consts::PI * (p - 0.5)).tan();
+    }
+    if v <= 2.0 {
+        return (2.0 * p - 1.0) / (2.0 * p * (1.0 - p)).sqrt();
+    }
+    let z = normal_quantile(p);
+    let z3 = z.powi(3);
+    let z5 = z.powi(5);
+    let z7 = z.powi(7);
+    let z9 = z.powi(9);
+    z + (z3 + z) / (4.0 * v)
+        + (5.0 * z5 + 16.0 * z3 + 3.0 * z) / (96.0 * v.powi(2))
+        + (3.0 * z7 + 19.0 * z5 + 17.0 * z3 - 15.0 * z) / (384.0 * v.powi(3))
+        + (79.0 * z9 + 776.0 * z7 + 1482.0 * z5 - 1920.0 * z3 - 945.0 * z) / (92160.0 * v.powi(4))
+}
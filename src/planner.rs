@@ -0,0 +1,235 @@
+//! Production planning over recipes and machine capacities.
+//!
+//! When several recipes can make the same item, or a recipe can run on several
+//! machines, the mix of recipe run rates is chosen by linear programming. There is
+//! one variable per recipe and machine pair, the run rate of the recipe on that
+//! machine in cycles per time unit, plus one for each recipe no machine runs, which
+//! is not limited by capacity.
+//!
+//! Every item is valued at its `Item::cost`, the price it is bought or sold at.
+//! Items no recipe makes are raw materials and are bought, up to their availability.
+//! Every other item must be made at least as fast as it is consumed plus its demand.
+//! The objective either maximises profit, the value of net production less the cost
+//! of raw materials, or meets the demand at the lowest raw material cost.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use uuid::Uuid;
+use crate::lp::{LinearProgram, Relation};
+use crate::machine::{Item, Machine, Recipe};
+use crate::queue::QUANTITY_TOLERANCE;
+use crate::recipe_analysis::recipe_items;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Objective {
+    MaximiseProfit,
+    MinimiseCost,
+}
+
+/// Everything a production plan is chosen from.
+pub struct PlanningProblem {
+    pub recipes: Vec<Arc<Recipe>>,
+    pub machines: Vec<Machine>,
+    /// Time units each machine is available per planning period. Machines without
+    /// an entry are available for the whole period.
+    pub capacities: HashMap<Uuid, f64>,
+    /// The quantity of each raw material that can be bought per planning period.
+    /// Raw materials without an entry are unlimited.
+    pub availability: HashMap<Uuid, f64>,
+    /// The quantity of each item that must be made per planning period.
+    pub demand: HashMap<Uuid, f64>,
+    pub objective: Objective,
+    /// Whether recipes may only run a whole number of cycles per period.
+    pub integer_runs: bool,
+    /// The length of the planning period in time units.
+    pub period: f64,
+}
+
+impl PlanningProblem {
+    pub fn new(recipes: Vec<Arc<Recipe>>, machines: Vec<Machine>, objective: Objective) -> PlanningProblem {
+        PlanningProblem {
+            recipes,
+            machines,
+            capacities: HashMap::new(),
+            availability: HashMap::new(),
+            demand: HashMap::new(),
+            objective,
+            integer_runs: false,
+            period: 1.0,
+        }
+    }
+}
+
+/// The cycles one recipe runs on one machine per planning period.
+#[derive(Clone, Debug)]
+pub struct PlannedRun {
+    pub recipe_id: Uuid,
+    pub machine_id: Option<Uuid>,
+    pub cycles: f64,
+    /// Machine time used, in time units.
+    pub machine_time: f64,
+}
+
+/// The net flow of an item in a plan, per planning period.
+#[derive(Clone, Debug)]
+pub struct PlannedItem {
+    pub item_id: Uuid,
+    pub name: String,
+    pub produced: f64,
+    pub consumed: f64,
+    /// The quantity bought, for raw materials.
+    pub bought: f64,
+    /// Production in excess of consumption, available for sale.
+    pub net_output: f64,
+}
+
+/// The chosen production plan.
+#[derive(Clone, Debug)]
+pub struct PlannedProduction {
+    pub runs: Vec<PlannedRun>,
+    pub items: Vec<PlannedItem>,
+    /// Machine time used over machine time available, per machine.
+    pub utilisation: Vec<(Uuid, f64)>,
+    pub revenue: f64,
+    pub raw_material_cost: f64,
+    pub profit: f64,
+}
+
+/// Chooses recipe run rates for the planning problem.
+pub fn plan(problem: &PlanningProblem) -> Result<PlannedProduction, &'static str> {
+    let items = recipe_items(&problem.recipes);
+    let producible: Vec<bool> = items.iter()
+        .map(|item| problem.recipes.iter().any(|recipe| recipe.output.iter().any(|(output, _)| output.id() == item.id())))
+        .collect();
+
+    // One variable per recipe and machine pair that can run it.
+    let mut variables: Vec<(usize, Option<usize>)> = Vec::new();
+    for (r, recipe) in problem.recipes.iter().enumerate() {
+        let runners: Vec<usize> = problem.machines.iter()
+            .enumerate()
+            .filter(|(_, machine)| machine.recipes.iter().any(|assigned| assigned.id == recipe.id))
+            .map(|(m, _)| m)
+            .collect();
+        if runners.is_empty() {
+            variables.push((r, None));
+        } else {
+            variables.extend(runners.into_iter().map(|m| (r, Some(m))));
+        }
+    }
+
+    // net[i][v]: net quantity of item i made per cycle of variable v.
+    let net: Vec<Vec<f64>> = items.iter()
+        .map(|item| {
+            variables.iter()
+                .map(|&(r, _)| net_per_cycle(&problem.recipes[r], item.id()))
+                .collect()
+        })
+        .collect();
+
+    let value = |item: &Item| item.cost.unwrap_or(0.0);
+    let objective: Vec<f64> = (0..variables.len())
+        .map(|v| {
+            items.iter()
+                .enumerate()
+                .map(|(i, item)| match (problem.objective, producible[i]) {
+                    (Objective::MaximiseProfit, _) => value(item) * net[i][v],
+                    // Minimising cost is maximising the negated spend on raw materials.
+                    (Objective::MinimiseCost, false) => value(item) * net[i][v],
+                    (Objective::MinimiseCost, true) => 0.0,
+                })
+                .sum()
+        })
+        .collect();
+    let mut lp = LinearProgram::new(objective);
+
+    for (i, item) in items.iter().enumerate() {
+        let demand = problem.demand.get(&item.id()).copied().unwrap_or(0.0);
+        if producible[i] {
+            lp.add_constraint(net[i].clone(), Relation::GreaterEq, demand);
+        } else {
+            // Raw materials: purchases, the negated net flow, are limited by availability
+            // and must also cover any demand for the raw material itself.
+            if let Some(&available) = problem.availability.get(&item.id()) {
+                lp.add_constraint(net[i].clone(), Relation::GreaterEq, demand - available);
+            }
+        }
+    }
+
+    for (m, machine) in problem.machines.iter().enumerate() {
+        let coefficients: Vec<f64> = variables.iter()
+            .map(|&(_, runner)| if runner == Some(m) { machine.time_per_part() } else { 0.0 })
+            .collect();
+        if coefficients.iter().any(|&coefficient| coefficient > 0.0) {
+            let capacity = problem.capacities.get(&machine.id).copied().unwrap_or(problem.period);
+            lp.add_constraint(coefficients, Relation::LessEq, capacity);
+        }
+    }
+
+    if problem.integer_runs {
+        for v in 0..variables.len() {
+            lp.set_integer(v);
+        }
+    }
+
+    let solution = lp.solve()?;
+
+    let runs: Vec<PlannedRun> = variables.iter()
+        .zip(&solution.values)
+        .filter(|(_, cycles)| **cycles > QUANTITY_TOLERANCE)
+        .map(|(&(r, m), &cycles)| PlannedRun {
+            recipe_id: problem.recipes[r].id,
+            machine_id: m.map(|m| problem.machines[m].id),
+            cycles,
+            machine_time: m.map_or(0.0, |m| cycles * problem.machines[m].time_per_part()),
+        })
+        .collect();
+
+    let mut revenue = 0.0;
+    let mut raw_material_cost = 0.0;
+    let planned_items: Vec<PlannedItem> = items.iter()
+        .enumerate()
+        .map(|(i, item)| {
+            let mut produced = 0.0;
+            let mut consumed = 0.0;
+            for (v, &(r, _)) in variables.iter().enumerate() {
+                let recipe = &problem.recipes[r];
+                produced += solution.values[v] * quantity(&recipe.output, item.id());
+                consumed += solution.values[v] * quantity(&recipe.input, item.id());
+            }
+            let balance = produced - consumed;
+            let bought = if producible[i] { 0.0 } else { (-balance).max(0.0) };
+            let net_output = balance.max(0.0);
+            revenue += value(item) * net_output;
+            raw_material_cost += value(item) * bought;
+            PlannedItem { item_id: item.id(), name: item.name.clone(), produced, consumed, bought, net_output }
+        })
+        .collect();
+
+    let utilisation = problem.machines.iter()
+        .map(|machine| {
+            let used: f64 = runs.iter()
+                .filter(|run| run.machine_id == Some(machine.id))
+                .map(|run| run.machine_time)
+                .sum();
+            let capacity = problem.capacities.get(&machine.id).copied().unwrap_or(problem.period);
+            (machine.id, if capacity > 0.0 { used / capacity } else { 0.0 })
+        })
+        .collect();
+
+    Ok(PlannedProduction {
+        runs,
+        items: planned_items,
+        utilisation,
+        revenue,
+        raw_material_cost,
+        profit: revenue - raw_material_cost,
+    })
+}
+
+fn quantity(list: &[(Arc<Item>, f64)], item_id: Uuid) -> f64 {
+    list.iter().filter(|(item, _)| item.id() == item_id).map(|(_, quantity)| quantity).sum()
+}
+
+fn net_per_cycle(recipe: &Recipe, item_id: Uuid) -> f64 {
+    quantity(&recipe.output, item_id) - quantity(&recipe.input, item_id)
+}
@@ -0,0 +1,170 @@
+//! Quality yield: scrap and rework of the parts a machine processes.
+//!
+//! Every part a machine finishes is good, scrap or sent to rework, with
+//! probabilities given per machine, optionally replaced per recipe, and replaced
+//! again for the first parts after each repair, while a machine is not yet running
+//! true. Good parts go to the output buffers. Scrap is discarded, at a cost per part.
+//! Parts sent to rework go to the machine's rework buffer, which an upstream machine
+//! takes parts from before its regular inputs, closing a rework loop; without a
+//! rework buffer they leave the system to be reworked elsewhere. With recipes a part
+//! is one run of the recipe, and a run sent to rework sends its outputs.
+//!
+//! A part sent back stays on a rework pass through every machine up to the one that
+//! sent it, and its outcomes are counted apart from those of parts on their first
+//! pass. The first pass yield is taken over first passes only, and rework passes
+//! are reported on their own.
+//!
+//! The outcomes of a cycle are drawn from the machine's quality stream when the
+//! cycle is finished, one number per part, and machines with perfect yield draw
+//! nothing, so that their streams are the same as without a quality model.
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// The probabilities that a part is scrapped or sent to rework. The rest are good.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct YieldRates {
+    #[serde(default)]
+    pub scrap: f64,
+    #[serde(default)]
+    pub rework: f64,
+}
+
+impl YieldRates {
+    pub fn new(scrap: f64, rework: f64) -> YieldRates {
+        YieldRates { scrap, rework }
+    }
+
+    /// The probability that a part is good.
+    pub fn good(&self) -> f64 {
+        1.0 - self.scrap - self.rework
+    }
+
+    pub fn is_perfect(&self) -> bool {
+        self.scrap == 0.0 && self.rework == 0.0
+    }
+
+    pub fn validate(&self) -> Result<(), &'static str> {
+        let valid = |rate: f64| (0.0..=1.0).contains(&rate);
+        if !valid(self.scrap) || !valid(self.rework) || self.scrap + self.rework > 1.0 {
+            return Err("Scrap and rework rates must be between 0 and 1 and add up to at most 1.");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RecipeYield {
+    pub recipe_id: Uuid,
+    pub rates: YieldRates,
+}
+
+/// The yield of the first parts a machine processes after each repair.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RepairYield {
+    pub parts: usize,
+    pub rates: YieldRates,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct QualityModel {
+    pub rates: YieldRates,
+    /// Rates replacing the machine's for cycles of the given recipes.
+    pub recipes: Vec<RecipeYield>,
+    pub after_repair: Option<RepairYield>,
+    /// The cost of every scrapped part.
+    pub scrap_cost: f64,
+}
+
+impl QualityModel {
+    pub fn new(rates: YieldRates) -> QualityModel {
+        QualityModel { rates, ..QualityModel::default() }
+    }
+
+    /// Whether every part is good, whatever the recipe or condition of the machine.
+    pub fn is_perfect(&self) -> bool {
+        self.rates.is_perfect()
+            && self.recipes.iter().all(|recipe| recipe.rates.is_perfect())
+            && self.after_repair.is_none_or(|repair| repair.rates.is_perfect())
+    }
+
+    /// The rates for a part of the given recipe, given how many parts the machine has
+    /// processed since it was last repaired, if it has been.
+    pub fn rates(&self, recipe_id: Option<Uuid>, parts_since_repair: Option<usize>) -> YieldRates {
+        if let Some(repair) = self.after_repair.filter(|repair| parts_since_repair.is_some_and(|parts| parts < repair.parts)) {
+            return repair.rates;
+        }
+        recipe_id
+            .and_then(|id| self.recipes.iter().find(|recipe| recipe.recipe_id == id))
+            .map_or(self.rates, |recipe| recipe.rates)
+    }
+
+    pub fn validate(&self) -> Result<(), &'static str> {
+        self.rates.validate()?;
+        for recipe in &self.recipes {
+            recipe.rates.validate()?;
+        }
+        if let Some(repair) = &self.after_repair {
+            repair.rates.validate()?;
+        }
+        if !(self.scrap_cost.is_finite() && self.scrap_cost >= 0.0) {
+            return Err("Scrap cost must be non-negative.");
+        }
+        Ok(())
+    }
+}
+
+/// How many parts turned out good, scrap or sent to rework.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QualityCounts {
+    pub good: usize,
+    pub scrap: usize,
+    pub rework: usize,
+}
+
+impl QualityCounts {
+    /// Counts a number of parts that are all good.
+    pub fn good(parts: usize) -> QualityCounts {
+        QualityCounts { good: parts, scrap: 0, rework: 0 }
+    }
+
+    pub fn total(&self) -> usize {
+        self.good + self.scrap + self.rework
+    }
+
+    /// The fraction of parts that were good the first time, 1 if there were none.
+    pub fn first_pass_yield(&self) -> f64 {
+        if self.total() == 0 {
+            return 1.0;
+        }
+        self.good as f64 / self.total() as f64
+    }
+
+    /// Draws the outcome of one part.
+    pub fn draw<R: Rng + ?Sized>(&mut self, rates: YieldRates, rng: &mut R) {
+        let u: f64 = rng.gen();
+        if u < rates.scrap {
+            self.scrap += 1;
+        } else if u < rates.scrap + rates.rework {
+            self.rework += 1;
+        } else {
+            self.good += 1;
+        }
+    }
+
+    pub fn add(&mut self, other: QualityCounts) {
+        self.good += other.good;
+        self.scrap += other.scrap;
+        self.rework += other.rework;
+    }
+
+    pub fn since(&self, earlier: QualityCounts) -> QualityCounts {
+        QualityCounts {
+            good: self.good - earlier.good,
+            scrap: self.scrap - earlier.scrap,
+            rework: self.rework - earlier.rework,
+        }
+    }
+}
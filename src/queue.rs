@@ -15,9 +15,9 @@ pub struct Queue {
 impl Queue {
     pub fn new(lambda: f64, mu: f64, size: usize) -> Queue {
         Queue {
-            lambda: lambda,
-            mu: mu,
-            size: size,
+            lambda,
+            mu,
+            size,
         }
     }
 
@@ -74,6 +74,33 @@ impl Queue {
     }
 }
 
+// returns Kingman's approximation of the average time an item waits before service
+// at a G/G/1 station, from the arrival and service rates and the coefficients of
+// variation of the interarrival and service times
+pub fn kingman_waiting_time(lambda: f64, mu: f64, arrival_cv: f64, service_cv: f64) -> f64 {
+    let utilisation = lambda / mu;
+    (arrival_cv.powi(2) + service_cv.powi(2)) / 2.0 * utilisation / (1.0 - utilisation) / mu
+}
+
+// returns the coefficient of variation of the time between departures from a G/G/1
+// station, which is the interarrival variation seen by the next station of a line
+pub fn departure_cv(utilisation: f64, arrival_cv: f64, service_cv: f64) -> f64 {
+    let utilisation2 = utilisation.powi(2).min(1.0);
+    (utilisation2 * service_cv.powi(2) + (1.0 - utilisation2) * arrival_cv.powi(2)).sqrt()
+}
+
+// returns the coefficient of variation of the effective processing time of a machine
+// that fails while processing, from the natural processing time and its variation,
+// the availability, and the mean and variation of the repair time
+pub fn effective_cv(processing_time: f64, processing_cv: f64, availability: f64, repair_time: f64, repair_cv: f64) -> f64 {
+    (processing_cv.powi(2) + (1.0 + repair_cv.powi(2)) * availability * (1.0 - availability) * repair_time / processing_time).sqrt()
+}
+
+/// Quantities closer together than this are treated as equal, to absorb rounding
+/// when fractional recipe quantities are moved in and out of buffers.
+pub const QUANTITY_TOLERANCE: f64 = 1e-9;
+
+#[derive(Clone)]
 pub struct Buffer {
     pub id: Uuid,
     pub name: Option<String>,
@@ -81,17 +108,21 @@ pub struct Buffer {
     pub num_items: usize,
     pub throughput: Option<f64>,
     pub items: Vec<(Arc<Item>, f64)>,
+    /// The parts in the buffer that are on a rework pass, each given by the machine
+    /// that sent it to rework, where the pass ends.
+    pub rework_passes: Vec<Uuid>,
 }
 
 impl Buffer {
     pub fn new(capacity: usize, throughput: Option<f64>, name: Option<String>) -> Buffer {
         Buffer {
             id: Uuid::new_v4(),
-            name: name,
-            capacity: capacity,
+            name,
+            capacity,
             num_items: 0,
-            throughput: throughput,
+            throughput,
             items: Vec::new(),
+            rework_passes: Vec::new(),
         }
     }
 
@@ -108,7 +139,7 @@ impl Buffer {
     }
 
     pub fn is_full(&self) -> bool {
-        self.num_items == self.capacity
+        self.room() <= 0.0
     }
 
     pub fn is_empty(&self) -> bool {
@@ -130,4 +161,73 @@ impl Buffer {
     pub fn change_name(&mut self, name: String) {
         self.name = Some(name);
     }
+
+    /// Marks one more part in the buffer as on a rework pass ending at the given
+    /// machine, if there is a part left to mark.
+    pub fn add_rework_pass(&mut self, machine_id: Uuid) -> bool {
+        let parts = self.num_items as f64 + self.total_quantity();
+        if (self.rework_passes.len() as f64) + 1.0 > parts + QUANTITY_TOLERANCE {
+            return false;
+        }
+        self.rework_passes.push(machine_id);
+        true
+    }
+
+    /// Takes the mark of a part on a rework pass, if the buffer holds one. Parts on
+    /// a rework pass are taken first.
+    pub fn take_rework_pass(&mut self) -> Option<Uuid> {
+        self.rework_passes.pop()
+    }
+
+    /// Returns the quantity of the given item held in the buffer.
+    pub fn quantity_of(&self, item_id: Uuid) -> f64 {
+        self.items.iter()
+            .filter(|(item, _)| item.id() == item_id)
+            .map(|(_, quantity)| quantity)
+            .sum()
+    }
+
+    /// Returns the total quantity of all items held in the buffer.
+    pub fn total_quantity(&self) -> f64 {
+        self.items.iter().map(|(_, quantity)| quantity).sum()
+    }
+
+    /// Returns the space left in the buffer. Discrete parts and item quantities
+    /// share the same capacity.
+    pub fn room(&self) -> f64 {
+        self.capacity as f64 - self.num_items as f64 - self.total_quantity()
+    }
+
+    /// Adds a quantity of an item to the buffer if there is room for all of it.
+    pub fn deposit(&mut self, item: Arc<Item>, quantity: f64) -> Result<(), &'static str> {
+        if quantity < 0.0 {
+            return Err("Quantity is negative, use withdraw to remove items.");
+        }
+        if quantity > self.room() + QUANTITY_TOLERANCE {
+            return Err("Buffer does not have room for the quantity.");
+        }
+        match self.items.iter_mut().find(|(existing, _)| existing.id() == item.id()) {
+            Some((_, existing_quantity)) => *existing_quantity += quantity,
+            None => self.items.push((item, quantity)),
+        }
+        Ok(())
+    }
+
+    /// Removes a quantity of an item from the buffer if enough of it is held.
+    pub fn withdraw(&mut self, item_id: Uuid, quantity: f64) -> Result<(), &'static str> {
+        if quantity < 0.0 {
+            return Err("Quantity is negative, use deposit to add items.");
+        }
+        if self.quantity_of(item_id) + QUANTITY_TOLERANCE < quantity {
+            return Err("Buffer does not hold enough of the item.");
+        }
+        let mut remaining = quantity;
+        for (_, held) in self.items.iter_mut().filter(|(item, _)| item.id() == item_id) {
+            let taken = held.min(remaining);
+            *held -= taken;
+            remaining -= taken;
+        }
+        self.items.retain(|(_, held)| *held > QUANTITY_TOLERANCE);
+        Ok(())
+    }
 }
\ No newline at end of file
@@ -0,0 +1,135 @@
+//! Reproducible random number streams for the whole model.
+//!
+//! Every source of randomness in the model, such as a machine's failure process or
+//! an arrival source, draws from its own stream. A stream is identified by the entity
+//! that owns it and the kind of process it drives. Entity ids are random, so the seed
+//! of a stream is derived from the registry seed, the order in which its owner was
+//! registered and the process kind. Models register their entities in a fixed order
+//! (a transfer line registers its machines from first to last), so two scenarios run
+//! with the same registry seed give every entity the same random numbers regardless
+//! of what else differs between them (common random numbers), changing one machine
+//! does not shift the numbers seen by any other, and runs reproduce across processes.
+//!
+//! A registry can also be made antithetic, in which case every stream returns 1 - u
+//! wherever the ordinary stream returns u. Averaging a run with its antithetic twin
+//! reduces variance when the output is monotone in the random numbers.
+
+use std::collections::HashMap;
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use uuid::Uuid;
+
+/// The process a random stream drives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StreamKind {
+    Processing,
+    Failure,
+    Repair,
+    Arrival,
+    Routing,
+    Setup,
+    Quality,
+    Maintenance,
+    Degradation,
+}
+
+/// A single seeded random stream.
+#[derive(Clone, Debug)]
+pub struct RandomStream {
+    rng: StdRng,
+    antithetic: bool,
+}
+
+impl RandomStream {
+    pub fn new(seed: u64, antithetic: bool) -> RandomStream {
+        RandomStream { rng: StdRng::seed_from_u64(seed), antithetic }
+    }
+}
+
+impl RngCore for RandomStream {
+    // Inverting every bit maps a uniform u drawn from the high bits onto 1 - u,
+    // up to the last bit of precision, which is what antithetic variates need.
+    fn next_u32(&mut self) -> u32 {
+        let value = self.rng.next_u32();
+        if self.antithetic { !value } else { value }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let value = self.rng.next_u64();
+        if self.antithetic { !value } else { value }
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.rng.fill_bytes(dest);
+        if self.antithetic {
+            for byte in dest.iter_mut() {
+                *byte = !*byte;
+            }
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+/// Hands out one independent stream per entity and process kind.
+#[derive(Clone, Debug)]
+pub struct RngRegistry {
+    pub seed: u64,
+    pub antithetic: bool,
+    owners: HashMap<Uuid, u64>,
+    streams: HashMap<(Uuid, StreamKind), RandomStream>,
+}
+
+impl RngRegistry {
+    pub fn new(seed: u64) -> RngRegistry {
+        RngRegistry { seed, antithetic: false, owners: HashMap::new(), streams: HashMap::new() }
+    }
+
+    /// Creates a registry whose streams are the antithetic twins of those of
+    /// a registry with the same seed.
+    pub fn new_antithetic(seed: u64) -> RngRegistry {
+        RngRegistry { seed, antithetic: true, owners: HashMap::new(), streams: HashMap::new() }
+    }
+
+    /// Registers an entity, fixing the streams it will be given. Registering an
+    /// entity again has no effect.
+    pub fn register(&mut self, owner: Uuid) -> u64 {
+        let next = self.owners.len() as u64;
+        *self.owners.entry(owner).or_insert(next)
+    }
+
+    /// Returns the stream of the given entity and process, creating it on first use.
+    /// Entities that were never registered are registered on their first draw.
+    pub fn stream(&mut self, owner: Uuid, kind: StreamKind) -> &mut RandomStream {
+        let seed = stream_seed(self.seed, self.register(owner), kind);
+        let antithetic = self.antithetic;
+        self.streams.entry((owner, kind)).or_insert_with(|| RandomStream::new(seed, antithetic))
+    }
+
+    /// Restarts every stream from the beginning, keeping the registration order.
+    pub fn reset(&mut self) {
+        self.streams.clear();
+    }
+}
+
+/// Derives the seed of a stream from the registry seed, the registration index of
+/// its owner and the process kind.
+pub fn stream_seed(seed: u64, owner_index: u64, kind: StreamKind) -> u64 {
+    mix(mix(substream_seed(seed, owner_index as usize)) ^ (kind as u64 + 1))
+}
+
+/// Derives the seed of the given substream of a seed, for example one replication
+/// of many, spreading substreams out so that neighbouring seeds do not overlap.
+pub fn substream_seed(seed: u64, index: usize) -> u64 {
+    mix(seed.wrapping_add((index as u64 + 1).wrapping_mul(0x9E37_79B9_7F4A_7C15)))
+}
+
+// SplitMix64 finaliser
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
@@ -0,0 +1,281 @@
+//! Production rate analysis over a recipe graph.
+//!
+//! Given a target output rate for one item, the recipe graph is walked backwards
+//! from that item: every item that is needed is made by its primary recipe, which
+//! in turn creates demand for the recipe inputs and supplies any other outputs as
+//! byproducts. Byproducts are used to cover demand for the same item before its own
+//! recipe is run, and whatever is left over is reported as surplus. Items no recipe
+//! makes are raw materials. Recipe run rates are converted into machine counts with
+//! the processing time of the machine that runs each recipe.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use uuid::Uuid;
+use crate::machine::{Item, Machine, Recipe};
+use crate::queue::QUANTITY_TOLERANCE;
+
+/// The largest number of passes over the item graph before a cyclic graph is
+/// considered not to converge.
+const MAX_PASSES: usize = 10_000;
+
+/// The run rate of one recipe in a production plan.
+#[derive(Clone, Debug)]
+pub struct RecipeRate {
+    pub recipe_id: Uuid,
+    pub name: String,
+    /// Recipe cycles per time unit.
+    pub cycles_per_time: f64,
+    /// The machine whose processing time sizes the recipe, if any machine runs it.
+    pub machine_id: Option<Uuid>,
+    /// The fractional number of machines needed to run the recipe at this rate.
+    pub machines_required: f64,
+    /// The number of whole machines needed.
+    pub machines: usize,
+}
+
+/// The flow of one item in a production plan, in quantity per time unit.
+#[derive(Clone, Debug)]
+pub struct ItemRate {
+    pub item_id: Uuid,
+    pub name: String,
+    pub produced: f64,
+    pub consumed: f64,
+    /// The part of the target rate made of this item.
+    pub target: f64,
+    /// Whether no recipe makes the item, so it has to be supplied from outside.
+    pub raw: bool,
+    /// The rate at which a raw material has to be supplied.
+    pub supply_required: f64,
+    /// Production in excess of consumption and target, typically from byproducts.
+    pub surplus: f64,
+}
+
+/// The recipe run rates and item flows needed to make an item at a target rate.
+#[derive(Clone, Debug)]
+pub struct ProductionPlan {
+    pub recipes: Vec<RecipeRate>,
+    pub items: Vec<ItemRate>,
+}
+
+impl ProductionPlan {
+    pub fn recipe(&self, recipe_id: Uuid) -> Option<&RecipeRate> {
+        self.recipes.iter().find(|recipe| recipe.recipe_id == recipe_id)
+    }
+
+    pub fn item(&self, item_id: Uuid) -> Option<&ItemRate> {
+        self.items.iter().find(|item| item.item_id == item_id)
+    }
+
+    /// Returns the items made in excess of what the plan needs.
+    pub fn surplus(&self) -> impl Iterator<Item = &ItemRate> {
+        self.items.iter().filter(|item| item.surplus > QUANTITY_TOLERANCE)
+    }
+
+    /// Returns the raw materials and the rates they have to be supplied at.
+    pub fn raw_materials(&self) -> impl Iterator<Item = &ItemRate> {
+        self.items.iter().filter(|item| item.raw && item.supply_required > QUANTITY_TOLERANCE)
+    }
+}
+
+/// Returns every item used or made by the recipes, in order of first appearance.
+pub fn recipe_items(recipes: &[Arc<Recipe>]) -> Vec<Arc<Item>> {
+    let mut items: Vec<Arc<Item>> = Vec::new();
+    for recipe in recipes {
+        for (item, _) in recipe.input.iter().chain(recipe.output.iter()) {
+            if !items.iter().any(|known| known.id() == item.id()) {
+                items.push(item.clone());
+            }
+        }
+    }
+    items
+}
+
+/// Returns the primary recipe of every item: the recipe listing it as first output,
+/// or failing that the first recipe listing it as an output at all.
+pub fn primary_recipes(recipes: &[Arc<Recipe>]) -> HashMap<Uuid, usize> {
+    let mut primary: HashMap<Uuid, usize> = HashMap::new();
+    for (index, recipe) in recipes.iter().enumerate() {
+        if let Some((item, _)) = recipe.output.first() {
+            let current = primary.get(&item.id()).copied();
+            let current_is_main = current.is_some_and(|current| {
+                recipes[current].output.first().is_some_and(|(first, _)| first.id() == item.id())
+            });
+            if !current_is_main {
+                primary.insert(item.id(), index);
+            }
+        }
+    }
+    for (index, recipe) in recipes.iter().enumerate() {
+        for (item, _) in recipe.output.iter().skip(1) {
+            primary.entry(item.id()).or_insert(index);
+        }
+    }
+    primary
+}
+
+/// Computes the recipe run rates, machine counts and item flows needed to make
+/// the target item at the given rate. Machine counts use the processing time of
+/// the first machine that has the recipe assigned.
+pub fn solve(recipes: &[Arc<Recipe>], machines: &[Machine], target: Uuid, rate: f64) -> Result<ProductionPlan, &'static str> {
+    if rate < 0.0 {
+        return Err("Target rate is negative.");
+    }
+    for recipe in recipes {
+        for (_, quantity) in recipe.input.iter().chain(recipe.output.iter()) {
+            if *quantity <= 0.0 {
+                return Err("Recipe quantities must be positive.");
+            }
+        }
+    }
+
+    let items = recipe_items(recipes);
+    if !items.iter().any(|item| item.id() == target) {
+        return Err("Target item does not appear in any recipe.");
+    }
+
+    let primary = primary_recipes(recipes);
+    let order = demand_order(recipes, &primary, &items, target);
+
+    let mut cycles = vec![0.0; recipes.len()];
+    let mut produced: HashMap<Uuid, f64> = HashMap::new();
+    let mut consumed: HashMap<Uuid, f64> = HashMap::new();
+    let mut converged = false;
+    for _ in 0..MAX_PASSES {
+        let mut changed = false;
+        for item_id in &order {
+            let Some(&index) = primary.get(item_id) else {
+                continue;
+            };
+            let demand = consumed.get(item_id).copied().unwrap_or(0.0)
+                + if *item_id == target { rate } else { 0.0 };
+            let shortfall = demand - produced.get(item_id).copied().unwrap_or(0.0);
+            if shortfall <= QUANTITY_TOLERANCE {
+                continue;
+            }
+            let recipe = &recipes[index];
+            let yield_per_cycle: f64 = recipe.output.iter()
+                .filter(|(item, _)| item.id() == *item_id)
+                .map(|(_, quantity)| quantity)
+                .sum();
+            let additional = shortfall / yield_per_cycle;
+            cycles[index] += additional;
+            for (item, quantity) in &recipe.input {
+                *consumed.entry(item.id()).or_insert(0.0) += quantity * additional;
+            }
+            for (item, quantity) in &recipe.output {
+                *produced.entry(item.id()).or_insert(0.0) += quantity * additional;
+            }
+            changed = true;
+        }
+        if !changed {
+            converged = true;
+            break;
+        }
+    }
+    if !converged {
+        return Err("Recipe graph contains a loop that consumes more than it produces.");
+    }
+
+    let recipe_rates = recipes.iter()
+        .zip(&cycles)
+        .filter(|(_, cycles)| **cycles > QUANTITY_TOLERANCE)
+        .map(|(recipe, &cycles_per_time)| {
+            let machine = machines.iter()
+                .find(|machine| machine.recipes.iter().any(|assigned| assigned.id == recipe.id));
+            let machines_required = machine.map_or(0.0, |machine| cycles_per_time * machine.time_per_part());
+            RecipeRate {
+                recipe_id: recipe.id,
+                name: recipe.name.clone(),
+                cycles_per_time,
+                machine_id: machine.map(|machine| machine.id),
+                machines_required,
+                machines: (machines_required - QUANTITY_TOLERANCE).ceil().max(0.0) as usize,
+            }
+        })
+        .collect();
+
+    let item_rates = items.iter()
+        .map(|item| {
+            let id = item.id();
+            let produced = produced.get(&id).copied().unwrap_or(0.0);
+            let consumed = consumed.get(&id).copied().unwrap_or(0.0);
+            let target_rate = if id == target { rate } else { 0.0 };
+            let raw = !primary.contains_key(&id);
+            let balance = produced - consumed - target_rate;
+            ItemRate {
+                item_id: id,
+                name: item.name.clone(),
+                produced,
+                consumed,
+                target: target_rate,
+                raw,
+                supply_required: if raw { (-balance).max(0.0) } else { 0.0 },
+                surplus: balance.max(0.0),
+            }
+        })
+        .collect();
+
+    Ok(ProductionPlan { recipes: recipe_rates, items: item_rates })
+}
+
+/// Orders the items so that an item comes before the inputs and byproducts of its
+/// primary recipe, which lets a single pass settle an acyclic graph. Items on a
+/// cycle are appended in discovery order and settled by repeated passes.
+fn demand_order(recipes: &[Arc<Recipe>], primary: &HashMap<Uuid, usize>, items: &[Arc<Item>], target: Uuid) -> Vec<Uuid> {
+    let successors = |item_id: &Uuid| -> Vec<Uuid> {
+        primary.get(item_id)
+            .map(|&index| {
+                let recipe = &recipes[index];
+                recipe.input.iter()
+                    .chain(recipe.output.iter())
+                    .map(|(item, _)| item.id())
+                    .filter(|id| id != item_id)
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+
+    // Only items reachable from the target take part.
+    let mut reachable: Vec<Uuid> = vec![target];
+    let mut seen: HashSet<Uuid> = HashSet::from([target]);
+    let mut queue: VecDeque<Uuid> = VecDeque::from([target]);
+    while let Some(item_id) = queue.pop_front() {
+        for next in successors(&item_id) {
+            if seen.insert(next) {
+                reachable.push(next);
+                queue.push_back(next);
+            }
+        }
+    }
+
+    let mut in_degree: HashMap<Uuid, usize> = reachable.iter().map(|&id| (id, 0)).collect();
+    for item_id in &reachable {
+        for next in successors(item_id) {
+            *in_degree.get_mut(&next).unwrap() += 1;
+        }
+    }
+    let mut order = Vec::new();
+    let mut ready: VecDeque<Uuid> = reachable.iter().copied().filter(|id| in_degree[id] == 0).collect();
+    while let Some(item_id) = ready.pop_front() {
+        order.push(item_id);
+        for next in successors(&item_id) {
+            let degree = in_degree.get_mut(&next).unwrap();
+            *degree -= 1;
+            if *degree == 0 {
+                ready.push_back(next);
+            }
+        }
+    }
+    for item_id in reachable {
+        if !order.contains(&item_id) {
+            order.push(item_id);
+        }
+    }
+    // Items outside the target's graph are kept last so their flows are reported.
+    for item in items {
+        if !order.contains(&item.id()) {
+            order.push(item.id());
+        }
+    }
+    order
+}
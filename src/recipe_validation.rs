@@ -0,0 +1,281 @@
+//! Structural checks over a set of recipes.
+//!
+//! The recipe graph links every input item of a recipe to each of its outputs.
+//! A cycle in that graph means an item is needed, directly or through other items,
+//! to make itself, which the rate analysis, MRP and cost roll-up cannot settle. Such
+//! loops are only accepted when one of their recipes is marked as recycling, which
+//! breaks the loop. Beyond cycles, every item a recipe consumes has to come from
+//! somewhere, either a recipe or a declared raw material, and every item made should
+//! either be consumed by another recipe or be a declared product.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use uuid::Uuid;
+use crate::machine::Recipe;
+use crate::recipe_analysis::recipe_items;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem found in a recipe set.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Diagnostic {
+    /// The items depend on each other through the recipes, and none of the recipes
+    /// is marked as recycling.
+    Cycle { item_ids: Vec<Uuid>, recipe_ids: Vec<Uuid> },
+    /// The item is consumed, but no recipe makes it and it is not a raw material.
+    UnproducibleItem { item_id: Uuid, recipe_ids: Vec<Uuid> },
+    /// The item is made, but no recipe consumes it and it is not a product.
+    OrphanOutput { item_id: Uuid, recipe_ids: Vec<Uuid> },
+    /// A recipe lists an item with a zero, negative or non-finite quantity.
+    NonPositiveQuantity { recipe_id: Uuid, item_id: Uuid, quantity: f64 },
+    /// A recipe makes nothing.
+    NoOutputs { recipe_id: Uuid },
+    /// A raw material is also made by a recipe other than a recycling one.
+    ProducedRawMaterial { item_id: Uuid, recipe_ids: Vec<Uuid> },
+}
+
+impl Diagnostic {
+    pub fn severity(&self) -> Severity {
+        match self {
+            Diagnostic::Cycle { .. }
+            | Diagnostic::UnproducibleItem { .. }
+            | Diagnostic::NonPositiveQuantity { .. }
+            | Diagnostic::NoOutputs { .. } => Severity::Error,
+            Diagnostic::OrphanOutput { .. } | Diagnostic::ProducedRawMaterial { .. } => Severity::Warning,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            Diagnostic::Cycle { item_ids, .. } => format!("{} items form a cycle with no recycling recipe.", item_ids.len()),
+            Diagnostic::UnproducibleItem { item_id, .. } => format!("Item {} is consumed but neither made nor a raw material.", item_id),
+            Diagnostic::OrphanOutput { item_id, .. } => format!("Item {} is made but neither consumed nor a product.", item_id),
+            Diagnostic::NonPositiveQuantity { recipe_id, item_id, quantity } => {
+                format!("Recipe {} lists item {} with quantity {}.", recipe_id, item_id, quantity)
+            }
+            Diagnostic::NoOutputs { recipe_id } => format!("Recipe {} has no outputs.", recipe_id),
+            Diagnostic::ProducedRawMaterial { item_id, .. } => format!("Raw material {} is also made by a recipe.", item_id),
+        }
+    }
+}
+
+/// Checks the recipes against the declared raw materials, which are bought in, and
+/// products, which leave the system.
+pub fn validate(recipes: &[Arc<Recipe>], raw_materials: &HashSet<Uuid>, products: &HashSet<Uuid>) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    for recipe in recipes {
+        if recipe.output.is_empty() {
+            diagnostics.push(Diagnostic::NoOutputs { recipe_id: recipe.id });
+        }
+        for (item, quantity) in recipe.input.iter().chain(recipe.output.iter()) {
+            if !(quantity.is_finite() && *quantity > 0.0) {
+                diagnostics.push(Diagnostic::NonPositiveQuantity { recipe_id: recipe.id, item_id: item.id(), quantity: *quantity });
+            }
+        }
+    }
+
+    let makers = |item_id: Uuid| -> Vec<Uuid> {
+        recipes.iter()
+            .filter(|recipe| recipe.output.iter().any(|(item, _)| item.id() == item_id))
+            .map(|recipe| recipe.id)
+            .collect()
+    };
+    let users = |item_id: Uuid| -> Vec<Uuid> {
+        recipes.iter()
+            .filter(|recipe| recipe.input.iter().any(|(item, _)| item.id() == item_id))
+            .map(|recipe| recipe.id)
+            .collect()
+    };
+    for item in recipe_items(recipes) {
+        let id = item.id();
+        let made_by = makers(id);
+        let used_by = users(id);
+        if raw_materials.contains(&id) {
+            // Recovering raw material from scrap is what recycling recipes are for.
+            let producers: Vec<Uuid> = recipes.iter()
+                .filter(|recipe| !recipe.recycling && made_by.contains(&recipe.id))
+                .map(|recipe| recipe.id)
+                .collect();
+            if !producers.is_empty() {
+                diagnostics.push(Diagnostic::ProducedRawMaterial { item_id: id, recipe_ids: producers });
+            }
+        } else if made_by.is_empty() {
+            diagnostics.push(Diagnostic::UnproducibleItem { item_id: id, recipe_ids: used_by });
+        } else if used_by.is_empty() && !products.contains(&id) {
+            diagnostics.push(Diagnostic::OrphanOutput { item_id: id, recipe_ids: made_by });
+        }
+    }
+
+    diagnostics.extend(cycles(recipes));
+    diagnostics
+}
+
+/// Returns whether none of the diagnostics is an error.
+pub fn is_valid(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics.iter().all(|diagnostic| diagnostic.severity() != Severity::Error)
+}
+
+/// Finds the strongly connected components of the item graph left once recycling
+/// recipes are removed, and reports every one that contains a cycle.
+fn cycles(recipes: &[Arc<Recipe>]) -> Vec<Diagnostic> {
+    let items: Vec<Uuid> = recipe_items(recipes).iter().map(|item| item.id()).collect();
+    let index: HashMap<Uuid, usize> = items.iter().enumerate().map(|(i, &id)| (id, i)).collect();
+    let mut edges: Vec<Vec<(usize, Uuid)>> = vec![Vec::new(); items.len()];
+    for recipe in recipes.iter().filter(|recipe| !recipe.recycling) {
+        for (input, _) in &recipe.input {
+            for (output, _) in &recipe.output {
+                edges[index[&input.id()]].push((index[&output.id()], recipe.id));
+            }
+        }
+    }
+
+    let mut tarjan = Tarjan {
+        edges: &edges,
+        next: 0,
+        order: vec![None; items.len()],
+        low: vec![0; items.len()],
+        stack: Vec::new(),
+        on_stack: vec![false; items.len()],
+        components: Vec::new(),
+    };
+    for node in 0..items.len() {
+        if tarjan.order[node].is_none() {
+            tarjan.visit(node);
+        }
+    }
+
+    tarjan.components.into_iter()
+        .filter_map(|component| {
+            let members: HashSet<usize> = component.iter().copied().collect();
+            let mut recipe_ids: Vec<Uuid> = Vec::new();
+            for &node in &component {
+                for &(target, recipe_id) in &edges[node] {
+                    if members.contains(&target) && !recipe_ids.contains(&recipe_id) {
+                        recipe_ids.push(recipe_id);
+                    }
+                }
+            }
+            // A single item is only a cycle if a recipe consumes and makes it.
+            if recipe_ids.is_empty() {
+                return None;
+            }
+            Some(Diagnostic::Cycle { item_ids: component.iter().map(|&node| items[node]).collect(), recipe_ids })
+        })
+        .collect()
+}
+
+struct Tarjan<'a> {
+    edges: &'a [Vec<(usize, Uuid)>],
+    next: usize,
+    order: Vec<Option<usize>>,
+    low: Vec<usize>,
+    stack: Vec<usize>,
+    on_stack: Vec<bool>,
+    components: Vec<Vec<usize>>,
+}
+
+impl Tarjan<'_> {
+    fn visit(&mut self, node: usize) {
+        self.order[node] = Some(self.next);
+        self.low[node] = self.next;
+        self.next += 1;
+        self.stack.push(node);
+        self.on_stack[node] = true;
+
+        for &(target, _) in &self.edges[node] {
+            match self.order[target] {
+                None => {
+                    self.visit(target);
+                    self.low[node] = self.low[node].min(self.low[target]);
+                }
+                Some(order) if self.on_stack[target] => self.low[node] = self.low[node].min(order),
+                Some(_) => {}
+            }
+        }
+
+        if Some(self.low[node]) == self.order[node] {
+            let mut component = Vec::new();
+            while let Some(member) = self.stack.pop() {
+                self.on_stack[member] = false;
+                component.push(member);
+                if member == node {
+                    break;
+                }
+            }
+            self.components.push(component);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Item;
+
+    fn item(name: &str) -> Arc<Item> {
+        Arc::new(Item::new(name.to_string(), 1.0, None))
+    }
+
+    fn recipe(name: &str, input: &[&Arc<Item>], output: &[&Arc<Item>]) -> Recipe {
+        let list = |items: &[&Arc<Item>]| items.iter().map(|&item| (item.clone(), 1.0)).collect();
+        Recipe::new(name.to_string(), list(input), list(output))
+    }
+
+    #[test]
+    fn accepts_chain_from_raw_material_to_product() {
+        let (ore, bar, bracket) = (item("ore"), item("bar"), item("bracket"));
+        let recipes = vec![Arc::new(recipe("smelt", &[&ore], &[&bar])), Arc::new(recipe("form", &[&bar], &[&bracket]))];
+        let diagnostics = validate(&recipes, &HashSet::from([ore.id()]), &HashSet::from([bracket.id()]));
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+    }
+
+    #[test]
+    fn reports_unproducible_and_orphan_items() {
+        let (ore, bar) = (item("ore"), item("bar"));
+        let smelt = Arc::new(recipe("smelt", &[&ore], &[&bar]));
+        let diagnostics = validate(std::slice::from_ref(&smelt), &HashSet::new(), &HashSet::new());
+        assert_eq!(diagnostics, vec![
+            Diagnostic::UnproducibleItem { item_id: ore.id(), recipe_ids: vec![smelt.id] },
+            Diagnostic::OrphanOutput { item_id: bar.id(), recipe_ids: vec![smelt.id] },
+        ]);
+        assert!(!is_valid(&diagnostics));
+        assert_eq!(diagnostics[1].severity(), Severity::Warning);
+    }
+
+    #[test]
+    fn reports_recipes_without_outputs_or_with_bad_quantities() {
+        let ore = item("ore");
+        let mut discard = recipe("discard", &[&ore], &[]);
+        discard.input[0].1 = 0.0;
+        let discard = Arc::new(discard);
+        let diagnostics = validate(std::slice::from_ref(&discard), &HashSet::from([ore.id()]), &HashSet::new());
+        assert_eq!(diagnostics, vec![
+            Diagnostic::NoOutputs { recipe_id: discard.id },
+            Diagnostic::NonPositiveQuantity { recipe_id: discard.id, item_id: ore.id(), quantity: 0.0 },
+        ]);
+    }
+
+    #[test]
+    fn accepts_cycles_only_through_recycling_recipes() {
+        let (metal, scrap) = (item("metal"), item("scrap"));
+        let cut = Arc::new(recipe("cut", &[&metal], &[&scrap]));
+        let mut remelt = recipe("remelt", &[&scrap], &[&metal]);
+        let raw = HashSet::from([metal.id()]);
+
+        let diagnostics = validate(&[cut.clone(), Arc::new(remelt.clone())], &raw, &HashSet::new());
+        let cycle = diagnostics.iter().find(|diagnostic| matches!(diagnostic, Diagnostic::Cycle { .. })).unwrap();
+        let Diagnostic::Cycle { item_ids, recipe_ids } = cycle else { unreachable!() };
+        assert_eq!(item_ids.len(), 2);
+        assert_eq!(recipe_ids.len(), 2);
+        assert!(!is_valid(&diagnostics));
+
+        remelt.recycling = true;
+        let diagnostics = validate(&[cut, Arc::new(remelt)], &raw, &HashSet::new());
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+    }
+}
@@ -0,0 +1,688 @@
+//! The model registry: a single owner for every entity of a factory model.
+//!
+//! Machines, buffers, items, recipes and transfer line layouts all live in one
+//! `ModelRegistry`, so an edit can check the entities it refers to and a removal can
+//! be refused, naming what still depends on the entity being removed. Edits and
+//! queries are `ModelCommand`s. They can be executed directly, or sent to the
+//! registry running as an actor task through a `ModelHandle`, which waits for the
+//! response to each command. The actor keeps the model's history (see `history`):
+//! it logs every edit that succeeds with when it was made, and takes commands to
+//! undo the last edit, replay the log to an earlier position and compare two
+//! positions.
+//!
+//! Commands that create an entity carry the id it is to be given, so that a
+//! sequence of commands builds the same model every time it is executed.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use uuid::Uuid;
+use crate::batch::BatchMode;
+use crate::blocking::BlockingPolicy;
+use crate::distribution::Distribution;
+use crate::failure::{FailureClock, FailureMode};
+use crate::quality::QualityModel;
+use crate::maintenance::{MaintenancePolicy, MaintenanceTrigger};
+use crate::degradation::{Degradation, DegradationStage, DegradationTransition};
+use crate::setup::{DispatchRule, SetupBasis, SetupMatrix};
+use crate::machine::{Item, Machine, MachineState, Recipe, RecipeRegistry};
+use crate::markov::MarkovChain;
+use crate::create_machine_chain;
+use crate::history::{EventLog, LoggedEvent, ModelDiff, ModelHistory};
+use crate::queue::Buffer;
+use crate::transfer_lines::TransferLine;
+
+/// Which side of a machine a buffer is connected to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BufferRole {
+    Input,
+    Output,
+    /// The buffer the machine sends parts to be reworked to, replacing any other.
+    Rework,
+    /// A buffer of parts sent back to the machine for rework.
+    ReworkInput,
+}
+
+/// The layout of a transfer line over registry machines and buffers: buffer i sits
+/// between machines i and i + 1.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LineLayout {
+    pub id: Uuid,
+    pub name: Option<String>,
+    pub machine_ids: Vec<Uuid>,
+    pub buffer_ids: Vec<Uuid>,
+}
+
+/// An edit to or query of the model.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ModelCommand {
+    CreateItem { id: Uuid, name: String, size: f64, cost: Option<f64> },
+    RemoveItem { item_id: Uuid },
+    CreateRecipe { id: Uuid, name: String },
+    AddRecipeInput { recipe_id: Uuid, item_id: Uuid, quantity: f64 },
+    AddRecipeOutput { recipe_id: Uuid, item_id: Uuid, quantity: f64 },
+    SetRecycling { recipe_id: Uuid, recycling: bool },
+    RemoveRecipe { recipe_id: Uuid },
+    CreateMachine { id: Uuid, processing_time: f64, output: Option<String> },
+    SetProcessingTime { machine_id: Uuid, processing_time: f64 },
+    /// Gives a machine a random processing time.
+    SetProcessingDistribution { machine_id: Uuid, distribution: Distribution },
+    /// Gives a machine the Idle, Working and Broken failure chain with the given per
+    /// step failure and repair probabilities.
+    SetFailureRates { machine_id: Uuid, failure_probability: f64, repair_probability: f64 },
+    /// Adds a failure mode with its own time to failure and time to repair distributions.
+    AddFailureMode {
+        machine_id: Uuid,
+        id: Uuid,
+        name: String,
+        clock: FailureClock,
+        time_to_failure: Distribution,
+        time_to_repair: Distribution,
+    },
+    RemoveFailureMode { machine_id: Uuid, name: String },
+    /// Replaces a machine's changeover times. The matrix refers to recipes or items
+    /// of the catalogue, according to its basis.
+    SetSetupMatrix { machine_id: Uuid, matrix: SetupMatrix },
+    SetDispatchRule { machine_id: Uuid, rule: DispatchRule },
+    /// Makes a machine process batches of parts, or single parts again with None.
+    SetBatchMode { machine_id: Uuid, batch: Option<BatchMode> },
+    SetBlockingPolicy { machine_id: Uuid, policy: BlockingPolicy },
+    /// Replaces a machine's yield. Recipe yields refer to recipes of the catalogue.
+    SetQuality { machine_id: Uuid, quality: QualityModel },
+    /// Gives a machine a preventive maintenance policy, or runs it to failure with None.
+    SetMaintenancePolicy { machine_id: Uuid, policy: Option<MaintenancePolicy> },
+    /// Makes a machine wear through degradation stages, the last being its failed
+    /// stage, starting from the first.
+    SetDegradation {
+        machine_id: Uuid,
+        stages: Vec<DegradationStage>,
+        transitions: Vec<DegradationTransition>,
+        time_to_repair: Distribution,
+    },
+    RemoveDegradation { machine_id: Uuid },
+    RemoveMachine { machine_id: Uuid },
+    AssignRecipe { machine_id: Uuid, recipe_id: Uuid },
+    UnassignRecipe { machine_id: Uuid, recipe_id: Uuid },
+    CreateBuffer { id: Uuid, capacity: usize, throughput: Option<f64>, name: Option<String> },
+    RemoveBuffer { buffer_id: Uuid },
+    /// Adds (or, with a negative quantity, withdraws) a quantity of an item held in a buffer.
+    ChangeBufferItem { buffer_id: Uuid, item_id: Uuid, quantity: f64 },
+    ConnectBuffer { machine_id: Uuid, buffer_id: Uuid, role: BufferRole },
+    DisconnectBuffer { machine_id: Uuid, buffer_id: Uuid },
+    /// Lays out a transfer line, connecting its machines through its buffers.
+    CreateLine { id: Uuid, name: Option<String>, machine_ids: Vec<Uuid>, buffer_ids: Vec<Uuid> },
+    /// Removes a line layout. The connections it made are kept.
+    RemoveLine { line_id: Uuid },
+    GetItems,
+    GetRecipes,
+    GetMachines,
+    GetLines,
+    /// Builds a transfer line with its own copies of the layout's machines and buffers.
+    BuildLine { line_id: Uuid },
+    /// Undoes the most recent edit of the history that has not been undone yet.
+    Undo,
+    /// Rebuilds the model as it was after the first `position` events of the history.
+    Replay { position: usize },
+    /// Compares the model after the first `from` events of the history with the
+    /// model after the first `to` events.
+    Diff { from: usize, to: usize },
+    GetHistory,
+}
+
+impl ModelCommand {
+    /// Whether the command changes the model.
+    pub fn is_edit(&self) -> bool {
+        !matches!(
+            self,
+            ModelCommand::GetItems
+                | ModelCommand::GetRecipes
+                | ModelCommand::GetMachines
+                | ModelCommand::GetLines
+                | ModelCommand::BuildLine { .. }
+                | ModelCommand::Undo
+                | ModelCommand::Replay { .. }
+                | ModelCommand::Diff { .. }
+                | ModelCommand::GetHistory
+        )
+    }
+
+    /// Whether the command works on the history of the model rather than the model.
+    pub fn is_history(&self) -> bool {
+        matches!(
+            self,
+            ModelCommand::Undo | ModelCommand::Replay { .. } | ModelCommand::Diff { .. } | ModelCommand::GetHistory
+        )
+    }
+}
+
+pub enum ModelResponse {
+    Done,
+    Created(Uuid),
+    Items(Vec<Arc<Item>>),
+    Recipes(Vec<Arc<Recipe>>),
+    Machines(Vec<Machine>),
+    Lines(Vec<LineLayout>),
+    Line(TransferLine),
+    /// The sequence number of the edit undone.
+    Undone(u64),
+    Model(ModelRegistry),
+    Diff(ModelDiff),
+    History(Vec<LoggedEvent>),
+}
+
+/// Something that refers to an entity the registry was asked to remove.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Referrer {
+    /// What holds the reference, such as "setup matrix of machine".
+    pub kind: &'static str,
+    /// The id of the machine, buffer or recipe holding it.
+    pub id: Uuid,
+}
+
+/// Why the registry refused a command.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ModelError {
+    Failed(&'static str),
+    /// A removal refused while other entities still refer to the entity.
+    InUse { message: &'static str, referrers: Vec<Referrer> },
+}
+
+impl From<&'static str> for ModelError {
+    fn from(message: &'static str) -> ModelError {
+        ModelError::Failed(message)
+    }
+}
+
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModelError::Failed(message) => write!(f, "{}", message),
+            ModelError::InUse { message, referrers } => {
+                let referrers: Vec<String> = referrers.iter().map(|referrer| format!("{} {}", referrer.kind, referrer.id)).collect();
+                write!(f, "{} It is referred to by the {}.", message, referrers.join(", the "))
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ModelRegistry {
+    catalogue: RecipeRegistry,
+    machines: Vec<Machine>,
+    buffers: Vec<Arc<Mutex<Buffer>>>,
+    lines: Vec<LineLayout>,
+}
+
+impl ModelRegistry {
+    pub fn new() -> ModelRegistry {
+        ModelRegistry::default()
+    }
+
+    /// Assembles a registry from entities that already refer to each other.
+    pub fn from_parts(catalogue: RecipeRegistry, machines: Vec<Machine>, buffers: Vec<Arc<Mutex<Buffer>>>, lines: Vec<LineLayout>) -> ModelRegistry {
+        ModelRegistry { catalogue, machines, buffers, lines }
+    }
+
+    pub fn catalogue(&self) -> &RecipeRegistry {
+        &self.catalogue
+    }
+
+    pub fn machines(&self) -> &[Machine] {
+        &self.machines
+    }
+
+    pub fn machine(&self, machine_id: Uuid) -> Option<&Machine> {
+        self.machines.iter().find(|machine| machine.id == machine_id)
+    }
+
+    pub fn buffers(&self) -> &[Arc<Mutex<Buffer>>] {
+        &self.buffers
+    }
+
+    pub fn buffer(&self, buffer_id: Uuid) -> Option<Arc<Mutex<Buffer>>> {
+        self.buffers.iter().find(|buffer| buffer.lock().unwrap().id == buffer_id).cloned()
+    }
+
+    pub fn lines(&self) -> &[LineLayout] {
+        &self.lines
+    }
+
+    /// Executes a command, leaving the model unchanged if it fails. Items and recipes
+    /// are only removed once nothing refers to them.
+    pub fn execute(&mut self, command: ModelCommand) -> Result<ModelResponse, ModelError> {
+        let (message, referrers) = match &command {
+            ModelCommand::RemoveItem { item_id } => ("Item is still in use.", self.item_referrers(*item_id)),
+            ModelCommand::RemoveRecipe { recipe_id } => ("Recipe is still in use.", self.recipe_referrers(*recipe_id)),
+            _ => ("", Vec::new()),
+        };
+        if !referrers.is_empty() {
+            return Err(ModelError::InUse { message, referrers });
+        }
+        Ok(self.apply(command)?)
+    }
+
+    /// The buffers holding an item and the recipes and setup matrices naming it.
+    fn item_referrers(&self, item_id: Uuid) -> Vec<Referrer> {
+        let buffers = self.buffers.iter()
+            .map(|buffer| buffer.lock().unwrap())
+            .filter(|buffer| buffer.quantity_of(item_id) > 0.0)
+            .map(|buffer| Referrer { kind: "contents of buffer", id: buffer.id });
+        let recipes = self.catalogue.recipes().iter()
+            .filter(|recipe| recipe.input.iter().chain(recipe.output.iter()).any(|(item, _)| item.id() == item_id))
+            .map(|recipe| Referrer { kind: "recipe", id: recipe.id });
+        let setups = self.machines.iter()
+            .filter(|machine| setup_refers_to(&machine.setup_matrix, SetupBasis::Item, item_id))
+            .map(|machine| Referrer { kind: "setup matrix of machine", id: machine.id });
+        buffers.chain(recipes).chain(setups).collect()
+    }
+
+    /// The machines holding a recipe or naming it in their setup matrix or yields.
+    fn recipe_referrers(&self, recipe_id: Uuid) -> Vec<Referrer> {
+        let mut referrers = Vec::new();
+        for machine in &self.machines {
+            if machine.recipes.iter().any(|recipe| recipe.id == recipe_id) {
+                referrers.push(Referrer { kind: "recipes of machine", id: machine.id });
+            }
+            if setup_refers_to(&machine.setup_matrix, SetupBasis::Recipe, recipe_id) {
+                referrers.push(Referrer { kind: "setup matrix of machine", id: machine.id });
+            }
+            if machine.quality.recipes.iter().any(|recipe| recipe.recipe_id == recipe_id) {
+                referrers.push(Referrer { kind: "recipe yields of machine", id: machine.id });
+            }
+        }
+        referrers
+    }
+
+    fn apply(&mut self, command: ModelCommand) -> Result<ModelResponse, &'static str> {
+        match command {
+            ModelCommand::CreateItem { id, name, size, cost } => {
+                self.catalogue.add_item(Item::with_id(id, name, size, cost)).map(ModelResponse::Created)
+            }
+            ModelCommand::RemoveItem { item_id } => self.catalogue.remove_item(item_id).map(|_| ModelResponse::Done),
+            ModelCommand::CreateRecipe { id, name } => {
+                let mut recipe = Recipe::new(name, vec![], vec![]);
+                recipe.id = id;
+                self.catalogue.add_recipe(recipe).map(ModelResponse::Created)
+            }
+            ModelCommand::AddRecipeInput { recipe_id, item_id, quantity } => {
+                self.catalogue.add_input(recipe_id, item_id, quantity)?;
+                self.refresh_recipe(recipe_id);
+                Ok(ModelResponse::Done)
+            }
+            ModelCommand::AddRecipeOutput { recipe_id, item_id, quantity } => {
+                self.catalogue.add_output(recipe_id, item_id, quantity)?;
+                self.refresh_recipe(recipe_id);
+                Ok(ModelResponse::Done)
+            }
+            ModelCommand::SetRecycling { recipe_id, recycling } => {
+                self.catalogue.set_recycling(recipe_id, recycling)?;
+                self.refresh_recipe(recipe_id);
+                Ok(ModelResponse::Done)
+            }
+            ModelCommand::RemoveRecipe { recipe_id } => self.catalogue.remove_recipe(recipe_id).map(|_| ModelResponse::Done),
+            ModelCommand::CreateMachine { id, processing_time, output } => {
+                if self.machine(id).is_some() {
+                    return Err("Machine already exists.");
+                }
+                if !(processing_time.is_finite() && processing_time > 0.0) {
+                    return Err("Processing time must be positive.");
+                }
+                let mut machine = Machine::new(MarkovChain::new(), processing_time, output);
+                machine.id = id;
+                self.machines.push(machine);
+                Ok(ModelResponse::Created(id))
+            }
+            ModelCommand::SetProcessingTime { machine_id, processing_time } => {
+                if !(processing_time.is_finite() && processing_time > 0.0) {
+                    return Err("Processing time must be positive.");
+                }
+                let machine = self.machine_mut(machine_id)?;
+                machine.processing_time = processing_time.into();
+                machine.cycle_time = processing_time;
+                Ok(ModelResponse::Done)
+            }
+            ModelCommand::SetProcessingDistribution { machine_id, distribution } => {
+                distribution.validate()?;
+                let machine = self.machine_mut(machine_id)?;
+                machine.cycle_time = distribution.mean();
+                machine.processing_time = distribution;
+                Ok(ModelResponse::Done)
+            }
+            ModelCommand::SetFailureRates { machine_id, failure_probability, repair_probability } => {
+                if ![failure_probability, repair_probability].iter().all(|probability| (0.0..=1.0).contains(probability)) {
+                    return Err("Probabilities must be between 0 and 1.");
+                }
+                let machine = self.machine_mut(machine_id)?;
+                let chain = &machine.markov_chain;
+                if ["Idle", "Working", "Broken"].iter().any(|state| chain.find_state(state).is_none()) {
+                    machine.markov_chain = create_machine_chain!(chain);
+                }
+                machine.set_failure_probability(failure_probability)?;
+                machine.set_repair_probability(repair_probability)?;
+                Ok(ModelResponse::Done)
+            }
+            ModelCommand::AddFailureMode { machine_id, id, name, clock, time_to_failure, time_to_repair } => {
+                let mut mode = FailureMode::new(name, clock, time_to_failure, time_to_repair);
+                mode.id = id;
+                self.machine_mut(machine_id)?.failure_model.add_mode(mode)?;
+                Ok(ModelResponse::Created(id))
+            }
+            ModelCommand::RemoveFailureMode { machine_id, name } => {
+                self.machine_mut(machine_id)?.failure_model.remove_mode(&name).map(|_| ModelResponse::Done)
+            }
+            ModelCommand::SetSetupMatrix { machine_id, matrix } => {
+                matrix.validate()?;
+                let known = |id: &Uuid| match matrix.basis {
+                    SetupBasis::Recipe => self.catalogue.recipe(*id).is_some(),
+                    SetupBasis::Item => self.catalogue.item(*id).is_some(),
+                };
+                if !matrix.times.iter().all(|setup| known(&setup.from) && known(&setup.to)) {
+                    return Err("Setup time refers to an unknown recipe or item.");
+                }
+                self.machine_mut(machine_id)?.setup_matrix = matrix;
+                Ok(ModelResponse::Done)
+            }
+            ModelCommand::SetDispatchRule { machine_id, rule } => {
+                self.machine_mut(machine_id)?.dispatch = rule;
+                Ok(ModelResponse::Done)
+            }
+            ModelCommand::SetBatchMode { machine_id, batch } => {
+                if let Some(batch) = &batch {
+                    batch.validate()?;
+                }
+                self.machine_mut(machine_id)?.batch = batch;
+                Ok(ModelResponse::Done)
+            }
+            ModelCommand::SetBlockingPolicy { machine_id, policy } => {
+                self.machine_mut(machine_id)?.blocking = policy;
+                Ok(ModelResponse::Done)
+            }
+            ModelCommand::SetQuality { machine_id, quality } => {
+                quality.validate()?;
+                if !quality.recipes.iter().all(|recipe| self.catalogue.recipe(recipe.recipe_id).is_some()) {
+                    return Err("Recipe yield refers to an unknown recipe.");
+                }
+                self.machine_mut(machine_id)?.quality = quality;
+                Ok(ModelResponse::Done)
+            }
+            ModelCommand::SetMaintenancePolicy { machine_id, policy } => {
+                if let Some(policy) = &policy {
+                    policy.validate()?;
+                }
+                let machine = self.machine_mut(machine_id)?;
+                if !condition_known(policy.as_ref(), machine.degradation.as_ref()) {
+                    return Err("Maintenance condition refers to an unknown degradation stage.");
+                }
+                machine.maintenance.policy = policy;
+                Ok(ModelResponse::Done)
+            }
+            ModelCommand::SetDegradation { machine_id, stages, transitions, time_to_repair } => {
+                let mut degradation = Degradation::new(stages, time_to_repair);
+                for transition in &transitions {
+                    degradation.set_transition(&transition.from, &transition.to, transition.probability)?;
+                }
+                degradation.validate()?;
+                self.replace_degradation(machine_id, Some(degradation))
+            }
+            ModelCommand::RemoveDegradation { machine_id } => self.replace_degradation(machine_id, None),
+            ModelCommand::RemoveMachine { machine_id } => {
+                let index = self.machines.iter().position(|machine| machine.id == machine_id).ok_or("Machine not found.")?;
+                if self.lines.iter().any(|line| line.machine_ids.contains(&machine_id)) {
+                    return Err("Machine is part of a transfer line.");
+                }
+                self.machines.remove(index);
+                Ok(ModelResponse::Done)
+            }
+            ModelCommand::AssignRecipe { machine_id, recipe_id } => {
+                let recipe = self.catalogue.recipe(recipe_id).ok_or("Recipe not found.")?;
+                self.machine_mut(machine_id)?.add_recipe(recipe);
+                Ok(ModelResponse::Done)
+            }
+            ModelCommand::UnassignRecipe { machine_id, recipe_id } => {
+                self.machine_mut(machine_id)?.remove_recipe(recipe_id).map(|_| ModelResponse::Done)
+            }
+            ModelCommand::CreateBuffer { id, capacity, throughput, name } => {
+                if self.buffer(id).is_some() {
+                    return Err("Buffer already exists.");
+                }
+                let mut buffer = Buffer::new(capacity, throughput, name);
+                buffer.id = id;
+                self.buffers.push(Arc::new(Mutex::new(buffer)));
+                Ok(ModelResponse::Created(id))
+            }
+            ModelCommand::RemoveBuffer { buffer_id } => {
+                let index = self.buffers.iter()
+                    .position(|buffer| buffer.lock().unwrap().id == buffer_id)
+                    .ok_or("Buffer not found.")?;
+                if self.machines.iter().any(|machine| uses_buffer(machine, &self.buffers[index])) {
+                    return Err("Buffer is connected to a machine.");
+                }
+                if self.lines.iter().any(|line| line.buffer_ids.contains(&buffer_id)) {
+                    return Err("Buffer is part of a transfer line.");
+                }
+                self.buffers.remove(index);
+                Ok(ModelResponse::Done)
+            }
+            ModelCommand::ChangeBufferItem { buffer_id, item_id, quantity } => {
+                let item = self.catalogue.item(item_id).ok_or("Item not found.")?;
+                let buffer = self.buffer(buffer_id).ok_or("Buffer not found.")?;
+                let mut buffer = buffer.lock().unwrap();
+                if quantity >= 0.0 {
+                    buffer.deposit(item, quantity)?;
+                } else {
+                    buffer.withdraw(item_id, -quantity)?;
+                }
+                Ok(ModelResponse::Done)
+            }
+            ModelCommand::ConnectBuffer { machine_id, buffer_id, role } => {
+                let buffer = self.buffer(buffer_id).ok_or("Buffer not found.")?;
+                let machine = self.machine_mut(machine_id)?;
+                // A machine may take back the parts it sends to rework itself.
+                let connected = machine.input_buffer.iter()
+                    .chain(&machine.output_buffer)
+                    .chain(machine.rework_buffer.iter().filter(|_| role != BufferRole::ReworkInput))
+                    .chain(machine.rework_inputs.iter().filter(|_| role != BufferRole::Rework))
+                    .any(|link| Arc::ptr_eq(link, &buffer));
+                if connected {
+                    return Err("Buffer is already connected to the machine.");
+                }
+                match role {
+                    BufferRole::Input => machine.add_input_buffer(buffer),
+                    BufferRole::Output => machine.add_output_buffer(buffer),
+                    BufferRole::Rework => machine.rework_buffer = Some(buffer),
+                    BufferRole::ReworkInput => machine.rework_inputs.push(buffer),
+                }
+                Ok(ModelResponse::Done)
+            }
+            ModelCommand::DisconnectBuffer { machine_id, buffer_id } => {
+                let buffer = self.buffer(buffer_id).ok_or("Buffer not found.")?;
+                if self.lines.iter().any(|line| line.machine_ids.contains(&machine_id) && line.buffer_ids.contains(&buffer_id)) {
+                    return Err("Connection is part of a transfer line.");
+                }
+                let machine = self.machine_mut(machine_id)?;
+                if !uses_buffer(machine, &buffer) {
+                    return Err("Buffer is not connected to the machine.");
+                }
+                machine.input_buffer.retain(|link| !Arc::ptr_eq(link, &buffer));
+                machine.output_buffer.retain(|link| !Arc::ptr_eq(link, &buffer));
+                machine.rework_inputs.retain(|link| !Arc::ptr_eq(link, &buffer));
+                if machine.rework_buffer.as_ref().is_some_and(|link| Arc::ptr_eq(link, &buffer)) {
+                    machine.rework_buffer = None;
+                }
+                Ok(ModelResponse::Done)
+            }
+            ModelCommand::CreateLine { id, name, machine_ids, buffer_ids } => {
+                if self.lines.iter().any(|line| line.id == id) {
+                    return Err("Transfer line already exists.");
+                }
+                if machine_ids.is_empty() || buffer_ids.len() + 1 != machine_ids.len() {
+                    return Err("A transfer line needs one buffer fewer than machines.");
+                }
+                let mut buffers = Vec::with_capacity(buffer_ids.len());
+                for buffer_id in &buffer_ids {
+                    buffers.push(self.buffer(*buffer_id).ok_or("Buffer not found.")?);
+                }
+                for machine_id in &machine_ids {
+                    self.machine(*machine_id).ok_or("Machine not found.")?;
+                }
+                for (i, buffer) in buffers.into_iter().enumerate() {
+                    let upstream = self.machine_mut(machine_ids[i])?;
+                    if !upstream.output_buffer.iter().any(|link| Arc::ptr_eq(link, &buffer)) {
+                        upstream.add_output_buffer(buffer.clone());
+                    }
+                    let downstream = self.machine_mut(machine_ids[i + 1])?;
+                    if !downstream.input_buffer.iter().any(|link| Arc::ptr_eq(link, &buffer)) {
+                        downstream.add_input_buffer(buffer);
+                    }
+                }
+                self.lines.push(LineLayout { id, name, machine_ids, buffer_ids });
+                Ok(ModelResponse::Created(id))
+            }
+            ModelCommand::RemoveLine { line_id } => {
+                let index = self.lines.iter().position(|line| line.id == line_id).ok_or("Transfer line not found.")?;
+                self.lines.remove(index);
+                Ok(ModelResponse::Done)
+            }
+            ModelCommand::GetItems => Ok(ModelResponse::Items(self.catalogue.items().to_vec())),
+            ModelCommand::GetRecipes => Ok(ModelResponse::Recipes(self.catalogue.recipes().to_vec())),
+            ModelCommand::GetMachines => Ok(ModelResponse::Machines(self.machines.clone())),
+            ModelCommand::GetLines => Ok(ModelResponse::Lines(self.lines.clone())),
+            ModelCommand::BuildLine { line_id } => self.build_line(line_id).map(ModelResponse::Line),
+            ModelCommand::Undo | ModelCommand::Replay { .. } | ModelCommand::Diff { .. } | ModelCommand::GetHistory => {
+                Err("Command needs the model history.")
+            }
+        }
+    }
+
+    /// Builds a transfer line from a layout. The line gets copies of the machines,
+    /// connected only to copies of the layout's buffers and of their rework buffers,
+    /// so simulating it leaves the registry untouched.
+    pub fn build_line(&self, line_id: Uuid) -> Result<TransferLine, &'static str> {
+        let layout = self.lines.iter().find(|line| line.id == line_id).ok_or("Transfer line not found.")?;
+        let mut line = TransferLine::new(vec![], vec![], vec![]);
+        line.id = layout.id;
+        // Copies of the rework buffers, made once so that the machines sending parts
+        // to one and taking parts from it share the copy.
+        let mut originals: Vec<Arc<Mutex<Buffer>>> = Vec::new();
+        let mut copies: Vec<Arc<Mutex<Buffer>>> = Vec::new();
+        let mut copy_rework = |link: &Arc<Mutex<Buffer>>| -> Arc<Mutex<Buffer>> {
+            if let Some(index) = originals.iter().position(|original| Arc::ptr_eq(original, link)) {
+                return copies[index].clone();
+            }
+            let copy = Arc::new(Mutex::new(link.lock().unwrap().clone()));
+            originals.push(link.clone());
+            copies.push(copy.clone());
+            copy
+        };
+        for machine_id in &layout.machine_ids {
+            let mut machine = self.machine(*machine_id).ok_or("Machine not found.")?.clone();
+            machine.input_buffer.clear();
+            machine.output_buffer.clear();
+            machine.rework_buffer = machine.rework_buffer.as_ref().map(&mut copy_rework);
+            machine.rework_inputs = machine.rework_inputs.iter().map(&mut copy_rework).collect();
+            line.push_machine(machine);
+        }
+        for buffer_id in &layout.buffer_ids {
+            let buffer = self.buffer(*buffer_id).ok_or("Buffer not found.")?;
+            let copy = buffer.lock().unwrap().clone();
+            line.push_buffer(copy);
+        }
+        Ok(line)
+    }
+
+    /// Gives every machine the recipe is assigned to its current version.
+    fn refresh_recipe(&mut self, recipe_id: Uuid) {
+        if let Some(recipe) = self.catalogue.recipe(recipe_id) {
+            for machine in self.machines.iter_mut().filter(|machine| machine.recipes.iter().any(|assigned| assigned.id == recipe_id)) {
+                machine.add_recipe(recipe.clone());
+            }
+        }
+    }
+
+    fn machine_mut(&mut self, machine_id: Uuid) -> Result<&mut Machine, &'static str> {
+        self.machines.iter_mut().find(|machine| machine.id == machine_id).ok_or("Machine not found.")
+    }
+
+    /// Replaces a machine's degradation, unless the machine is being repaired from
+    /// its failed stage or its maintenance policy watches a stage the new one lacks.
+    fn replace_degradation(&mut self, machine_id: Uuid, degradation: Option<Degradation>) -> Result<ModelResponse, &'static str> {
+        let machine = self.machine_mut(machine_id)?;
+        if machine.state == MachineState::Down && machine.degradation.as_ref().is_some_and(|old| old.repair_remaining.is_some()) {
+            return Err("Machine is being repaired from its degradation.");
+        }
+        if !condition_known(machine.maintenance.policy.as_ref(), degradation.as_ref()) {
+            return Err("Maintenance condition refers to an unknown degradation stage.");
+        }
+        machine.degradation = degradation;
+        Ok(ModelResponse::Done)
+    }
+
+    /// Runs the registry as an actor task, recording its history from here on into
+    /// the given empty log, and returns a handle for sending it commands.
+    pub fn spawn(self, log: EventLog) -> Result<ModelHandle, &'static str> {
+        Ok(ModelHandle::spawn(ModelHistory::with_base(self, log)?))
+    }
+}
+
+/// Whether a condition based policy names a stage of the machine's degradation.
+fn condition_known(policy: Option<&MaintenancePolicy>, degradation: Option<&Degradation>) -> bool {
+    match policy.map(|policy| &policy.trigger) {
+        Some(MaintenanceTrigger::Condition { stage }) => {
+            degradation.is_some_and(|degradation| degradation.stage_index(stage).is_some_and(|index| index < degradation.failed()))
+        }
+        _ => true,
+    }
+}
+
+/// Whether a setup matrix on the given basis lists a changeover from or to the product.
+fn setup_refers_to(matrix: &SetupMatrix, basis: SetupBasis, id: Uuid) -> bool {
+    matrix.basis == basis && matrix.times.iter().any(|setup| setup.from == id || setup.to == id)
+}
+
+fn uses_buffer(machine: &Machine, buffer: &Arc<Mutex<Buffer>>) -> bool {
+    machine.input_buffer.iter()
+        .chain(&machine.output_buffer)
+        .chain(&machine.rework_buffer)
+        .chain(&machine.rework_inputs)
+        .any(|link| Arc::ptr_eq(link, buffer))
+}
+
+/// A command, the author it is logged under, and where to send its response.
+type ModelRequest = (ModelCommand, Option<String>, oneshot::Sender<Result<ModelResponse, ModelError>>);
+
+async fn model_registry_actor(mut rx: mpsc::Receiver<ModelRequest>, mut history: ModelHistory) {
+    while let Some((command, author, reply)) = rx.recv().await {
+        // A caller that stopped waiting for its response is not an error for the registry.
+        let _ = reply.send(history.execute(command, author));
+    }
+}
+
+/// A handle to a running model registry. Handles can be cloned and shared between tasks.
+#[derive(Clone)]
+pub struct ModelHandle {
+    tx: mpsc::Sender<ModelRequest>,
+}
+
+impl ModelHandle {
+    /// Runs a model history as the registry actor task and returns a handle for
+    /// sending it commands.
+    pub fn spawn(history: ModelHistory) -> ModelHandle {
+        let (tx, rx) = mpsc::channel(32);
+        tokio::spawn(model_registry_actor(rx, history));
+        ModelHandle { tx }
+    }
+
+    /// Sends a command to the registry and waits for its response.
+    pub async fn send(&self, command: ModelCommand) -> Result<ModelResponse, ModelError> {
+        self.send_as(command, None).await
+    }
+
+    /// Sends a command to the registry, logging it under the given author if it is
+    /// an edit, and waits for its response.
+    pub async fn send_as(&self, command: ModelCommand, author: Option<String>) -> Result<ModelResponse, ModelError> {
+        let (reply, response) = oneshot::channel();
+        self.tx.send((command, author, reply)).await.map_err(|_| "Model registry has stopped.")?;
+        response.await.map_err(|_| "Model registry has stopped.")?
+    }
+}
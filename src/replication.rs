@@ -0,0 +1,257 @@
+//! Independent replications of a transfer line simulation.
+//!
+//! Every replication runs on its own deep copy of the line with its own random
+//! stream registry, so replications are independent and a given seed always
+//! reproduces the same result. Antithetic pairs can be used for variance reduction.
+//! Each replication steps its line on its own `SimClock`, as fast as possible or
+//! paced in scaled real time.
+//! Replications are spread over the available CPU cores with scoped threads, and
+//! every KPI is summarised as a mean with a Student-t confidence interval across
+//! replications. Cycle time is summarised as mean WIP over mean throughput, so a
+//! replication in which no part left the line does not make it infinite.
+
+use std::thread;
+use crate::clock::{ClockMode, SimClock};
+use crate::machine::MachineState;
+use crate::output_analysis::{self, Estimate};
+use crate::quality::QualityCounts;
+use crate::random::{self, RngRegistry};
+use crate::simulation::SimulationRun;
+use crate::transfer_lines::TransferLine;
+
+/// The number of replications a precision driven run starts with.
+const INITIAL_REPLICATIONS: usize = 10;
+
+/// Settings for a set of replications.
+#[derive(Clone, Debug)]
+pub struct ReplicationConfig {
+    /// The number of time steps simulated in every replication, including warm-up.
+    pub horizon: usize,
+    /// The number of time steps discarded at the start of every replication. When
+    /// None, the warm-up of every replication is detected with MSER-5.
+    pub warmup: Option<usize>,
+    /// The seed of the first replication; replication i uses substream i of this seed.
+    pub seed: u64,
+    /// Whether odd replications are the antithetic twins of the even ones before them.
+    pub antithetic: bool,
+    pub confidence: f64,
+    /// The number of worker threads, defaulting to the number of CPU cores.
+    pub threads: usize,
+    /// How every replication's clock relates simulated time steps to wall time.
+    pub clock: ClockMode,
+}
+
+impl ReplicationConfig {
+    pub fn new(horizon: usize, seed: u64) -> ReplicationConfig {
+        ReplicationConfig {
+            horizon,
+            warmup: None,
+            seed,
+            antithetic: false,
+            confidence: 0.95,
+            threads: thread::available_parallelism().map_or(1, |threads| threads.get()),
+            clock: ClockMode::AsFastAsPossible,
+        }
+    }
+}
+
+/// The KPIs of a single replication, in a fixed order shared by all replications.
+#[derive(Clone, Debug)]
+pub struct Replication {
+    pub seed: u64,
+    /// The number of time steps discarded as warm-up.
+    pub warmup: usize,
+    pub kpis: Vec<(String, f64)>,
+}
+
+/// A KPI summarised across replications.
+#[derive(Clone, Debug)]
+pub struct KpiSummary {
+    pub name: String,
+    pub estimate: Estimate,
+}
+
+/// The result of a set of replications.
+#[derive(Clone, Debug)]
+pub struct ReplicationReport {
+    pub replications: Vec<Replication>,
+    pub kpis: Vec<KpiSummary>,
+}
+
+impl ReplicationReport {
+    /// Returns the summary of the named KPI.
+    pub fn kpi(&self, name: &str) -> Option<&Estimate> {
+        self.kpis.iter().find(|kpi| kpi.name == name).map(|kpi| &kpi.estimate)
+    }
+
+    /// Returns the largest relative half width among the named KPIs, or an error if
+    /// any of them is not a KPI of the report.
+    pub fn worst_precision(&self, names: &[&str]) -> Result<f64, &'static str> {
+        names.iter()
+            .map(|name| self.kpi(name).map(Estimate::relative_precision).ok_or("Unknown KPI name."))
+            .try_fold(0.0, |worst: f64, precision| Ok(worst.max(precision?)))
+    }
+}
+
+/// Runs the given number of independent replications of the line in parallel.
+/// The line itself is not modified.
+pub fn replicate(line: &TransferLine, config: &ReplicationConfig, replications: usize) -> ReplicationReport {
+    let results = run_replications(line, config, 0..replications);
+    summarise(results, config)
+}
+
+/// Runs replications until the confidence interval of every named KPI is within the
+/// given relative precision (half width over mean), or the replication limit is hit.
+/// The number of additional replications is estimated from the current half widths,
+/// which shrink with the square root of the number of replications. Fails if a name
+/// is not one of the KPIs of the first replications.
+pub fn replicate_to_precision(
+    line: &TransferLine,
+    config: &ReplicationConfig,
+    kpis: &[&str],
+    relative_precision: f64,
+    max_replications: usize,
+) -> Result<ReplicationReport, &'static str> {
+    let mut results = run_replications(line, config, 0..INITIAL_REPLICATIONS.min(max_replications));
+    loop {
+        let report = summarise(results.clone(), config);
+        let precision = report.worst_precision(kpis)?;
+        let done = results.len();
+        if precision <= relative_precision || done >= max_replications {
+            return Ok(report);
+        }
+        let required = if precision.is_finite() {
+            (done as f64 * (precision / relative_precision).powi(2)).ceil() as usize
+        } else {
+            done * 2
+        };
+        let mut next = required.max(done + config.threads.max(1)).min(max_replications);
+        if config.antithetic && next % 2 == 1 && next < max_replications {
+            next += 1;
+        }
+        results.extend(run_replications(line, config, done..next));
+    }
+}
+
+fn run_replications(line: &TransferLine, config: &ReplicationConfig, indices: std::ops::Range<usize>) -> Vec<Replication> {
+    let indices: Vec<usize> = indices.collect();
+    let threads = config.threads.max(1).min(indices.len().max(1));
+    let chunk_size = indices.len().div_ceil(threads).max(1);
+    thread::scope(|scope| {
+        let workers: Vec<_> = indices.chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk.iter().map(|&index| run_replication(line, config, index)).collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        workers.into_iter()
+            .flat_map(|worker| worker.join().expect("replication worker panicked"))
+            .collect()
+    })
+}
+
+fn run_replication(line: &TransferLine, config: &ReplicationConfig, index: usize) -> Replication {
+    let (seed, mut streams) = if config.antithetic {
+        let seed = random::substream_seed(config.seed, index / 2);
+        let streams = if index % 2 == 1 { RngRegistry::new_antithetic(seed) } else { RngRegistry::new(seed) };
+        (seed, streams)
+    } else {
+        let seed = random::substream_seed(config.seed, index);
+        (seed, RngRegistry::new(seed))
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .expect("replication clock runtime could not be built");
+    let mut clock = SimClock::new(config.clock);
+    let run = runtime.block_on(line.clone().run_on(config.horizon, &mut streams, &mut clock));
+    let warmup = config.warmup.unwrap_or_else(|| {
+        let wip: Vec<f64> = run.wip.iter().map(|&items| items as f64).collect();
+        output_analysis::mser(&wip, output_analysis::MSER_BATCH)
+    });
+    Replication { seed, warmup, kpis: kpis(&run, warmup.min(run.len())) }
+}
+
+/// Returns the KPIs of a run after discarding the warm-up period.
+pub fn kpis(run: &SimulationRun, warmup: usize) -> Vec<(String, f64)> {
+    let steps = (run.len() - warmup).max(1) as f64;
+    let throughput = run.output[warmup..].iter().sum::<usize>() as f64 / steps;
+    let wip = run.wip[warmup..].iter().sum::<usize>() as f64 / steps;
+    let cycle_time = if throughput > 0.0 { wip / throughput } else { f64::INFINITY };
+    let quality: Vec<QualityCounts> = (0..run.num_machines).map(|machine| run.quality_since(machine, warmup)).collect();
+    let rework: Vec<QualityCounts> = (0..run.num_machines).map(|machine| run.rework_since(machine, warmup)).collect();
+    // The rolled yield of the line: the chance that a part passes every machine good
+    // the first time. Parts on a rework pass are left out, as they already failed once.
+    let first_pass_yield: f64 = quality.iter().map(QualityCounts::first_pass_yield).product();
+    let rework_passes: usize = rework.iter().map(QualityCounts::total).sum();
+    let scrap_cost: f64 = quality.iter().zip(&rework).zip(&run.scrap_costs)
+        .map(|((counts, rework), cost)| (counts.scrap + rework.scrap) as f64 * cost)
+        .sum();
+    let maintenance_cost: f64 = run.maintenance_costs.iter().skip(warmup).sum();
+
+    let mut kpis = vec![
+        ("throughput".to_string(), throughput),
+        ("wip".to_string(), wip),
+        ("cycle_time".to_string(), cycle_time),
+        ("first_pass_yield".to_string(), first_pass_yield),
+        ("rework_passes".to_string(), rework_passes as f64 / steps),
+        ("scrap_cost".to_string(), scrap_cost / steps),
+        ("maintenance_cost".to_string(), maintenance_cost / steps),
+    ];
+    for (name, in_use) in run.resources_in_use(warmup) {
+        kpis.push((format!("{}_in_use", name), in_use));
+    }
+    let states = [
+        ("working", MachineState::Working),
+        ("starved", MachineState::Starved),
+        ("blocked", MachineState::Blocked),
+        ("down", MachineState::Down),
+        ("setup", MachineState::Setup),
+        ("maintenance", MachineState::Maintenance),
+    ];
+    for machine in 0..run.num_machines {
+        for (name, state) in states {
+            let count = run.states[warmup..].iter().filter(|step| step[machine] == state).count();
+            kpis.push((format!("machine_{}_{}", machine, name), count as f64 / steps));
+        }
+        kpis.push((format!("machine_{}_first_pass_yield", machine), quality[machine].first_pass_yield()));
+        kpis.push((format!("machine_{}_rework_passes", machine), rework[machine].total() as f64 / steps));
+    }
+    for buffer in 0..run.num_buffers {
+        let level = run.buffer_levels[warmup..].iter().map(|levels| levels[buffer]).sum::<usize>() as f64;
+        kpis.push((format!("buffer_{}_level", buffer), level / steps));
+    }
+    kpis
+}
+
+/// Summarises every KPI across replications. Antithetic twins are not independent
+/// of each other, so with antithetic replications the interval is built from the
+/// average of each complete pair instead.
+fn summarise(replications: Vec<Replication>, config: &ReplicationConfig) -> ReplicationReport {
+    let names: Vec<String> = replications.first()
+        .map(|replication| replication.kpis.iter().map(|(name, _)| name.clone()).collect())
+        .unwrap_or_default();
+    let values = |i: usize| -> Vec<f64> {
+        let values: Vec<f64> = replications.iter().map(|replication| replication.kpis[i].1).collect();
+        if config.antithetic {
+            values.chunks_exact(2).map(|pair| (pair[0] + pair[1]) / 2.0).collect()
+        } else {
+            values
+        }
+    };
+    let position = |kpi: &str| names.iter().position(|name| name == kpi);
+    let kpis = names.iter()
+        .enumerate()
+        .map(|(i, name)| {
+            let estimate = match (name.as_str(), position("wip"), position("throughput")) {
+                ("cycle_time", Some(wip), Some(throughput)) => {
+                    output_analysis::ratio_estimate(&values(wip), &values(throughput), config.confidence)
+                }
+                _ => output_analysis::estimate(&values(i), config.confidence),
+            };
+            KpiSummary { name: name.clone(), estimate }
+        })
+        .collect();
+    ReplicationReport { replications, kpis }
+}
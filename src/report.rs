@@ -0,0 +1,122 @@
+//! Tabular reports for the command line, written as aligned text, CSV or JSON.
+//!
+//! A report is a list of tables. Cells are JSON values, so that numbers stay
+//! numbers in JSON output; text output shows fractional numbers to four decimal
+//! places and values that are not finite as `-`.
+
+use clap::ValueEnum;
+use serde_json::{json, Map, Value};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Csv,
+}
+
+#[derive(Clone, Debug)]
+pub struct Table {
+    pub title: String,
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+impl Table {
+    pub fn new(title: &str, columns: &[&str]) -> Table {
+        Table {
+            title: title.to_string(),
+            columns: columns.iter().map(|column| column.to_string()).collect(),
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, row: Vec<Value>) {
+        debug_assert_eq!(row.len(), self.columns.len(), "row does not match the table's columns");
+        self.rows.push(row);
+    }
+}
+
+/// Renders the tables in the given format. CSV output separates tables with an
+/// empty line.
+pub fn render(tables: &[Table], format: OutputFormat) -> String {
+    match format {
+        OutputFormat::Table => tables.iter().map(render_text).collect::<Vec<_>>().join("\n"),
+        OutputFormat::Csv => tables.iter().map(render_csv).collect::<Vec<_>>().join("\n"),
+        OutputFormat::Json => {
+            let tables: Vec<Value> = tables.iter()
+                .map(|table| {
+                    let rows: Vec<Value> = table.rows.iter()
+                        .map(|row| {
+                            let fields: Map<String, Value> = table.columns.iter().cloned().zip(row.iter().cloned()).collect();
+                            Value::Object(fields)
+                        })
+                        .collect();
+                    json!({ "table": table.title, "rows": rows })
+                })
+                .collect();
+            format!("{:#}\n", Value::Array(tables))
+        }
+    }
+}
+
+fn render_text(table: &Table) -> String {
+    let cells: Vec<Vec<String>> = table.rows.iter().map(|row| row.iter().map(text).collect()).collect();
+    let widths: Vec<usize> = table.columns.iter()
+        .enumerate()
+        .map(|(column, name)| cells.iter().map(|row| row[column].len()).fold(name.len(), usize::max))
+        .collect();
+    let mut output = format!("{}\n", table.title);
+    let header: Vec<String> = table.columns.iter().zip(&widths).map(|(name, &width)| format!("{:<width$}", name)).collect();
+    output += header.join("  ").trim_end();
+    output.push('\n');
+    output += &widths.iter().map(|&width| "-".repeat(width)).collect::<Vec<_>>().join("  ");
+    output.push('\n');
+    for (row, values) in cells.iter().zip(&table.rows) {
+        let line: Vec<String> = row.iter()
+            .zip(values)
+            .zip(&widths)
+            .map(|((cell, value), &width)| match value {
+                Value::Number(_) => format!("{:>width$}", cell),
+                _ => format!("{:<width$}", cell),
+            })
+            .collect();
+        output += line.join("  ").trim_end();
+        output.push('\n');
+    }
+    output
+}
+
+fn render_csv(table: &Table) -> String {
+    let mut output = String::new();
+    let quote = |cell: String| {
+        if cell.contains([',', '"', '\n']) {
+            format!("\"{}\"", cell.replace('"', "\"\""))
+        } else {
+            cell
+        }
+    };
+    output += &table.columns.iter().cloned().map(quote).collect::<Vec<_>>().join(",");
+    output.push('\n');
+    for row in &table.rows {
+        let cells: Vec<String> = row.iter()
+            .map(|value| match value {
+                Value::Number(number) => number.to_string(),
+                Value::Null => String::new(),
+                _ => text(value),
+            })
+            .map(quote)
+            .collect();
+        output += &cells.join(",");
+        output.push('\n');
+    }
+    output
+}
+
+fn text(value: &Value) -> String {
+    match value {
+        Value::Null => "-".to_string(),
+        Value::String(text) => text.clone(),
+        Value::Number(number) if number.is_f64() => format!("{:.4}", number.as_f64().unwrap()),
+        other => other.to_string(),
+    }
+}
@@ -0,0 +1,88 @@
+//! Sequence dependent setups and the dispatching rules that avoid them.
+//!
+//! A machine running several recipes may need a changeover when it switches from
+//! one product to another. Products are either the recipes themselves or the first
+//! item each recipe outputs, so that recipes making the same item share setups.
+//! The setup matrix gives the setup time from one product to the next; pairs it
+//! does not list take its default, and switching to the same product needs no setup
+//! unless the matrix lists it.
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::distribution::Distribution;
+use crate::machine::Recipe;
+
+/// What the rows and columns of a setup matrix stand for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SetupBasis {
+    #[default]
+    Recipe,
+    /// The first output item of a recipe.
+    Item,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SetupTime {
+    pub from: Uuid,
+    pub to: Uuid,
+    pub time: Distribution,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SetupMatrix {
+    pub basis: SetupBasis,
+    pub times: Vec<SetupTime>,
+    /// The setup time between different products the matrix does not list.
+    pub default: Option<Distribution>,
+}
+
+impl SetupMatrix {
+    pub fn new(basis: SetupBasis) -> SetupMatrix {
+        SetupMatrix { basis, times: Vec::new(), default: None }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.times.is_empty() && self.default.is_none()
+    }
+
+    /// Sets the setup time from one product to another, replacing any time given before.
+    pub fn set(&mut self, from: Uuid, to: Uuid, time: Distribution) {
+        self.times.retain(|setup| (setup.from, setup.to) != (from, to));
+        self.times.push(SetupTime { from, to, time });
+    }
+
+    /// The setup time from one product to another, if a changeover is needed.
+    pub fn time(&self, from: Uuid, to: Uuid) -> Option<&Distribution> {
+        match self.times.iter().find(|setup| setup.from == from && setup.to == to) {
+            Some(setup) => Some(&setup.time),
+            None if from != to => self.default.as_ref(),
+            None => None,
+        }
+    }
+
+    /// The product a recipe makes, as far as setups are concerned.
+    pub fn product(&self, recipe: &Recipe) -> Uuid {
+        match self.basis {
+            SetupBasis::Recipe => recipe.id,
+            SetupBasis::Item => recipe.output.first().map_or(recipe.id, |(item, _)| item.id()),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), &'static str> {
+        self.times.iter().map(|setup| &setup.time).chain(&self.default).try_for_each(Distribution::validate)
+    }
+}
+
+/// How a machine with several recipes chooses the recipe of its next cycle among
+/// those it can start.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DispatchRule {
+    /// Takes turns, starting after the recipe run last, so that no recipe is starved.
+    #[default]
+    RoundRobin,
+    /// Keeps to the product run last while it can be started, to save setups,
+    /// taking turns after at most `max_run` cycles of the same product.
+    Batching { max_run: Option<usize> },
+}
@@ -0,0 +1,142 @@
+//! Records what happened during a simulation run of a transfer line, one entry per
+//! time step, so that runs can be analysed after the fact.
+
+use crate::machine::MachineState;
+use crate::maintenance::ResourceNeed;
+use crate::quality::QualityCounts;
+
+/// The trace of a single simulation run.
+pub struct SimulationRun {
+    /// The state of every machine at every time step, indexed as states[step][machine].
+    pub states: Vec<Vec<MachineState>>,
+    /// The number of items in every buffer at the end of every time step.
+    pub buffer_levels: Vec<Vec<usize>>,
+    /// The number of finished parts leaving the line in every time step.
+    pub output: Vec<usize>,
+    /// The number of items in the line at the end of every time step.
+    pub wip: Vec<usize>,
+    /// The parts every machine finished in every time step, by outcome, indexed as
+    /// quality[step][machine].
+    pub quality: Vec<Vec<QualityCounts>>,
+    /// The parts every machine finished on a rework pass in every time step, by
+    /// outcome, indexed as rework[step][machine]. These are not in `quality`.
+    pub rework: Vec<Vec<QualityCounts>>,
+    /// The cost of a part scrapped by each machine.
+    pub scrap_costs: Vec<f64>,
+    /// The cost of maintenance and failures across the line in every time step.
+    pub maintenance_costs: Vec<f64>,
+    /// The resources a maintenance of each machine ties up.
+    pub maintenance_resources: Vec<Vec<ResourceNeed>>,
+    pub num_machines: usize,
+    pub num_buffers: usize,
+}
+
+impl SimulationRun {
+    pub fn new(num_machines: usize, num_buffers: usize) -> SimulationRun {
+        SimulationRun {
+            states: Vec::new(),
+            buffer_levels: Vec::new(),
+            output: Vec::new(),
+            wip: Vec::new(),
+            quality: Vec::new(),
+            rework: Vec::new(),
+            scrap_costs: vec![0.0; num_machines],
+            maintenance_costs: Vec::new(),
+            maintenance_resources: vec![Vec::new(); num_machines],
+            num_machines,
+            num_buffers,
+        }
+    }
+
+    /// Appends one time step to the run.
+    pub fn record(&mut self, states: Vec<MachineState>, buffer_levels: Vec<usize>, output: usize, wip: usize) {
+        self.states.push(states);
+        self.buffer_levels.push(buffer_levels);
+        self.output.push(output);
+        self.wip.push(wip);
+    }
+
+    /// Appends the outcomes of the parts finished in the last recorded time step, on
+    /// their first pass and on a rework pass.
+    pub fn record_quality(&mut self, quality: Vec<QualityCounts>, rework: Vec<QualityCounts>) {
+        self.quality.push(quality);
+        self.rework.push(rework);
+    }
+
+    /// Appends the maintenance and failure cost of the last recorded time step.
+    pub fn record_maintenance_cost(&mut self, cost: f64) {
+        self.maintenance_costs.push(cost);
+    }
+
+    /// Returns the mean quantity of each resource tied up by maintenance per time
+    /// step from the given time step on, by resource name in order of first use.
+    pub fn resources_in_use(&self, from: usize) -> Vec<(String, f64)> {
+        let mut in_use: Vec<(String, f64)> = Vec::new();
+        for (machine, resources) in self.maintenance_resources.iter().enumerate() {
+            let steps = self.states.iter().skip(from).filter(|step| step[machine] == MachineState::Maintenance).count();
+            for resource in resources {
+                let quantity = resource.quantity * steps as f64;
+                match in_use.iter_mut().find(|(name, _)| *name == resource.name) {
+                    Some((_, total)) => *total += quantity,
+                    None => in_use.push((resource.name.clone(), quantity)),
+                }
+            }
+        }
+        let steps = self.len().saturating_sub(from).max(1) as f64;
+        in_use.into_iter().map(|(name, total)| (name, total / steps)).collect()
+    }
+
+    /// Returns the outcomes of the parts the given machine finished from the given
+    /// time step on.
+    pub fn quality_since(&self, machine_index: usize, from: usize) -> QualityCounts {
+        let mut counts = QualityCounts::default();
+        for step in self.quality.iter().skip(from) {
+            counts.add(step[machine_index]);
+        }
+        counts
+    }
+
+    /// Returns the outcomes of the parts the given machine finished on a rework pass
+    /// from the given time step on.
+    pub fn rework_since(&self, machine_index: usize, from: usize) -> QualityCounts {
+        let mut counts = QualityCounts::default();
+        for step in self.rework.iter().skip(from) {
+            counts.add(step[machine_index]);
+        }
+        counts
+    }
+
+    /// Returns the number of time steps in the run.
+    pub fn len(&self) -> usize {
+        self.output.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.output.is_empty()
+    }
+
+    /// Returns the average number of parts leaving the line per time step.
+    pub fn throughput(&self) -> f64 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        self.output.iter().sum::<usize>() as f64 / self.len() as f64
+    }
+
+    /// Returns the average number of items in the line.
+    pub fn average_wip(&self) -> f64 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        self.wip.iter().sum::<usize>() as f64 / self.len() as f64
+    }
+
+    /// Returns the fraction of time steps the given machine spent in the given state.
+    pub fn state_fraction(&self, machine_index: usize, state: MachineState) -> f64 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let count = self.states.iter().filter(|step| step[machine_index] == state).count();
+        count as f64 / self.len() as f64
+    }
+}
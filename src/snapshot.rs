@@ -0,0 +1,549 @@
+//! Saving and loading whole factory models.
+//!
+//! A snapshot flattens the model graph into records that refer to each other by id:
+//! every item is stored once, and recipes, buffer contents and machines list the
+//! ids of the items, recipes and buffers they use. Loading checks that every id
+//! refers to a record in the snapshot and then rebuilds the graph, so that every
+//! recipe and buffer using an item shares one `Arc<Item>` and machines on either
+//! side of a buffer share one `Arc<Mutex<Buffer>>`, exactly as when it was saved.
+//!
+//! Snapshots are written as JSON, for reading and editing by hand, or in a compact
+//! binary form: the bytes `MSYS`, the schema version as a little endian u32, and the
+//! snapshot encoded with bincode. Both carry the schema version, which is checked
+//! before the rest of the snapshot is read. Models had no saved form before version
+//! 1, so there is nothing to migrate: a snapshot of any other version, or without
+//! one, is rejected.
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::batch::BatchMode;
+use crate::blocking::BlockingPolicy;
+use crate::distribution::Distribution;
+use crate::failure::FailureModel;
+use crate::quality::{QualityCounts, QualityModel};
+use crate::maintenance::MaintenanceSchedule;
+use crate::degradation::Degradation;
+use crate::setup::{DispatchRule, SetupMatrix};
+use crate::machine::{Item, Machine, MachineState, Recipe, RecipeRegistry};
+use crate::markov::MarkovChain;
+use crate::queue::Buffer;
+use crate::registry::{LineLayout, ModelRegistry};
+use crate::transfer_lines::TransferLine;
+
+/// The version of the snapshot format written by this build.
+pub const SCHEMA_VERSION: u32 = 1;
+const BINARY_MAGIC: &[u8; 4] = b"MSYS";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SnapshotFormat {
+    Json,
+    Binary,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SnapshotError {
+    Io(String),
+    Decode(String),
+    SchemaVersion { found: u32, expected: u32 },
+    DuplicateId(Uuid),
+    /// A record refers to an id that no record of the given kind has.
+    MissingReference { kind: &'static str, id: Uuid },
+    InvalidMarkovChain(Uuid),
+    /// The snapshot does not hold the single transfer line that was asked for.
+    NotALine,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ItemRecord {
+    pub id: Uuid,
+    pub name: String,
+    pub size: f64,
+    pub cost: Option<f64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RecipeRecord {
+    pub id: Uuid,
+    pub name: String,
+    pub input: Vec<(Uuid, f64)>,
+    pub output: Vec<(Uuid, f64)>,
+    pub recycling: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BufferRecord {
+    pub id: Uuid,
+    pub name: Option<String>,
+    pub capacity: usize,
+    pub num_items: usize,
+    pub throughput: Option<f64>,
+    pub items: Vec<(Uuid, f64)>,
+    pub rework_passes: Vec<Uuid>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MachineRecord {
+    pub id: Uuid,
+    pub markov_chain: MarkovChain,
+    pub failure_model: FailureModel,
+    pub processing_time: Distribution,
+    pub output_name: Option<String>,
+    pub hourly_rate: f64,
+    pub input_buffers: Vec<Uuid>,
+    pub output_buffers: Vec<Uuid>,
+    pub recipes: Vec<Uuid>,
+    pub state: MachineState,
+    pub num_items: usize,
+    pub progress: f64,
+    pub cycle_time: f64,
+    pub completed: usize,
+    pub current_recipe: Option<usize>,
+    pub last_recipe: Option<usize>,
+    pub recipe_cycles: Vec<(Uuid, usize)>,
+    pub setup_matrix: SetupMatrix,
+    pub dispatch: DispatchRule,
+    pub setup_remaining: f64,
+    pub setups: usize,
+    pub run_length: usize,
+    pub batch: Option<BatchMode>,
+    pub batch_wait: f64,
+    pub blocking: BlockingPolicy,
+    pub quality: QualityModel,
+    pub rework_buffer: Option<Uuid>,
+    pub rework_inputs: Vec<Uuid>,
+    pub cycle_outcome: Option<QualityCounts>,
+    pub cycle_rework: Vec<Uuid>,
+    pub cycle_rework_outcome: QualityCounts,
+    pub quality_counts: QualityCounts,
+    pub rework_counts: QualityCounts,
+    pub parts_since_repair: Option<usize>,
+    pub maintenance: MaintenanceSchedule,
+    pub degradation: Option<Degradation>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LineRecord {
+    pub id: Uuid,
+    pub name: Option<String>,
+    pub machine_ids: Vec<Uuid>,
+    pub buffer_ids: Vec<Uuid>,
+    /// The time step a simulated line had reached.
+    pub time_step: Option<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ModelSnapshot {
+    pub schema_version: u32,
+    pub items: Vec<ItemRecord>,
+    pub recipes: Vec<RecipeRecord>,
+    /// Recipes in the catalogue rather than only assigned to machines.
+    pub catalogue: Vec<Uuid>,
+    pub buffers: Vec<BufferRecord>,
+    pub machines: Vec<MachineRecord>,
+    pub lines: Vec<LineRecord>,
+}
+
+impl ModelSnapshot {
+    /// Takes a snapshot of everything in the registry.
+    pub fn of_registry(model: &ModelRegistry) -> ModelSnapshot {
+        let mut snapshot = ModelSnapshot::empty();
+        for item in model.catalogue().items() {
+            snapshot.add_item(item);
+        }
+        for recipe in model.catalogue().recipes() {
+            snapshot.add_recipe(recipe);
+            snapshot.catalogue.push(recipe.id);
+        }
+        for buffer in model.buffers() {
+            snapshot.add_buffer(buffer);
+        }
+        for machine in model.machines() {
+            snapshot.add_machine(machine);
+        }
+        snapshot.lines = model.lines().iter()
+            .map(|line| LineRecord {
+                id: line.id,
+                name: line.name.clone(),
+                machine_ids: line.machine_ids.clone(),
+                buffer_ids: line.buffer_ids.clone(),
+                time_step: None,
+            })
+            .collect();
+        snapshot
+    }
+
+    /// Takes a snapshot of a transfer line, including its simulation state.
+    pub fn of_line(line: &TransferLine) -> ModelSnapshot {
+        let mut snapshot = ModelSnapshot::empty();
+        for buffer in &line.buffers {
+            snapshot.add_buffer(buffer);
+        }
+        for machine in &line.machines {
+            snapshot.add_machine(machine);
+        }
+        snapshot.lines.push(LineRecord {
+            id: line.id,
+            name: None,
+            machine_ids: line.machines.iter().map(|machine| machine.id).collect(),
+            buffer_ids: line.buffers.iter().map(|buffer| buffer.lock().unwrap().id).collect(),
+            time_step: Some(line.time_step),
+        });
+        snapshot
+    }
+
+    /// Rebuilds the registry the snapshot was taken of.
+    pub fn to_registry(&self) -> Result<ModelRegistry, SnapshotError> {
+        let graph = self.rebuild()?;
+        let mut catalogue = RecipeRegistry::new();
+        for record in &self.items {
+            catalogue.add_shared_item(graph.items[&record.id].clone()).map_err(|_| SnapshotError::DuplicateId(record.id))?;
+        }
+        for recipe_id in &self.catalogue {
+            let recipe = graph.recipes.get(recipe_id)
+                .ok_or(SnapshotError::MissingReference { kind: "recipe", id: *recipe_id })?;
+            catalogue.add_recipe((**recipe).clone()).map_err(|_| SnapshotError::DuplicateId(*recipe_id))?;
+        }
+        // Machines are given the catalogue's recipes, which share the catalogue's items.
+        let mut machines = graph.machines;
+        for machine in machines.iter_mut() {
+            for recipe in machine.recipes.iter_mut() {
+                if let Some(shared) = catalogue.recipe(recipe.id) {
+                    *recipe = shared;
+                }
+            }
+        }
+        let lines = self.lines.iter()
+            .map(|line| LineLayout {
+                id: line.id,
+                name: line.name.clone(),
+                machine_ids: line.machine_ids.clone(),
+                buffer_ids: line.buffer_ids.clone(),
+            })
+            .collect();
+        Ok(ModelRegistry::from_parts(catalogue, machines, graph.buffers, lines))
+    }
+
+    /// Rebuilds the single transfer line the snapshot was taken of.
+    pub fn to_line(&self) -> Result<TransferLine, SnapshotError> {
+        let [record] = self.lines.as_slice() else {
+            return Err(SnapshotError::NotALine);
+        };
+        let graph = self.rebuild()?;
+        let mut line = TransferLine::new(vec![], vec![], vec![]);
+        line.id = record.id;
+        for machine_id in &record.machine_ids {
+            let machine = graph.machines.iter()
+                .find(|machine| machine.id == *machine_id)
+                .ok_or(SnapshotError::MissingReference { kind: "machine", id: *machine_id })?;
+            line.processing_times.push(machine.processing_time.clone());
+            line.machines.push(machine.clone());
+        }
+        for buffer_id in &record.buffer_ids {
+            let buffer = graph.buffers.iter()
+                .find(|buffer| buffer.lock().unwrap().id == *buffer_id)
+                .ok_or(SnapshotError::MissingReference { kind: "buffer", id: *buffer_id })?;
+            line.capacities.push(buffer.lock().unwrap().capacity);
+            line.buffers.push(buffer.clone());
+        }
+        line.time_step = record.time_step.unwrap_or(1);
+        line.num_items = line.machines.iter().map(|machine| machine.num_items).sum::<usize>()
+            + line.buffers.iter().map(|buffer| buffer.lock().unwrap().num_items).sum::<usize>();
+        Ok(line)
+    }
+
+    pub fn encode(&self, format: SnapshotFormat) -> Result<Vec<u8>, SnapshotError> {
+        match format {
+            SnapshotFormat::Json => serde_json::to_vec_pretty(self).map_err(|error| SnapshotError::Decode(error.to_string())),
+            SnapshotFormat::Binary => {
+                let mut bytes = BINARY_MAGIC.to_vec();
+                bytes.extend_from_slice(&self.schema_version.to_le_bytes());
+                let body = bincode::serialize(self).map_err(|error| SnapshotError::Decode(error.to_string()))?;
+                bytes.extend_from_slice(&body);
+                Ok(bytes)
+            }
+        }
+    }
+
+    /// Decodes a snapshot in either format, telling them apart by the binary header.
+    pub fn decode(bytes: &[u8]) -> Result<ModelSnapshot, SnapshotError> {
+        if let Some(body) = bytes.strip_prefix(BINARY_MAGIC) {
+            let version = body.get(..4)
+                .map(|version| u32::from_le_bytes([version[0], version[1], version[2], version[3]]))
+                .ok_or(SnapshotError::Decode("Binary snapshot header is truncated.".to_string()))?;
+            check_version(version)?;
+            return bincode::deserialize(&body[4..]).map_err(|error| SnapshotError::Decode(error.to_string()));
+        }
+        // The version is read on its own first, so that a snapshot from another
+        // version is reported as such rather than as a decoding error.
+        let value: serde_json::Value = serde_json::from_slice(bytes).map_err(|error| SnapshotError::Decode(error.to_string()))?;
+        let version = value.get("schema_version")
+            .and_then(|version| version.as_u64())
+            .ok_or(SnapshotError::Decode("Snapshot has no schema version.".to_string()))?;
+        check_version(version as u32)?;
+        serde_json::from_value(value).map_err(|error| SnapshotError::Decode(error.to_string()))
+    }
+
+    pub fn save(&self, path: &Path, format: SnapshotFormat) -> Result<(), SnapshotError> {
+        std::fs::write(path, self.encode(format)?).map_err(|error| SnapshotError::Io(error.to_string()))
+    }
+
+    pub fn load(path: &Path) -> Result<ModelSnapshot, SnapshotError> {
+        let bytes = std::fs::read(path).map_err(|error| SnapshotError::Io(error.to_string()))?;
+        ModelSnapshot::decode(&bytes)
+    }
+
+    fn empty() -> ModelSnapshot {
+        ModelSnapshot {
+            schema_version: SCHEMA_VERSION,
+            items: Vec::new(),
+            recipes: Vec::new(),
+            catalogue: Vec::new(),
+            buffers: Vec::new(),
+            machines: Vec::new(),
+            lines: Vec::new(),
+        }
+    }
+
+    fn add_item(&mut self, item: &Item) {
+        if !self.items.iter().any(|record| record.id == item.id()) {
+            self.items.push(ItemRecord { id: item.id(), name: item.name.clone(), size: item.size, cost: item.cost });
+        }
+    }
+
+    fn add_recipe(&mut self, recipe: &Recipe) {
+        if self.recipes.iter().any(|record| record.id == recipe.id) {
+            return;
+        }
+        for (item, _) in recipe.input.iter().chain(recipe.output.iter()) {
+            self.add_item(item);
+        }
+        let ids = |list: &[(Arc<Item>, f64)]| list.iter().map(|(item, quantity)| (item.id(), *quantity)).collect();
+        self.recipes.push(RecipeRecord {
+            id: recipe.id,
+            name: recipe.name.clone(),
+            input: ids(&recipe.input),
+            output: ids(&recipe.output),
+            recycling: recipe.recycling,
+        });
+    }
+
+    fn add_buffer(&mut self, buffer: &Arc<Mutex<Buffer>>) {
+        let buffer = buffer.lock().unwrap();
+        if self.buffers.iter().any(|record| record.id == buffer.id) {
+            return;
+        }
+        for (item, _) in &buffer.items {
+            self.add_item(item);
+        }
+        self.buffers.push(BufferRecord {
+            id: buffer.id,
+            name: buffer.name.clone(),
+            capacity: buffer.capacity,
+            num_items: buffer.num_items,
+            throughput: buffer.throughput,
+            items: buffer.items.iter().map(|(item, quantity)| (item.id(), *quantity)).collect(),
+            rework_passes: buffer.rework_passes.clone(),
+        });
+    }
+
+    fn add_machine(&mut self, machine: &Machine) {
+        for recipe in &machine.recipes {
+            self.add_recipe(recipe);
+        }
+        // Buffers only reachable through the machine are kept too.
+        for buffer in machine.input_buffer.iter().chain(&machine.output_buffer).chain(&machine.rework_buffer).chain(&machine.rework_inputs) {
+            self.add_buffer(buffer);
+        }
+        let ids = |links: &[Arc<Mutex<Buffer>>]| links.iter().map(|link| link.lock().unwrap().id).collect();
+        let mut recipe_cycles: Vec<(Uuid, usize)> = machine.recipe_cycles.iter().map(|(id, cycles)| (*id, *cycles)).collect();
+        recipe_cycles.sort();
+        self.machines.push(MachineRecord {
+            id: machine.id,
+            markov_chain: machine.markov_chain.clone(),
+            failure_model: machine.failure_model.clone(),
+            processing_time: machine.processing_time.clone(),
+            output_name: machine.output_name.clone(),
+            hourly_rate: machine.hourly_rate,
+            input_buffers: ids(&machine.input_buffer),
+            output_buffers: ids(&machine.output_buffer),
+            recipes: machine.recipes.iter().map(|recipe| recipe.id).collect(),
+            state: machine.state,
+            num_items: machine.num_items,
+            progress: machine.progress,
+            cycle_time: machine.cycle_time,
+            completed: machine.completed,
+            current_recipe: machine.current_recipe,
+            last_recipe: machine.last_recipe,
+            recipe_cycles,
+            setup_matrix: machine.setup_matrix.clone(),
+            dispatch: machine.dispatch,
+            setup_remaining: machine.setup_remaining,
+            setups: machine.setups,
+            run_length: machine.run_length,
+            batch: machine.batch,
+            batch_wait: machine.batch_wait,
+            blocking: machine.blocking,
+            quality: machine.quality.clone(),
+            rework_buffer: machine.rework_buffer.as_ref().map(|link| link.lock().unwrap().id),
+            rework_inputs: ids(&machine.rework_inputs),
+            cycle_outcome: machine.cycle_outcome,
+            cycle_rework: machine.cycle_rework.clone(),
+            cycle_rework_outcome: machine.cycle_rework_outcome,
+            quality_counts: machine.quality_counts,
+            rework_counts: machine.rework_counts,
+            parts_since_repair: machine.parts_since_repair,
+            maintenance: machine.maintenance.clone(),
+            degradation: machine.degradation.clone(),
+        });
+    }
+
+    /// Checks every reference in the snapshot and rebuilds the shared graph.
+    fn rebuild(&self) -> Result<Graph, SnapshotError> {
+        let mut seen: HashSet<Uuid> = HashSet::new();
+        let ids = self.items.iter().map(|item| item.id)
+            .chain(self.recipes.iter().map(|recipe| recipe.id))
+            .chain(self.buffers.iter().map(|buffer| buffer.id))
+            .chain(self.machines.iter().map(|machine| machine.id))
+            .chain(self.lines.iter().map(|line| line.id));
+        for id in ids {
+            if !seen.insert(id) {
+                return Err(SnapshotError::DuplicateId(id));
+            }
+        }
+
+        let items: HashMap<Uuid, Arc<Item>> = self.items.iter()
+            .map(|record| (record.id, Arc::new(Item::with_id(record.id, record.name.clone(), record.size, record.cost))))
+            .collect();
+        let item = |id: &Uuid| items.get(id).cloned().ok_or(SnapshotError::MissingReference { kind: "item", id: *id });
+        let linked = |list: &[(Uuid, f64)]| -> Result<Vec<(Arc<Item>, f64)>, SnapshotError> {
+            list.iter().map(|(id, quantity)| Ok((item(id)?, *quantity))).collect()
+        };
+
+        let mut recipes: HashMap<Uuid, Arc<Recipe>> = HashMap::new();
+        for record in &self.recipes {
+            let mut recipe = Recipe::new(record.name.clone(), linked(&record.input)?, linked(&record.output)?);
+            recipe.id = record.id;
+            recipe.recycling = record.recycling;
+            recipes.insert(record.id, Arc::new(recipe));
+        }
+
+        let mut buffers: Vec<Arc<Mutex<Buffer>>> = Vec::with_capacity(self.buffers.len());
+        for record in &self.buffers {
+            let mut buffer = Buffer::new(record.capacity, record.throughput, record.name.clone());
+            buffer.id = record.id;
+            buffer.num_items = record.num_items;
+            buffer.items = linked(&record.items)?;
+            buffer.rework_passes = record.rework_passes.clone();
+            buffers.push(Arc::new(Mutex::new(buffer)));
+        }
+        let buffer_index: HashMap<Uuid, usize> = self.buffers.iter().enumerate().map(|(i, record)| (record.id, i)).collect();
+        let buffer = |id: &Uuid| {
+            buffer_index.get(id)
+                .map(|&index| buffers[index].clone())
+                .ok_or(SnapshotError::MissingReference { kind: "buffer", id: *id })
+        };
+
+        let mut machines = Vec::with_capacity(self.machines.len());
+        for record in &self.machines {
+            if !record.markov_chain.is_consistent() {
+                return Err(SnapshotError::InvalidMarkovChain(record.id));
+            }
+            if let Err(error) = record.processing_time.validate() {
+                return Err(SnapshotError::Decode(format!("Machine {}: {}", record.id, error)));
+            }
+            let failure_model = &record.failure_model;
+            let modes_valid = failure_model.modes.iter()
+                .all(|mode| mode.time_to_failure.validate().is_ok() && mode.time_to_repair.validate().is_ok());
+            if record.batch.is_some_and(|batch| batch.validate().is_err()) {
+                return Err(SnapshotError::Decode(format!("Machine {} has an invalid batch mode.", record.id)));
+            }
+            if record.quality.validate().is_err() {
+                return Err(SnapshotError::Decode(format!("Machine {} has an invalid yield.", record.id)));
+            }
+            if record.maintenance.policy.as_ref().is_some_and(|policy| policy.validate().is_err()) {
+                return Err(SnapshotError::Decode(format!("Machine {} has an invalid maintenance policy.", record.id)));
+            }
+            if record.degradation.as_ref().is_some_and(|degradation| degradation.validate().is_err()) {
+                return Err(SnapshotError::Decode(format!("Machine {} has an invalid degradation.", record.id)));
+            }
+            if record.setup_matrix.validate().is_err() {
+                return Err(SnapshotError::Decode(format!("Machine {} has an invalid setup time.", record.id)));
+            }
+            if !modes_valid || failure_model.repairing.is_some_and(|index| index >= failure_model.modes.len()) {
+                return Err(SnapshotError::Decode(format!("Machine {} has an invalid failure mode.", record.id)));
+            }
+            let mut machine = Machine::new(record.markov_chain.clone(), record.processing_time.clone(), record.output_name.clone());
+            machine.id = record.id;
+            machine.failure_model = record.failure_model.clone();
+            machine.hourly_rate = record.hourly_rate;
+            machine.input_buffer = record.input_buffers.iter().map(buffer).collect::<Result<_, _>>()?;
+            machine.output_buffer = record.output_buffers.iter().map(buffer).collect::<Result<_, _>>()?;
+            machine.rework_buffer = record.rework_buffer.as_ref().map(buffer).transpose()?;
+            machine.rework_inputs = record.rework_inputs.iter().map(buffer).collect::<Result<_, _>>()?;
+            machine.recipes = record.recipes.iter()
+                .map(|id| recipes.get(id).cloned().ok_or(SnapshotError::MissingReference { kind: "recipe", id: *id }))
+                .collect::<Result<_, _>>()?;
+            let in_range = |index: Option<usize>| index.is_none_or(|index| index < machine.recipes.len());
+            if !in_range(record.current_recipe) || !in_range(record.last_recipe) {
+                return Err(SnapshotError::Decode(format!("Machine {} refers to a recipe it does not have.", record.id)));
+            }
+            machine.state = record.state;
+            machine.num_items = record.num_items;
+            machine.progress = record.progress;
+            machine.cycle_time = record.cycle_time;
+            machine.completed = record.completed;
+            machine.current_recipe = record.current_recipe;
+            machine.last_recipe = record.last_recipe;
+            machine.recipe_cycles = record.recipe_cycles.iter().copied().collect();
+            machine.setup_matrix = record.setup_matrix.clone();
+            machine.dispatch = record.dispatch;
+            machine.setup_remaining = record.setup_remaining;
+            machine.setups = record.setups;
+            machine.run_length = record.run_length;
+            machine.batch = record.batch;
+            machine.batch_wait = record.batch_wait;
+            machine.blocking = record.blocking;
+            machine.quality = record.quality.clone();
+            machine.cycle_outcome = record.cycle_outcome;
+            machine.cycle_rework = record.cycle_rework.clone();
+            machine.cycle_rework_outcome = record.cycle_rework_outcome;
+            machine.quality_counts = record.quality_counts;
+            machine.rework_counts = record.rework_counts;
+            machine.parts_since_repair = record.parts_since_repair;
+            machine.maintenance = record.maintenance.clone();
+            machine.degradation = record.degradation.clone();
+            machines.push(machine);
+        }
+
+        for line in &self.lines {
+            for id in &line.machine_ids {
+                if !self.machines.iter().any(|machine| machine.id == *id) {
+                    return Err(SnapshotError::MissingReference { kind: "machine", id: *id });
+                }
+            }
+            for id in &line.buffer_ids {
+                buffer(id)?;
+            }
+        }
+
+        Ok(Graph { items, recipes, buffers, machines })
+    }
+}
+
+struct Graph {
+    items: HashMap<Uuid, Arc<Item>>,
+    recipes: HashMap<Uuid, Arc<Recipe>>,
+    buffers: Vec<Arc<Mutex<Buffer>>>,
+    machines: Vec<Machine>,
+}
+
+fn check_version(found: u32) -> Result<(), SnapshotError> {
+    if found == SCHEMA_VERSION {
+        Ok(())
+    } else {
+        Err(SnapshotError::SchemaVersion { found, expected: SCHEMA_VERSION })
+    }
+}
@@ -1,32 +1,38 @@
-/// The goal of this module is to provide a way to represent a transfer line
-/// in a manufacturing system. A transfer line is a set of machines, M_1 to M_n,
-/// which are connected by a set of buffers B_1 to B_n-1. Each buffer B_i is
-/// connected to machines M_i and M_i+1. The first machine M_1 is connected to
-/// a source of items, and the last machine M_n is connected to a sink of items.
-/// Each machine M_i has a processing time P_i, and each buffer B_i has a
-/// capacity C_i. 
-/// Each machine in the transfer line is represented by a Markov chain. The
-/// state of the Markov chain is the state of the machine, Idle, Working, or
-/// Broken.
-/// 
-/// The transfer line is represented by a struct called TransferLine.
-
-use rand::*;
-use std::collections::HashSet;
-use crate::machine::Machine;
+//! The goal of this module is to provide a way to represent a transfer line
+//! in a manufacturing system. A transfer line is a set of machines, M_1 to M_n,
+//! which are connected by a set of buffers B_1 to B_n-1. Each buffer B_i is
+//! connected to machines M_i and M_i+1. The first machine M_1 is connected to
+//! a source of items, and the last machine M_n is connected to a sink of items.
+//! Each machine M_i has a processing time P_i, and each buffer B_i has a
+//! capacity C_i. 
+//! Each machine in the transfer line is represented by a Markov chain. The
+//! state of the Markov chain is the state of the machine, Idle, Working, or
+//! Broken.
+//! 
+//! The transfer line is represented by a struct called TransferLine.
+
+use std::sync::{Arc, Mutex};
+use crate::clock::SimClock;
+use crate::distribution::Distribution;
+use crate::machine::{Machine, MachineState};
 use crate::markov::MarkovChain;
+use crate::quality::QualityCounts;
 use crate::queue::Buffer;
+use crate::random::RngRegistry;
+use crate::simulation::SimulationRun;
 use uuid::Uuid;
 
 /// A struct representing a transfer line in a manufacturing system.
+/// Buffer B_i sits between machines M_i and M_i+1 and is shared with both
+/// machines as their output and input buffer respectively.
 pub struct TransferLine {
     pub id: Uuid,
     /// The machines in the transfer line.
     pub machines: Vec<Machine>,
     /// The buffers in the transfer line.
-    pub buffers: Vec<Buffer>,
+    pub buffers: Vec<Arc<Mutex<Buffer>>>,
     /// The processing times of the machines.
-    pub processing_times: Vec<f64>,
+    pub processing_times: Vec<Distribution>,
     /// The capacities of the buffers.
     pub capacities: Vec<usize>,
     /// The number of items in the transfer line.
@@ -38,35 +44,66 @@ pub struct TransferLine {
 impl TransferLine {
     /// Creates a new transfer line.
     pub fn new(processing_times: Vec<f64>, capacities: Vec<usize>, throughputs: Vec<Option<f64>>) -> TransferLine {
-        let mut machines = Vec::new();
-        let mut buffers = Vec::new();
-        let num_machines = processing_times.len();
-        let num_buffers = capacities.len();
-        for i in 0..num_machines {
-            machines.push(Machine::new(MarkovChain::new(), processing_times[i], None));
-        }
-        for i in 0..num_buffers {
-            buffers.push(Buffer::new(capacities[i], throughputs[i], None));
-        }
-        TransferLine {
+        let mut transfer_line = TransferLine {
             id: Uuid::new_v4(),
-            machines: machines,
-            buffers: buffers,
-            processing_times: processing_times,
-            capacities: capacities,
+            machines: Vec::new(),
+            buffers: Vec::new(),
+            processing_times: Vec::new(),
+            capacities: Vec::new(),
             num_items: 0,
             time_step: 1,
+        };
+        for processing_time in processing_times {
+            transfer_line.add_machine(processing_time, None);
+        }
+        for (i, capacity) in capacities.into_iter().enumerate() {
+            transfer_line.add_buffer(capacity, throughputs.get(i).copied().flatten());
         }
+        transfer_line
     }
 
-    /// Adds a machine to the transfer line.
-    pub fn add_machine(&mut self, processing_time: f64, output: Option<String>) {
-        self.machines.push(Machine::new(MarkovChain::new(), processing_time, output));
+    /// Adds a machine to the end of the transfer line.
+    pub fn add_machine(&mut self, processing_time: impl Into<Distribution>, output: Option<String>) {
+        self.push_machine(Machine::new(MarkovChain::new(), processing_time, output));
     }
 
-    /// Adds a buffer to the transfer line.
+    /// Adds an already configured machine to the end of the transfer line.
+    pub fn push_machine(&mut self, machine: Machine) {
+        self.processing_times.push(machine.processing_time.clone());
+        self.machines.push(machine);
+        self.connect(self.machines.len() - 1);
+    }
+
+    /// Adds a buffer to the end of the transfer line.
     pub fn add_buffer(&mut self, capacity: usize, throughput: Option<f64>) {
-        self.buffers.push(Buffer::new(capacity, throughput, None));
+        self.push_buffer(Buffer::new(capacity, throughput, None));
+    }
+
+    /// Adds an already configured buffer to the end of the transfer line.
+    pub fn push_buffer(&mut self, buffer: Buffer) {
+        self.capacities.push(buffer.capacity);
+        self.buffers.push(Arc::new(Mutex::new(buffer)));
+        self.connect(self.buffers.len());
+    }
+
+    /// Connects buffer B_i-1 between machines M_i-1 and M_i once all three exist.
+    fn connect(&mut self, machine_index: usize) {
+        if machine_index == 0 || machine_index >= self.machines.len() || machine_index > self.buffers.len() {
+            return;
+        }
+        let buffer = self.buffers[machine_index - 1].clone();
+        self.machines[machine_index - 1].add_output_buffer(buffer.clone());
+        self.machines[machine_index].add_input_buffer(buffer);
+    }
+
+    /// Changes the processing time of the machine at the given position. A cycle
+    /// in progress takes the mean of the new processing time.
+    pub fn set_processing_time(&mut self, machine_index: usize, processing_time: impl Into<Distribution>) {
+        let processing_time = processing_time.into();
+        let machine = &mut self.machines[machine_index];
+        machine.cycle_time = processing_time.mean();
+        machine.processing_time = processing_time.clone();
+        self.processing_times[machine_index] = processing_time;
     }
 
     /// Adds an item to the transfer line.
@@ -84,11 +121,135 @@ impl TransferLine {
         self.num_items
     }
 
-    // Steps the transfer line forward one time step.
-    // pub fn step(&mut self) {
-    //     // step the machines forward
-    //     for machine in &mut self.machines {
-    //         self.step(machine);
-    //     }
-    // }     
+    /// Returns the number of finished parts that have left the transfer line.
+    pub fn num_completed(&self) -> usize {
+        self.machines.last().map_or(0, |machine| machine.completed)
+    }
+
+    /// Steps the transfer line forward one time step and returns the state each
+    /// machine spent the step in.
+    /// Machines are stepped from the end of the line backwards so that space freed
+    /// downstream is available within the same step, while a part still moves at
+    /// most one station per step.
+    pub fn step(&mut self, streams: &mut RngRegistry) -> Vec<MachineState> {
+        let mut states = vec![MachineState::Idle; self.machines.len()];
+        for (i, machine) in self.machines.iter_mut().enumerate().rev() {
+            states[i] = machine.step(streams);
+        }
+        self.num_items = self.machines.iter().map(|machine| machine.num_items).sum::<usize>()
+            + self.buffers.iter().chain(&self.rework_buffers()).map(|buffer| buffer.lock().unwrap().num_items).sum::<usize>();
+        self.time_step += 1;
+        states
+    }
+
+    /// The rework buffers the machines of the line send parts to or take them from.
+    pub fn rework_buffers(&self) -> Vec<Arc<Mutex<Buffer>>> {
+        let mut buffers: Vec<Arc<Mutex<Buffer>>> = Vec::new();
+        for link in self.machines.iter().flat_map(|machine| machine.rework_buffer.iter().chain(&machine.rework_inputs)) {
+            if !buffers.iter().any(|buffer| Arc::ptr_eq(buffer, link)) {
+                buffers.push(link.clone());
+            }
+        }
+        buffers
+    }
+
+    /// Runs the transfer line for the given number of time steps and records the run.
+    /// The machines are registered with the random stream registry in line order first,
+    /// and their failure modes after them, so that each keeps its streams across runs
+    /// and copies of the line.
+    pub fn run(&mut self, horizon: usize, streams: &mut RngRegistry) -> SimulationRun {
+        let mut run = self.start_run(streams);
+        for _ in 0..horizon {
+            self.record_step(&mut run, streams);
+        }
+        run
+    }
+
+    /// Runs the transfer line like `run`, taking one time step per unit of simulated
+    /// time on the given clock, so that the run can be paced, paused and stepped.
+    pub async fn run_on(&mut self, horizon: usize, streams: &mut RngRegistry, clock: &mut SimClock<()>) -> SimulationRun {
+        let mut run = self.start_run(streams);
+        for _ in 0..horizon {
+            clock.schedule_in(1.0, ());
+            if clock.next_event().await.is_none() {
+                break;
+            }
+            self.record_step(&mut run, streams);
+        }
+        run
+    }
+
+    fn start_run(&self, streams: &mut RngRegistry) -> SimulationRun {
+        for machine in &self.machines {
+            streams.register(machine.id);
+        }
+        for mode in self.machines.iter().flat_map(|machine| &machine.failure_model.modes) {
+            streams.register(mode.id);
+        }
+        let mut run = SimulationRun::new(self.machines.len(), self.buffers.len());
+        run.scrap_costs = self.machines.iter().map(|machine| machine.quality.scrap_cost).collect();
+        run.maintenance_resources = self.machines.iter()
+            .map(|machine| machine.maintenance.policy.as_ref().map_or_else(Vec::new, |policy| policy.resources.clone()))
+            .collect();
+        run
+    }
+
+    fn record_step(&mut self, run: &mut SimulationRun, streams: &mut RngRegistry) {
+        let completed = self.num_completed();
+        let maintenance_cost = self.maintenance_cost();
+        let counts: Vec<QualityCounts> = self.machines.iter().map(|machine| machine.quality_counts).collect();
+        let rework_counts: Vec<QualityCounts> = self.machines.iter().map(|machine| machine.rework_counts).collect();
+        let states = self.step(streams);
+        let levels = self.buffers.iter().map(|buffer| buffer.lock().unwrap().num_items).collect();
+        let quality = self.machines.iter().zip(counts).map(|(machine, counts)| machine.quality_counts.since(counts)).collect();
+        let rework = self.machines.iter().zip(rework_counts).map(|(machine, counts)| machine.rework_counts.since(counts)).collect();
+        run.record(states, levels, self.num_completed() - completed, self.num_items);
+        run.record_quality(quality, rework);
+        run.record_maintenance_cost(self.maintenance_cost() - maintenance_cost);
+    }
+
+    /// The cost of the maintenance and failures of every machine so far.
+    fn maintenance_cost(&self) -> f64 {
+        self.machines.iter().map(|machine| machine.maintenance.cost()).sum()
+    }
+}
+
+impl Clone for TransferLine {
+    /// Deep copies the transfer line, giving the copy its own buffers wired up the same
+    /// way. Rework buffers shared by two machines stay shared between their copies.
+    fn clone(&self) -> TransferLine {
+        let copy = |buffer: &Arc<Mutex<Buffer>>| Arc::new(Mutex::new(buffer.lock().unwrap().clone()));
+        let buffers: Vec<Arc<Mutex<Buffer>>> = self.buffers.iter().map(copy).collect();
+        let rework_buffers = self.rework_buffers();
+        let rework_copies: Vec<Arc<Mutex<Buffer>>> = rework_buffers.iter().map(copy).collect();
+        let rewire_one = |link: &Arc<Mutex<Buffer>>| -> Arc<Mutex<Buffer>> {
+            if let Some(index) = self.buffers.iter().position(|buffer| Arc::ptr_eq(buffer, link)) {
+                return buffers[index].clone();
+            }
+            match rework_buffers.iter().position(|buffer| Arc::ptr_eq(buffer, link)) {
+                Some(index) => rework_copies[index].clone(),
+                None => copy(link),
+            }
+        };
+        let rewire = |links: &Vec<Arc<Mutex<Buffer>>>| -> Vec<Arc<Mutex<Buffer>>> { links.iter().map(rewire_one).collect() };
+        let machines = self.machines.iter()
+            .map(|machine| {
+                let mut copy = machine.clone();
+                copy.input_buffer = rewire(&machine.input_buffer);
+                copy.output_buffer = rewire(&machine.output_buffer);
+                copy.rework_buffer = machine.rework_buffer.as_ref().map(rewire_one);
+                copy.rework_inputs = rewire(&machine.rework_inputs);
+                copy
+            })
+            .collect();
+        TransferLine {
+            id: self.id,
+            machines,
+            buffers,
+            processing_times: self.processing_times.clone(),
+            capacities: self.capacities.clone(),
+            num_items: self.num_items,
+            time_step: self.time_step,
+        }
+    }
 }